@@ -0,0 +1,393 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use chrono::{UTC, DateTime, Duration};
+
+use core::{Device, Measurement, MeasurementPoint};
+use super::{Rule, Action};
+
+/// Name of the file of the data directory where `Action::Log` write events
+pub const ALERT_LOG_FILENAME: &'static str = "alerts.log";
+
+/// State of a rule for a device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// The condition is not met
+    Ok,
+    /// The condition is met for less than the duration of the rule
+    Pending,
+    Firing,
+    /// No point received for the staleness timeout of the rule
+    Stale,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            State::Ok      => write!(f, "{}", "ok"),
+            State::Pending => write!(f, "{}", "pending"),
+            State::Firing  => write!(f, "{}", "firing"),
+            State::Stale   => write!(f, "{}", "stale"),
+        }
+    }
+}
+
+impl FromStr for State {
+
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<State, ()> {
+        match s {
+            "ok"      => Ok(State::Ok),
+            "pending" => Ok(State::Pending),
+            "firing"  => Ok(State::Firing),
+            "stale"   => Ok(State::Stale),
+            _         => Err(()),
+        }
+    }
+}
+
+/// Current state of a rule for a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub rule: String,
+    pub device: Device,
+    pub state: State,
+    /// Date of the last change of state
+    pub since: DateTime<UTC>,
+    /// Last measurement checked against the condition
+    pub value: Option<Measurement>,
+    /// Date the last point of the device was received
+    pub last_seen: DateTime<UTC>,
+    /// State resumed when a stale device is seen again
+    pub before_stale: State,
+}
+
+impl Status {
+
+    /// Format this status as a line, `rule slug state since value`
+    ///
+    /// The value is `-` if no measurement was checked yet.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {} {} {}\n", self.rule, self.device.get_slug(), self.state,
+                self.since.to_rfc3339(), optional(&self.value))
+    }
+
+    /// Parse a line created by `to_line`
+    pub fn from_line(line: &str) -> Option<Status> {
+        let parts: Vec<&str> = line.trim_right_matches('\n').split(' ').collect();
+
+        if parts.len() != 5 {
+            return None;
+        }
+
+        let device = match Device::with_slug(parts[1]) { Ok(x) => x, Err(_) => return None };
+        let state = match State::from_str(parts[2]) { Ok(x) => x, Err(_) => return None };
+        let since = match DateTime::parse_from_rfc3339(parts[3]) {
+            Ok(x)  => x.with_timezone(&UTC),
+            Err(_) => return None,
+        };
+        let value = match parts[4] {
+            "-" => None,
+            s   => match Measurement::from_str(s) { Ok(x) => Some(x), Err(_) => return None },
+        };
+
+        Some( Status {
+            rule: parts[0].to_string(),
+            device: device,
+            state: state,
+            since: since,
+            value: value,
+            last_seen: since,
+            before_stale: State::Ok,
+        })
+    }
+}
+
+/// A change of state of a rule for a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub rule: String,
+    pub device: Device,
+    pub previous: State,
+    pub state: State,
+    pub date: DateTime<UTC>,
+    pub value: Option<Measurement>,
+    /// Actions of the rule to run
+    pub actions: Vec<Action>,
+}
+
+impl Event {
+
+    /// Format this event as a line, `date rule slug previous state value`
+    pub fn to_line(&self) -> String {
+        format!("{} {} {} {} {} {}\n", self.date.to_rfc3339(), self.rule,
+                self.device.get_slug(), self.previous, self.state, optional(&self.value))
+    }
+}
+
+fn optional(value: &Option<Measurement>) -> String {
+    match *value {
+        Some(ref m) => m.to_string(),
+        None        => "-".to_string(),
+    }
+}
+
+/// Keep the state of every rule for every device seen since it started
+///
+/// Conditions and durations are evaluated on the dates of the points while
+/// staleness is checked against the server clock: the date points are
+/// received, given to `observe`, and the date given to `check_stale`. So
+/// late or backfilled points keep a device alive. A stale device seen again
+/// resume the state it had, a `Firing` rule is not resolved by staleness.
+/// Only `Pending` to `Ok` and `Ok` to `Pending` changes don't give an
+/// `Event`.
+pub struct AlertEngine {
+    rules: Vec<Rule>,
+    statuses: BTreeMap<(usize, Device), Status>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<Rule>) -> AlertEngine {
+        AlertEngine {
+            rules: rules,
+            statuses: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_rules<'a>(&'a self) -> &'a [Rule] {
+        &self.rules
+    }
+
+    /// Return `true` if a rule has a staleness timeout
+    pub fn checks_stale(&self) -> bool {
+        self.rules.iter().any(|r| r.get_stale().is_some())
+    }
+
+    /// Evaluate the rules selecting the device of `mp`, received at `now`,
+    /// and return the changes of state
+    ///
+    /// A measurement of another unit than the condition of a rule is
+    /// ignored by it, except to tell that the device is alive.
+    pub fn observe(&mut self, mp: &MeasurementPoint, now: &DateTime<UTC>) -> Vec<Event> {
+        let mut events = Vec::new();
+        let date = mp.get_date();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.get_selector().matches(mp.get_device()) {
+                continue;
+            }
+
+            let key = (index, mp.get_device().clone());
+            let status = self.statuses.entry(key).or_insert(Status {
+                rule: rule.get_name().to_string(),
+                device: mp.get_device().clone(),
+                state: State::Ok,
+                since: date,
+                value: None,
+                last_seen: *now,
+                before_stale: State::Ok,
+            });
+
+            if *now > status.last_seen {
+                status.last_seen = *now;
+            }
+
+            if status.state == State::Stale {
+                let state = status.before_stale;
+                change(status, rule, state, date, &mut events);
+            }
+
+            let condition = match rule.get_condition() {
+                Some(x) => x,
+                None    => continue,
+            };
+
+            let value = match mp.get_data().iter().nth(rule.get_channel()) {
+                Some(m) if m.get_unit() == condition.threshold.get_unit() => m.clone(),
+                Some(m) => {
+                    debug!("Rule {} ignore {} of {}, not in {:?}", rule.get_name(), m,
+                           mp.get_device(), condition.threshold.get_unit());
+                    continue;
+                },
+                None => continue,
+            };
+
+            status.value = Some(value.clone());
+
+            match status.state {
+                State::Ok if condition.is_met(&value) => {
+                    if rule.get_duration() <= Duration::zero() {
+                        change(status, rule, State::Firing, date, &mut events);
+                    } else {
+                        status.state = State::Pending;
+                        status.since = date;
+                    }
+                },
+                State::Pending if !condition.is_met(&value) => {
+                    status.state = State::Ok;
+                    status.since = date;
+                },
+                State::Pending if date - status.since >= rule.get_duration() => {
+                    change(status, rule, State::Firing, date, &mut events);
+                },
+                State::Firing if condition.is_cleared(&value, rule.get_hysteresis()) => {
+                    change(status, rule, State::Ok, date, &mut events);
+                },
+                _ => {},
+            }
+        }
+
+        events
+    }
+
+    /// Mark stale the devices silent for longer than the staleness timeout
+    /// of their rules at `now`, and return the changes of state
+    pub fn check_stale(&mut self, now: &DateTime<UTC>) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for (&(index, _), status) in self.statuses.iter_mut() {
+            let rule = &self.rules[index];
+
+            let stale = match rule.get_stale() {
+                Some(x) => x,
+                None    => continue,
+            };
+
+            if status.state != State::Stale && *now - status.last_seen >= stale {
+                status.before_stale = status.state;
+                change(status, rule, State::Stale, *now, &mut events);
+            }
+        }
+
+        events
+    }
+
+    /// Return the status of every rule and device pair, sorted by rule
+    pub fn statuses(&self) -> Vec<Status> {
+        self.statuses.values().cloned().collect()
+    }
+}
+
+fn change(status: &mut Status, rule: &Rule, state: State, date: DateTime<UTC>,
+          events: &mut Vec<Event>) {
+
+    events.push( Event {
+        rule: rule.get_name().to_string(),
+        device: status.device.clone(),
+        previous: status.state,
+        state: state,
+        date: date,
+        value: status.value.clone(),
+        actions: rule.get_actions().to_vec(),
+    });
+
+    status.state = state;
+    status.since = date;
+}
+
+
+#[test]
+fn test_alert_engine() {
+    use chrono::TimeZone;
+    use core::{DeviceSelector, MeasurementsList};
+    use super::Condition;
+
+    let mut rule = Rule::new("core-hot", DeviceSelector::from_str("temp*@core.lm").unwrap(), 1)
+                        .unwrap();
+    rule.set_condition( Condition::from_str("> 80[K]").unwrap(),
+                        Some(Measurement::from_str("2[K]").unwrap()) ).unwrap();
+    rule.set_duration( Duration::seconds(60) );
+    rule.set_stale( Some(Duration::seconds(300)) );
+    rule.add_action(Action::Log);
+
+    let mut engine = AlertEngine::new(vec![rule]);
+    assert!( engine.checks_stale() );
+
+    let start = UTC.ymd(2015, 6, 1).and_hms(12, 0, 0);
+    let device = Device::with_slug("temp1@core.lm").unwrap();
+
+    let mut observe = |secs: i64, data: &str| {
+        let date = start + Duration::seconds(secs);
+        let mp = MeasurementPoint::new(device.clone(), date, MeasurementsList::from_str(data).unwrap());
+        engine.observe(&mp, &date).iter().map(|e| e.state).collect::<Vec<_>>()
+    };
+
+    // Pending for less than 60 s, then back to normal
+    assert_eq!( observe(0, "1[V] 81[K]"), vec![] );
+    assert_eq!( observe(30, "1[V] 79[K]"), vec![] );
+
+    // Fire after 60 s above the threshold
+    assert_eq!( observe(40, "1[V] 81[K]"), vec![] );
+    assert_eq!( observe(100, "1[V] 82[K]"), vec![State::Firing] );
+
+    // Other units and missing channels are ignored, the hysteresis keep firing
+    assert_eq!( observe(110, "1[V] 10[V]"), vec![] );
+    assert_eq!( observe(120, "1[V]"), vec![] );
+    assert_eq!( observe(130, "1[V] 79[K]"), vec![] );
+    assert_eq!( observe(140, "1[V] 78[K]"), vec![State::Ok] );
+
+    let statuses = engine.statuses();
+    assert_eq!( statuses.len(), 1 );
+    assert_eq!( statuses[0].state, State::Ok );
+    assert_eq!( Status::from_line(&statuses[0].to_line()).unwrap().to_line(),
+                statuses[0].to_line() );
+
+    // Silent for 5 minutes, then alive again
+    let events = engine.check_stale(&(start + Duration::seconds(300)));
+    assert!( events.is_empty() );
+
+    let events = engine.check_stale(&(start + Duration::seconds(440)));
+    assert_eq!( events.len(), 1 );
+    assert_eq!( events[0].to_line(),
+                "2015-06-01T12:07:20+00:00 core-hot temp1@core.lm ok stale 78[K]\n" );
+    assert_eq!( events[0].actions, vec![Action::Log] );
+    assert!( engine.check_stale(&(start + Duration::seconds(500))).is_empty() );
+
+    // A point taken long ago but received now tell the device is alive
+    let now = start + Duration::seconds(600);
+    let mp = MeasurementPoint::new(device.clone(), start + Duration::seconds(200),
+                                   MeasurementsList::from_str("1[V] 20[K]").unwrap());
+    let events = engine.observe(&mp, &now);
+    assert_eq!( events.len(), 1 );
+    assert_eq!( (events[0].previous, events[0].state), (State::Stale, State::Ok) );
+    assert!( engine.check_stale(&(now + Duration::seconds(200))).is_empty() );
+
+    // A firing rule is still firing after being stale
+    let mp = MeasurementPoint::new(device.clone(), now, MeasurementsList::from_str("1[V] 90[K]").unwrap());
+    engine.observe(&mp, &now);
+    let mp = MeasurementPoint::new(device.clone(), now + Duration::seconds(60),
+                                   MeasurementsList::from_str("1[V] 90[K]").unwrap());
+    assert_eq!( engine.observe(&mp, &(now + Duration::seconds(60)))[0].state, State::Firing );
+    assert_eq!( engine.check_stale(&(now + Duration::seconds(400)))[0].state, State::Stale );
+
+    let date = now + Duration::seconds(500);
+    let mp = MeasurementPoint::new(device.clone(), date, MeasurementsList::from_str("1[V] 85[K]").unwrap());
+    let events = engine.observe(&mp, &date);
+    assert_eq!( events.len(), 1 );
+    assert_eq!( (events[0].previous, events[0].state), (State::Stale, State::Firing) );
+
+    // Other devices are not tracked
+    let other = Device::with_slug("fan1@core.lm").unwrap();
+    let mp = MeasurementPoint::new(other, start, MeasurementsList::from_str("1[V] 90[K]").unwrap());
+    assert!( engine.observe(&mp, &now).is_empty() );
+    assert_eq!( engine.statuses().len(), 1 );
+}
@@ -0,0 +1,34 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Alerting rules evaluated on incoming `MeasurementPoint`
+//!
+//! A `Rule` watch a measurement position of the devices matching a
+//! selector. It fires when the measurement meet its `Condition` long enough
+//! and resolves once the measurement is back past the hysteresis. It can
+//! also fire when a device stay silent too long.
+//!
+//! `AlertEngine` keep the state of every rule and device pair and return an
+//! `Event` on each change. Running the `Action`s of the rule is left to the
+//! caller, the logger server.
+
+mod rule;
+pub use self::rule::{Rule, Condition, Comparison, Action, RuleError};
+
+mod engine;
+pub use self::engine::{AlertEngine, State, Status, Event, ALERT_LOG_FILENAME};
@@ -0,0 +1,406 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
+use chrono::Duration;
+
+use core::{DeviceSelector, Measurement};
+use regex;
+
+/// Comparison of a measurement with a threshold
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    /// `>`
+    Above,
+    /// `>=`
+    AtLeast,
+    /// `<`
+    Below,
+    /// `<=`
+    AtMost,
+}
+
+/// Condition on a measurement, like `> 80[K]`
+///
+/// # Example
+///
+/// ```
+/// use orion::alert::Condition;
+/// use orion::core::Measurement;
+/// use std::str::FromStr;
+///
+/// let condition = Condition::from_str("> 80[K]").unwrap();
+/// assert!( condition.is_met(&Measurement::from_str("81[K]").unwrap()) );
+/// assert!( !condition.is_met(&Measurement::from_str("80[K]").unwrap()) );
+///
+/// // Measurements of another unit never meet a condition
+/// assert!( !condition.is_met(&Measurement::from_str("81[V]").unwrap()) );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub comparison: Comparison,
+    pub threshold: Measurement,
+}
+
+impl Condition {
+
+    /// Return `true` if `m` meet this condition
+    pub fn is_met(&self, m: &Measurement) -> bool {
+        if m.get_unit() != self.threshold.get_unit() {
+            return false;
+        }
+
+        let (v, t) = (m.get_value(), self.threshold.get_value());
+
+        match self.comparison {
+            Comparison::Above   => v > t,
+            Comparison::AtLeast => v >= t,
+            Comparison::Below   => v < t,
+            Comparison::AtMost  => v <= t,
+        }
+    }
+
+    /// Return `true` if `m` is back past the threshold by more than
+    /// `hysteresis`, in the unit of the threshold
+    pub fn is_cleared(&self, m: &Measurement, hysteresis: f32) -> bool {
+        if m.get_unit() != self.threshold.get_unit() {
+            return false;
+        }
+
+        let (v, t) = (m.get_value(), self.threshold.get_value());
+
+        match self.comparison {
+            Comparison::Above   => v <= t - hysteresis,
+            Comparison::AtLeast => v < t - hysteresis,
+            Comparison::Below   => v >= t + hysteresis,
+            Comparison::AtMost  => v > t + hysteresis,
+        }
+    }
+}
+
+impl FromStr for Condition {
+
+    type Err = RuleError;
+
+    /// Parse a comparison operator, `>`, `>=`, `<` or `<=`, followed by a
+    /// `Measurement`
+    fn from_str(s: &str) -> Result<Condition, RuleError> {
+        let re = regex!(r"^(>=|<=|>|<)\s*(\S+)$");
+
+        let data = match re.captures(s.trim()) {
+            Some(x) => x,
+            None    => return Err(RuleError::InvalidCondition),
+        };
+
+        let comparison = match data.at(1) {
+            Some(">")  => Comparison::Above,
+            Some(">=") => Comparison::AtLeast,
+            Some("<")  => Comparison::Below,
+            Some("<=") => Comparison::AtMost,
+            _          => unreachable!(),
+        };
+
+        let threshold = match Measurement::from_str(data.at(2).unwrap_or("")) {
+            Ok(x)  => x,
+            Err(_) => return Err(RuleError::InvalidCondition),
+        };
+
+        Ok( Condition {
+            comparison: comparison,
+            threshold: threshold,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.comparison {
+            Comparison::Above   => ">",
+            Comparison::AtLeast => ">=",
+            Comparison::Below   => "<",
+            Comparison::AtMost  => "<=",
+        };
+
+        write!(f, "{} {}", op, self.threshold)
+    }
+}
+
+/// What to do when the state of an alert change
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Append the event to the alerts log of the data directory
+    Log,
+    /// Run a shell command, the event is given in its environment
+    Command(String),
+    /// Publish the event on a nanomsg PUB socket bound to this address
+    Socket(String),
+}
+
+impl FromStr for Action {
+
+    type Err = RuleError;
+
+    /// Parse `log`, `command:<shell command>` or `socket:<address>`
+    ///
+    /// ```
+    /// use orion::alert::Action;
+    /// use std::str::FromStr;
+    ///
+    /// assert_eq!( Action::from_str("command:logger hot").unwrap(),
+    ///             Action::Command("logger hot".to_string()) );
+    /// assert!( Action::from_str("socket:").is_err() );
+    /// ```
+    fn from_str(s: &str) -> Result<Action, RuleError> {
+        if s == "log" {
+            return Ok(Action::Log);
+        }
+
+        let (kind, arg) = match s.find(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None      => return Err(RuleError::InvalidAction),
+        };
+
+        match (kind, arg.trim()) {
+            (_, "")              => Err(RuleError::InvalidAction),
+            ("command", command) => Ok(Action::Command(command.to_string())),
+            ("socket", address)  => Ok(Action::Socket(address.to_string())),
+            _                    => Err(RuleError::InvalidAction),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Log                  => write!(f, "log"),
+            Action::Command(ref command) => write!(f, "command:{}", command),
+            Action::Socket(ref address)  => write!(f, "socket:{}", address),
+        }
+    }
+}
+
+/// Alerting rule on a measurement position of the selected devices
+///
+/// # Example
+///
+/// ```
+/// # extern crate orion;
+/// # extern crate chrono;
+/// use orion::alert::{Rule, Condition, Action};
+/// use orion::core::{DeviceSelector, Measurement};
+/// use chrono::Duration;
+/// use std::str::FromStr;
+///
+/// # fn main() {
+/// let selector = DeviceSelector::from_str("temp1@core-isa-000.lm-sensors").unwrap();
+/// let mut rule = Rule::new("core-hot", selector, 0).unwrap();
+///
+/// rule.set_condition(
+///     Condition::from_str("> 80[K]").unwrap(),
+///     Some( Measurement::from_str("2[K]").unwrap() ),
+/// ).unwrap();
+/// rule.set_duration( Duration::minutes(1) );
+/// rule.set_stale( Some(Duration::minutes(5)) );
+/// rule.add_action(Action::Log);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Rule {
+    name: String,
+    selector: DeviceSelector,
+    channel: usize,
+    condition: Option<Condition>,
+    hysteresis: f32,
+    duration: Duration,
+    stale: Option<Duration>,
+    actions: Vec<Action>,
+}
+
+impl Rule {
+
+    /// Construct a rule without condition nor action on the measurement
+    /// position `channel`
+    ///
+    /// # Failures
+    ///
+    /// `RuleError::InvalidName` if `name` is empty or hold another
+    /// character than alphanumerics, `-` or `_`.
+    pub fn new(name: &str, selector: DeviceSelector, channel: usize)
+        -> Result<Rule, RuleError> {
+
+        if !regex!(r"^[\w-]+$").is_match(name) {
+            return Err(RuleError::InvalidName);
+        }
+
+        Ok( Rule {
+            name: name.to_string(),
+            selector: selector,
+            channel: channel,
+            condition: None,
+            hysteresis: 0.0,
+            duration: Duration::zero(),
+            stale: None,
+            actions: Vec::new(),
+        })
+    }
+
+    /// Fire when `condition` is met, and resolve when the measurement is
+    /// back past the threshold by more than `hysteresis`
+    ///
+    /// # Failures
+    ///
+    /// `RuleError::UnitMismatch` if `hysteresis` is not in the unit of the
+    /// threshold, `RuleError::NegativeHysteresis` if it is negative.
+    pub fn set_condition(&mut self, condition: Condition, hysteresis: Option<Measurement>)
+        -> Result<(), RuleError> {
+
+        let hysteresis = match hysteresis {
+            Some(h) => {
+                if h.get_unit() != condition.threshold.get_unit() {
+                    return Err(RuleError::UnitMismatch);
+                }
+                if h.get_value() < 0.0 {
+                    return Err(RuleError::NegativeHysteresis);
+                }
+                h.get_value()
+            },
+            None => 0.0,
+        };
+
+        self.condition = Some(condition);
+        self.hysteresis = hysteresis;
+        Ok( () )
+    }
+
+    /// Only fire when the condition is met for at least `duration`
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    /// Fire when a device sent no point for `stale`
+    pub fn set_stale(&mut self, stale: Option<Duration>) {
+        self.stale = stale;
+    }
+
+    pub fn add_action(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+
+    pub fn get_name<'a>(&'a self) -> &'a str {
+        &self.name
+    }
+
+    pub fn get_selector<'a>(&'a self) -> &'a DeviceSelector {
+        &self.selector
+    }
+
+    pub fn get_channel(&self) -> usize {
+        self.channel
+    }
+
+    pub fn get_condition<'a>(&'a self) -> Option<&'a Condition> {
+        self.condition.as_ref()
+    }
+
+    pub fn get_hysteresis(&self) -> f32 {
+        self.hysteresis
+    }
+
+    pub fn get_duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn get_stale(&self) -> Option<Duration> {
+        self.stale
+    }
+
+    pub fn get_actions<'a>(&'a self) -> &'a [Action] {
+        &self.actions
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleError {
+    InvalidName,
+    InvalidCondition,
+    InvalidAction,
+    UnitMismatch,
+    NegativeHysteresis,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for RuleError {
+    fn description(&self) -> &str {
+        match *self {
+            RuleError::InvalidName        => "Invalid rule name",
+            RuleError::InvalidCondition   => "Invalid condition, use an operator and a measurement like '> 80[K]'",
+            RuleError::InvalidAction      => "Invalid action, use log, command:<command> or socket:<address>",
+            RuleError::UnitMismatch       => "Hysteresis and threshold units differ",
+            RuleError::NegativeHysteresis => "Negative hysteresis",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+
+#[test]
+fn test_rule() {
+    let m = |s| Measurement::from_str(s).unwrap();
+
+    let above = Condition::from_str(">80[K]").unwrap();
+    assert_eq!( above.to_string(), "> 80[K]" );
+    assert!( above.is_met(&m("80.5[K]")) );
+    assert!( !above.is_cleared(&m("79[K]"), 2.0) );
+    assert!( above.is_cleared(&m("78[K]"), 2.0) );
+
+    let at_most = Condition::from_str("<= -1.5[V]").unwrap();
+    assert!( at_most.is_met(&m("-1.5[V]")) );
+    assert!( !at_most.is_cleared(&m("-1[V]"), 0.5) );
+    assert!( at_most.is_cleared(&m("-0.9[V]"), 0.5) );
+
+    for s in ["80[K]", "=> 80[K]", "> 80", "> hot[K]"].iter() {
+        assert_eq!( Condition::from_str(s), Err(RuleError::InvalidCondition) );
+    }
+
+    let selector = DeviceSelector::all();
+    assert_eq!( Rule::new("core hot", selector.clone(), 0).unwrap_err(),
+                RuleError::InvalidName );
+
+    let mut rule = Rule::new("core-hot", selector, 0).unwrap();
+    assert_eq!( rule.set_condition(above.clone(), Some(m("2[V]"))),
+                Err(RuleError::UnitMismatch) );
+    assert_eq!( rule.set_condition(above.clone(), Some(m("-2[K]"))),
+                Err(RuleError::NegativeHysteresis) );
+    assert!( rule.set_condition(above, Some(m("2[K]"))).is_ok() );
+    assert_eq!( rule.get_hysteresis(), 2.0 );
+
+    assert_eq!( Action::from_str("log"), Ok(Action::Log) );
+    assert_eq!( Action::from_str("socket:ipc:///tmp/alerts.ipc"),
+                Ok(Action::Socket("ipc:///tmp/alerts.ipc".to_string())) );
+    assert_eq!( Action::from_str("mail:root"), Err(RuleError::InvalidAction) );
+}
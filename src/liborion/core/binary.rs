@@ -0,0 +1,371 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Compact binary encoding of `MeasurementPoint`
+//!
+//! A record holds a timestamp and a measurements list:
+//!
+//! ```text
+//! varint(zigzag(seconds since epoch)) varint(nanoseconds)
+//! varint(number of measurements)
+//! { tag value }*
+//! ```
+//!
+//! The low bits of `tag` hold the `Unit` code and the high bit tells if
+//! `value` is a little-endian `f32` (4 bytes) or `f64` (8 bytes). A typical
+//! point with one measurement takes 12 bytes instead of 40 in a text file.
+//!
+//! A frame is what `Channel` sends to the server: the `BINARY_FRAME_TAG`
+//! byte, the length prefixed device slug and a record. The tags of the
+//! point follow as a length prefixed string when there are some.
+
+use super::{Device, Measurement, MeasurementsList, MeasurementPoint, Tags, Unit};
+use std::fmt;
+use std::error::Error;
+use std::convert::From;
+use std::io;
+use std::io::{Read, Write};
+use std::str;
+use std::str::FromStr;
+use chrono::{UTC, TimeZone, Timelike, LocalResult};
+
+use varint;
+
+/// First byte of a binary frame
+///
+/// Text requests always start with a printable character.
+pub const BINARY_FRAME_TAG: u8 = 0x00;
+
+const TAG_F64: u8 = 0x80;
+const TAG_UNIT_MASK: u8 = 0x7f;
+
+/// Return `true` if `data` hold a binary frame rather than a text request
+pub fn is_binary_frame(data: &[u8]) -> bool {
+    data.first() == Some(&BINARY_FRAME_TAG)
+}
+
+impl MeasurementPoint {
+
+    /// Write this point as a binary record
+    ///
+    /// The device is not part of a record, it is implied by the file or
+    /// the frame holding it.
+    pub fn write_record<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let date = self.get_date();
+
+        try!( varint::write_i64(w, date.timestamp()) );
+        try!( varint::write_u64(w, date.nanosecond() as u64) );
+
+        try!( varint::write_u64(w, self.get_data().len() as u64) );
+
+        for meas in self.get_data().iter() {
+            let bits = meas.get_value().to_bits();
+            let value = [ bits as u8, (bits >> 8) as u8,
+                          (bits >> 16) as u8, (bits >> 24) as u8 ];
+
+            try!( w.write_all(&[meas.get_unit().code()]) );
+            try!( w.write_all(&value) );
+        }
+
+        Ok( () )
+    }
+
+    /// Read one binary record taken from `device`
+    ///
+    /// Return `Ok(None)` if `r` is at end of file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::{Device, MeasurementPoint};
+    ///
+    /// let device = Device::with_slug("port@node.driver").unwrap();
+    /// let mp = MeasurementPoint::from_line(
+    ///     device.clone(),
+    ///     "2015-06-01T12:00:00+00:00 3[V]"
+    /// ).unwrap();
+    ///
+    /// let mut buf = Vec::new();
+    /// mp.write_record(&mut buf).unwrap();
+    ///
+    /// let read = MeasurementPoint::read_record(&mut &buf[..], &device);
+    /// assert_eq!( read.unwrap(), Some(mp) );
+    /// ```
+    pub fn read_record<R: Read>(r: &mut R, device: &Device)
+        -> Result<Option<MeasurementPoint>, ParseBinaryError> {
+
+        let secs = match try!( varint::read_i64(r) ) {
+            Some(x) => x,
+            None    => return Ok(None),
+        };
+        let nanos = try!( read_u64(r) );
+
+        if nanos > 1_999_999_999 {
+            return Err(ParseBinaryError::InvalidTimestamp);
+        }
+
+        let date = match UTC.timestamp_opt(secs, nanos as u32) {
+            LocalResult::Single(x) => x,
+            _ => return Err(ParseBinaryError::InvalidTimestamp),
+        };
+
+        let count = try!( read_u64(r) );
+        let mut data = MeasurementsList::new();
+
+        for _ in 0..count {
+            let mut tag = [0u8; 1];
+            try!( r.read_exact(&mut tag) );
+
+            let unit = match Unit::from_code(tag[0] & TAG_UNIT_MASK) {
+                Some(x) => x,
+                None    => return Err(ParseBinaryError::InvalidUnit),
+            };
+
+            let value = if tag[0] & TAG_F64 != 0 {
+                let mut buf = [0u8; 8];
+                try!( r.read_exact(&mut buf) );
+
+                let mut bits = 0u64;
+                for (i, byte) in buf.iter().enumerate() {
+                    bits |= (*byte as u64) << (8 * i);
+                }
+                f64::from_bits(bits) as f32
+            } else {
+                let mut buf = [0u8; 4];
+                try!( r.read_exact(&mut buf) );
+
+                let mut bits = 0u32;
+                for (i, byte) in buf.iter().enumerate() {
+                    bits |= (*byte as u32) << (8 * i);
+                }
+                f32::from_bits(bits)
+            };
+
+            data.push( Measurement::new(value, unit) );
+        }
+
+        Ok( Some( MeasurementPoint::new(device.clone(), date, data) ) )
+    }
+
+    /// Encode this point, including its device and tags, as a binary frame
+    pub fn to_frame(&self) -> Vec<u8> {
+        let slug = self.get_device().get_slug().as_bytes();
+        let mut frame = Vec::with_capacity(slug.len() + 24);
+
+        frame.push(BINARY_FRAME_TAG);
+
+        // Writing to a Vec can't fail
+        varint::write_u64(&mut frame, slug.len() as u64).unwrap();
+        frame.extend(slug.iter().cloned());
+        self.write_record(&mut frame).unwrap();
+
+        if !self.get_tags().is_empty() {
+            let tags = self.get_tags().to_string();
+
+            varint::write_u64(&mut frame, tags.len() as u64).unwrap();
+            frame.extend(tags.as_bytes().iter().cloned());
+        }
+
+        frame
+    }
+
+    /// Decode a binary frame created by `to_frame`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::{Device, MeasurementsList, MeasurementPoint};
+    /// use std::str::FromStr;
+    ///
+    /// let mp = MeasurementPoint::now(
+    ///     Device::with_slug("port@node.driver").unwrap(),
+    ///     MeasurementsList::from_str("3[V]").unwrap(),
+    /// );
+    ///
+    /// let frame = mp.to_frame();
+    /// assert_eq!( MeasurementPoint::from_frame(&frame).unwrap(), mp );
+    /// ```
+    pub fn from_frame(frame: &[u8]) -> Result<MeasurementPoint, ParseBinaryError> {
+        if !is_binary_frame(frame) {
+            return Err(ParseBinaryError::InvalidFrame);
+        }
+
+        let mut r = &frame[1..];
+
+        let len = try!( read_u64(&mut r) ) as usize;
+        if len > r.len() {
+            return Err(ParseBinaryError::Truncated);
+        }
+
+        let device = match str::from_utf8(&r[..len]) {
+            Ok(slug) => match Device::with_slug(slug) {
+                Ok(x)  => x,
+                Err(_) => return Err(ParseBinaryError::InvalidDevice),
+            },
+            Err(_) => return Err(ParseBinaryError::InvalidDevice),
+        };
+        r = &r[len..];
+
+        let mut mp = match try!( MeasurementPoint::read_record(&mut r, &device) ) {
+            Some(x) => x,
+            None    => return Err(ParseBinaryError::Truncated),
+        };
+
+        if !r.is_empty() {
+            let len = try!( read_u64(&mut r) ) as usize;
+            if len != r.len() {
+                return Err(ParseBinaryError::InvalidFrame);
+            }
+
+            let tags = match str::from_utf8(r).map(Tags::from_str) {
+                Ok(Ok(x)) => x,
+                _         => return Err(ParseBinaryError::InvalidTags),
+            };
+            mp.set_tags(tags);
+        }
+
+        Ok(mp)
+    }
+}
+
+/// Read a varint that must be present
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, ParseBinaryError> {
+    match try!( varint::read_u64(r) ) {
+        Some(x) => Ok(x),
+        None    => Err(ParseBinaryError::Truncated),
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseBinaryError {
+    Truncated,
+    InvalidTimestamp,
+    InvalidUnit,
+    InvalidDevice,
+    InvalidTags,
+    InvalidFrame,
+    Io(io::Error),
+}
+
+impl fmt::Display for ParseBinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseBinaryError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseBinaryError::Truncated        => "Truncated record",
+            ParseBinaryError::InvalidTimestamp => "Invalid timestamp",
+            ParseBinaryError::InvalidUnit      => "Invalid unit",
+            ParseBinaryError::InvalidDevice    => "Invalid device",
+            ParseBinaryError::InvalidTags      => "Invalid tags",
+            ParseBinaryError::InvalidFrame     => "Invalid frame",
+            ParseBinaryError::Io(_)            => "I/O error",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ParseBinaryError::Io(ref err) => Some(err as &Error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseBinaryError {
+    fn from(err: io::Error) -> ParseBinaryError {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => ParseBinaryError::Truncated,
+            _ => ParseBinaryError::Io(err),
+        }
+    }
+}
+
+
+#[test]
+fn test_binary_record_roundtrip() {
+    let device = Device::with_slug("temp1@core-isa-000.lm-sensors").unwrap();
+    let mp = MeasurementPoint::from_line(
+        device.clone(),
+        "2015-06-01T12:00:00.250+00:00 3[V] -1.5[A] 300.15[K]"
+    ).unwrap();
+
+    let mut buf = Vec::new();
+    mp.write_record(&mut buf).unwrap();
+    mp.write_record(&mut buf).unwrap();
+
+    // 5 bytes of seconds, 4 of nanoseconds, 1 of count, 3 * 5 of values
+    assert_eq!( buf.len(), 2 * 25 );
+
+    let mut r = &buf[..];
+    assert_eq!( MeasurementPoint::read_record(&mut r, &device).unwrap(), Some(mp.clone()) );
+    assert_eq!( MeasurementPoint::read_record(&mut r, &device).unwrap(), Some(mp) );
+    assert_eq!( MeasurementPoint::read_record(&mut r, &device).unwrap(), None );
+}
+
+#[test]
+fn test_binary_record_errors() {
+    let device = Device::with_slug("port@node.driver").unwrap();
+
+    // Count of 1 but no measurement
+    let mut truncated: &[u8] = &[0x02, 0x00, 0x01];
+    let err = MeasurementPoint::read_record(&mut truncated, &device).unwrap_err();
+    assert_eq!( err.description(), "Truncated record" );
+
+    let mut bad_unit: &[u8] = &[0x02, 0x00, 0x01, 0x7f, 0, 0, 0, 0];
+    let err = MeasurementPoint::read_record(&mut bad_unit, &device).unwrap_err();
+    assert_eq!( err.description(), "Invalid unit" );
+
+    // f64 values are accepted
+    let mut wide: &[u8] = &[0x02, 0x00, 0x01, 0x81, 0, 0, 0, 0, 0, 0, 0x08, 0x40];
+    let mp = MeasurementPoint::read_record(&mut wide, &device).unwrap().unwrap();
+    assert_eq!( mp.get_data().to_string(), "3[V]" );
+}
+
+#[test]
+fn test_binary_frame() {
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let mp = MeasurementPoint::from_line(
+        device,
+        "2015-06-01T12:00:00+00:00 3[V]"
+    ).unwrap();
+
+    let frame = mp.to_frame();
+    assert!( is_binary_frame(&frame) );
+    assert!( !is_binary_frame(b"LOGGER/1.0 STOP") );
+    assert_eq!( MeasurementPoint::from_frame(&frame).unwrap(), mp );
+
+    let err = MeasurementPoint::from_frame(&frame[..frame.len() - 1]).unwrap_err();
+    assert_eq!( err.description(), "Truncated record" );
+
+    let err = MeasurementPoint::from_frame(b"LOGGER/1.0 STOP").unwrap_err();
+    assert_eq!( err.description(), "Invalid frame" );
+
+    let mut tagged = mp.clone();
+    tagged.set_tags( Tags::from_str("site=lab,run=42").unwrap() );
+
+    let frame = tagged.to_frame();
+    assert_eq!( MeasurementPoint::from_frame(&frame).unwrap(), tagged );
+
+    let mut frame = mp.to_frame();
+    frame.extend(b"\x03a=\x20".iter().cloned());
+    let err = MeasurementPoint::from_frame(&frame).unwrap_err();
+    assert_eq!( err.description(), "Invalid tags" );
+}
@@ -15,10 +15,52 @@
 // You should have received a copy of the GNU General Public License
 // along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
 use regex;
 
+/// Maximum length of a port, node or driver name
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Maximum number of sub-addresses of a port, including the port itself
+pub const MAX_PORT_DEPTH: usize = 8;
+
+/// Names that can't be used as port, node or driver
+///
+/// They are special file names on some systems, so they can't be used as
+/// directory names in the storage layout.
+pub const RESERVED_NAMES: &'static [&'static str] = &[
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
 /// Internal representation of a device
 ///
+/// A device is identified by a slug of this form: `port@node.driver`.
+///
+/// The port can be a path of sub-addresses separated by `/`, from the
+/// innermost to the outermost, when a node reach devices through several
+/// buses. For example `reg40001/slave3@gw1.modbus` is the register 40001
+/// of the Modbus slave 3 behind the gateway `gw1`. The port path hold at
+/// most `MAX_PORT_DEPTH` sub-addresses and every sub-address but the last
+/// one can't be only made of digits, see `storage::device_dir`.
+///
+/// Each of `node`, `driver` and sub-address of `port`:
+///
+/// - hold between 1 and `MAX_NAME_LEN` characters
+/// - start with a letter or a digit followed by letters, digits, `-` or `_`
+/// - is not one of `RESERVED_NAMES`
+///
+/// Letters are converted to lowercase, so `Temp1@Core.LM` and
+/// `temp1@core.lm` are the same device. A valid name can't hold a path
+/// separator nor be `.` or `..`, so it is safe to use it as a directory
+/// name.
+///
+/// Data stored under names that are not canonical, like written before
+/// these rules, are found by `storage::legacy_device_dirs`.
+///
 /// # Example
 ///
 /// ```
@@ -26,12 +68,14 @@ use regex;
 ///
 /// let device = Device::with_slug("port@node.driver");
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Device {
     slug  : String,
     port  : String,
     node  : String,
     driver: String,
+    /// Sub-addresses of `port`, from the innermost
+    path  : Vec<String>,
 }
 
 impl Device {
@@ -49,108 +93,109 @@ impl Device {
     ///
     /// # Failures
     ///
-    /// `port`, `node` and `driver` string must only contains alphanumerics,
-    /// `-` or `_` characters.
+    /// `port`, `node` and `driver` must follow the grammar described in
+    /// `Device`, otherwise the matching `ParseDeviceError` is returned.
     ///
     /// ```
     /// use orion::core::Device;
     ///
     /// // Invalid device
-    /// assert!( Device::new("port$", "node", "driver").is_none() );
+    /// assert!( Device::new("port$", "node", "driver").is_err() );
     ///
     /// // Valid device
     /// let device = Device::new("port-10", "node_2", "drivers1").unwrap();
     /// ```
-    pub fn new(port: &str, node: &str, driver: &str) -> Option< Device > {
+    pub fn new(port: &str, node: &str, driver: &str) -> Result<Device, ParseDeviceError> {
+        let mut path = Vec::new();
 
-        let device = Device {
-            slug   : format!("{}@{}.{}", port, node, driver),
-            port   : port.to_string(),
-            node   : node.to_string(),
-            driver : driver.to_string(),
-        };
+        for name in port.split('/') {
+            path.push( try!( canonical_name(name) ) );
+        }
 
-        match device.is_valid() {
-            true  => Some(device),
-            false => None,
+        if path.len() > MAX_PORT_DEPTH {
+            return Err(ParseDeviceError::InvalidPath);
         }
+
+        let inner = &path[..path.len() - 1];
+        if inner.iter().any(|name| name.chars().all(|c| c.is_digit(10))) {
+            return Err(ParseDeviceError::InvalidPath);
+        }
+
+        let port = path.join("/");
+        let node = try!( canonical_name(node) );
+        let driver = try!( canonical_name(driver) );
+
+        Ok( Device {
+            slug   : format!("{}@{}.{}", port, node, driver),
+            port   : port,
+            node   : node,
+            driver : driver,
+            path   : path,
+        })
     }
 
     /// Construct a new `Device` for a given slug
     ///
-    /// A device slug has this form : `"port@node.driver"
+    /// A device slug has this form : `"port@node.driver"`
     ///
     /// # Example
     ///
     /// ```
     /// use orion::core::Device;
     ///
-    /// let device = Device::with_slug("port@node.driver").unwrap();
+    /// let device = Device::with_slug("Port@Node.Driver").unwrap();
+    /// assert_eq!( device.get_slug(), "port@node.driver" );
+    ///
+    /// let device = Device::with_slug("reg40001/slave3@gw1.modbus").unwrap();
+    /// assert_eq!( device.get_port(), "reg40001/slave3" );
+    /// assert_eq!( device.get_port_path(), ["reg40001", "slave3"] );
     /// ```
     ///
     /// # Failures
     ///
-    /// -  A slug must respect this form : `port@node.driver`.
-    /// - `port`, `node` and `driver` must only contains alphanumerics,
-    ///   `-` or `_` characters.
+    /// - `ParseDeviceError::InvalidFormat` if the slug don't have one `@`
+    ///   followed by one `.`
+    /// - The errors of `Device::new` for `port`, `node` and `driver`
     ///
     /// ```
     /// use orion::core::Device;
     ///
     /// // Invalid slug
-    /// assert!( Device::with_slug("port.10@node$1.driver").is_none() );
+    /// assert!( Device::with_slug("port.10@node$1.driver").is_err() );
+    /// assert!( Device::with_slug("@.").is_err() );
+    /// assert!( Device::with_slug("port@nodeXdriver").is_err() );
     ///
     /// // Valid slug
     /// let device = Device::with_slug("port-10@node_2.drivers1").unwrap();
     /// ```
-    pub fn with_slug(slug : &str) -> Option< Device > {
-
-        let re = regex!(r"^([\w-]*)@([\w-]*).([\w-]*)$");
-
-        let data = match re.captures(slug) {
-            Some(x) => x,
-            None    => return None,
+    pub fn with_slug(slug : &str) -> Result<Device, ParseDeviceError> {
+        let (port, rest) = match slug.find('@') {
+            Some(pos) => (&slug[..pos], &slug[pos + 1..]),
+            None      => return Err(ParseDeviceError::InvalidFormat),
         };
 
-
-        let device = Device {
-            slug   : slug.to_string(),
-            port   : match data.at(1) {
-                        Some(x) => x.to_string(),
-                        None    => unreachable!(),
-                     },
-            node   : match data.at(2) {
-                        Some(x) => x.to_string(),
-                        None    => unreachable!(),
-                     },
-            driver : match data.at(3) {
-                        Some(x) => x.to_string(),
-                        None    => unreachable!(),
-                     },
+        let (node, driver) = match rest.find('.') {
+            Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+            None      => return Err(ParseDeviceError::InvalidFormat),
         };
 
-        Some(device)
-    }
-
-    fn is_valid(&self) -> bool {
-        let re = regex!(r"^[\w-]*@[\w-]*.[\w-]*$");
-
-        if re.is_match( &self.slug ) == false {
-            debug!("is_valid failed on {:?}", self);
-            return false;
-        }
-
-        true
+        Device::new(port, node, driver)
     }
 
     pub fn get_slug<'a>(&'a self) -> &'a str {
         return &self.slug
     }
 
+    /// Return the port with its sub-addresses, like `reg40001/slave3`
     pub fn get_port<'a>(&'a self) -> &'a str {
         return &self.port
     }
 
+    /// Return the sub-addresses of the port, from the innermost
+    pub fn get_port_path<'a>(&'a self) -> &'a [String] {
+        return &self.path
+    }
+
     pub fn get_node<'a>(&'a self) -> &'a str {
         return &self.node
     }
@@ -160,6 +205,82 @@ impl Device {
     }
 }
 
+/// Validate a port, node or driver name and return it in lowercase
+fn canonical_name(name: &str) -> Result<String, ParseDeviceError> {
+    if name.is_empty() {
+        return Err(ParseDeviceError::Empty);
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        return Err(ParseDeviceError::TooLong);
+    }
+
+    let re = regex!(r"^[a-zA-Z0-9][a-zA-Z0-9_-]*$");
+
+    if !re.is_match(name) {
+        return Err(ParseDeviceError::InvalidCharacter);
+    }
+
+    let name = name.to_lowercase();
+
+    if RESERVED_NAMES.contains(&&name[..]) {
+        return Err(ParseDeviceError::Reserved);
+    }
+
+    Ok(name)
+}
+
+impl FromStr for Device {
+
+    type Err = ParseDeviceError;
+
+    /// Parse a slug, see `Device::with_slug`
+    fn from_str(s: &str) -> Result<Device, ParseDeviceError> {
+        Device::with_slug(s)
+    }
+}
+
+impl fmt::Display for Device {
+
+    /// Format the slug of the device
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.slug)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseDeviceError {
+    InvalidFormat,
+    Empty,
+    TooLong,
+    InvalidCharacter,
+    Reserved,
+    InvalidPath,
+}
+
+impl fmt::Display for ParseDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseDeviceError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseDeviceError::InvalidFormat    => "Slug must have the form port@node.driver",
+            ParseDeviceError::Empty            => "Empty port, node or driver",
+            ParseDeviceError::TooLong          => "Port, node or driver too long",
+            ParseDeviceError::InvalidCharacter => "Invalid character in port, node or driver",
+            ParseDeviceError::Reserved         => "Reserved port, node or driver name",
+            ParseDeviceError::InvalidPath      => "Too many or numeric port sub-addresses",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
 
 #[test]
 fn test_device_new() {
@@ -170,7 +291,7 @@ fn test_device_new() {
         "NODE",
         "DRIVER",
     );
-    assert!( dev1.is_some() );
+    assert!( dev1.is_ok() );
 
     // Invalid new device
     let dev2 = Device::new(
@@ -178,7 +299,7 @@ fn test_device_new() {
         "NODE@",
         "DRIVERS.",
     );
-    assert!( dev2.is_none() );
+    assert!( dev2.is_err() );
 }
 
 #[test]
@@ -186,12 +307,50 @@ fn test_device_with_slug() {
     // Valid new device
     let dev1 = Device::with_slug("port@node.driver");
 
-    assert!( dev1.is_some() );
+    assert!( dev1.is_ok() );
 
     // Invalid new device
     let dev2 = Device::with_slug("port@node.driver.driver");
 
-    assert!( dev2.is_none() );
+    assert!( dev2.is_err() );
+}
+
+#[test]
+fn test_device_grammar() {
+    let long: String = ::std::iter::repeat("a").take(MAX_NAME_LEN + 1).collect();
+
+    let errors = [ ("@.", ParseDeviceError::Empty),
+                   ("a@b", ParseDeviceError::InvalidFormat),
+                   ("a.b", ParseDeviceError::InvalidFormat),
+                   ("..@node.driver", ParseDeviceError::InvalidCharacter),
+                   ("a@no/de.driver", ParseDeviceError::InvalidCharacter),
+                   ("-a@node.driver", ParseDeviceError::InvalidCharacter),
+                   ("a@node.dri ver", ParseDeviceError::InvalidCharacter),
+                   ("port@Nul.driver", ParseDeviceError::Reserved),
+                   ("a//b@node.driver", ParseDeviceError::Empty),
+                   ("a/../b@node.driver", ParseDeviceError::InvalidCharacter),
+                   ("40001/slave3@gw1.modbus", ParseDeviceError::InvalidPath),
+                   ("a/b/c/d/e/f/g/h/i@node.driver", ParseDeviceError::InvalidPath),
+                   (&format!("{}@node.driver", long), ParseDeviceError::TooLong) ];
+
+    for &(slug, err) in errors.iter() {
+        assert_eq!( Device::with_slug(slug), Err(err) );
+    }
+
+    let dev1 = Device::from_str("Temp1@Core.LM-sensors").unwrap();
+    let dev2 = Device::new("temp1", "core", "lm-sensors").unwrap();
+    assert_eq!( dev1, dev2 );
+    assert_eq!( dev1.to_string(), "temp1@core.lm-sensors" );
+
+    let device = Device::with_slug("Reg40001/slave3@gw1.modbus").unwrap();
+    assert_eq!( device, Device::new("reg40001/SLAVE3", "gw1", "modbus").unwrap() );
+    assert_eq!( device.get_slug(), "reg40001/slave3@gw1.modbus" );
+    assert!( Device::with_slug("reg40001/3@gw1.modbus").is_ok() );
+
+    let mut devices = vec![ Device::with_slug("b@n.d").unwrap(),
+                            Device::with_slug("a@n.d").unwrap() ];
+    devices.sort();
+    assert_eq!( devices[0].get_port(), "a" );
 }
 
 #[test]
@@ -224,6 +383,3 @@ fn test_device_get_port_node_and_driver() {
     assert_eq!(dev2.get_node(), "node");
     assert_eq!(dev2.get_driver(), "driver");
 }
-
-
-
@@ -37,7 +37,7 @@ use regex;
 /// let meas = Measurement::new(4.0, Unit::Ampere);
 ///
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Measurement {
     value: f32,
     unit:  Unit,
@@ -62,6 +62,16 @@ impl Measurement {
             unit: unit,
         }
     }
+
+    /// Return the value of this `Measurement`
+    pub fn get_value(&self) -> f32 {
+        self.value
+    }
+
+    /// Return the `Unit` of this `Measurement`
+    pub fn get_unit(&self) -> Unit {
+        self.unit
+    }
 }
 
 
@@ -0,0 +1,222 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use super::{Device, MeasurementsList, Tags};
+use std::fmt;
+use std::error::Error;
+use std::convert::From;
+use std::str::FromStr;
+use chrono::{UTC, DateTime};
+
+use super::ParseMeasurementsListError;
+
+/// A `MeasurementsList` taken from a `Device` at a given time
+///
+/// A point can also hold `Tags`, they are not part of its text line and
+/// are kept by `TaggedStorage`.
+///
+/// # Example
+///
+/// ```
+/// use orion::core::{Device, MeasurementsList, MeasurementPoint};
+/// use std::str::FromStr;
+///
+/// let mp = MeasurementPoint::now(
+///     Device::with_slug("port@node.driver").unwrap(),
+///     MeasurementsList::from_str("3[V] 1.2[A]").unwrap(),
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementPoint {
+    date: DateTime<UTC>,
+    data: MeasurementsList,
+    device: Device,
+    tags: Tags,
+}
+
+impl MeasurementPoint {
+
+    /// Construct a new `MeasurementPoint` taken at `date`
+    pub fn new(device: Device, date: DateTime<UTC>, data: MeasurementsList)
+        -> MeasurementPoint {
+
+        MeasurementPoint {
+            date: date,
+            data: data,
+            device: device,
+            tags: Tags::new(),
+        }
+    }
+
+    /// Construct a new `MeasurementPoint` using current time as date
+    pub fn now(device: Device, data: MeasurementsList) -> MeasurementPoint {
+        MeasurementPoint::new(device, UTC::now(), data)
+    }
+
+    /// Parse a line of a text data file for the given `device`
+    ///
+    /// A line has this form : `RFC3339-timestamp measurements-list`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::{Device, MeasurementPoint};
+    ///
+    /// let device = Device::with_slug("port@node.driver").unwrap();
+    /// let mp = MeasurementPoint::from_line(
+    ///     device,
+    ///     "2015-06-01T12:00:00+00:00 3[V] 1.2[A]"
+    /// ).unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - `ParseMeasurementPointError::InvalidFormat` if no space separate
+    ///   the timestamp from the measurements
+    /// - `ParseMeasurementPointError::InvalidTimestamp` if the timestamp is
+    ///   not a valid IETF RFC3339 string
+    /// - `ParseMeasurementPointError::InvalidMeasurementsList` if the
+    ///   measurements can't be parsed by `MeasurementsList::from_str`
+    pub fn from_line(device: Device, line: &str)
+        -> Result<MeasurementPoint, ParseMeasurementPointError> {
+
+        let line = line.trim_right_matches('\n');
+
+        let (timestamp, data) = match line.find(' ') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None      => return Err(ParseMeasurementPointError::InvalidFormat),
+        };
+
+        let date = match DateTime::parse_from_rfc3339(timestamp) {
+            Ok(x)  => x.with_timezone(&UTC),
+            Err(_) => return Err(ParseMeasurementPointError::InvalidTimestamp),
+        };
+
+        let data = try!( MeasurementsList::from_str(data) );
+
+        Ok( MeasurementPoint::new(device, date, data) )
+    }
+
+    /// Format this `MeasurementPoint` as a line of a text data file
+    ///
+    /// The returned line is terminated by `\n`.
+    pub fn to_line(&self) -> String {
+        let mut line = String::with_capacity(80);
+
+        line.push_str( &self.date.to_rfc3339() );
+        line.push(' ');
+
+        line.push_str( &self.data.to_string() );
+        line.push('\n');
+
+        line
+    }
+
+    pub fn get_date(&self) -> DateTime<UTC> {
+        self.date
+    }
+
+    pub fn get_data<'a>(&'a self) -> &'a MeasurementsList {
+        &self.data
+    }
+
+    pub fn get_device<'a>(&'a self) -> &'a Device {
+        &self.device
+    }
+
+    pub fn get_tags<'a>(&'a self) -> &'a Tags {
+        &self.tags
+    }
+
+    pub fn set_tags(&mut self, tags: Tags) {
+        self.tags = tags;
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseMeasurementPointError {
+    InvalidFormat,
+    InvalidTimestamp,
+    InvalidMeasurementsList(ParseMeasurementsListError),
+}
+
+impl fmt::Display for ParseMeasurementPointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseMeasurementPointError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseMeasurementPointError::InvalidFormat              => "Invalid format",
+            ParseMeasurementPointError::InvalidTimestamp           => "Invalid timestamp",
+            ParseMeasurementPointError::InvalidMeasurementsList(_) => "Invalid measurements list",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ParseMeasurementPointError::InvalidMeasurementsList(ref err) => Some(err as &Error),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseMeasurementsListError> for ParseMeasurementPointError {
+    fn from(err: ParseMeasurementsListError) -> ParseMeasurementPointError {
+        ParseMeasurementPointError::InvalidMeasurementsList(err)
+    }
+}
+
+
+#[test]
+fn test_measurement_point_from_line() {
+    let device = Device::with_slug("port@node.driver").unwrap();
+
+    let mp = MeasurementPoint::from_line(
+        device.clone(),
+        "2015-06-01T12:00:00+02:00 3[V] -1.5[A]\n"
+    ).unwrap();
+    assert_eq!( mp.get_date().to_rfc3339(), "2015-06-01T10:00:00+00:00" );
+    assert_eq!( mp.get_data().to_string(), "3[V] -1.5[A]" );
+
+    let err = MeasurementPoint::from_line(device.clone(), "3[V]").unwrap_err();
+    assert_eq!( err.description(), "Invalid format" );
+
+    let err = MeasurementPoint::from_line(device.clone(), "yesterday 3[V]")
+                               .unwrap_err();
+    assert_eq!( err.description(), "Invalid timestamp" );
+
+    let err = MeasurementPoint::from_line(
+        device.clone(),
+        "2015-06-01T12:00:00+00:00 3[V"
+    ).unwrap_err();
+    assert_eq!( err.description(), "Invalid measurements list" );
+}
+
+#[test]
+fn test_measurement_point_to_line() {
+    let line = "2015-06-01T12:00:00+00:00 3[V] -1.5[A]\n";
+    let mp = MeasurementPoint::from_line(
+        Device::with_slug("port@node.driver").unwrap(),
+        line
+    ).unwrap();
+
+    assert_eq!( mp.to_line(), line );
+}
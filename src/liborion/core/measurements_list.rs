@@ -21,6 +21,7 @@ use std::fmt;
 use std::error::Error;
 use std::convert::From;
 use std::str::FromStr;
+use std::slice;
 
 use super::ParseMeasurementError;
 use regex;
@@ -35,7 +36,7 @@ use regex;
 /// let meas_list = MeasurementsList::new();
 ///
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MeasurementsList {
     list : Vec<Measurement>,
 }
@@ -57,6 +58,37 @@ impl MeasurementsList {
             list: Vec::new(),
         }
     }
+
+    /// Append a `Measurement` at the end of the list
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::{MeasurementsList, Measurement, Unit};
+    ///
+    /// let mut meas_list = MeasurementsList::new();
+    /// meas_list.push( Measurement::new(3.0, Unit::Volt) );
+    ///
+    /// assert_eq!( meas_list.len(), 1 );
+    /// ```
+    pub fn push(&mut self, measurement: Measurement) {
+        self.list.push(measurement);
+    }
+
+    /// Return the number of `Measurement` in the list
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Return `true` if the list contains no `Measurement`
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Iterate over every `Measurement` of the list
+    pub fn iter(&self) -> slice::Iter<Measurement> {
+        self.list.iter()
+    }
 }
 
 impl FromStr for MeasurementsList {
@@ -22,6 +22,11 @@ pub use self::unit::ParseUnitError;
 
 mod device;
 pub use self::device::Device;
+pub use self::device::MAX_PORT_DEPTH;
+
+mod selector;
+pub use self::selector::DeviceSelector;
+pub use self::selector::ParseDeviceSelectorError;
 
 mod measurement;
 pub use self::measurement::Measurement;
@@ -30,3 +35,15 @@ pub use self::measurement::ParseMeasurementError;
 mod measurements_list;
 pub use self::measurements_list::MeasurementsList;
 pub use self::measurements_list::ParseMeasurementsListError;
+
+mod measurement_point;
+pub use self::measurement_point::MeasurementPoint;
+pub use self::measurement_point::ParseMeasurementPointError;
+
+mod tags;
+pub use self::tags::Tags;
+pub use self::tags::ParseTagsError;
+
+mod binary;
+pub use self::binary::{BINARY_FRAME_TAG, is_binary_frame};
+pub use self::binary::ParseBinaryError;
@@ -0,0 +1,318 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use super::Device;
+use std::fmt;
+use std::error::Error;
+use std::str::FromStr;
+use regex;
+
+/// Select devices with a glob pattern on each part of their slug
+///
+/// A selector has the form of a slug, `port@node.driver`, where each part
+/// can hold these wildcards, and is converted to lowercase like slugs:
+///
+/// - `*` match any sequence of characters
+/// - `?` match any character
+/// - `[abc]`, `[a-z]` match a character of the set, `[!abc]` any other
+///
+/// The port part can hold a path of sub-addresses like device slugs, `*`
+/// also match the `/` between sub-addresses while `?` and sets don't. So
+/// `*@gw1.modbus` select every device of `gw1` and `*/slave3@gw1.modbus`
+/// only the registers of the slave 3.
+///
+/// # Example
+///
+/// ```
+/// use orion::core::{Device, DeviceSelector};
+/// use std::str::FromStr;
+///
+/// let selector = DeviceSelector::from_str("temp*@core-isa-*.lm-sensors").unwrap();
+///
+/// let device = Device::with_slug("temp1@core-isa-000.lm-sensors").unwrap();
+/// assert!( selector.matches(&device) );
+///
+/// let device = Device::with_slug("fan1@core-isa-000.lm-sensors").unwrap();
+/// assert!( !selector.matches(&device) );
+///
+/// let selector = DeviceSelector::from_str("*/slave3@gw1.modbus").unwrap();
+///
+/// let device = Device::with_slug("reg40001/slave3@gw1.modbus").unwrap();
+/// assert!( selector.matches(&device) );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSelector {
+    source: String,
+    port: String,
+    node: String,
+    driver: String,
+}
+
+impl DeviceSelector {
+
+    /// Return a selector matching every device
+    pub fn all() -> DeviceSelector {
+        DeviceSelector::from_str("*@*.*").unwrap()
+    }
+
+    /// Return `true` if `device` is selected
+    pub fn matches(&self, device: &Device) -> bool {
+        self.matches_driver(device.get_driver())
+        && self.matches_node(device.get_node())
+        && self.matches_port(device.get_port())
+    }
+
+    pub fn matches_port(&self, port: &str) -> bool {
+        glob_match(&self.port, port)
+    }
+
+    pub fn matches_node(&self, node: &str) -> bool {
+        glob_match(&self.node, node)
+    }
+
+    pub fn matches_driver(&self, driver: &str) -> bool {
+        glob_match(&self.driver, driver)
+    }
+
+    /// Return the number of characters which are not wildcards
+    ///
+    /// Used to prefer the most specific of several matching selectors.
+    pub fn specificity(&self) -> usize {
+        let parts = [&self.port, &self.node, &self.driver];
+
+        parts.iter().map(|part| {
+            let mut count = 0;
+            let mut in_set = false;
+
+            for c in part.chars() {
+                match c {
+                    '[' => in_set = true,
+                    ']' => in_set = false,
+                    '*' | '?' => {},
+                    _ if !in_set => count += 1,
+                    _ => {},
+                }
+            }
+
+            count
+        }).sum()
+    }
+
+    pub fn get_port<'a>(&'a self) -> &'a str {
+        &self.port
+    }
+
+    pub fn get_node<'a>(&'a self) -> &'a str {
+        &self.node
+    }
+
+    pub fn get_driver<'a>(&'a self) -> &'a str {
+        &self.driver
+    }
+}
+
+/// Match `text` against a glob `pattern`, see `DeviceSelector`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    if pattern.is_empty() {
+        return text.is_empty();
+    }
+
+    match pattern[0] {
+        '*' => (0..text.len() + 1).any(|i| match_from(&pattern[1..], &text[i..])),
+        '?' => !text.is_empty() && text[0] != '/' && match_from(&pattern[1..], &text[1..]),
+        '[' => {
+            let end = match pattern.iter().position(|c| *c == ']') {
+                Some(x) => x,
+                None    => return false,
+            };
+
+            !text.is_empty() && text[0] != '/' && in_set(&pattern[1..end], text[0])
+            && match_from(&pattern[end + 1..], &text[1..])
+        },
+        c => !text.is_empty() && text[0] == c && match_from(&pattern[1..], &text[1..]),
+    }
+}
+
+fn in_set(set: &[char], c: char) -> bool {
+    let (negate, set) = match set.first() {
+        Some(&'!') => (true, &set[1..]),
+        _          => (false, set),
+    };
+
+    let mut found = false;
+    let mut i = 0;
+
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            found = found || (set[i] <= c && c <= set[i + 2]);
+            i += 3;
+        } else {
+            found = found || set[i] == c;
+            i += 1;
+        }
+    }
+
+    found != negate
+}
+
+impl FromStr for DeviceSelector {
+
+    type Err = ParseDeviceSelectorError;
+
+    /// Parse a selector
+    ///
+    /// # Failures
+    ///
+    /// `ParseDeviceSelectorError::Invalid` if `s` don't have the form
+    /// `port@node.driver`, a part is empty, a set is not closed or a part
+    /// hold another character than alphanumerics, `-`, `_` or wildcards.
+    /// Only the port can hold `/`.
+    ///
+    /// ```
+    /// use orion::core::DeviceSelector;
+    /// use std::str::FromStr;
+    ///
+    /// assert!( DeviceSelector::from_str("*@arduino100.*").is_ok() );
+    /// assert!( DeviceSelector::from_str("*@arduino100").is_err() );
+    /// assert!( DeviceSelector::from_str("temp[1-3@core.lm").is_err() );
+    /// ```
+    fn from_str(s: &str) -> Result<DeviceSelector, ParseDeviceSelectorError> {
+        let re = regex!(r"^([\w*?\[\]!/-]+)@([\w*?\[\]!-]+)\.([\w*?\[\]!-]+)$");
+
+        let data = match re.captures(s) {
+            Some(x) => x,
+            None    => return Err(ParseDeviceSelectorError::Invalid),
+        };
+
+        let part = |i| match data.at(i) {
+            Some(x) => x.to_lowercase(),
+            None    => unreachable!(),
+        };
+
+        let selector = DeviceSelector {
+            source: s.to_lowercase(),
+            port: part(1),
+            node: part(2),
+            driver: part(3),
+        };
+
+        for part in [&selector.port, &selector.node, &selector.driver].iter() {
+            if !sets_are_closed(part) {
+                return Err(ParseDeviceSelectorError::Invalid);
+            }
+        }
+
+        Ok(selector)
+    }
+}
+
+fn sets_are_closed(part: &str) -> bool {
+    let mut in_set = false;
+
+    for c in part.chars() {
+        match c {
+            '[' if in_set => return false,
+            '['           => in_set = true,
+            ']' if in_set => in_set = false,
+            ']'           => return false,
+            _             => {},
+        }
+    }
+
+    !in_set
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseDeviceSelectorError {
+    Invalid,
+}
+
+impl fmt::Display for ParseDeviceSelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseDeviceSelectorError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseDeviceSelectorError::Invalid => "Invalid device selector",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+
+#[test]
+fn test_device_selector() {
+    let device = Device::with_slug("temp1@core-isa-000.lm-sensors").unwrap();
+
+    let selected = [ "temp1@core-isa-000.lm-sensors", "*@*.*", "temp?@core-isa-*.lm-*",
+                     "temp[0-9]@*.lm-sensors", "temp[!2]@*.*", "*1@*000.*sensors" ];
+    let rejected = [ "temp2@core-isa-000.lm-sensors", "temp@*.*", "temp[2-9]@*.*",
+                     "*@*.arduino", "temp1?@*.*" ];
+
+    for s in selected.iter() {
+        assert!( DeviceSelector::from_str(s).unwrap().matches(&device), "{}", s );
+    }
+
+    for s in rejected.iter() {
+        assert!( !DeviceSelector::from_str(s).unwrap().matches(&device), "{}", s );
+    }
+
+    assert_eq!( DeviceSelector::from_str("*@*.*").unwrap().specificity(), 0 );
+    assert_eq!( DeviceSelector::from_str("t[ab]*@n.d").unwrap().specificity(), 3 );
+    assert_eq!( DeviceSelector::all().to_string(), "*@*.*" );
+
+    assert!( DeviceSelector::from_str("TEMP1@*.*").unwrap().matches(&device) );
+    assert!( DeviceSelector::from_str("a]@b.c").is_err() );
+    assert!( DeviceSelector::from_str("a@b.c.d").is_err() );
+    assert!( DeviceSelector::from_str("a@.c").is_err() );
+    assert!( DeviceSelector::from_str("a@b/c.d").is_err() );
+
+    let device = Device::with_slug("reg40001/slave3@gw1.modbus").unwrap();
+
+    let selected = [ "*@*.*", "*/slave3@gw1.modbus", "reg*@*.*", "reg40001/*@gw1.*",
+                     "*/slave?@*.*" ];
+    let rejected = [ "slave3@gw1.modbus", "reg40001@gw1.modbus", "reg4000?slave3@*.*",
+                     "reg40001[/]slave3@*.*" ];
+
+    for s in selected.iter() {
+        assert!( DeviceSelector::from_str(s).unwrap().matches(&device), "{}", s );
+    }
+
+    for s in rejected.iter() {
+        assert!( !DeviceSelector::from_str(s).unwrap().matches(&device), "{}", s );
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fmt;
+use std::error::Error;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::str::FromStr;
+
+use regex;
+
+/// Labels attached to a `MeasurementPoint`, like `site=lab,run=42`
+///
+/// Keys follow the grammar of device names, see `Device`, and are
+/// converted to lowercase. Values keep their case and hold letters,
+/// digits, `-`, `_`, `.`, `:` or `/`. Tags are sorted by key.
+///
+/// # Example
+///
+/// ```
+/// use orion::core::Tags;
+/// use std::str::FromStr;
+///
+/// let tags = Tags::from_str("Site=lab,run=42").unwrap();
+/// assert_eq!( tags.get("site"), Some("lab") );
+/// assert_eq!( tags.to_string(), "run=42,site=lab" );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tags {
+    tags: BTreeMap<String, String>,
+}
+
+impl Tags {
+
+    /// Construct an empty `Tags`
+    pub fn new() -> Tags {
+        Tags {
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Set the value of `key`
+    ///
+    /// # Failures
+    ///
+    /// `ParseTagsError::InvalidKey` or `ParseTagsError::InvalidValue` if
+    /// `key` or `value` don't follow the grammar described in `Tags`.
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), ParseTagsError> {
+        let key_re = regex!(r"^[a-zA-Z0-9][a-zA-Z0-9_-]{0,63}$");
+        let value_re = regex!(r"^[a-zA-Z0-9_.:/-]{1,64}$");
+
+        if !key_re.is_match(key) {
+            return Err(ParseTagsError::InvalidKey);
+        }
+
+        if !value_re.is_match(value) {
+            return Err(ParseTagsError::InvalidValue);
+        }
+
+        self.tags.insert(key.to_lowercase(), value.to_string());
+        Ok( () )
+    }
+
+    pub fn get<'a>(&'a self, key: &str) -> Option<&'a str> {
+        self.tags.get(key).map(|v| &v[..])
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<String, String> {
+        self.tags.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Return `true` if every tag of `other` has the same value here
+    ///
+    /// ```
+    /// use orion::core::Tags;
+    /// use std::str::FromStr;
+    ///
+    /// let tags = Tags::from_str("site=lab,run=42").unwrap();
+    /// assert!( tags.contains(&Tags::from_str("site=lab").unwrap()) );
+    /// assert!( !tags.contains(&Tags::from_str("site=roof").unwrap()) );
+    /// ```
+    pub fn contains(&self, other: &Tags) -> bool {
+        other.iter().all(|(k, v)| self.tags.get(k) == Some(v))
+    }
+
+    /// Return the tags of `keys`, missing keys are skipped
+    pub fn select(&self, keys: &[String]) -> Tags {
+        let mut selected = Tags::new();
+
+        for key in keys.iter() {
+            if let Some(value) = self.tags.get(&key.to_lowercase()) {
+                selected.tags.insert(key.to_lowercase(), value.clone());
+            }
+        }
+
+        selected
+    }
+}
+
+impl FromStr for Tags {
+
+    type Err = ParseTagsError;
+
+    /// Parse a comma separated list of `key=value`
+    ///
+    /// An empty string is an empty `Tags`.
+    ///
+    /// # Failures
+    ///
+    /// - `ParseTagsError::InvalidFormat` if a tag has no `=`
+    /// - `ParseTagsError::InvalidKey` or `ParseTagsError::InvalidValue`
+    ///   if a key or a value is invalid
+    /// - `ParseTagsError::Duplicate` if a key is given twice
+    fn from_str(s: &str) -> Result<Tags, ParseTagsError> {
+        let mut tags = Tags::new();
+
+        if s.is_empty() {
+            return Ok(tags);
+        }
+
+        for tag in s.split(',') {
+            let (key, value) = match tag.find('=') {
+                Some(pos) => (&tag[..pos], &tag[pos + 1..]),
+                None      => return Err(ParseTagsError::InvalidFormat),
+            };
+
+            if tags.get(&key.to_lowercase()).is_some() {
+                return Err(ParseTagsError::Duplicate);
+            }
+
+            try!( tags.insert(key, value) );
+        }
+
+        Ok(tags)
+    }
+}
+
+impl fmt::Display for Tags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tags: Vec<String> = self.tags.iter()
+                                         .map(|(k, v)| format!("{}={}", k, v))
+                                         .collect();
+
+        write!(f, "{}", tags.join(","))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseTagsError {
+    InvalidFormat,
+    InvalidKey,
+    InvalidValue,
+    Duplicate,
+}
+
+impl fmt::Display for ParseTagsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseTagsError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseTagsError::InvalidFormat => "Tag without '='",
+            ParseTagsError::InvalidKey    => "Invalid tag key",
+            ParseTagsError::InvalidValue  => "Invalid tag value",
+            ParseTagsError::Duplicate     => "Duplicate tag key",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+
+#[test]
+fn test_tags() {
+    let tags = Tags::from_str("site=lab,Building=B1,run=2015-06-01T12:00").unwrap();
+    assert_eq!( tags.len(), 3 );
+    assert_eq!( tags.get("building"), Some("B1") );
+    assert_eq!( tags.to_string(), "building=B1,run=2015-06-01T12:00,site=lab" );
+    assert_eq!( Tags::from_str(&tags.to_string()).unwrap(), tags );
+
+    assert!( Tags::from_str("").unwrap().is_empty() );
+
+    let errors = [ ("site", ParseTagsError::InvalidFormat),
+                   ("site=", ParseTagsError::InvalidValue),
+                   ("site=a b", ParseTagsError::InvalidValue),
+                   ("site=a=b", ParseTagsError::InvalidValue),
+                   ("=lab", ParseTagsError::InvalidKey),
+                   ("-site=lab", ParseTagsError::InvalidKey),
+                   ("site=lab,", ParseTagsError::InvalidFormat),
+                   ("site=lab,SITE=roof", ParseTagsError::Duplicate) ];
+
+    for &(s, err) in errors.iter() {
+        assert_eq!( Tags::from_str(s), Err(err) );
+    }
+
+    let keys = vec!["Site".to_string(), "floor".to_string()];
+    assert_eq!( tags.select(&keys).to_string(), "site=lab" );
+    assert!( tags.contains(&Tags::new()) );
+}
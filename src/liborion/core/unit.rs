@@ -29,7 +29,7 @@ use regex;
 ///
 /// let Unit = Unit::Volt;
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Unit {
     Volt,
     Ohm,
@@ -40,6 +40,52 @@ pub enum Unit {
     Kilogram,
 }
 
+impl Unit {
+
+    /// Return the one byte code used to store this `Unit` in binary form
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::Unit;
+    ///
+    /// assert_eq!( Unit::Volt.code(), 1 );
+    /// ```
+    pub fn code(&self) -> u8 {
+        match *self {
+            Unit::Volt     => 1,
+            Unit::Ohm      => 2,
+            Unit::Ampere   => 3,
+            Unit::Watt     => 4,
+            Unit::Kelvin   => 5,
+            Unit::Second   => 6,
+            Unit::Kilogram => 7,
+        }
+    }
+
+    /// Return the `Unit` for a code returned by `Unit::code`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::Unit;
+    ///
+    /// assert_eq!( Unit::from_code(3), Some(Unit::Ampere) );
+    /// assert_eq!( Unit::from_code(0), None );
+    /// ```
+    pub fn from_code(code: u8) -> Option<Unit> {
+        match code {
+            1 => Some(Unit::Volt),
+            2 => Some(Unit::Ohm),
+            3 => Some(Unit::Ampere),
+            4 => Some(Unit::Watt),
+            5 => Some(Unit::Kelvin),
+            6 => Some(Unit::Second),
+            7 => Some(Unit::Kilogram),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum ParseUnitError {
@@ -177,3 +223,16 @@ fn test_unit_to_string() {
     assert_eq!( Unit::Kilogram.to_string() , "kg" );
 }
 
+
+#[test]
+fn test_unit_code() {
+    let units = [ Unit::Volt, Unit::Ohm, Unit::Ampere, Unit::Watt,
+                  Unit::Kelvin, Unit::Second, Unit::Kilogram ];
+
+    for unit in units.iter() {
+        assert_eq!( Unit::from_code(unit.code()), Some(*unit) );
+    }
+
+    assert!( Unit::from_code(0).is_none() );
+    assert!( Unit::from_code(8).is_none() );
+}
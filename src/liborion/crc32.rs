@@ -0,0 +1,43 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! CRC-32 (IEEE 802.3) checksum, as used by zlib and PNG
+
+/// Return the CRC-32 of `data`
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+
+#[test]
+fn test_crc32_checksum() {
+    assert_eq!( checksum(b""), 0 );
+    assert_eq!( checksum(b"123456789"), 0xcbf4_3926 );
+    assert_eq!( checksum(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339 );
+}
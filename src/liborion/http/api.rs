@@ -0,0 +1,770 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{UTC, DateTime, TimeZone};
+use rustc_serialize::json::Json;
+use openssl::ssl::SslContext;
+
+use core::{Device, DeviceSelector, MeasurementsList, MeasurementPoint, Tags};
+use logger::{Channel, SharedMetrics, AccessList, Client};
+use storage::{Storage, Resolution, Query, Aggregate, parse_step};
+use tls;
+use super::{Request, Response};
+use super::json::{device_json, point_json, series_json};
+
+/// Where the points posted to an `Api` are sent
+pub trait PointSink {
+
+    /// Send `mp` for the client owning `token`, if any, fail with
+    /// `io::ErrorKind::Other` if it is refused
+    fn add(&mut self, mp: &MeasurementPoint, token: Option<&str>) -> io::Result<()>;
+}
+
+/// Send the points to the logger server, like `orion-logger add`
+///
+/// A point of a client is sent with its token, so the server apply its
+/// permissions.
+impl PointSink for Channel {
+    fn add(&mut self, mp: &MeasurementPoint, token: Option<&str>) -> io::Result<()> {
+        match token {
+            Some(token) => self.add_as(mp, token),
+            None        => Channel::add(self, mp),
+        }
+    }
+}
+
+/// REST API of the logger, with JSON replies
+///
+/// - `POST /points`: log points given as JSON, an object or an array of
+///   objects like `{"device": "temp1@core.lm", "timestamp": "2015-06-01T12:00:00Z",
+///   "value": "300[K]", "tags": {"site": "lab"}}` where `timestamp` and
+///   `tags` are optional, or as lines of the `export` command with another
+///   content type. Points are sent to a `PointSink` only when they are all
+///   valid.
+/// - `GET /devices`: every device with stored points
+/// - `GET /devices/{slug}/points`: the points of a device, with the
+///   optional `from` and `to` RFC3339 parameters. With `step` and `agg`,
+///   the series aggregated by `Query`, which also accept repeated `tag`
+///   and `group_by` parameters.
+/// - `GET /status`: version, uptime and numbers of requests, devices and
+///   series
+/// - `GET /metrics`: the `Metrics` of the server for Prometheus, when they
+///   are given with `set_metrics`
+///
+/// With an `AccessList`, `POST /points` needs the token of a client in an
+/// `Authorization: Bearer <token>` header, and the client must be allowed
+/// to write every posted device. Reading stay open to anyone.
+///
+/// Errors are replied as `{"error": "message"}`.
+pub struct Api {
+    storage: Box<Storage>,
+    root: PathBuf,
+    resolutions: Vec<Resolution>,
+    sink: Box<PointSink>,
+    metrics: Option<SharedMetrics>,
+    access: Option<AccessList>,
+    started: DateTime<UTC>,
+    requests: u64,
+}
+
+impl Api {
+
+    /// Construct an `Api` reading `storage` whose root is `root`, and
+    /// sending the posted points to `sink`
+    pub fn new(storage: Box<Storage>, root: &Path, sink: Box<PointSink>) -> Api {
+        Api {
+            storage: storage,
+            root: root.to_path_buf(),
+            resolutions: Vec::new(),
+            sink: sink,
+            metrics: None,
+            access: None,
+            started: UTC::now(),
+            requests: 0,
+        }
+    }
+
+    /// Use the rollups of `resolutions` for aggregated points
+    pub fn set_resolutions(&mut self, resolutions: Vec<Resolution>) {
+        self.resolutions = resolutions;
+    }
+
+    /// Export `metrics` on `GET /metrics`
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Accept posted points only from the clients of `access`
+    pub fn set_access(&mut self, access: AccessList) {
+        self.access = Some(access);
+    }
+
+    /// Return the response to `request`
+    pub fn handle(&mut self, request: &Request) -> Response {
+        self.requests += 1;
+
+        let path = request.path.trim_right_matches('/');
+        let method = &request.method[..];
+
+        if path == "/metrics" && self.metrics.is_some() {
+            return match method {
+                "GET" => self.get_metrics(),
+                _     => error(405, "Method not allowed"),
+            };
+        }
+
+        let result = match path {
+            "/points"  if method == "POST" => self.post_points(request),
+            "/devices" if method == "GET"  => self.get_devices(),
+            "/status"  if method == "GET"  => self.get_status(),
+            "/points" | "/devices" | "/status" => Err( error(405, "Method not allowed") ),
+            _ if path.starts_with("/devices/") && path.ends_with("/points") => {
+                let slug = &path["/devices/".len()..path.len() - "/points".len()];
+
+                if method == "GET" {
+                    self.get_points(slug, request)
+                } else {
+                    Err( error(405, "Method not allowed") )
+                }
+            },
+            _ => Err( error(404, "Not found") ),
+        };
+
+        match result {
+            Ok(json)      => Response::json(200, json.to_string()),
+            Err(response) => response,
+        }
+    }
+
+    fn post_points(&mut self, request: &Request) -> Result<Json, Response> {
+        let body = match str::from_utf8(&request.body) {
+            Ok(x)  => x,
+            Err(_) => return Err( error(400, "Body is not UTF-8") ),
+        };
+
+        let json = request.header("content-type")
+                          .map(|t| t.starts_with("application/json"))
+                          .unwrap_or(false);
+
+        let client = try!( self.client(request) );
+
+        let points = if json {
+            try!( parse_json_points(body) )
+        } else {
+            try!( parse_line_points(body) )
+        };
+
+        if let Some(ref client) = client {
+            if let Some(mp) = points.iter().find(|mp| !client.can_write(mp.get_device())) {
+                let message = format!("Forbidden device {}", mp.get_device().get_slug());
+                return Err( error(403, &message) );
+            }
+        }
+
+        let token = client.as_ref().map(|c| c.token());
+
+        for (i, mp) in points.iter().enumerate() {
+            if let Err(e) = self.sink.add(mp, token) {
+                let status = if e.kind() == io::ErrorKind::Other { 400 } else { 503 };
+                let mut reply = BTreeMap::new();
+
+                reply.insert("error".to_string(), Json::String(e.to_string()));
+                reply.insert("accepted".to_string(), Json::U64(i as u64));
+                return Err( Response::json(status, Json::Object(reply).to_string()) );
+            }
+        }
+
+        let mut reply = BTreeMap::new();
+        reply.insert("accepted".to_string(), Json::U64(points.len() as u64));
+        Ok( Json::Object(reply) )
+    }
+
+    /// Return the client authenticated by the bearer token of `request`
+    ///
+    /// There is no client without `AccessList`.
+    fn client(&self, request: &Request) -> Result<Option<Client>, Response> {
+        let access = match self.access {
+            Some(ref x) => x,
+            None        => return Ok(None),
+        };
+
+        let token = request.header("authorization").and_then(|h| {
+            let mut parts = h.splitn(2, ' ');
+
+            match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.to_lowercase() == "bearer" => {
+                    Some(token.trim())
+                },
+                _ => None,
+            }
+        });
+
+        match token.and_then(|t| access.authenticate(t)) {
+            Some(client) => Ok( Some(client.clone()) ),
+            None         => {
+                let mut response = error(401, "Unauthorized");
+                response.add_header("WWW-Authenticate", "Bearer");
+                Err(response)
+            },
+        }
+    }
+
+    fn get_devices(&self) -> Result<Json, Response> {
+        let mut devices = try!( self.storage.devices().map_err(storage_error) );
+        devices.sort();
+
+        Ok( Json::Array(devices.iter().map(device_json).collect()) )
+    }
+
+    fn get_points(&self, slug: &str, request: &Request) -> Result<Json, Response> {
+        let device = match Device::with_slug(slug) {
+            Ok(x)  => x,
+            Err(e) => return Err( error(400, &format!("{} '{}'", e, slug)) ),
+        };
+
+        let from = try!( timestamp_param(request, "from", UTC.timestamp(0, 0)) );
+        let to = try!( timestamp_param(request, "to", UTC::now()) );
+
+        let (step, agg) = match (request.param("step"), request.param("agg")) {
+            (None, None) => {
+                let points = try!( self.storage.read_range(&device, &from, &to)
+                                               .map_err(storage_error) );
+                return Ok( Json::Array(points.iter().map(point_json).collect()) );
+            },
+            (Some(step), Some(agg)) => (step, agg),
+            _ => return Err( error(400, "step and agg must be given together") ),
+        };
+
+        let step = match parse_step(step) {
+            Some(x) => x,
+            None    => return Err( error(400, &format!("Invalid step '{}'", step)) ),
+        };
+
+        let agg = match Aggregate::from_str(agg) {
+            Ok(x)  => x,
+            Err(e) => return Err( error(400, &format!("{} '{}'", e, agg)) ),
+        };
+
+        let filter: Vec<&str> = request.query.iter()
+                                             .filter(|p| p.0 == "tag")
+                                             .map(|p| &p.1[..])
+                                             .collect();
+        let filter = match Tags::from_str(&filter.join(",")) {
+            Ok(x)  => x,
+            Err(e) => return Err( error(400, &format!("{}", e)) ),
+        };
+
+        let group_by = request.query.iter()
+                                    .filter(|p| p.0 == "group_by")
+                                    .map(|p| p.1.clone())
+                                    .collect();
+
+        // A slug is a selector of its device, `parse_step` only return
+        // positive steps
+        let selector = DeviceSelector::from_str(device.get_slug()).unwrap();
+        let mut query = Query::new(selector, from, to, step, agg).unwrap();
+        query.set_filter(filter);
+        query.set_group_by(group_by);
+
+        let series = try!( query.run(&*self.storage, &self.root, &self.resolutions)
+                                .map_err(storage_error) );
+
+        Ok( Json::Array(series.iter().map(series_json).collect()) )
+    }
+
+    fn get_status(&self) -> Result<Json, Response> {
+        let devices = try!( self.storage.devices().map_err(storage_error) );
+        let series = try!( self.storage.series().map_err(storage_error) );
+        let uptime = (UTC::now() - self.started).num_seconds();
+
+        let mut status = BTreeMap::new();
+        status.insert("version".to_string(), Json::String(env!("CARGO_PKG_VERSION").to_string()));
+        status.insert("uptime".to_string(), Json::I64(uptime));
+        status.insert("requests".to_string(), Json::U64(self.requests));
+        status.insert("devices".to_string(), Json::U64(devices.len() as u64));
+        status.insert("series".to_string(), Json::U64(series.len() as u64));
+
+        Ok( Json::Object(status) )
+    }
+
+    fn get_metrics(&self) -> Response {
+        let text = match self.metrics.as_ref().map(|m| m.lock()) {
+            Some(Ok(metrics)) => metrics.to_prometheus(),
+            _                 => return error(500, "Metrics unavailable"),
+        };
+
+        Response::new(200, "text/plain; version=0.0.4", text.into_bytes())
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    let mut reply = BTreeMap::new();
+    reply.insert("error".to_string(), Json::String(message.to_string()));
+
+    Response::json(status, Json::Object(reply).to_string())
+}
+
+fn storage_error(e: io::Error) -> Response {
+    error!("HTTP API storage failure: {}", e);
+
+    error(500, "Storage failure")
+}
+
+fn timestamp_param(request: &Request, name: &str, default: DateTime<UTC>)
+    -> Result<DateTime<UTC>, Response> {
+
+    match request.param(name) {
+        Some(s) => match DateTime::parse_from_rfc3339(s) {
+            Ok(x)  => Ok( x.with_timezone(&UTC) ),
+            Err(_) => Err( error(400, &format!("Invalid timestamp '{}'", s)) ),
+        },
+        None => Ok(default),
+    }
+}
+
+/// Parse a point object or an array of point objects
+fn parse_json_points(body: &str) -> Result<Vec<MeasurementPoint>, Response> {
+    let json = match Json::from_str(body) {
+        Ok(x)  => x,
+        Err(e) => return Err( error(400, &format!("Invalid JSON: {}", e)) ),
+    };
+
+    let objects = match json {
+        Json::Array(x) => x,
+        other          => vec![other],
+    };
+
+    let mut points = Vec::new();
+
+    for (i, object) in objects.iter().enumerate() {
+        match json_point(object) {
+            Ok(mp) => points.push(mp),
+            Err(e) => return Err( error(400, &format!("Point {}: {}", i, e)) ),
+        }
+    }
+
+    Ok(points)
+}
+
+fn json_point(object: &Json) -> Result<MeasurementPoint, String> {
+    let field = |name| object.find(name).and_then(|v| v.as_string());
+
+    let device = match field("device").map(Device::with_slug) {
+        Some(Ok(x))  => x,
+        Some(Err(e)) => return Err( e.to_string() ),
+        None         => return Err( "Missing device".to_string() ),
+    };
+
+    let data = match field("value").map(MeasurementsList::from_str) {
+        Some(Ok(x))  => x,
+        Some(Err(e)) => return Err( e.to_string() ),
+        None         => return Err( "Missing value".to_string() ),
+    };
+
+    let date = match field("timestamp").map(DateTime::parse_from_rfc3339) {
+        Some(Ok(x))  => x.with_timezone(&UTC),
+        Some(Err(_)) => return Err( "Invalid timestamp".to_string() ),
+        None         => UTC::now(),
+    };
+
+    let mut tags = Tags::new();
+
+    if let Some(object) = object.find("tags") {
+        let object = match object.as_object() {
+            Some(x) => x,
+            None    => return Err( "Tags must be an object".to_string() ),
+        };
+
+        for (key, value) in object.iter() {
+            match value.as_string().map(|v| tags.insert(key, v)) {
+                Some(Ok(_))  => {},
+                Some(Err(e)) => return Err( e.to_string() ),
+                None         => return Err( "Tag values must be strings".to_string() ),
+            }
+        }
+    }
+
+    let mut mp = MeasurementPoint::new(device, date, data);
+    mp.set_tags(tags);
+    Ok(mp)
+}
+
+/// Parse lines of the `export` command, `slug timestamp measurements`
+fn parse_line_points(body: &str) -> Result<Vec<MeasurementPoint>, Response> {
+    let mut points = Vec::new();
+
+    for (i, line) in body.lines().enumerate().filter(|l| !l.1.trim().is_empty()) {
+        let parsed = match line.find(' ') {
+            Some(pos) => match Device::with_slug(&line[..pos]) {
+                Ok(device) => MeasurementPoint::from_line(device, &line[pos + 1..])
+                                               .map_err(|e| e.to_string()),
+                Err(e)     => Err( e.to_string() ),
+            },
+            None => Err( "Invalid format".to_string() ),
+        };
+
+        match parsed {
+            Ok(mp) => points.push(mp),
+            Err(e) => return Err( error(400, &format!("Line {}: {}", i + 1, e)) ),
+        }
+    }
+
+    Ok(points)
+}
+
+/// Maximum number of connections served at the same time by `serve`
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// Seconds given to a client to send its request, and to each write of the
+/// response
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// Serve an `Api` over HTTP
+pub struct HttpServer {
+    listener: TcpListener,
+    tls: Option<Arc<SslContext>>,
+}
+
+impl HttpServer {
+
+    /// Listen on `address`, like `127.0.0.1:8080`
+    pub fn bind(address: &str) -> io::Result<HttpServer> {
+        Ok( HttpServer {
+            listener: try!( TcpListener::bind(address) ),
+            tls: None,
+        })
+    }
+
+    /// Serve HTTPS with the server context `ctx`, see `TlsOptions`
+    pub fn set_tls(&mut self, ctx: SslContext) {
+        self.tls = Some( Arc::new(ctx) );
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept a connection and reply to its request
+    ///
+    /// The client has 10 seconds to send the whole request, it is
+    /// disconnected past this deadline.
+    pub fn handle_one(&self, api: &mut Api) -> io::Result<()> {
+        let (stream, peer) = try!( self.listener.accept() );
+        let ctx = self.tls.as_ref().map(|x| &**x);
+
+        serve_connection(stream, &peer, ctx, |request| api.handle(&request))
+    }
+
+    /// Reply to requests forever, failures are only logged
+    ///
+    /// Each connection is read and written by its own thread, up to
+    /// `MAX_CONNECTIONS` at the same time, so a slow client does not hold
+    /// the others. The requests are then handled one at a time by `api` on
+    /// the calling thread.
+    pub fn serve(&self, api: &mut Api) {
+        let listener = match self.listener.try_clone() {
+            Ok(x)  => x,
+            Err(e) => {
+                error!("HTTP API disabled, failed to share the listener: {}", e);
+                return;
+            },
+        };
+
+        let (requests, received) = mpsc::channel();
+        let tls = self.tls.clone();
+        thread::spawn( move || accept_connections(listener, tls, requests) );
+
+        for (request, replies) in received {
+            let replies: Sender<Response> = replies;
+            let _ = replies.send( api.handle(&request) );
+        }
+    }
+}
+
+/// Spawn a thread for each connection to `listener`, sending its request
+/// to `requests` with the channel of the response
+fn accept_connections(listener: TcpListener, tls: Option<Arc<SslContext>>,
+                      requests: Sender<(Request, Sender<Response>)>) {
+    let active = Arc::new( AtomicUsize::new(0) );
+
+    for stream in listener.incoming() {
+        let stream = match stream.and_then(|x| x.peer_addr().map(|peer| (x, peer))) {
+            Ok(x)  => x,
+            Err(e) => {
+                warn!("HTTP connection failed: {}", e);
+                continue;
+            },
+        };
+
+        if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            warn!("HTTP connection from {} dropped, {} connections already served",
+                  stream.1, MAX_CONNECTIONS);
+            continue;
+        }
+
+        active.fetch_add(1, Ordering::SeqCst);
+        let active = active.clone();
+        let tls = tls.clone();
+        let requests = requests.clone();
+
+        thread::spawn( move || {
+            let (stream, peer) = stream;
+            let handler = |request| {
+                let (replies, response) = mpsc::channel();
+
+                match requests.send((request, replies)).ok().and_then(|_| response.recv().ok()) {
+                    Some(x) => x,
+                    None    => error(503, "API unavailable"),
+                }
+            };
+
+            if let Err(e) = serve_connection(stream, &peer, tls.as_ref().map(|x| &**x), handler) {
+                warn!("HTTP connection from {} failed: {}", peer, e);
+            }
+
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Read a request from `stream` within `REQUEST_TIMEOUT` and write the
+/// response of `handler`
+fn serve_connection<F>(stream: TcpStream, peer: &SocketAddr, tls: Option<&SslContext>,
+                       handler: F) -> io::Result<()>
+    where F: FnOnce(Request) -> Response {
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT);
+    try!( stream.set_write_timeout(Some(timeout)) );
+    let mut stream = DeadlineStream { stream: stream, deadline: Instant::now() + timeout };
+
+    match tls {
+        Some(ctx) => reply(&mut try!( tls::accept(ctx, stream) ), peer, handler),
+        None      => reply(&mut stream, peer, handler),
+    }
+}
+
+/// Read a request from `stream` and write the reply of `handler`
+fn reply<S, F>(stream: &mut S, peer: &SocketAddr, handler: F) -> io::Result<()>
+    where S: Read + Write, F: FnOnce(Request) -> Response {
+    let request = Request::read_from(&mut BufReader::new(&mut *stream));
+
+    let response = match request {
+        Ok(request) => {
+            debug!("HTTP {} {} from {}", request.method, request.path, peer);
+            handler(request)
+        },
+        Err(e) => match e.to_response() {
+            Some(x) => x,
+            None    => return Err( io::Error::new(io::ErrorKind::Other, e) ),
+        },
+    };
+
+    response.write_to(stream)
+}
+
+/// A `TcpStream` failing the reads past `deadline`
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err( io::Error::new(io::ErrorKind::TimedOut, "Request deadline exceeded") );
+        }
+
+        try!( self.stream.set_read_timeout(Some(self.deadline - now)) );
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[test]
+fn test_api_loopback() {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use storage::Format;
+    use logger::Metrics;
+
+    struct StorageSink(Box<Storage>);
+
+    impl PointSink for StorageSink {
+        fn add(&mut self, mp: &MeasurementPoint, _: Option<&str>) -> io::Result<()> {
+            self.0.append(mp)
+        }
+    }
+
+    let root = env::temp_dir().join("orion_test_api_loopback");
+    let _ = fs::remove_dir_all(&root);
+
+    let sink = Box::new( StorageSink(Format::Text.open(&root).unwrap()) );
+    let mut api = Api::new(Format::Text.open(&root).unwrap(), &root, sink);
+    let metrics = Metrics::shared();
+    metrics.lock().unwrap().requests = 3;
+    api.set_metrics(metrics);
+    let server = HttpServer::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+
+    let mut request = |method: &str, path: &str, content_type: &str, body: &str| {
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(client, "{} {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+               method, path, content_type, body.len(), body).unwrap();
+
+        server.handle_one(&mut api).unwrap();
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+
+        let status = reply[9..12].to_string();
+        let body = reply[reply.find("\r\n\r\n").unwrap() + 4..].to_string();
+        (status, body)
+    };
+
+    let json = r#"[{"device": "temp1@core.lm", "timestamp": "2015-06-01T12:00:00Z",
+                    "value": "300[K]", "tags": {"site": "lab"}},
+                   {"device": "temp1@core.lm", "timestamp": "2015-06-01T12:00:30Z",
+                    "value": "302[K]", "tags": {"site": "lab"}}]"#;
+    assert_eq!( request("POST", "/points", "application/json", json),
+                ("200".to_string(), r#"{"accepted":2}"#.to_string()) );
+
+    let lines = "temp2@core.lm 2015-06-01T12:00:00+00:00 3[V] 1.5[A]\n";
+    assert_eq!( request("POST", "/points", "text/plain", lines).0, "200" );
+
+    // Nothing is sent when a point is invalid
+    let json = r#"[{"device": "temp1@core.lm", "value": "1[K]"}, {"device": "bad", "value": "1[K]"}]"#;
+    assert_eq!( request("POST", "/points", "application/json", json).0, "400" );
+
+    let (status, body) = request("GET", "/devices", "text/plain", "");
+    assert_eq!( status, "200" );
+    assert_eq!( body, r#"[{"driver":"lm","node":"core","port":"temp1","slug":"temp1@core.lm"},{"driver":"lm","node":"core","port":"temp2","slug":"temp2@core.lm"}]"# );
+
+    let (status, body) = request("GET", "/devices/temp2@core.lm/points", "text/plain", "");
+    assert_eq!( status, "200" );
+    assert_eq!( body, r#"[{"data":[{"unit":"V","value":3.0},{"unit":"A","value":1.5}],"tags":{},"timestamp":"2015-06-01T12:00:00+00:00"}]"# );
+
+    let path = "/devices/temp1@core.lm/points?from=2015-06-01T00:00:00Z&to=2015-06-02T00:00:00Z\
+                &step=1m&agg=mean&group_by=site";
+    let (status, body) = request("GET", path, "text/plain", "");
+    assert_eq!( status, "200" );
+    assert_eq!( body, r#"[{"channel":0,"device":"temp1@core.lm","tags":{"site":"lab"},"values":[{"timestamp":"2015-06-01T12:00:00+00:00","unit":"K","value":301.0}]}]"# );
+
+    assert_eq!( request("GET", "/devices/temp1@core.lm/points?step=1m", "text/plain", "").0, "400" );
+    assert_eq!( request("DELETE", "/devices", "text/plain", "").0, "405" );
+    assert_eq!( request("GET", "/nothing", "text/plain", "").0, "404" );
+
+    let (status, body) = request("GET", "/status", "text/plain", "");
+    assert_eq!( status, "200" );
+    let status = Json::from_str(&body).unwrap();
+    assert_eq!( status.find("devices"), Some(&Json::U64(2)) );
+    assert_eq!( status.find("requests"), Some(&Json::U64(10)) );
+
+    let (status, body) = request("GET", "/metrics", "text/plain", "");
+    assert_eq!( status, "200" );
+    assert!( body.contains("\norion_logger_requests_total 3\n") );
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_api_access() {
+    use std::cell::RefCell;
+    use std::env;
+    use std::rc::Rc;
+    use storage::Format;
+
+    struct TokenSink(Rc<RefCell<Vec<Option<String>>>>);
+
+    impl PointSink for TokenSink {
+        fn add(&mut self, _: &MeasurementPoint, token: Option<&str>) -> io::Result<()> {
+            self.0.borrow_mut().push( token.map(|x| x.to_string()) );
+            Ok( () )
+        }
+    }
+
+    let root = env::temp_dir().join("orion_test_api_access");
+    let tokens = Rc::new( RefCell::new(Vec::new()) );
+    let sink = Box::new( TokenSink(tokens.clone()) );
+    let mut api = Api::new(Format::Text.open(&root).unwrap(), &root, sink);
+    api.set_access( AccessList::from_str(r#"
+        [[client]]
+        name = "weather"
+        token = "abc123"
+        write = ["*@weather.*"]
+    "#).unwrap() );
+
+    let mut request = |method: &str, path: &str, authorization: Option<&str>, body: &str| {
+        let mut headers = vec![ ("content-type".to_string(), "text/plain".to_string()) ];
+        if let Some(x) = authorization {
+            headers.push( ("authorization".to_string(), x.to_string()) );
+        }
+
+        api.handle(&Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: Vec::new(),
+            headers: headers,
+            body: body.as_bytes().to_vec(),
+        })
+    };
+
+    let line = "wind@weather.davis 2015-06-01T12:00:00+00:00 3[V]\n";
+    let mut out = Vec::new();
+    request("POST", "/points", None, line).write_to(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!( out.starts_with("HTTP/1.1 401 Unauthorized\r\n") );
+    assert!( out.contains("\r\nWWW-Authenticate: Bearer\r\n") );
+
+    assert_eq!( request("POST", "/points", Some("Bearer nope"), line).status, 401 );
+    assert_eq!( request("POST", "/points", Some("Basic abc123"), line).status, 401 );
+    assert_eq!( request("POST", "/points", Some("Bearer abc123"), line).status, 200 );
+
+    let other = "temp1@core.lm 2015-06-01T12:00:00+00:00 300[K]\n";
+    let mut out = Vec::new();
+    request("POST", "/points", Some("bearer abc123"), &(line.to_string() + other))
+        .write_to(&mut out).unwrap();
+    assert!( String::from_utf8(out).unwrap().starts_with("HTTP/1.1 403 Forbidden\r\n") );
+
+    // Reading is open
+    assert_eq!( request("GET", "/devices", None, "").status, 200 );
+
+    assert_eq!( *tokens.borrow(), [ Some("abc123".to_string()) ] );
+}
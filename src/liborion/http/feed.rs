@@ -0,0 +1,592 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver};
+use std::sync::mpsc::{TrySendError, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use rustc_serialize::json::Json;
+
+use core::{DeviceSelector, MeasurementPoint};
+use super::{Request, Response};
+use super::json::point_json;
+use super::websocket::{Frame, handshake, write_handshake};
+use super::websocket::{OP_TEXT, OP_CLOSE, OP_PING, OP_PONG};
+
+/// Number of messages queued for a client before its points are coalesced
+pub const FEED_QUEUE_LEN: usize = 64;
+
+/// Time between two flushes of the points waiting for slow or downsampled
+/// clients
+const FLUSH_PERIOD_MS: u64 = 100;
+
+/// Most clients of a `FeedServer`, others are answered 503
+pub const MAX_FEED_CLIENTS: usize = 64;
+
+/// What a feed client want to receive
+///
+/// Sent by the client as a text message like
+/// `{"subscribe": "temp*@core-*.lm-sensors", "interval": 5}`, where the
+/// optional `interval` is the minimal number of seconds between two points
+/// of a device. A new subscription replace the previous one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub selector: DeviceSelector,
+    pub interval: Option<Duration>,
+}
+
+impl FromStr for Subscription {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Subscription, String> {
+        let json = match Json::from_str(s) {
+            Ok(x)  => x,
+            Err(e) => return Err( format!("Invalid JSON: {}", e) ),
+        };
+
+        let selector = match json.find("subscribe").and_then(|x| x.as_string()) {
+            Some(x) => match DeviceSelector::from_str(x) {
+                Ok(x)  => x,
+                Err(e) => return Err( format!("{} '{}'", e, x) ),
+            },
+            None => return Err( "Missing subscribe".to_string() ),
+        };
+
+        let interval = match json.find("interval") {
+            Some(x) => match x.as_f64() {
+                Some(x) if x > 0.0 => Some( Duration::from_millis((x * 1000.0) as u64) ),
+                _ => return Err( "Interval must be a positive number of seconds".to_string() ),
+            },
+            None => None,
+        };
+
+        Ok( Subscription {
+            selector: selector,
+            interval: interval,
+        })
+    }
+}
+
+/// A connected client
+struct Client {
+    sender: SyncSender<Frame>,
+    subscription: Option<Subscription>,
+    /// Latest point of each device not sent yet, with the number of points
+    /// it replaced
+    pending: BTreeMap<String, (MeasurementPoint, u64)>,
+    /// End of the interval of the subscription, if a point was sent in it
+    next_flush: Option<Instant>,
+}
+
+impl Client {
+
+    /// Send the pending points until the queue of the client is full
+    ///
+    /// Return `false` if the client is gone.
+    fn flush(&mut self, now: Instant) -> bool {
+        if self.pending.is_empty() || self.next_flush.map(|t| now < t).unwrap_or(false) {
+            return true;
+        }
+
+        while let Some(slug) = self.pending.keys().next().cloned() {
+            let frame = {
+                let &(ref mp, coalesced) = &self.pending[&slug];
+                Frame::text(&feed_message(mp, coalesced))
+            };
+
+            match self.sender.try_send(frame) {
+                Ok(_) => { self.pending.remove(&slug); },
+                Err(TrySendError::Full(_)) => break,
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        if let Some(interval) = self.subscription.as_ref().and_then(|s| s.interval) {
+            self.next_flush = Some(now + interval);
+        }
+
+        true
+    }
+}
+
+/// Return the feed message of `mp`
+///
+/// It is the JSON point returned by the API with its `device`, and the
+/// number of newer points it replaced as `coalesced` if any.
+fn feed_message(mp: &MeasurementPoint, coalesced: u64) -> String {
+    let mut message = match point_json(mp) {
+        Json::Object(x) => x,
+        _               => unreachable!(),
+    };
+
+    message.insert("device".to_string(), Json::String(mp.get_device().get_slug().to_string()));
+
+    if coalesced > 0 {
+        message.insert("coalesced".to_string(), Json::U64(coalesced));
+    }
+
+    Json::Object(message).to_string()
+}
+
+/// Dispatch points to the subscribed clients
+///
+/// Each client has a bounded queue of `FEED_QUEUE_LEN` messages. When it is
+/// full, or until the `interval` of the subscription is elapsed, only the
+/// latest point of each device is kept: a slow client receive fewer points
+/// but never stale ones, and never slow down the others.
+struct Feed {
+    clients: HashMap<usize, Client>,
+}
+
+impl Feed {
+    fn new() -> Feed {
+        Feed {
+            clients: HashMap::new(),
+        }
+    }
+
+    fn connect(&mut self, id: usize, sender: SyncSender<Frame>) {
+        self.clients.insert(id, Client {
+            sender: sender,
+            subscription: None,
+            pending: BTreeMap::new(),
+            next_flush: None,
+        });
+    }
+
+    fn disconnect(&mut self, id: usize) {
+        self.clients.remove(&id);
+    }
+
+    fn subscribe(&mut self, id: usize, subscription: Subscription) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            let mut reply = BTreeMap::new();
+            reply.insert("subscribed".to_string(),
+                         Json::String(subscription.selector.to_string()));
+
+            client.pending.clear();
+            client.next_flush = None;
+            client.subscription = Some(subscription);
+            let _ = client.sender.try_send( Frame::text(&Json::Object(reply).to_string()) );
+        }
+    }
+
+    /// Send `frame` to a client, it is dropped if its queue is full
+    fn send(&mut self, id: usize, frame: Frame) {
+        if let Some(client) = self.clients.get(&id) {
+            let _ = client.sender.try_send(frame);
+        }
+    }
+
+    fn publish(&mut self, mp: &MeasurementPoint, now: Instant) {
+        let mut gone = Vec::new();
+
+        for (id, client) in self.clients.iter_mut() {
+            match client.subscription {
+                Some(ref s) if s.selector.matches(mp.get_device()) => {},
+                _ => continue,
+            }
+
+            let slug = mp.get_device().get_slug().to_string();
+            let coalesced = match client.pending.get(&slug) {
+                Some(&(_, n)) => n + 1,
+                None          => 0,
+            };
+            client.pending.insert(slug, (mp.clone(), coalesced));
+
+            if !client.flush(now) {
+                gone.push(*id);
+            }
+        }
+
+        for id in gone.iter() {
+            self.disconnect(*id);
+        }
+    }
+
+    /// Send the points waiting for their interval or for room in a queue
+    fn flush(&mut self, now: Instant) {
+        let gone: Vec<usize> = self.clients.iter_mut()
+                                           .filter_map(|(id, c)| if c.flush(now) { None }
+                                                                 else { Some(*id) })
+                                           .collect();
+
+        for id in gone.iter() {
+            self.disconnect(*id);
+        }
+    }
+}
+
+enum FeedEvent {
+    Point(MeasurementPoint),
+    Connect(usize, SyncSender<Frame>),
+    Subscribe(usize, Subscription),
+    Send(usize, Frame),
+    Disconnect(usize),
+    Stop,
+}
+
+/// Stop the `FeedServer` when dropped, shared by the clones of a `FeedSender`
+struct StopOnDrop(Mutex<Sender<FeedEvent>>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        if let Ok(events) = self.0.lock() {
+            let _ = events.send(FeedEvent::Stop);
+        }
+    }
+}
+
+/// Handle to send points to a `FeedServer`, from any thread
+#[derive(Clone)]
+pub struct FeedSender {
+    events: Sender<FeedEvent>,
+    _stop: Arc<StopOnDrop>,
+}
+
+impl FeedSender {
+
+    /// Send `mp` to the subscribed clients
+    ///
+    /// Return `false` if the `FeedServer` is stopped.
+    pub fn send(&self, mp: MeasurementPoint) -> bool {
+        self.events.send(FeedEvent::Point(mp)).is_ok()
+    }
+}
+
+/// WebSocket live feed of points
+///
+/// Clients connect with a WebSocket handshake on any path, then send a
+/// `Subscription`. For each new point of a subscribed device they receive a
+/// text message like the points of the API with a `device` field:
+///
+/// ```text
+/// {"data":[{"unit":"K","value":300.0}],"device":"temp1@core.lm","tags":{},"timestamp":"2015-06-01T12:00:00+00:00"}
+/// ```
+///
+/// The reply to a subscription is `{"subscribed": "<selector>"}` and to an
+/// invalid message `{"error": "<message>"}`.
+pub struct FeedServer {
+    listener: TcpListener,
+    events: Sender<FeedEvent>,
+    receiver: Receiver<FeedEvent>,
+    sender: FeedSender,
+}
+
+impl FeedServer {
+
+    /// Listen on `address`, like `127.0.0.1:8081`
+    pub fn bind(address: &str) -> io::Result<FeedServer> {
+        let (events, receiver) = channel();
+        let sender = FeedSender {
+            events: events.clone(),
+            _stop: Arc::new( StopOnDrop(Mutex::new(events.clone())) ),
+        };
+
+        Ok( FeedServer {
+            listener: try!( TcpListener::bind(address) ),
+            events: events,
+            receiver: receiver,
+            sender: sender,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Return a handle to send the points to publish
+    pub fn sender(&self) -> FeedSender {
+        self.sender.clone()
+    }
+
+    /// Accept clients and dispatch them the points, until every
+    /// `FeedSender` is dropped
+    ///
+    /// At most `MAX_FEED_CLIENTS` are connected at the same time. The
+    /// clients are then disconnected and the listener is closed.
+    pub fn serve(self) {
+        let FeedServer { listener, events, receiver, sender } = self;
+        drop(sender);
+
+        let address = listener.local_addr();
+        let stopped = Arc::new( AtomicBool::new(false) );
+        let accepting = stopped.clone();
+
+        thread::spawn( move || {
+            let active = Arc::new( AtomicUsize::new(0) );
+
+            for (id, stream) in listener.incoming().enumerate() {
+                if accepting.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                match stream {
+                    Ok(stream) => {
+                        if active.load(Ordering::SeqCst) >= MAX_FEED_CLIENTS {
+                            warn!("Feed client dropped, {} clients already connected",
+                                  MAX_FEED_CLIENTS);
+                            let response = Response::new(503, "text/plain",
+                                                         b"Too many feed clients".to_vec());
+                            let _ = response.write_to(&mut &stream);
+                            continue;
+                        }
+
+                        let events = events.clone();
+                        let active = active.clone();
+
+                        active.fetch_add(1, Ordering::SeqCst);
+                        thread::spawn( move || {
+                            serve_client(id, stream, events);
+                            active.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    },
+                    Err(e) => warn!("Feed connection failed: {}", e),
+                }
+            }
+        });
+
+        let mut feed = Feed::new();
+        let period = Duration::from_millis(FLUSH_PERIOD_MS);
+        let mut next_flush = Instant::now() + period;
+
+        loop {
+            // Flushed on time even when points keep coming
+            let now = Instant::now();
+            if now >= next_flush {
+                feed.flush(now);
+                next_flush = now + period;
+            }
+
+            match receiver.recv_timeout(next_flush - now) {
+                Ok(FeedEvent::Point(mp))         => feed.publish(&mp, Instant::now()),
+                Ok(FeedEvent::Connect(id, s))    => feed.connect(id, s),
+                Ok(FeedEvent::Subscribe(id, s))  => feed.subscribe(id, s),
+                Ok(FeedEvent::Send(id, frame))   => feed.send(id, frame),
+                Ok(FeedEvent::Disconnect(id))    => feed.disconnect(id),
+                Err(RecvTimeoutError::Timeout)   => {},
+                Ok(FeedEvent::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        // Wake up the accept thread so it closes the listener
+        stopped.store(true, Ordering::SeqCst);
+        if let Ok(address) = address {
+            let _ = TcpStream::connect(address);
+        }
+    }
+}
+
+/// Run the handshake of a client, then read its messages
+///
+/// Frames are written by a second thread, draining the queue of the client.
+fn serve_client(id: usize, stream: TcpStream, events: Sender<FeedEvent>) {
+    let accept = {
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+
+        let mut reader = BufReader::new(&stream);
+        let result = Request::read_from(&mut reader).map_err(|e| e.to_response())
+                                                    .and_then(|r| handshake(&r).map_err(Some));
+        match result {
+            Ok(x) => x,
+            Err(response) => {
+                if let Some(response) = response {
+                    let _ = response.write_to(&mut &stream);
+                }
+                return;
+            },
+        }
+    };
+
+    if write_handshake(&mut &stream, &accept).is_err() {
+        return;
+    }
+    debug!("Feed client {} connected", id);
+
+    let (sender, queue) = sync_channel(FEED_QUEUE_LEN);
+    if events.send(FeedEvent::Connect(id, sender)).is_err() {
+        return;
+    }
+
+    let mut writer = match stream.try_clone() {
+        Ok(x)  => x,
+        Err(_) => return,
+    };
+
+    thread::spawn( move || {
+        for frame in queue.iter() {
+            if writer.write_all(&frame.to_bytes()).is_err() || frame.opcode == OP_CLOSE {
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let _ = stream.set_read_timeout(None);
+    let mut reader = &stream;
+
+    loop {
+        let frame = match Frame::read_from(&mut reader) {
+            Ok(x)  => x,
+            Err(e) => {
+                debug!("Feed client {} disconnected: {}", id, e);
+                break;
+            },
+        };
+
+        let event = match frame.opcode {
+            OP_TEXT => match String::from_utf8(frame.payload).ok()
+                                                             .ok_or("Invalid UTF-8".to_string())
+                                                             .and_then(|s| Subscription::from_str(&s)) {
+                Ok(s)  => FeedEvent::Subscribe(id, s),
+                Err(e) => {
+                    let mut reply = BTreeMap::new();
+                    reply.insert("error".to_string(), Json::String(e));
+                    FeedEvent::Send(id, Frame::text(&Json::Object(reply).to_string()))
+                },
+            },
+            OP_PING  => FeedEvent::Send(id, Frame::new(OP_PONG, frame.payload)),
+            OP_CLOSE => {
+                let _ = events.send(FeedEvent::Send(id, Frame::new(OP_CLOSE, Vec::new())));
+                break;
+            },
+            _ => continue,
+        };
+
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+
+    let _ = events.send(FeedEvent::Disconnect(id));
+}
+
+
+#[test]
+fn test_feed() {
+    use core::Device;
+
+    let device = Device::with_slug("temp1@core.lm").unwrap();
+    let point = |line: &str| MeasurementPoint::from_line(device.clone(), line).unwrap();
+    let text = |frame: Frame| String::from_utf8(frame.payload).unwrap();
+
+    let mut feed = Feed::new();
+    let (sender, queue) = sync_channel(2);
+    let now = Instant::now();
+
+    feed.connect(0, sender);
+    feed.publish(&point("2015-06-01T12:00:00+00:00 300[K]"), now);
+    assert!( queue.try_recv().is_err() );
+
+    let subscription = Subscription::from_str(r#"{"subscribe": "*@core.lm"}"#).unwrap();
+    feed.subscribe(0, subscription);
+    assert_eq!( text(queue.recv().unwrap()), r#"{"subscribed":"*@core.lm"}"# );
+
+    feed.publish(&point("2015-06-01T12:00:01+00:00 301[K]"), now);
+    assert_eq!( text(queue.recv().unwrap()),
+                r#"{"data":[{"unit":"K","value":301.0}],"device":"temp1@core.lm","tags":{},"timestamp":"2015-06-01T12:00:01+00:00"}"# );
+
+    // A full queue coalesce the points of a device
+    for i in 2..6 {
+        feed.publish(&point(&format!("2015-06-01T12:00:0{}+00:00 30{}[K]", i, i)), now);
+    }
+    assert!( text(queue.recv().unwrap()).contains("302") );
+    assert!( text(queue.recv().unwrap()).contains("303") );
+    assert!( queue.try_recv().is_err() );
+
+    feed.flush(now);
+    let message = text(queue.recv().unwrap());
+    assert!( message.contains("305") && message.contains(r#""coalesced":1"#) );
+
+    // With an interval, only the latest point is sent once it is elapsed
+    let subscription = Subscription::from_str(r#"{"subscribe": "temp1@core.lm", "interval": 10}"#)
+                                    .unwrap();
+    assert_eq!( subscription.interval, Some(Duration::from_secs(10)) );
+    feed.subscribe(0, subscription);
+    queue.recv().unwrap();
+
+    feed.publish(&point("2015-06-01T12:00:06+00:00 306[K]"), now);
+    feed.publish(&point("2015-06-01T12:00:07+00:00 307[K]"), now);
+    feed.publish(&point("2015-06-01T12:00:08+00:00 308[K]"), now);
+    assert!( text(queue.recv().unwrap()).contains("306") );
+    assert!( queue.try_recv().is_err() );
+
+    feed.flush(now + Duration::from_secs(5));
+    assert!( queue.try_recv().is_err() );
+    feed.flush(now + Duration::from_secs(10));
+    assert!( text(queue.recv().unwrap()).contains("308") );
+
+    // A gone client is removed
+    drop(queue);
+    feed.publish(&point("2015-06-01T12:00:20+00:00 320[K]"), now + Duration::from_secs(20));
+    assert!( feed.clients.is_empty() );
+
+    assert!( Subscription::from_str(r#"{"subscribe": "bad"}"#).is_err() );
+    assert!( Subscription::from_str(r#"{"subscribe": "*@*.lm", "interval": -1}"#).is_err() );
+}
+
+#[test]
+fn test_feed_server_stop() {
+    let server = FeedServer::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let sender = server.sender();
+    let handle = thread::spawn( move || server.serve() );
+
+    let other = sender.clone();
+    drop(sender);
+    assert!( other.events.send(FeedEvent::Disconnect(0)).is_ok() );
+    drop(other);
+
+    handle.join().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!( TcpStream::connect(address).is_err() );
+}
+
+#[test]
+fn test_feed_server_full() {
+    use std::io::Read;
+
+    let server = FeedServer::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let _sender = server.sender();
+    thread::spawn( move || server.serve() );
+
+    // Clients in their handshake count
+    let clients: Vec<_> = (0..MAX_FEED_CLIENTS).map(|_| {
+        TcpStream::connect(address).unwrap()
+    }).collect();
+
+    let mut reply = String::new();
+    let mut other = TcpStream::connect(address).unwrap();
+    other.read_to_string(&mut reply).unwrap();
+    assert!( reply.starts_with("HTTP/1.1 503 Service Unavailable\r\n") );
+
+    // A freed place is taken again
+    drop(clients);
+    thread::sleep(Duration::from_millis(200));
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert!( (&stream).read(&mut [0; 16]).is_err() );
+}
@@ -0,0 +1,84 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! JSON representation of the core types, shared by the API and the feed
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use rustc_serialize::json::Json;
+
+use core::{Device, Measurement, MeasurementPoint, Tags};
+use storage::Series;
+
+/// Return a JSON number with the shortest decimal form of `value`
+pub fn number(value: f32) -> Json {
+    Json::F64( f64::from_str(&value.to_string()).unwrap_or(value as f64) )
+}
+
+pub fn measurement_json(m: &Measurement) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("value".to_string(), number(m.get_value()));
+    object.insert("unit".to_string(), Json::String(m.get_unit().to_string()));
+
+    Json::Object(object)
+}
+
+pub fn tags_json(tags: &Tags) -> Json {
+    Json::Object( tags.iter().map(|(k, v)| (k.clone(), Json::String(v.clone()))).collect() )
+}
+
+pub fn device_json(device: &Device) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("slug".to_string(), Json::String(device.get_slug().to_string()));
+    object.insert("port".to_string(), Json::String(device.get_port().to_string()));
+    object.insert("node".to_string(), Json::String(device.get_node().to_string()));
+    object.insert("driver".to_string(), Json::String(device.get_driver().to_string()));
+
+    Json::Object(object)
+}
+
+pub fn point_json(mp: &MeasurementPoint) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("timestamp".to_string(), Json::String(mp.get_date().to_rfc3339()));
+    object.insert("data".to_string(), Json::Array(mp.get_data().iter()
+                                                    .map(measurement_json)
+                                                    .collect()));
+    object.insert("tags".to_string(), tags_json(mp.get_tags()));
+
+    Json::Object(object)
+}
+
+pub fn series_json(series: &Series) -> Json {
+    let values = series.values.iter().map(|&(ref date, ref m)| {
+        let mut value = match measurement_json(m) {
+            Json::Object(x) => x,
+            _               => unreachable!(),
+        };
+        value.insert("timestamp".to_string(), Json::String(date.to_rfc3339()));
+
+        Json::Object(value)
+    }).collect();
+
+    let mut object = BTreeMap::new();
+    object.insert("device".to_string(), Json::String(series.device.get_slug().to_string()));
+    object.insert("channel".to_string(), Json::U64(series.channel as u64));
+    object.insert("tags".to_string(), tags_json(&series.tags));
+    object.insert("values".to_string(), Json::Array(values));
+
+    Json::Object(object)
+}
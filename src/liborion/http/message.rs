@@ -0,0 +1,382 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fmt;
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::str;
+use std::str::FromStr;
+
+/// Maximum size of the body of a request
+pub const MAX_BODY_LEN: usize = 1 << 20;
+
+/// Maximum size of the request line and of each header
+const MAX_LINE_LEN: usize = 8192;
+
+/// Maximum number of headers of a request
+const MAX_HEADERS: usize = 100;
+
+/// An HTTP/1.x request
+///
+/// Only requests with a `Content-Length` body are supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Percent-decoded path, without the query
+    pub path: String,
+    /// Percent-decoded query parameters, in order
+    pub query: Vec<(String, String)>,
+    /// Headers with lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+
+    /// Read a request from `r`
+    ///
+    /// # Failures
+    ///
+    /// - `HttpError::Io` if reading `r` fail or it ends before the body
+    /// - `HttpError::BadRequest` if the request is not valid HTTP/1.x
+    /// - `HttpError::TooLarge` if a line or the body is too long, or if
+    ///   there are too many headers
+    /// - `HttpError::Unsupported` for a chunked body
+    pub fn read_from<R: BufRead>(r: &mut R) -> Result<Request, HttpError> {
+        let line = try!( read_line(r) );
+        let parts: Vec<&str> = line.split(' ').collect();
+
+        if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") || !parts[1].starts_with('/') {
+            return Err(HttpError::BadRequest);
+        }
+
+        let (path, query) = match parts[1].find('?') {
+            Some(pos) => (&parts[1][..pos], &parts[1][pos + 1..]),
+            None      => (parts[1], ""),
+        };
+
+        let mut request = Request {
+            method: parts[0].to_string(),
+            path: try!( percent_decode(path) ),
+            query: try!( parse_query(query) ),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        loop {
+            let line = try!( read_line(r) );
+
+            if line.is_empty() {
+                break;
+            }
+
+            if request.headers.len() == MAX_HEADERS {
+                return Err(HttpError::TooLarge);
+            }
+
+            match line.find(':') {
+                Some(pos) => request.headers.push( (line[..pos].trim().to_lowercase(),
+                                                    line[pos + 1..].trim().to_string()) ),
+                None      => return Err(HttpError::BadRequest),
+            }
+        }
+
+        if request.header("transfer-encoding").is_some() {
+            return Err(HttpError::Unsupported);
+        }
+
+        // usize::from_str would accept a sign, as in "+4"
+        let len = match request.header("content-length") {
+            Some(x) if !x.is_empty() && x.bytes().all(|b| b >= b'0' && b <= b'9') => {
+                try!( usize::from_str(x).map_err(|_| HttpError::BadRequest) )
+            },
+            Some(_) => return Err(HttpError::BadRequest),
+            None    => 0,
+        };
+
+        if len > MAX_BODY_LEN {
+            return Err(HttpError::TooLarge);
+        }
+
+        try!( r.take(len as u64).read_to_end(&mut request.body) );
+
+        if request.body.len() != len {
+            return Err(HttpError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                    "Truncated body")));
+        }
+
+        Ok(request)
+    }
+
+    /// Return the value of the first header named `name`, in lowercase
+    pub fn header<'a>(&'a self, name: &str) -> Option<&'a str> {
+        self.headers.iter().find(|h| h.0 == name).map(|h| &h.1[..])
+    }
+
+    /// Return the value of the first query parameter named `name`
+    pub fn param<'a>(&'a self, name: &str) -> Option<&'a str> {
+        self.query.iter().find(|p| p.0 == name).map(|p| &p.1[..])
+    }
+}
+
+/// Read a line terminated by `\r\n` or `\n`, without its terminator
+fn read_line<R: BufRead>(r: &mut R) -> Result<String, HttpError> {
+    let mut line = Vec::new();
+    try!( r.take(MAX_LINE_LEN as u64).read_until(b'\n', &mut line) );
+
+    if line.last() != Some(&b'\n') {
+        return Err( if line.len() >= MAX_LINE_LEN {
+            HttpError::TooLarge
+        } else {
+            HttpError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated request"))
+        });
+    }
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).map_err(|_| HttpError::BadRequest)
+}
+
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, HttpError> {
+    let mut params = Vec::new();
+
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(pos) => (&param[..pos], &param[pos + 1..]),
+            None      => (param, ""),
+        };
+
+        params.push( (try!( percent_decode(name) ), try!( percent_decode(value) )) );
+    }
+
+    Ok(params)
+}
+
+/// Decode the `%XX` escapes of `s`
+///
+/// `+` is kept as is, so timestamps like `2015-06-01T12:00:00+02:00` can be
+/// given unescaped.
+///
+/// ```
+/// use orion::http::percent_decode;
+///
+/// assert_eq!( percent_decode("reg40001%2Fslave3@gw1.modbus").unwrap(),
+///             "reg40001/slave3@gw1.modbus" );
+/// assert!( percent_decode("%zz").is_err() );
+/// assert!( percent_decode("%+1").is_err() );
+/// ```
+pub fn percent_decode(s: &str) -> Result<String, HttpError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix would accept a sign, as in "%+1"
+            let hex = match bytes.get(i + 1..i + 3) {
+                Some(x) if x.iter().all(|&b| (b as char).is_digit(16)) => x,
+                _ => return Err(HttpError::BadRequest),
+            };
+
+            match u8::from_str_radix(str::from_utf8(hex).unwrap(), 16) {
+                Ok(x)  => decoded.push(x),
+                Err(_) => return Err(HttpError::BadRequest),
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| HttpError::BadRequest)
+}
+
+/// An HTTP/1.1 response, the connection is closed after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    /// Headers sent besides `Content-Type`, `Content-Length` and `Connection`
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status: status,
+            content_type: content_type.to_string(),
+            headers: Vec::new(),
+            body: body,
+        }
+    }
+
+    /// Add the header `name` with `value`, like `WWW-Authenticate`
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push( (name.to_string(), value.to_string()) );
+    }
+
+    /// Construct a `Response` with a JSON body
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status, "application/json", body.into_bytes())
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!( write!(w, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status)) );
+        try!( write!(w, "Content-Type: {}\r\n", self.content_type) );
+        try!( write!(w, "Content-Length: {}\r\n", self.body.len()) );
+        for &(ref name, ref value) in self.headers.iter() {
+            try!( write!(w, "{}: {}\r\n", name, value) );
+        }
+        try!( write!(w, "Connection: close\r\n\r\n") );
+        try!( w.write_all(&self.body) );
+        w.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _   => "Unknown",
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    BadRequest,
+    TooLarge,
+    Unsupported,
+}
+
+impl HttpError {
+
+    /// Return the response sent for this error, if the client can read it
+    pub fn to_response(&self) -> Option<Response> {
+        let status = match *self {
+            HttpError::Io(_)       => return None,
+            HttpError::BadRequest  => 400,
+            HttpError::TooLarge    => 413,
+            HttpError::Unsupported => 501,
+        };
+
+        Some( Response::new(status, "text/plain", self.description().as_bytes().to_vec()) )
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for HttpError {
+    fn description(&self) -> &str {
+        match *self {
+            HttpError::Io(_)       => "I/O error",
+            HttpError::BadRequest  => "Invalid HTTP request",
+            HttpError::TooLarge    => "Request too large",
+            HttpError::Unsupported => "Chunked requests are not supported",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            HttpError::Io(ref err) => Some(err as &Error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> HttpError {
+        HttpError::Io(err)
+    }
+}
+
+
+#[test]
+fn test_request() {
+    let raw = b"POST /devices/a%2Fb@n.d/points?from=2015-06-01T12:00:00+00:00&agg HTTP/1.1\r\n\
+                Host: localhost\r\nContent-Length: 4\r\n\r\nbodyextra";
+
+    let request = Request::read_from(&mut &raw[..]).unwrap();
+    assert_eq!( request.method, "POST" );
+    assert_eq!( request.path, "/devices/a/b@n.d/points" );
+    assert_eq!( request.param("from"), Some("2015-06-01T12:00:00+00:00") );
+    assert_eq!( request.param("agg"), Some("") );
+    assert_eq!( request.header("host"), Some("localhost") );
+    assert_eq!( request.body, b"body" );
+
+    let bad: [&[u8]; 7] = [ b"GET /\r\n\r\n", b"GET / HTTP/1.1\r\nHost\r\n\r\n",
+                            b"GET /%4 HTTP/1.1\r\n\r\n", b"GET x HTTP/1.0\r\n\r\n",
+                            b"GET /%+1 HTTP/1.1\r\n\r\n",
+                            b"POST / HTTP/1.1\r\nContent-Length: +4\r\n\r\nbody",
+                            b"POST / HTTP/1.1\r\nContent-Length: \r\n\r\n" ];
+    for raw in bad.iter() {
+        match Request::read_from(&mut &raw[..]) {
+            Err(HttpError::BadRequest) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+    assert!( Request::read_from(&mut &chunked[..]).is_err() );
+
+    let mut many = b"GET / HTTP/1.1\r\n".to_vec();
+    for i in 0..MAX_HEADERS + 1 {
+        many.extend_from_slice( format!("X-{}: {}\r\n", i, i).as_bytes() );
+    }
+    many.extend_from_slice(b"\r\n");
+    match Request::read_from(&mut &many[..]) {
+        Err(HttpError::TooLarge) => {},
+        other => panic!("{:?}", other),
+    }
+
+    let mut out = Vec::new();
+    Response::json(404, "{}".to_string()).write_to(&mut out).unwrap();
+    assert_eq!( String::from_utf8(out).unwrap(),
+                "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\n\
+                 Content-Length: 2\r\nConnection: close\r\n\r\n{}" );
+
+    let mut out = Vec::new();
+    let mut response = Response::json(401, "{}".to_string());
+    response.add_header("WWW-Authenticate", "Bearer");
+    response.write_to(&mut out).unwrap();
+    assert_eq!( String::from_utf8(out).unwrap(),
+                "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\n\
+                 Content-Length: 2\r\nWWW-Authenticate: Bearer\r\nConnection: close\r\n\r\n{}" );
+
+    let mut out = Vec::new();
+    Response::json(403, "{}".to_string()).write_to(&mut out).unwrap();
+    assert!( String::from_utf8(out).unwrap().starts_with("HTTP/1.1 403 Forbidden\r\n") );
+}
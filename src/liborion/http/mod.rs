@@ -0,0 +1,42 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Embedded HTTP/JSON API of the logger
+//!
+//! `Request` and `Response` implement the small subset of HTTP/1.1 needed
+//! by the API: one request per connection, bodies sized by
+//! `Content-Length`. `Api` route requests to a `Storage` and send posted
+//! points to a `PointSink`, `HttpServer` serve it on a TCP socket.
+//!
+//! `FeedServer` push the new points to WebSocket clients, like the live
+//! dashboards.
+
+mod message;
+pub use self::message::{Request, Response, HttpError, percent_decode, MAX_BODY_LEN};
+
+mod json;
+
+mod api;
+pub use self::api::{Api, PointSink, HttpServer};
+
+mod websocket;
+pub use self::websocket::{Frame, accept_key, handshake, write_handshake, MAX_FRAME_LEN};
+pub use self::websocket::{OP_CONTINUATION, OP_TEXT, OP_BINARY, OP_CLOSE, OP_PING, OP_PONG};
+
+mod feed;
+pub use self::feed::{FeedServer, FeedSender, Subscription, FEED_QUEUE_LEN, MAX_FEED_CLIENTS};
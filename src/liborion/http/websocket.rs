@@ -0,0 +1,205 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::io;
+use std::io::{Read, Write};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use openssl::crypto::hash::{hash, Type};
+
+use super::{Request, Response};
+
+/// GUID appended to the client key by the opening handshake, see RFC 6455
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of the payload of a frame sent by a client
+pub const MAX_FRAME_LEN: usize = 1 << 16;
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+/// Return the `Sec-WebSocket-Accept` value answering the `key` of a client
+///
+/// ```
+/// use orion::http::accept_key;
+///
+/// assert_eq!( accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=" );
+/// ```
+pub fn accept_key(key: &str) -> String {
+    let mut data = key.as_bytes().to_vec();
+    data.extend(WEBSOCKET_GUID.as_bytes().iter().cloned());
+
+    hash(Type::SHA1, &data).to_base64(STANDARD)
+}
+
+/// Return the reply to a WebSocket opening handshake
+///
+/// The reply is a `101 Switching Protocols` response to be written with
+/// `write_handshake`, or an error response if `request` is not a valid
+/// WebSocket upgrade.
+pub fn handshake(request: &Request) -> Result<String, Response> {
+    let upgrade = request.header("upgrade").map(|x| x.to_lowercase());
+    let connection = request.header("connection").map(|x| x.to_lowercase());
+
+    if request.method != "GET" || upgrade.as_ref().map(|x| &x[..]) != Some("websocket")
+       || !connection.map(|x| x.contains("upgrade")).unwrap_or(false) {
+        return Err( Response::new(400, "text/plain", b"Expected a WebSocket upgrade".to_vec()) );
+    }
+
+    if request.header("sec-websocket-version") != Some("13") {
+        return Err( Response::new(400, "text/plain", b"Unsupported WebSocket version".to_vec()) );
+    }
+
+    match request.header("sec-websocket-key") {
+        Some(key) => Ok( accept_key(key) ),
+        None      => Err( Response::new(400, "text/plain", b"Missing WebSocket key".to_vec()) ),
+    }
+}
+
+/// Write the `101 Switching Protocols` response with the `accept` key
+pub fn write_handshake<W: Write>(w: &mut W, accept: &str) -> io::Result<()> {
+    try!( write!(w, "HTTP/1.1 101 Switching Protocols\r\n") );
+    try!( write!(w, "Upgrade: websocket\r\nConnection: Upgrade\r\n") );
+    try!( write!(w, "Sec-WebSocket-Accept: {}\r\n\r\n", accept) );
+    w.flush()
+}
+
+/// A WebSocket frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: u8, payload: Vec<u8>) -> Frame {
+        Frame {
+            opcode: opcode,
+            payload: payload,
+        }
+    }
+
+    pub fn text(text: &str) -> Frame {
+        Frame::new(OP_TEXT, text.as_bytes().to_vec())
+    }
+
+    /// Read a frame sent by a client
+    ///
+    /// # Failures
+    ///
+    /// Fail with `io::ErrorKind::InvalidData` if the frame is not masked, is
+    /// fragmented or its payload is larger than `MAX_FRAME_LEN`. Fragmented
+    /// messages are not needed by the feed, whose clients only send short
+    /// subscriptions.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Frame> {
+        let mut header = [0u8; 2];
+        try!( r.read_exact(&mut header) );
+
+        if header[0] & 0x80 == 0 || header[0] & 0x0f == OP_CONTINUATION {
+            return Err( invalid("Fragmented WebSocket frame") );
+        }
+
+        if header[1] & 0x80 == 0 {
+            return Err( invalid("Unmasked WebSocket frame") );
+        }
+
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                try!( r.read_exact(&mut len) );
+                (len[0] as u64) << 8 | len[1] as u64
+            },
+            127 => {
+                let mut len = [0u8; 8];
+                try!( r.read_exact(&mut len) );
+                len.iter().fold(0, |acc, b| acc << 8 | *b as u64)
+            },
+            len => len as u64,
+        };
+
+        if len > MAX_FRAME_LEN as u64 {
+            return Err( invalid("WebSocket frame too large") );
+        }
+
+        let mut mask = [0u8; 4];
+        try!( r.read_exact(&mut mask) );
+
+        let mut payload = vec![0u8; len as usize];
+        try!( r.read_exact(&mut payload) );
+
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok( Frame::new(header[0] & 0x0f, payload) )
+    }
+
+    /// Encode the frame as sent by a server, unmasked
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 10);
+        bytes.push(0x80 | self.opcode);
+
+        let len = self.payload.len();
+
+        if len < 126 {
+            bytes.push(len as u8);
+        } else if len < 1 << 16 {
+            bytes.push(126);
+            bytes.push( (len >> 8) as u8 );
+            bytes.push( len as u8 );
+        } else {
+            bytes.push(127);
+            for i in (0..8).rev() {
+                bytes.push( ((len as u64) >> (i * 8)) as u8 );
+            }
+        }
+
+        bytes.extend(self.payload.iter().cloned());
+        bytes
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+#[test]
+fn test_frame() {
+    let frame = Frame::text("{\"subscribe\": \"*@*.lm\"}");
+
+    // Frames of clients are masked
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut bytes = vec![0x81, 0x80 | frame.payload.len() as u8];
+    bytes.extend(mask.iter().cloned());
+    bytes.extend(frame.payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+    assert_eq!( Frame::read_from(&mut &bytes[..]).unwrap(), frame );
+
+    // Unmasked, like a frame of a server
+    let bytes = frame.to_bytes();
+    assert_eq!( &bytes[..2], &[0x81, frame.payload.len() as u8] );
+    assert!( Frame::read_from(&mut &bytes[..]).is_err() );
+
+    let long = Frame::new(OP_BINARY, vec![0; 300]).to_bytes();
+    assert_eq!( &long[..4], &[0x82, 126, 0x01, 0x2c] );
+    assert_eq!( long.len(), 304 );
+}
@@ -0,0 +1,204 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::i64;
+use std::str::FromStr;
+use chrono::{UTC, TimeZone};
+
+use core::{Device, Measurement, MeasurementsList, MeasurementPoint, Unit};
+
+/// Driver of the devices whose Graphite rule has no `{driver}`
+pub const DEFAULT_GRAPHITE_DRIVER: &'static str = "graphite";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `*`
+    Any,
+    Port,
+    Node,
+    Driver,
+}
+
+/// Map Graphite metric paths on devices
+///
+/// A rule is a dotted metric path whose segments may be `*`, matching any
+/// name, or `{port}`, `{node}` and `{driver}` giving the device of the
+/// metric. Its values are measurements in `unit`. `{port}` and `{node}` are
+/// mandatory, the driver default to `DEFAULT_GRAPHITE_DRIVER`.
+///
+/// ```
+/// use orion::core::Unit;
+/// use orion::ingest::GraphiteRule;
+///
+/// let rule = GraphiteRule::new("servers.{node}.*.{port}", Unit::Kelvin).unwrap();
+///
+/// let device = rule.device("servers.core.sensors.temp1").unwrap().unwrap();
+/// assert_eq!( device.get_slug(), "temp1@core.graphite" );
+///
+/// assert!( rule.device("servers.core.temp1").is_none() );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphiteRule {
+    segments: Vec<Segment>,
+    unit: Unit,
+}
+
+impl GraphiteRule {
+
+    /// Construct a rule matching `pattern`
+    ///
+    /// # Failures
+    ///
+    /// Return a message if a segment is empty or hold a `*`, `{` or `}`
+    /// with other characters, or if a field is missing or repeated.
+    pub fn new(pattern: &str, unit: Unit) -> Result<GraphiteRule, String> {
+        let mut segments = Vec::new();
+
+        for name in pattern.split('.') {
+            let segment = match name {
+                "{port}"   => Segment::Port,
+                "{node}"   => Segment::Node,
+                "{driver}" => Segment::Driver,
+                "*"        => Segment::Any,
+                x if !x.is_empty() && !x.contains(|c| "*{}".contains(c)) => {
+                    Segment::Literal(x.to_string())
+                },
+                x => return Err( format!("Invalid segment '{}'", x) ),
+            };
+
+            match segment {
+                Segment::Port | Segment::Node | Segment::Driver if segments.contains(&segment) => {
+                    return Err( format!("Duplicate field {}", name) );
+                },
+                _ => {},
+            }
+
+            segments.push(segment);
+        }
+
+        if !segments.contains(&Segment::Port) || !segments.contains(&Segment::Node) {
+            return Err( "Missing {port} or {node}".to_string() );
+        }
+
+        Ok( GraphiteRule {
+            segments: segments,
+            unit: unit,
+        })
+    }
+
+    /// Return the device of the metric at `path`
+    ///
+    /// Return `None` if `path` don't match the rule, or a message if its
+    /// segments are not valid device names.
+    pub fn device(&self, path: &str) -> Option<Result<Device, String>> {
+        let names: Vec<&str> = path.split('.').collect();
+
+        if names.len() != self.segments.len() {
+            return None;
+        }
+
+        let (mut port, mut node, mut driver) = ("", "", DEFAULT_GRAPHITE_DRIVER);
+
+        for (segment, name) in self.segments.iter().zip(names.iter()) {
+            match *segment {
+                Segment::Literal(ref x) => if x != name { return None },
+                Segment::Port           => port = name,
+                Segment::Node           => node = name,
+                Segment::Driver         => driver = name,
+                Segment::Any            => {},
+            }
+        }
+
+        Some( Device::new(port, node, driver).map_err(|e| format!("{} in '{}'", e, path)) )
+    }
+}
+
+/// Parse a Graphite plaintext line, `path value timestamp`
+///
+/// The point is built with the first rule matching the path. The timestamp
+/// is in seconds since the epoch, a missing or negative one is the current
+/// time.
+///
+/// Return `Ok(None)` if no rule match the path.
+pub fn parse_graphite(line: &str, rules: &[GraphiteRule])
+    -> Result<Option<MeasurementPoint>, String> {
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err( format!("Invalid Graphite line '{}'", line) );
+    }
+
+    let (rule, device) = match rules.iter()
+                                    .filter_map(|r| r.device(parts[0]).map(|d| (r, d)))
+                                    .next() {
+        Some((rule, Ok(device))) => (rule, device),
+        Some((_, Err(e)))        => return Err(e),
+        None                     => return Ok(None),
+    };
+
+    let value = match f32::from_str(parts[1]) {
+        Ok(x)  => x,
+        Err(_) => return Err( format!("Invalid value '{}'", parts[1]) ),
+    };
+
+    let date = match parts.get(2).map(|t| f64::from_str(t)) {
+        Some(Ok(t)) if t >= 0.0 && t < i64::MAX as f64 => {
+            match UTC.timestamp_opt(t as i64, 0).single() {
+                Some(x) => x,
+                None    => return Err( format!("Invalid timestamp '{}'", parts[2]) ),
+            }
+        },
+        Some(Ok(t)) if t < 0.0 => UTC::now(),
+        None                   => UTC::now(),
+        Some(_)                => return Err( format!("Invalid timestamp '{}'", parts[2]) ),
+    };
+
+    let mut data = MeasurementsList::new();
+    data.push( Measurement::new(value, rule.unit) );
+
+    Ok( Some( MeasurementPoint::new(device, date, data) ) )
+}
+
+
+#[test]
+fn test_graphite() {
+    let rules = [ GraphiteRule::new("servers.{node}.{driver}.{port}", Unit::Kelvin).unwrap(),
+                  GraphiteRule::new("power.{node}.{port}", Unit::Watt).unwrap() ];
+
+    let mp = parse_graphite("servers.core.lm.temp1 300.5 1433160000", &rules).unwrap().unwrap();
+    assert_eq!( mp.get_device().get_slug(), "temp1@core.lm" );
+    assert_eq!( mp.to_line(), "2015-06-01T12:00:00+00:00 300.5[K]\n" );
+
+    let mp = parse_graphite("power.rack1.psu1 12\n", &rules).unwrap().unwrap();
+    assert_eq!( mp.get_device().get_slug(), "psu1@rack1.graphite" );
+
+    assert_eq!( parse_graphite("other.core.temp1 3 1433160000", &rules), Ok(None) );
+    assert!( parse_graphite("power.rack1.psu1 twelve", &rules).is_err() );
+    assert!( parse_graphite("power.rack1.psu$ 12", &rules).is_err() );
+    assert!( parse_graphite("power.rack1.psu1", &rules).is_err() );
+    assert!( parse_graphite("power.rack1.psu1 12 1e20", &rules).is_err() );
+    assert!( parse_graphite("power.rack1.psu1 12 inf", &rules).is_err() );
+    assert!( parse_graphite("power.rack1.psu1 12 NaN", &rules).is_err() );
+
+    assert!( GraphiteRule::new("servers.{port}", Unit::Kelvin).is_err() );
+    assert!( GraphiteRule::new("{node}.{port}.{port}", Unit::Kelvin).is_err() );
+    assert!( GraphiteRule::new("{node}..{port}", Unit::Kelvin).is_err() );
+    assert!( GraphiteRule::new("{node}.a*.{port}", Unit::Kelvin).is_err() );
+}
@@ -0,0 +1,302 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use chrono::{UTC, TimeZone};
+
+use core::{Device, Measurement, MeasurementsList, MeasurementPoint, Unit};
+
+/// Map InfluxDB points on devices
+///
+/// A rule apply to the points of a `measurement`, or of every measurement
+/// with `*`. Its `device` is a slug where `{key}` is replaced by the value
+/// of the tag `key`, or by the measurement name for `{measurement}`. Its
+/// `fields`, like `temp[K]`, give the name and unit of the fields stored as
+/// measurements, in order.
+///
+/// ```
+/// use orion::ingest::{InfluxRule, parse_influx};
+///
+/// let fields = vec![ "temp[K]".to_string(), "power[W]".to_string() ];
+/// let rule = InfluxRule::new("sensors", "{sensor}@{host}.influx", &fields).unwrap();
+///
+/// let line = "sensors,host=core,sensor=cpu0 power=12i,temp=300.5 1433160000000000000";
+/// let mp = parse_influx(line, &[rule]).unwrap().unwrap();
+///
+/// assert_eq!( mp.get_device().get_slug(), "cpu0@core.influx" );
+/// assert_eq!( mp.to_line(), "2015-06-01T12:00:00+00:00 300.5[K] 12[W]\n" );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfluxRule {
+    measurement: String,
+    device: String,
+    fields: Vec<(String, Unit)>,
+}
+
+impl InfluxRule {
+
+    /// Construct a rule
+    ///
+    /// # Failures
+    ///
+    /// Return a message if `device` is not a valid slug once its
+    /// placeholders are filled, or if a field is not like `name[unit]`.
+    pub fn new(measurement: &str, device: &str, fields: &[String])
+        -> Result<InfluxRule, String> {
+
+        let example = fill(device, |_| Some("x")).unwrap_or(String::new());
+
+        if let Err(e) = Device::with_slug(&example) {
+            return Err( format!("{} in device '{}'", e, device) );
+        }
+
+        if fields.is_empty() {
+            return Err( "No field".to_string() );
+        }
+
+        let mut parsed = Vec::new();
+
+        for field in fields.iter() {
+            let unit = match (field.find('['), field.ends_with(']')) {
+                (Some(pos), true) if pos > 0 => {
+                    Unit::from_str(&field[pos + 1..field.len() - 1]).ok()
+                                                                    .map(|u| (pos, u))
+                },
+                _ => None,
+            };
+
+            match unit {
+                Some((pos, unit)) => parsed.push( (field[..pos].to_string(), unit) ),
+                None => return Err( format!("Invalid field '{}', use name[unit]", field) ),
+            }
+        }
+
+        Ok( InfluxRule {
+            measurement: measurement.to_string(),
+            device: device.to_string(),
+            fields: parsed,
+        })
+    }
+
+    fn matches(&self, measurement: &str) -> bool {
+        self.measurement == "*" || self.measurement == measurement
+    }
+}
+
+/// Replace each `{key}` of `template` by `value(key)`
+///
+/// Return `None` if a value is missing or a brace is not closed.
+fn fill<'a, F: Fn(&str) -> Option<&'a str>>(template: &str, value: F) -> Option<String> {
+    let mut filled = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(x) => start + x,
+            None    => return None,
+        };
+
+        filled.push_str(&rest[..start]);
+        match value(&rest[start + 1..end]) {
+            Some(x) => filled.push_str(x),
+            None    => return None,
+        }
+        rest = &rest[end + 1..];
+    }
+
+    filled.push_str(rest);
+    Some(filled)
+}
+
+/// A field value of the line protocol
+#[derive(Debug, Clone, PartialEq)]
+enum FieldValue {
+    Number(f64),
+    Text,
+}
+
+/// Parse an InfluxDB line protocol line,
+/// `measurement,tag=value field=value timestamp`
+///
+/// The point is built with the first rule matching the measurement. The
+/// timestamp is in nanoseconds since the epoch, a missing one is the current
+/// time. Integers and booleans fields are converted to numbers, string fields
+/// can't be stored.
+///
+/// Return `Ok(None)` for a comment, an empty line or if no rule match the
+/// measurement.
+pub fn parse_influx(line: &str, rules: &[InfluxRule])
+    -> Result<Option<MeasurementPoint>, String> {
+
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let sections = split(line, ' ');
+
+    if sections.len() < 2 || sections.len() > 3 {
+        return Err( format!("Invalid line '{}'", line) );
+    }
+
+    let key = split(sections[0], ',');
+    let measurement = unescape(key[0]);
+
+    let rule = match rules.iter().find(|r| r.matches(&measurement)) {
+        Some(x) => x,
+        None    => return Ok(None),
+    };
+
+    let mut tags = HashMap::new();
+    tags.insert("measurement".to_string(), measurement.clone());
+
+    for tag in key[1..].iter() {
+        let pair = split(tag, '=');
+
+        if pair.len() != 2 || pair[0].is_empty() || pair[1].is_empty() {
+            return Err( format!("Invalid tag '{}'", tag) );
+        }
+
+        tags.insert(unescape(pair[0]), unescape(pair[1]));
+    }
+
+    let mut fields = HashMap::new();
+
+    for field in split(sections[1], ',').iter() {
+        let pair = split(field, '=');
+
+        if pair.len() != 2 || pair[0].is_empty() {
+            return Err( format!("Invalid field '{}'", field) );
+        }
+
+        fields.insert(unescape(pair[0]), try!( field_value(pair[1]) ));
+    }
+
+    let slug = match fill(&rule.device, |key| tags.get(key).map(|v| &v[..])) {
+        Some(x) => x,
+        None    => return Err( format!("Missing tag for device '{}'", rule.device) ),
+    };
+
+    let device = try!( Device::with_slug(&slug).map_err(|e| format!("{} '{}'", e, slug)) );
+
+    let mut data = MeasurementsList::new();
+
+    for &(ref name, unit) in rule.fields.iter() {
+        match fields.get(name) {
+            Some(&FieldValue::Number(x)) => data.push( Measurement::new(x as f32, unit) ),
+            Some(&FieldValue::Text)      => return Err( format!("String field '{}'", name) ),
+            None                         => return Err( format!("Missing field '{}'", name) ),
+        }
+    }
+
+    let date = match sections.get(2).map(|t| i64::from_str(t)) {
+        Some(Ok(ns)) => {
+            let (secs, nanos) = (ns / 1_000_000_000, ns % 1_000_000_000);
+
+            if nanos < 0 {
+                UTC.timestamp(secs - 1, (nanos + 1_000_000_000) as u32)
+            } else {
+                UTC.timestamp(secs, nanos as u32)
+            }
+        },
+        Some(Err(_)) => return Err( format!("Invalid timestamp '{}'", sections[2]) ),
+        None         => UTC::now(),
+    };
+
+    Ok( Some( MeasurementPoint::new(device, date, data) ) )
+}
+
+fn field_value(value: &str) -> Result<FieldValue, String> {
+    if value.starts_with('"') {
+        return Ok(FieldValue::Text);
+    }
+
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE"      => return Ok( FieldValue::Number(1.0) ),
+        "f" | "F" | "false" | "False" | "FALSE"   => return Ok( FieldValue::Number(0.0) ),
+        _ => {},
+    }
+
+    let number = value.trim_right_matches(|c| c == 'i' || c == 'u');
+
+    match f64::from_str(number) {
+        Ok(x)  => Ok( FieldValue::Number(x) ),
+        Err(_) => Err( format!("Invalid field value '{}'", value) ),
+    }
+}
+
+/// Split `s` at each `separator` not escaped by a backslash nor quoted
+fn split(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Remove the backslashes escaping `,`, `=`, ` ` and `"`
+fn unescape(s: &str) -> String {
+    s.replace("\\,", ",").replace("\\=", "=").replace("\\ ", " ").replace("\\\"", "\"")
+}
+
+
+#[test]
+fn test_influx() {
+    let fields = vec![ "value[K]".to_string() ];
+    let rules = [ InfluxRule::new("temp", "{sensor}@{host}.influx", &fields).unwrap(),
+                  InfluxRule::new("*", "{measurement}@{host}.influx",
+                                  &vec![ "on[V]".to_string() ]).unwrap() ];
+
+    let mp = parse_influx("temp,host=core,sensor=cpu0 value=300 1433160000500000000",
+                          &rules).unwrap().unwrap();
+    assert_eq!( mp.get_device().get_slug(), "cpu0@core.influx" );
+    assert_eq!( mp.get_date(), UTC.ymd(2015, 6, 1).and_hms_milli(12, 0, 0, 500) );
+
+    let mp = parse_influx(r#"fan,host=core,label=cpu\ fan on=t,name="big one""#,
+                          &rules).unwrap().unwrap();
+    assert_eq!( mp.get_device().get_slug(), "fan@core.influx" );
+    assert_eq!( mp.get_data().to_string(), "1[V]" );
+
+    assert_eq!( parse_influx("# comment", &rules), Ok(None) );
+    assert!( parse_influx("temp,host=core value=300", &rules).is_err() );
+    assert!( parse_influx("temp,host=core,sensor=cpu0 other=1", &rules).is_err() );
+    assert!( parse_influx("temp,host=core,sensor=cpu0 value=\"hot\"", &rules).is_err() );
+    assert!( parse_influx("temp,host=core,sensor=cpu0 value=300 soon", &rules).is_err() );
+    assert!( parse_influx("temp,host=core,sensor=cpu0", &rules).is_err() );
+
+    assert!( InfluxRule::new("temp", "{sensor}@{host}", &fields).is_err() );
+    assert!( InfluxRule::new("temp", "{sensor}@{host}.influx", &vec![ "value".to_string() ]).is_err() );
+    assert!( InfluxRule::new("temp", "{sensor}@{host}.influx", &vec![ "v[Car]".to_string() ]).is_err() );
+}
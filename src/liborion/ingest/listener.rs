@@ -0,0 +1,248 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr};
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+
+use core::MeasurementPoint;
+use super::{GraphiteRule, InfluxRule, parse_graphite, parse_influx};
+
+/// Maximum size of a datagram received by `listen_udp`
+const MAX_DATAGRAM_LEN: usize = 65536;
+
+/// Maximum size of a line received by `listen_tcp`
+const MAX_LINE_LEN: usize = 65536;
+
+/// Maximum number of connections read at the same time by `listen_tcp`
+pub const MAX_INGEST_CONNECTIONS: usize = 64;
+
+/// Number of points queued between the listeners and the server, a full
+/// queue slows down the TCP clients
+pub const INGEST_QUEUE_LEN: usize = 1024;
+
+/// A text protocol with one point per line, and its mapping rules
+#[derive(Debug, Clone)]
+pub enum LineProtocol {
+    Graphite(Vec<GraphiteRule>),
+    Influx(Vec<InfluxRule>),
+}
+
+impl LineProtocol {
+
+    /// Parse `line`, see `parse_graphite` and `parse_influx`
+    pub fn parse(&self, line: &str) -> Result<Option<MeasurementPoint>, String> {
+        match *self {
+            LineProtocol::Graphite(ref rules) => parse_graphite(line, rules),
+            LineProtocol::Influx(ref rules)   => parse_influx(line, rules),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LineProtocol::Graphite(_) => "Graphite",
+            LineProtocol::Influx(_)   => "Influx",
+        }
+    }
+
+    /// Send the point of `line` to `points`
+    ///
+    /// Invalid lines and lines without matching rule are logged and
+    /// skipped. Return `false` if `points` is closed.
+    fn forward(&self, line: &str, points: &SyncSender<MeasurementPoint>) -> bool {
+        match self.parse(line) {
+            Ok(Some(mp)) => points.send(mp).is_ok(),
+            Ok(None) => {
+                if !line.trim().is_empty() {
+                    debug!("No {} rule for '{}'", self.name(), line.trim());
+                }
+                true
+            },
+            Err(e) => {
+                warn!("Skip {} line '{}': {}", self.name(), line.trim(), e);
+                true
+            },
+        }
+    }
+}
+
+/// Accept `protocol` lines on TCP at `address`, and send their points to
+/// `points`
+///
+/// Each connection is read by its own thread, up to
+/// `MAX_INGEST_CONNECTIONS` at the same time. Return the bound address.
+pub fn listen_tcp(address: &str, protocol: LineProtocol, points: SyncSender<MeasurementPoint>)
+    -> io::Result<SocketAddr> {
+
+    let listener = try!( TcpListener::bind(address) );
+    let local = try!( listener.local_addr() );
+
+    thread::spawn( move || {
+        let active = Arc::new( AtomicUsize::new(0) );
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(_) if active.load(Ordering::SeqCst) >= MAX_INGEST_CONNECTIONS => {
+                    warn!("{} connection dropped, {} connections already read",
+                          protocol.name(), MAX_INGEST_CONNECTIONS);
+                },
+                Ok(stream) => {
+                    let protocol = protocol.clone();
+                    let points = points.clone();
+                    let active = active.clone();
+
+                    active.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn( move || {
+                        read_lines(stream, &protocol, &points);
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    });
+                },
+                Err(e) => warn!("{} connection failed: {}", protocol.name(), e),
+            }
+        }
+    });
+
+    Ok(local)
+}
+
+/// Forward the lines of `stream`, until it is closed or a line is longer
+/// than `MAX_LINE_LEN`
+fn read_lines(stream: TcpStream, protocol: &LineProtocol, points: &SyncSender<MeasurementPoint>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+
+        match reader.by_ref().take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line) {
+            Ok(0) => return,
+            Ok(_) if line.len() > MAX_LINE_LEN => {
+                warn!("{} connection closed, line longer than {} bytes",
+                      protocol.name(), MAX_LINE_LEN);
+                return;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                debug!("{} connection closed: {}", protocol.name(), e);
+                return;
+            },
+        }
+
+        match str::from_utf8(&line) {
+            Ok(text) => if !protocol.forward(text.trim_right_matches(&['\r', '\n'][..]), points) {
+                return;
+            },
+            Err(_) => warn!("Skip {} line, not UTF-8", protocol.name()),
+        }
+    }
+}
+
+/// Accept datagrams of `protocol` lines on UDP at `address`, and send their
+/// points to `points`
+///
+/// Return the bound address.
+pub fn listen_udp(address: &str, protocol: LineProtocol, points: SyncSender<MeasurementPoint>)
+    -> io::Result<SocketAddr> {
+
+    let socket = try!( UdpSocket::bind(address) );
+    let local = try!( socket.local_addr() );
+
+    thread::spawn( move || {
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    warn!("{} datagram failed: {}", protocol.name(), e);
+                    continue;
+                },
+            };
+
+            let text = match str::from_utf8(&buf[..len]) {
+                Ok(x)  => x,
+                Err(_) => {
+                    warn!("Skip {} datagram, not UTF-8", protocol.name());
+                    continue;
+                },
+            };
+
+            for line in text.lines() {
+                if !protocol.forward(line, &points) {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(local)
+}
+
+
+#[test]
+fn test_listeners() {
+    use std::io::Write;
+    use std::sync::mpsc::sync_channel;
+    use std::time::Duration;
+    use core::Unit;
+
+    let graphite = LineProtocol::Graphite(vec![
+        GraphiteRule::new("servers.{node}.{port}", Unit::Kelvin).unwrap(),
+    ]);
+    let influx = LineProtocol::Influx(vec![
+        InfluxRule::new("power", "{psu}@{host}.influx", &vec![ "watts[W]".to_string() ]).unwrap(),
+    ]);
+
+    let (sender, points) = sync_channel(INGEST_QUEUE_LEN);
+    let tcp = listen_tcp("127.0.0.1:0", graphite, sender.clone()).unwrap();
+    let udp = listen_udp("127.0.0.1:0", influx, sender).unwrap();
+
+    let mut stream = TcpStream::connect(tcp).unwrap();
+    stream.write_all(b"servers.core.temp1 300 1433160000\nbad line\n").unwrap();
+    stream.write_all(b"servers.core.temp2 301 1433160000\r\n").unwrap();
+    drop(stream);
+
+    let timeout = Duration::from_secs(5);
+    let slugs: Vec<String> = (0..2).map(|_| {
+        points.recv_timeout(timeout).unwrap().get_device().get_slug().to_string()
+    }).collect();
+    assert_eq!( slugs, ["temp1@core.graphite", "temp2@core.graphite"] );
+
+    // A connection is closed on a line too long
+    let mut stream = TcpStream::connect(tcp).unwrap();
+    stream.write_all(b"servers.core.temp3 303\n").unwrap();
+    let _ = stream.write_all(&vec![b'x'; MAX_LINE_LEN + 1]);
+    let _ = stream.write_all(b"\nservers.core.temp4 304\n");
+    drop(stream);
+
+    let mp = points.recv_timeout(timeout).unwrap();
+    assert_eq!( mp.get_device().get_slug(), "temp3@core.graphite" );
+    assert!( points.recv_timeout(Duration::from_millis(200)).is_err() );
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(b"power,host=rack1,psu=psu1 watts=120i 1433160000000000000\n", udp).unwrap();
+
+    let mp = points.recv_timeout(timeout).unwrap();
+    assert_eq!( mp.get_device().get_slug(), "psu1@rack1.influx" );
+    assert_eq!( mp.to_line(), "2015-06-01T12:00:00+00:00 120[W]\n" );
+}
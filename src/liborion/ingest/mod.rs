@@ -0,0 +1,34 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Ingestion of the Graphite plaintext and InfluxDB line protocols
+//!
+//! Rules map the metric paths of Graphite and the measurements and tags of
+//! InfluxDB on devices, and give the units of their values. `listen_tcp`
+//! and `listen_udp` parse the lines received on a socket and send their
+//! points to a channel.
+
+mod graphite;
+pub use self::graphite::{GraphiteRule, parse_graphite, DEFAULT_GRAPHITE_DRIVER};
+
+mod influx;
+pub use self::influx::{InfluxRule, parse_influx};
+
+mod listener;
+pub use self::listener::{LineProtocol, listen_tcp, listen_udp};
+pub use self::listener::{MAX_INGEST_CONNECTIONS, INGEST_QUEUE_LEN};
@@ -17,12 +17,27 @@
 
 extern crate regex;
 extern crate nanomsg;
+extern crate chrono;
+extern crate rusqlite;
+extern crate toml;
+extern crate rustc_serialize;
+extern crate openssl;
+extern crate libc;
 
 #[macro_use] extern crate log;
 #[macro_use] extern crate patch;
 
 
 
+mod varint;
+mod crc32;
+
 pub mod core;
 pub mod logger;
+pub mod storage;
+pub mod alert;
+pub mod http;
+pub mod mqtt;
+pub mod ingest;
+pub mod tls;
 
@@ -0,0 +1,436 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use toml;
+
+use core::{Device, DeviceSelector};
+
+/// Start of a request carrying a token, the token end at the first new line
+pub const AUTH_PREFIX: &'static str = "LOGGER/1.0 AUTH ";
+
+/// Request stopping the server, reserved to the admin clients
+pub const STOP_REQUEST: &'static str = "LOGGER/1.0 STOP";
+
+/// A client allowed to send requests to the logger server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub name: String,
+    token: String,
+    /// Admin clients can write any device and stop the server
+    pub admin: bool,
+    /// Devices the client can write
+    pub write: Vec<DeviceSelector>,
+}
+
+impl Client {
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Return `true` if the client can log points of `device`
+    pub fn can_write(&self, device: &Device) -> bool {
+        self.admin || self.write.iter().any(|s| s.matches(device))
+    }
+}
+
+/// Clients of the logger server, loaded from a TOML file
+///
+/// The file hold a `[[client]]` table per client:
+///
+/// ```toml
+/// [[client]]
+/// name = "weather-station"
+/// token = "c2VjcmV0LXRva2Vu"
+/// write = ["*@weather.*", "temp*@roof.lm-sensors"]
+///
+/// [[client]]
+/// name = "operator"
+/// token = "YW5vdGhlci10b2tlbg"
+/// admin = true
+/// ```
+///
+/// Every request of a client start with `LOGGER/1.0 AUTH <token>` and a
+/// new line, see `with_token`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessList {
+    clients: Vec<Client>,
+}
+
+impl AccessList {
+
+    /// Load the clients from the file at `path`
+    pub fn load(path: &Path) -> Result<AccessList, AuthError> {
+        let mut content = String::new();
+        let mut file = try!( File::open(path) );
+        try!( file.read_to_string(&mut content) );
+
+        AccessList::from_str(&content)
+    }
+
+    /// Return the client owning `token`
+    pub fn authenticate(&self, token: &str) -> Option<&Client> {
+        self.clients.iter().find(|c| same_token(&c.token, token))
+    }
+
+    /// Return the client called `name`, like the common name of its TLS
+    /// certificate
+    pub fn find(&self, name: &str) -> Option<&Client> {
+        self.clients.iter().find(|c| c.name == name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+}
+
+impl FromStr for AccessList {
+
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<AccessList, AuthError> {
+        let mut parser = toml::Parser::new(s);
+
+        let table = match parser.parse() {
+            Some(x) => x,
+            None    => {
+                let msg = parser.errors.iter()
+                                       .map(|e| e.desc.clone())
+                                       .collect::<Vec<_>>()
+                                       .join(", ");
+                return Err( AuthError::Invalid(msg) );
+            },
+        };
+
+        let entries = match table.get("client") {
+            Some(x) => match x.as_slice() {
+                Some(x) => x,
+                None    => return Err( invalid("client must be an array of tables") ),
+            },
+            None => &[],
+        };
+
+        let mut clients: Vec<Client> = Vec::new();
+
+        for entry in entries.iter() {
+            let client = try!( from_toml(entry) );
+
+            if clients.iter().any(|c| c.token == client.token) {
+                return Err( invalid(&format!("Token of {} already used", client.name)) );
+            }
+
+            clients.push(client);
+        }
+
+        Ok( AccessList { clients: clients } )
+    }
+}
+
+fn invalid(msg: &str) -> AuthError {
+    AuthError::Invalid(msg.to_string())
+}
+
+fn from_toml(entry: &toml::Value) -> Result<Client, AuthError> {
+    let string = |key: &str| match entry.lookup(key).map(|x| x.as_str()) {
+        Some(Some(x)) => Ok( x.to_string() ),
+        Some(None)    => Err( invalid(&format!("{} must be a string", key)) ),
+        None          => Err( invalid(&format!("Missing {}", key)) ),
+    };
+
+    let name = try!( string("name") );
+    let token = try!( string("token") );
+
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Err( invalid(&format!("Invalid token for {}", name)) );
+    }
+
+    let admin = match entry.lookup("admin") {
+        Some(x) => match x.as_bool() {
+            Some(x) => x,
+            None    => return Err( invalid("admin must be a boolean") ),
+        },
+        None => false,
+    };
+
+    let mut write = Vec::new();
+
+    if let Some(x) = entry.lookup("write") {
+        let selectors = match x.as_slice() {
+            Some(x) => x,
+            None    => return Err( invalid("write must be an array of strings") ),
+        };
+
+        for selector in selectors.iter() {
+            let s = match selector.as_str() {
+                Some(x) => x,
+                None    => return Err( invalid("write must be an array of strings") ),
+            };
+
+            match DeviceSelector::from_str(s) {
+                Ok(x)  => write.push(x),
+                Err(e) => return Err( invalid(&format!("{} '{}'", e, s)) ),
+            }
+        }
+    }
+
+    Ok( Client {
+        name: name,
+        token: token,
+        admin: admin,
+        write: write,
+    })
+}
+
+/// Compare two tokens in a time independent of their content
+fn same_token(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Prefix `request` with the `AUTH` line of `token`
+///
+/// # Example
+///
+/// ```
+/// use orion::logger::{with_token, split_token};
+///
+/// let request = with_token("secret", b"LOGGER/1.0 STOP");
+/// assert_eq!( request, b"LOGGER/1.0 AUTH secret\nLOGGER/1.0 STOP".to_vec() );
+///
+/// assert_eq!( split_token(&request), (Some("secret"), &b"LOGGER/1.0 STOP"[..]) );
+/// assert_eq!( split_token(b"LOGGER/1.0 STOP"), (None, &b"LOGGER/1.0 STOP"[..]) );
+/// ```
+pub fn with_token(token: &str, request: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(AUTH_PREFIX.len() + token.len() + 1 + request.len());
+
+    buf.extend(AUTH_PREFIX.as_bytes());
+    buf.extend(token.as_bytes());
+    buf.push(b'\n');
+    buf.extend(request);
+
+    buf
+}
+
+/// Split the token of a request made by `with_token` from the request
+///
+/// Return no token if the request don't start with an `AUTH` line.
+pub fn split_token(request: &[u8]) -> (Option<&str>, &[u8]) {
+    if !request.starts_with(AUTH_PREFIX.as_bytes()) {
+        return (None, request);
+    }
+
+    let rest = &request[AUTH_PREFIX.len()..];
+
+    match rest.iter().position(|&b| b == b'\n') {
+        Some(end) => match ::std::str::from_utf8(&rest[..end]) {
+            Ok(token) => (Some(token), &rest[end + 1..]),
+            Err(_)    => (None, request),
+        },
+        None => (None, request),
+    }
+}
+
+/// Check the token of `request` against `access`
+///
+/// Return the client sending the request, and the request without its
+/// `AUTH` line. Without `access` anyone can send any request and there is
+/// no client. The devices of the points are checked by `Client::can_write`.
+///
+/// # Failures
+///
+/// - `Denied::Unauthorized` if the token is missing or unknown
+/// - `Denied::Forbidden` for a `STOP_REQUEST` of a client not admin
+pub fn authorize<'a, 'r>(access: Option<&'a AccessList>, request: &'r [u8])
+    -> Result<(Option<&'a Client>, &'r [u8]), Denied> {
+
+    let (token, request) = split_token(request);
+
+    let access = match access {
+        Some(x) => x,
+        None    => return Ok( (None, request) ),
+    };
+
+    let client = match token.and_then(|t| access.authenticate(t)) {
+        Some(x) => x,
+        None    => return Err(Denied::Unauthorized),
+    };
+
+    if request == STOP_REQUEST.as_bytes() && !client.admin {
+        return Err(Denied::Forbidden);
+    }
+
+    Ok( (Some(client), request) )
+}
+
+/// Why a request is refused by `authorize`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denied {
+    Unauthorized,
+    Forbidden,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.description().fmt(f)
+    }
+}
+
+impl Error for Denied {
+    fn description(&self) -> &str {
+        match *self {
+            Denied::Unauthorized => "Unauthorized",
+            Denied::Forbidden    => "Forbidden",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::Io(ref err)      => write!(f, "{}", err),
+            AuthError::Invalid(ref msg) => write!(f, "Invalid client list: {}", msg),
+        }
+    }
+}
+
+impl Error for AuthError {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::Io(ref err)  => err.description(),
+            AuthError::Invalid(..)  => "Invalid client list",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            AuthError::Io(ref err) => Some(err),
+            _                      => None,
+        }
+    }
+}
+
+impl From<io::Error> for AuthError {
+    fn from(err: io::Error) -> AuthError {
+        AuthError::Io(err)
+    }
+}
+
+
+#[test]
+fn test_access_list() {
+    let access = AccessList::from_str(r#"
+        [[client]]
+        name = "weather"
+        token = "abc123"
+        write = ["*@weather.*", "temp1@roof.lm-sensors"]
+
+        [[client]]
+        name = "operator"
+        token = "xyz789"
+        admin = true
+    "#).unwrap();
+
+    assert_eq!( access.len(), 2 );
+    assert_eq!( access.find("operator").map(|c| c.token()), Some("xyz789") );
+    assert_eq!( access.find("xyz789"), None );
+    assert_eq!( access.authenticate("nope"), None );
+    assert_eq!( access.authenticate("abc12"), None );
+
+    let weather = access.authenticate("abc123").unwrap();
+    assert_eq!( weather.name, "weather" );
+    assert!( !weather.admin );
+
+    let device = |s| Device::with_slug(s).unwrap();
+    assert!( weather.can_write(&device("wind@weather.davis")) );
+    assert!( weather.can_write(&device("temp1@roof.lm-sensors")) );
+    assert!( !weather.can_write(&device("temp2@roof.lm-sensors")) );
+
+    let operator = access.authenticate("xyz789").unwrap();
+    assert!( operator.admin );
+    assert!( operator.can_write(&device("temp2@roof.lm-sensors")) );
+
+    assert_eq!( AccessList::from_str("").unwrap().len(), 0 );
+
+    let errors = [
+        "[[client]]\nname = \"a\"",
+        "[[client]]\nname = \"a\"\ntoken = \"with space\"",
+        "[[client]]\nname = \"a\"\ntoken = \"t\"\nadmin = \"yes\"",
+        "[[client]]\nname = \"a\"\ntoken = \"t\"\nwrite = [\"bad\"]",
+        "[[client]]\nname = \"a\"\ntoken = \"t\"\n[[client]]\nname = \"b\"\ntoken = \"t\"",
+        "client = 1",
+    ];
+
+    for s in errors.iter() {
+        assert!( AccessList::from_str(s).is_err(), "{}", s );
+    }
+}
+
+#[test]
+fn test_authorize() {
+    let access = AccessList::from_str(r#"
+        [[client]]
+        name = "weather"
+        token = "abc123"
+        write = ["*@weather.*"]
+
+        [[client]]
+        name = "operator"
+        token = "xyz789"
+        admin = true
+    "#).unwrap();
+    let access = Some(&access);
+    let stop = STOP_REQUEST.as_bytes();
+
+    // Anything goes without client list
+    assert_eq!( authorize(None, stop), Ok( (None, stop) ) );
+    assert_eq!( authorize(None, &with_token("nope", stop)), Ok( (None, stop) ) );
+
+    assert_eq!( authorize(access, stop), Err(Denied::Unauthorized) );
+    assert_eq!( authorize(access, &with_token("nope", b"LOGGER/1.0 ALERTS")),
+                Err(Denied::Unauthorized) );
+    assert_eq!( authorize(access, &with_token("abc123", stop)), Err(Denied::Forbidden) );
+
+    let request = with_token("abc123", b"LOGGER/1.0 ALERTS");
+    let (client, request) = authorize(access, &request).unwrap();
+    assert_eq!( client.map(|c| &c.name[..]), Some("weather") );
+    assert_eq!( request, b"LOGGER/1.0 ALERTS" );
+
+    let request = with_token("xyz789", stop);
+    let (client, request) = authorize(access, &request).unwrap();
+    assert!( client.unwrap().admin );
+    assert_eq!( request, stop );
+}
@@ -0,0 +1,209 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+
+use varint;
+use tls::MAX_MESSAGE_LEN;
+
+/// Start of a request holding many binary frames
+pub const BATCH_PREFIX: &'static str = "LOGGER/1.0 BATCH\n";
+
+/// Most frames accepted in a batch
+pub const MAX_BATCH_LEN: usize = 10000;
+
+/// Most bytes in a batch sent by `AsyncChannel`, leaving room for the token
+/// line below the `MAX_MESSAGE_LEN` of TLS and nanomsg messages
+pub const MAX_BATCH_SIZE: usize = MAX_MESSAGE_LEN - 4096;
+
+/// Result of a frame of a batch, the error is the reason of the server
+pub type BatchResult = Result<(), String>;
+
+/// Return `true` if `request` was made by `encode_batch`
+pub fn is_batch(request: &[u8]) -> bool {
+    request.starts_with(BATCH_PREFIX.as_bytes())
+}
+
+/// Encode the binary frames of many points in a single request
+///
+/// Each frame is tagged by an id, used to find its result in the reply.
+/// After `BATCH_PREFIX`, each frame is written as its id and its length,
+/// both as varints, then its content.
+///
+/// # Example
+///
+/// ```
+/// use orion::logger::{encode_batch, decode_batch};
+///
+/// let request = encode_batch(&[ (7, vec![1, 2, 3]), (8, vec![4]) ]);
+/// assert_eq!( request, b"LOGGER/1.0 BATCH\n\x07\x03\x01\x02\x03\x08\x01\x04".to_vec() );
+/// assert_eq!( decode_batch(&request).unwrap(), vec![ (7, vec![1, 2, 3]), (8, vec![4]) ] );
+/// ```
+pub fn encode_batch(frames: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut request = BATCH_PREFIX.as_bytes().to_vec();
+
+    for &(id, ref frame) in frames.iter() {
+        varint::write_u64(&mut request, id).unwrap();
+        varint::write_u64(&mut request, frame.len() as u64).unwrap();
+        request.extend(frame);
+    }
+
+    request
+}
+
+/// Return the bytes taken by a frame in a request made by `encode_batch`
+pub fn batch_frame_size(id: u64, frame: &[u8]) -> usize {
+    varint_size(id) + varint_size(frame.len() as u64) + frame.len()
+}
+
+fn varint_size(mut value: u64) -> usize {
+    let mut size = 1;
+
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+
+    size
+}
+
+/// Decode a request made by `encode_batch`
+///
+/// # Failures
+///
+/// Fail with `ErrorKind::InvalidData` if `request` is not a batch, is
+/// truncated or hold more than `MAX_BATCH_LEN` frames.
+pub fn decode_batch(request: &[u8]) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    if !is_batch(request) {
+        return Err( invalid("Not a batch") );
+    }
+
+    let mut r = &request[BATCH_PREFIX.len()..];
+    let mut frames = Vec::new();
+
+    while let Some(id) = try!( varint::read_u64(&mut r) ) {
+        if frames.len() == MAX_BATCH_LEN {
+            return Err( invalid("Too many frames in batch") );
+        }
+
+        let len = match try!( varint::read_u64(&mut r) ) {
+            Some(x) if x as usize <= r.len() => x as usize,
+            _ => return Err( invalid("Truncated batch") ),
+        };
+
+        let mut frame = vec![0; len];
+        try!( r.read_exact(&mut frame) );
+        frames.push( (id, frame) );
+    }
+
+    Ok(frames)
+}
+
+/// Return the reply to a batch, holding the result of each frame
+///
+/// It is `LOGGER/1.0 OK` then a line per frame, with its id followed by
+/// `OK` or by `ERROR` and the reason.
+///
+/// # Example
+///
+/// ```
+/// use orion::logger::{batch_reply, parse_batch_reply};
+///
+/// let results = vec![ (7, Ok(())), (8, Err("Invalid unit".to_string())) ];
+/// let reply = batch_reply(&results);
+/// assert_eq!( reply, "LOGGER/1.0 OK\n7 OK\n8 ERROR Invalid unit\n" );
+///
+/// let parsed = parse_batch_reply(&reply).unwrap();
+/// assert_eq!( parsed.get(&8), Some(&Err("Invalid unit".to_string())) );
+/// ```
+pub fn batch_reply(results: &[(u64, BatchResult)]) -> String {
+    let mut reply = "LOGGER/1.0 OK\n".to_string();
+
+    for &(id, ref result) in results.iter() {
+        match *result {
+            Ok(_)        => reply.push_str(&format!("{} OK\n", id)),
+            Err(ref msg) => reply.push_str(&format!("{} ERROR {}\n", id, msg)),
+        }
+    }
+
+    reply
+}
+
+/// Parse a reply made by `batch_reply`, by frame id
+///
+/// # Failures
+///
+/// Fail with `ErrorKind::Other` and the reply as message if the whole batch
+/// was refused, and with `ErrorKind::InvalidData` for an invalid line.
+pub fn parse_batch_reply(reply: &str) -> io::Result<HashMap<u64, BatchResult>> {
+    let mut lines = reply.lines();
+
+    if lines.next() != Some("LOGGER/1.0 OK") {
+        return Err( io::Error::new(io::ErrorKind::Other, reply) );
+    }
+
+    let mut results = HashMap::new();
+
+    for line in lines {
+        let mut parts = line.splitn(3, ' ');
+
+        let id = parts.next().and_then(|x| x.parse().ok());
+        let result = match (parts.next(), parts.next()) {
+            (Some("OK"), None)         => Some( Ok( () ) ),
+            (Some("ERROR"), Some(msg)) => Some( Err( msg.to_string() ) ),
+            _                          => None,
+        };
+
+        match (id, result) {
+            (Some(id), Some(result)) => { results.insert(id, result); },
+            _ => return Err( io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("Invalid batch reply '{}'", line)) ),
+        }
+    }
+
+    Ok(results)
+}
+
+
+#[test]
+fn test_batch() {
+    assert!( decode_batch(b"LOGGER/1.0 STOP").is_err() );
+    assert_eq!( decode_batch(BATCH_PREFIX.as_bytes()).unwrap(), vec![] );
+
+    // Truncated frame and truncated length
+    assert!( decode_batch(b"LOGGER/1.0 BATCH\n\x01\x05\x01\x02").is_err() );
+    assert!( decode_batch(b"LOGGER/1.0 BATCH\n\x01").is_err() );
+
+    let frames: Vec<_> = (0..MAX_BATCH_LEN as u64 + 1).map(|id| (id, vec![0])).collect();
+    assert_eq!( decode_batch(&encode_batch(&frames[1..])).unwrap().len(), MAX_BATCH_LEN );
+    assert!( decode_batch(&encode_batch(&frames)).is_err() );
+
+    let frames = vec![ (300, vec![0xff; 200]), (0, vec![]) ];
+    assert_eq!( decode_batch(&encode_batch(&frames)).unwrap(), frames );
+    assert_eq!( frames.iter().fold(BATCH_PREFIX.len(), |size, &(id, ref frame)| {
+        size + batch_frame_size(id, frame)
+    }), encode_batch(&frames).len() );
+
+    assert!( parse_batch_reply("LOGGER/1.0 ERROR Unauthorized").is_err() );
+    assert!( parse_batch_reply("LOGGER/1.0 OK\n1 MAYBE\n").is_err() );
+    assert!( parse_batch_reply("LOGGER/1.0 OK\nx OK\n").is_err() );
+    assert_eq!( parse_batch_reply("LOGGER/1.0 OK\n").unwrap().len(), 0 );
+}
@@ -15,20 +15,87 @@
 // You should have received a copy of the GNU General Public License
 // along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
+use std::mem;
 use std::ops::Drop;
 use std::io::{Write,Read};
+use std::io::{Error, ErrorKind};
 use std::io::Result as IOResult;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 use nanomsg::Socket;
 use nanomsg::Endpoint;
 use nanomsg::Protocol;
 use nanomsg::Result as NanoResult;
 
+use core::MeasurementPoint;
+use tls;
+use tls::{TlsOptions, TlsStream};
+use super::with_token;
+use super::Spool;
+
 const CLIENT_DEVICE_URL: &'static str = "ipc:///tmp/orion_logger_front.ipc";
 
+/// How a `Channel` deal with a slow or unreachable server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelOptions {
+    /// Wait forever without timeout
+    pub send_timeout: Option<Duration>,
+    pub receive_timeout: Option<Duration>,
+    /// Attempts after a failed request, each after a new connection
+    ///
+    /// A request whose reply is lost is sent again, so a point may be
+    /// logged twice. Likewise with a spool, a point spooled after a lost
+    /// reply is logged again when replayed.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each following one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// File keeping the points while the server is unreachable, see `Spool`
+    pub spool: Option<PathBuf>,
+}
+
+impl ChannelOptions {
+
+    /// Return the options of `Channel::new`, waiting forever without retry
+    /// nor spool
+    pub fn new() -> ChannelOptions {
+        ChannelOptions {
+            send_timeout: None,
+            receive_timeout: None,
+            retries: 0,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            spool: None,
+        }
+    }
+}
+
 pub struct Channel {
 
-    socket: Socket,
-    endpoint: Endpoint,
+    target: Target,
+    transport: Transport,
+    token: Option<String>,
+    options: ChannelOptions,
+    spool: Option<Spool>,
+}
+
+/// Server of a `Channel`, kept to connect again
+enum Target {
+    Local,
+    Tls(String, TlsOptions),
+}
+
+/// Connection of a `Channel` to the server
+enum Transport {
+    /// Local IPC socket of the server
+    Nanomsg(Socket, Endpoint),
+    /// TLS endpoint of a remote server, messages are written by
+    /// `tls::write_message`
+    Tls(TlsStream),
+    /// Connected again by the next request
+    Closed,
 }
 
 impl Channel {
@@ -38,25 +105,306 @@ impl Channel {
 
         Ok(
             Channel{
-                socket: socket,
-                endpoint: endpoint,
+                target: Target::Local,
+                transport: Transport::Nanomsg(socket, endpoint),
+                token: None,
+                options: ChannelOptions::new(),
+                spool: None,
+            }
+        )
+    }
+
+    /// Connect to the TLS endpoint of a remote server at `address`, like
+    /// `logger.example.com:7878`
+    ///
+    /// `options` need the `ca` of the server, and a client certificate if
+    /// the server require one. If the server is unreachable, the connection
+    /// is made by the next request.
+    pub fn connect_tls(address: &str, options: &TlsOptions) -> IOResult<Channel> {
+        try!( options.client_context()
+                     .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string())) );
+
+        let target = Target::Tls(address.to_string(), options.clone());
+
+        let transport = match connect(&target) {
+            Ok(x)  => x,
+            Err(e) => {
+                debug!("Failed to connect to the logger server: {}", e);
+                Transport::Closed
+            },
+        };
+
+        Ok(
+            Channel{
+                transport: transport,
+                target: target,
+                token: None,
+                options: ChannelOptions::new(),
+                spool: None,
             }
         )
     }
 
+    /// Authenticate every following request with `token`
+    ///
+    /// Needed when the server is started with a client list, see
+    /// `AccessList`.
+    pub fn set_token(&mut self, token: &str) {
+        self.token = Some(token.to_string());
+    }
+
+    /// Send `mp` like `add`, authenticated with `token` instead of the
+    /// token of the channel
+    ///
+    /// Used to forward the points of another client with its permissions,
+    /// on a channel without spool: the spooled points are always replayed
+    /// with the token of the channel.
+    pub fn add_as(&mut self, mp: &MeasurementPoint, token: &str) -> IOResult<()> {
+        let own = mem::replace(&mut self.token, Some(token.to_string()));
+        let result = self.add(mp);
+        self.token = own;
+
+        result
+    }
+
+    /// Set the timeouts, retries and spool of the channel
+    ///
+    /// Fail if the spool can't be opened.
+    pub fn set_options(&mut self, options: ChannelOptions) -> IOResult<()> {
+        self.spool = match options.spool {
+            Some(ref path) => Some( try!( Spool::open(path) ) ),
+            None           => None,
+        };
+        self.options = options;
+
+        set_timeouts(&mut self.transport, &self.options)
+    }
+
+    /// Return the number of points waiting in the spool, as seen by the last
+    /// operation of this channel
+    pub fn spooled(&self) -> usize {
+        self.spool.as_ref().map_or(0, |s| s.len())
+    }
+
     pub fn request(&mut self, data: String) -> IOResult<String> {
-        let mut reply = String::new();
+        self.request_bytes(data.as_bytes())
+    }
 
-        try!( self.socket.write_all(data.as_bytes()) );
-        try!( self.socket.read_to_string(&mut reply) );
+    /// Send a raw request, like a binary frame, and wait for the reply
+    ///
+    /// A failed request is sent again up to `retries` times, each time on a
+    /// new connection, so the server may receive it more than once.
+    pub fn request_bytes(&mut self, data: &[u8]) -> IOResult<String> {
+        let retries = self.options.retries;
+        self.exchange(data, retries)
+    }
 
-        Ok( reply )
+    /// Send a raw request once, ignoring `retries`
+    ///
+    /// For requests the server must not receive twice, the error may come
+    /// after the server logged them.
+    pub fn request_once(&mut self, data: &[u8]) -> IOResult<String> {
+        self.exchange(data, 0)
+    }
+
+    fn exchange(&mut self, data: &[u8], retries: u32) -> IOResult<String> {
+        let request = match self.token {
+            Some(ref token) => with_token(token, data),
+            None            => data.to_vec(),
+        };
+
+        let mut delay = self.options.backoff;
+        let mut attempt = 0;
+
+        loop {
+            if let Transport::Closed = self.transport {
+                self.reconnect();
+            }
+
+            let err = match transfer(&mut self.transport, &request) {
+                Ok(reply) => return Ok(reply),
+                Err(e)    => e,
+            };
+
+            // The reply may still come, drop the connection to not read it
+            // as the reply of the next request
+            self.shutdown();
+            self.transport = Transport::Closed;
+
+            if attempt >= retries {
+                return Err(err);
+            }
+
+            attempt += 1;
+            warn!("Request to the logger server failed: {}, retry {} of {} in {:?}",
+                  err, attempt, retries, delay);
+
+            thread::sleep(delay);
+            delay = cmp::min(delay * 2, self.options.max_backoff);
+        }
+    }
+
+    fn reconnect(&mut self) {
+        let transport = connect(&self.target).and_then(|mut transport| {
+            try!( set_timeouts(&mut transport, &self.options) );
+            Ok(transport)
+        });
+
+        match transport {
+            Ok(x)  => self.transport = x,
+            Err(e) => debug!("Failed to connect to the logger server: {}", e),
+        }
+    }
+
+    /// Send a `MeasurementPoint` to the logger server as a binary frame
+    ///
+    /// With a spool, the spooled points are sent first, and `mp` is spooled
+    /// instead of failing if the server is unreachable. The spool file is
+    /// always checked, as other processes may have spooled points.
+    ///
+    /// # Failures
+    ///
+    /// Fail with `ErrorKind::Other` and the server reply as message if the
+    /// server don't accept the point.
+    pub fn add(&mut self, mp: &MeasurementPoint) -> IOResult<()> {
+        if self.replay(0).is_err() {
+            return self.spool(mp);
+        }
+
+        let reply = match self.request_bytes(&mp.to_frame()) {
+            Ok(x)  => x,
+            Err(e) => match self.spool {
+                Some(_) => {
+                    warn!("Spool {:?}, the logger server is unreachable: {}", mp, e);
+                    return self.spool(mp);
+                },
+                None => return Err(e),
+            },
+        };
+
+        if reply == "LOGGER/1.0 OK" {
+            Ok( () )
+        } else {
+            Err( Error::new(ErrorKind::Other, reply) )
+        }
+    }
+
+    /// Send the spooled points to the server, oldest first
+    ///
+    /// Return the number of points sent. Points refused by the server are
+    /// logged and dropped. On failure the points not sent stay spooled.
+    pub fn flush(&mut self) -> IOResult<usize> {
+        let retries = self.options.retries;
+        self.replay(retries)
+    }
+
+    fn replay(&mut self, retries: u32) -> IOResult<usize> {
+        let mut spool = match self.spool.take() {
+            Some(x) => x,
+            None    => return Ok(0),
+        };
+
+        let result = self.replay_from(&mut spool, retries);
+        self.spool = Some(spool);
+
+        result
+    }
+
+    fn replay_from(&mut self, spool: &mut Spool, retries: u32) -> IOResult<usize> {
+        spool.drain(|mp| {
+            match try!( self.exchange(&mp.to_frame(), retries) ) {
+                ref reply if reply == "LOGGER/1.0 OK" => {},
+                reply => warn!("Spooled point {:?} refused: {}", mp, reply),
+            }
+
+            Ok( () )
+        })
+    }
+
+    fn spool(&mut self, mp: &MeasurementPoint) -> IOResult<()> {
+        match self.spool {
+            Some(ref mut spool) => spool.push(mp),
+            None                => Ok( () ),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if let Transport::Nanomsg(_, ref mut endpoint) = self.transport {
+            endpoint.shutdown();
+        }
     }
 }
 
 impl Drop for Channel {
     fn drop(&mut self) {
-        self.endpoint.shutdown();
+        self.shutdown();
+    }
+}
+
+fn connect(target: &Target) -> IOResult<Transport> {
+    match *target {
+        Target::Local => {
+            let mut socket = try!( Socket::new(Protocol::Req) );
+            let endpoint = try!( socket.connect(CLIENT_DEVICE_URL) );
+
+            Ok( Transport::Nanomsg(socket, endpoint) )
+        },
+        Target::Tls(ref address, ref options) => {
+            let ctx = try!( options.client_context()
+                                   .map_err(|e| Error::new(ErrorKind::Other, e.to_string())) );
+
+            Ok( Transport::Tls( try!( tls::connect(&ctx, address) ) ) )
+        },
+    }
+}
+
+fn set_timeouts(transport: &mut Transport, options: &ChannelOptions) -> IOResult<()> {
+    match *transport {
+        Transport::Nanomsg(ref mut socket, _) => {
+            try!( socket.set_send_timeout(millis(options.send_timeout)) );
+            try!( socket.set_receive_timeout(millis(options.receive_timeout)) );
+        },
+        Transport::Tls(ref stream) => {
+            try!( stream.get_ref().set_write_timeout(options.send_timeout) );
+            try!( stream.get_ref().set_read_timeout(options.receive_timeout) );
+        },
+        Transport::Closed => {},
+    }
+
+    Ok( () )
+}
+
+/// Return a timeout as expected by nanomsg, -1 for none
+fn millis(timeout: Option<Duration>) -> isize {
+    match timeout {
+        Some(d) => (d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000) as isize,
+        None    => -1,
+    }
+}
+
+/// Send `request` and wait for the reply
+fn transfer(transport: &mut Transport, request: &[u8]) -> IOResult<String> {
+    match *transport {
+        Transport::Nanomsg(ref mut socket, _) => {
+            let mut reply = String::new();
+
+            try!( socket.write_all(request) );
+            try!( socket.read_to_string(&mut reply) );
+
+            Ok( reply )
+        },
+        Transport::Tls(ref mut stream) => {
+            try!( tls::write_message(stream, request) );
+
+            match try!( tls::read_message(stream) ) {
+                Some(reply) => String::from_utf8(reply).map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, e)
+                }),
+                None => Err( Error::new(ErrorKind::UnexpectedEof, "Connection closed") ),
+            }
+        },
+        Transport::Closed => Err( Error::new(ErrorKind::NotConnected,
+                                             "Not connected to the logger server") ),
     }
 }
 
@@ -64,3 +412,76 @@ impl Drop for Channel {
 fn test_channel_new() {
     let channel = Channel::new().unwrap();
 }
+
+#[test]
+fn test_channel_spool() {
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use core::Device;
+
+    let root = env::temp_dir().join("orion_test_channel_spool");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    tls::generate_self_signed("localhost", 1, &root.join("server.pem"), &root.join("server.key"))
+        .unwrap();
+
+    let mut server = TlsOptions::new();
+    server.cert = Some(root.join("server.pem"));
+    server.key = Some(root.join("server.key"));
+
+    let mut client = TlsOptions::new();
+    client.ca = Some(root.join("server.pem"));
+
+    let device = Device::with_slug("temp1@core.lm").unwrap();
+    let points: Vec<_> = (0..3).map(|i| {
+        let line = format!("2015-06-01T12:00:0{}+00:00 30{}[K]", i, i);
+        MeasurementPoint::from_line(device.clone(), &line).unwrap()
+    }).collect();
+
+    // The points are spooled while the server is down
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut channel = Channel::connect_tls(&format!("localhost:{}", port), &client).unwrap();
+    channel.set_options(ChannelOptions {
+        retries: 1,
+        backoff: Duration::from_millis(10),
+        spool: Some(root.join("spool.txt")),
+        .. ChannelOptions::new()
+    }).unwrap();
+
+    channel.add(&points[0]).unwrap();
+    channel.add(&points[1]).unwrap();
+    assert_eq!( channel.spooled(), 2 );
+
+    // A server dropping its first connection, then logging the points
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let ctx = server.server_context().unwrap();
+    let (received, logged) = mpsc::channel();
+
+    thread::spawn( move || {
+        drop( listener.accept().unwrap() );
+
+        for stream in listener.incoming() {
+            let mut stream = tls::accept(&ctx, stream.unwrap()).unwrap();
+
+            while let Some(frame) = tls::read_message(&mut stream).unwrap() {
+                received.send( MeasurementPoint::from_frame(&frame).unwrap() ).unwrap();
+                tls::write_message(&mut stream, b"LOGGER/1.0 OK").unwrap();
+            }
+        }
+    });
+
+    // Without retry, the replay before a new point fail on the dropped
+    // connection and the point is spooled after the others
+    channel.add(&points[2]).unwrap();
+    assert_eq!( channel.spooled(), 3 );
+
+    // The flush retry and send the points in order
+    assert_eq!( channel.flush().unwrap(), 3 );
+    assert_eq!( channel.spooled(), 0 );
+    assert_eq!( logged.iter().take(3).collect::<Vec<_>>(), points );
+
+    fs::remove_dir_all(&root).unwrap();
+}
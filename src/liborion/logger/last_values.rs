@@ -0,0 +1,206 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use core::{Device, DeviceSelector, MeasurementPoint, Tags};
+
+/// Name of the file holding the last values saved by the server
+pub const LAST_VALUES_FILENAME: &'static str = "last.txt";
+
+/// Latest point of each device, by slug
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastValues {
+    points: BTreeMap<String, MeasurementPoint>,
+}
+
+impl LastValues {
+    pub fn new() -> LastValues {
+        LastValues {
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// Keep `mp` if it is the latest point of its device
+    ///
+    /// Return `false` if the cached point of the device is newer.
+    pub fn update(&mut self, mp: &MeasurementPoint) -> bool {
+        let slug = mp.get_device().get_slug();
+
+        if let Some(latest) = self.points.get(slug) {
+            if latest.get_date() > mp.get_date() {
+                return false;
+            }
+        }
+
+        self.points.insert(slug.to_string(), mp.clone());
+        true
+    }
+
+    pub fn get(&self, device: &Device) -> Option<&MeasurementPoint> {
+        self.points.get(device.get_slug())
+    }
+
+    /// Return the latest point of each device matching `selector`, sorted
+    /// by slug
+    pub fn select(&self, selector: &DeviceSelector) -> Vec<&MeasurementPoint> {
+        self.points.values().filter(|mp| selector.matches(mp.get_device())).collect()
+    }
+
+    pub fn iter(&self) -> btree_map::Values<String, MeasurementPoint> {
+        self.points.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Load the last values saved at `path`
+    ///
+    /// A missing file give an empty `LastValues`, invalid lines are logged
+    /// and skipped.
+    pub fn load(path: &Path) -> io::Result<LastValues> {
+        let mut values = LastValues::new();
+
+        let file = match File::open(path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(values),
+            Err(e) => return Err(e),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+
+            match parse_last_value(&line) {
+                Some(mp) => { values.update(&mp); },
+                None     => warn!("Skip invalid last value '{}' of {:?}", line, path),
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Save the last values to `path`, replacing it at once
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+
+        {
+            let mut file = try!( File::create(&tmp) );
+
+            for mp in self.iter() {
+                try!( file.write_all(last_value_line(mp).as_bytes()) );
+            }
+
+            try!( file.sync_all() );
+        }
+
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Return the line of `mp` in a last values file and in the reply of a
+/// `GET` request
+///
+/// It is the slug, the tags in braces, then the line of `mp` in a text data
+/// file:
+///
+/// ```
+/// use orion::core::{Device, MeasurementPoint};
+/// use orion::logger::{last_value_line, parse_last_value};
+///
+/// let device = Device::with_slug("temp1@core.lm").unwrap();
+/// let mp = MeasurementPoint::from_line(device, "2015-06-01T12:00:00+00:00 300[K]").unwrap();
+///
+/// let line = last_value_line(&mp);
+/// assert_eq!( line, "temp1@core.lm {} 2015-06-01T12:00:00+00:00 300[K]\n" );
+/// assert_eq!( parse_last_value(&line), Some(mp) );
+/// ```
+pub fn last_value_line(mp: &MeasurementPoint) -> String {
+    format!("{} {{{}}} {}", mp.get_device().get_slug(), mp.get_tags(), mp.to_line())
+}
+
+/// Parse a line written by `last_value_line`
+pub fn parse_last_value(line: &str) -> Option<MeasurementPoint> {
+    let mut parts = line.splitn(3, ' ');
+
+    let device = match parts.next().map(Device::with_slug) {
+        Some(Ok(x)) => x,
+        _           => return None,
+    };
+
+    let tags = match parts.next() {
+        Some(x) if x.starts_with('{') && x.ends_with('}') => {
+            match Tags::from_str(&x[1..x.len() - 1]) {
+                Ok(x)  => x,
+                Err(_) => return None,
+            }
+        },
+        _ => return None,
+    };
+
+    match parts.next().map(|rest| MeasurementPoint::from_line(device, rest)) {
+        Some(Ok(mut mp)) => {
+            mp.set_tags(tags);
+            Some(mp)
+        },
+        _ => None,
+    }
+}
+
+
+#[test]
+fn test_last_values_file() {
+    use std::env;
+
+    let root = env::temp_dir().join("orion_test_last_values_file");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let path = root.join(LAST_VALUES_FILENAME);
+    assert!( LastValues::load(&path).unwrap().is_empty() );
+
+    let mut values = LastValues::new();
+
+    for &(slug, line) in [ ("temp1@core.lm", "2015-06-01T12:00:00+00:00 300[K]"),
+                           ("reg1/slave3@gw1.modbus", "2015-06-01T12:00:01+00:00 3.5[V] -1[A]") ].iter() {
+        let mut mp = MeasurementPoint::from_line(Device::with_slug(slug).unwrap(), line).unwrap();
+        mp.set_tags(Tags::from_str("site=lab").unwrap());
+        values.update(&mp);
+    }
+
+    values.save(&path).unwrap();
+    assert_eq!( LastValues::load(&path).unwrap(), values );
+
+    // Invalid lines are skipped
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"temp2@core.lm 2015-06-01T12:00:00+00:00 300[K]\n").unwrap();
+    assert_eq!( LastValues::load(&path).unwrap(), values );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,179 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use core::Device;
+use super::LastValues;
+
+/// Counters of the logger server and latest values of the devices
+///
+/// Shared as `SharedMetrics` between the server, which update it, and the
+/// HTTP API, which export it for Prometheus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    pub last_values: LastValues,
+    /// Requests received by the server
+    pub requests: u64,
+    /// Points stored
+    pub points: u64,
+    /// Points refused, like invalid frames or points not matching the
+    /// device registry
+    pub rejected: u64,
+    /// Points that failed to be stored
+    pub write_errors: u64,
+    /// Points in the write-ahead log not checkpointed yet
+    pub queue_depth: u64,
+}
+
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            last_values: LastValues::new(),
+            requests: 0,
+            points: 0,
+            rejected: 0,
+            write_errors: 0,
+            queue_depth: 0,
+        }
+    }
+
+    pub fn shared() -> SharedMetrics {
+        Arc::new( Mutex::new( Metrics::new() ) )
+    }
+
+    /// Return the metrics in the Prometheus text exposition format
+    ///
+    /// The latest value of each measurement of a device is a
+    /// `orion_value` gauge labelled with its `port`, `node`, `driver`,
+    /// `unit` and measurement position as `channel`.
+    ///
+    /// ```
+    /// use orion::core::{Device, MeasurementPoint};
+    /// use orion::logger::Metrics;
+    ///
+    /// let device = Device::with_slug("temp1@core.lm").unwrap();
+    /// let mp = MeasurementPoint::from_line(device, "2015-06-01T12:00:00+00:00 300[K]").unwrap();
+    ///
+    /// let mut metrics = Metrics::new();
+    /// metrics.last_values.update(&mp);
+    ///
+    /// assert!( metrics.to_prometheus().contains(
+    ///     "orion_value{port=\"temp1\",node=\"core\",driver=\"lm\",unit=\"K\",channel=\"0\"} 300\n"
+    /// ));
+    /// ```
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP orion_value Latest value of a device measurement\n");
+        out.push_str("# TYPE orion_value gauge\n");
+
+        for mp in self.last_values.iter() {
+            let labels = device_labels(mp.get_device());
+
+            for (channel, m) in mp.get_data().iter().enumerate() {
+                let _ = writeln!(out, "orion_value{{{},unit=\"{}\",channel=\"{}\"}} {}",
+                                 labels, escape(&m.get_unit().to_string()), channel,
+                                 m.get_value());
+            }
+        }
+
+        out.push_str("# HELP orion_last_timestamp_seconds Time of the latest point of a device\n");
+        out.push_str("# TYPE orion_last_timestamp_seconds gauge\n");
+
+        for mp in self.last_values.iter() {
+            let _ = writeln!(out, "orion_last_timestamp_seconds{{{}}} {}",
+                             device_labels(mp.get_device()), mp.get_date().timestamp());
+        }
+
+        let counters = [
+            ("orion_logger_requests_total", "counter", "Requests received by the server",
+             self.requests),
+            ("orion_logger_points_total", "counter", "Points stored", self.points),
+            ("orion_logger_rejected_total", "counter", "Points refused", self.rejected),
+            ("orion_logger_write_errors_total", "counter", "Points that failed to be stored",
+             self.write_errors),
+            ("orion_logger_queue_depth", "gauge",
+             "Points in the write-ahead log not checkpointed yet", self.queue_depth),
+        ];
+
+        for &(name, kind, help, value) in counters.iter() {
+            let _ = write!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                           name, help, name, kind, name, value);
+        }
+
+        out
+    }
+}
+
+fn device_labels(device: &Device) -> String {
+    format!("port=\"{}\",node=\"{}\",driver=\"{}\"",
+            escape(device.get_port()), escape(device.get_node()), escape(device.get_driver()))
+}
+
+/// Escape a Prometheus label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[test]
+fn test_metrics() {
+    use std::str::FromStr;
+    use core::{DeviceSelector, MeasurementPoint};
+
+    let point = |slug: &str, line: &str| {
+        MeasurementPoint::from_line(Device::with_slug(slug).unwrap(), line).unwrap()
+    };
+
+    let mut metrics = Metrics::new();
+    let values = &mut metrics.last_values;
+
+    assert!( values.update(&point("temp1@core.lm", "2015-06-01T12:00:01+00:00 300[K]")) );
+    assert!( !values.update(&point("temp1@core.lm", "2015-06-01T12:00:00+00:00 290[K]")) );
+    assert!( values.update(&point("reg1/slave3@gw1.modbus", "2015-06-01T12:00:00+00:00 3.5[V] -1[A]")) );
+
+    let device = Device::with_slug("temp1@core.lm").unwrap();
+    assert_eq!( values.get(&device).unwrap().to_line(), "2015-06-01T12:00:01+00:00 300[K]\n" );
+    assert_eq!( values.select(&DeviceSelector::from_str("*@*.modbus").unwrap()).len(), 1 );
+    assert_eq!( values.len(), 2 );
+
+    metrics.requests = 4;
+    metrics.points = 3;
+    metrics.rejected = 1;
+
+    let text = metrics.to_prometheus();
+    let lines: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+
+    assert_eq!( lines, [
+        "orion_value{port=\"reg1/slave3\",node=\"gw1\",driver=\"modbus\",unit=\"V\",channel=\"0\"} 3.5",
+        "orion_value{port=\"reg1/slave3\",node=\"gw1\",driver=\"modbus\",unit=\"A\",channel=\"1\"} -1",
+        "orion_value{port=\"temp1\",node=\"core\",driver=\"lm\",unit=\"K\",channel=\"0\"} 300",
+        "orion_last_timestamp_seconds{port=\"reg1/slave3\",node=\"gw1\",driver=\"modbus\"} 1433160000",
+        "orion_last_timestamp_seconds{port=\"temp1\",node=\"core\",driver=\"lm\"} 1433160001",
+        "orion_logger_requests_total 4",
+        "orion_logger_points_total 3",
+        "orion_logger_rejected_total 1",
+        "orion_logger_write_errors_total 0",
+        "orion_logger_queue_depth 0",
+    ] );
+    assert!( text.contains("# TYPE orion_logger_queue_depth gauge\n") );
+}
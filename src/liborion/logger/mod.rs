@@ -17,5 +17,30 @@
 
 mod channel;
 
-pub use self::channel::Channel;
+pub use self::channel::{Channel, ChannelOptions};
 
+mod spool;
+pub use self::spool::Spool;
+
+mod batch;
+pub use self::batch::{BATCH_PREFIX, MAX_BATCH_LEN, MAX_BATCH_SIZE, BatchResult};
+pub use self::batch::{is_batch, encode_batch, decode_batch, batch_reply, parse_batch_reply};
+
+mod pipeline;
+pub use self::pipeline::{AsyncChannel, ReplyFuture, ASYNC_QUEUE_LEN};
+
+mod subscriber;
+pub use self::subscriber::{Subscriber, PUBLISH_URL};
+pub use self::subscriber::{topic, encode_publication, decode_publication};
+
+
+mod last_values;
+pub use self::last_values::{LastValues, LAST_VALUES_FILENAME};
+pub use self::last_values::{last_value_line, parse_last_value};
+
+mod auth;
+pub use self::auth::{AccessList, Client, AuthError, AUTH_PREFIX, STOP_REQUEST};
+pub use self::auth::{with_token, split_token, authorize, Denied};
+
+mod metrics;
+pub use self::metrics::{Metrics, SharedMetrics};
@@ -0,0 +1,301 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver};
+use std::thread;
+use std::thread::JoinHandle;
+
+use core::MeasurementPoint;
+use super::Channel;
+use super::{BATCH_PREFIX, MAX_BATCH_SIZE, encode_batch, parse_batch_reply};
+use super::batch::batch_frame_size;
+
+/// Most points waiting to be sent by an `AsyncChannel`
+pub const ASYNC_QUEUE_LEN: usize = 10000;
+
+/// Where the worker of an `AsyncChannel` send its batches
+trait Exchange: Send {
+    fn exchange(&mut self, request: &[u8]) -> io::Result<String>;
+}
+
+impl Exchange for Channel {
+    fn exchange(&mut self, request: &[u8]) -> io::Result<String> {
+        self.request_once(request)
+    }
+}
+
+/// A point waiting to be sent, with where to send its result
+struct Job {
+    id: u64,
+    frame: Vec<u8>,
+    reply: Sender<io::Result<()>>,
+}
+
+/// Result of a point queued by `AsyncChannel::add`, known once the server
+/// replied
+pub struct ReplyFuture {
+    receiver: Receiver<io::Result<()>>,
+    result: Option<io::Result<()>>,
+}
+
+impl ReplyFuture {
+
+    /// Return `true` if the result is known, without blocking
+    pub fn is_ready(&mut self) -> bool {
+        if self.result.is_none() {
+            self.result = self.receiver.try_recv().ok();
+        }
+
+        self.result.is_some()
+    }
+
+    /// Block until the server replied and return the result, like
+    /// `Channel::add`
+    pub fn wait(self) -> io::Result<()> {
+        match self.result {
+            Some(result) => result,
+            None         => self.receiver.recv().unwrap_or_else(|_| {
+                Err( Error::new(ErrorKind::BrokenPipe, "AsyncChannel stopped") )
+            }),
+        }
+    }
+}
+
+/// Stop the worker once the last `AsyncChannel` is dropped, after it sent
+/// the queued points
+struct Worker(Option<JoinHandle<()>>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Send points to the logger server without waiting for each reply
+///
+/// A background thread send the queued points in batches, over a `Channel`,
+/// so many points share a round trip. Each point get a `ReplyFuture`,
+/// replies are matched to points by an id. Clones share the same queue, so
+/// blocking `send` from many threads are batched together.
+///
+/// The spool and the retries of the `Channel` are not used: a batch is
+/// sent once, a lost reply fail its points rather than logging them twice.
+/// A batch is cut before its encoded size exceed `MAX_BATCH_SIZE`.
+#[derive(Clone)]
+pub struct AsyncChannel {
+    jobs: SyncSender<Job>,
+    next_id: Arc<AtomicUsize>,
+    /// Dropped after `jobs`, so the worker see the end of the queue
+    _worker: Arc<Worker>,
+}
+
+impl AsyncChannel {
+
+    /// Send the points through `channel`, at most `batch_len` by request,
+    /// each request sent once
+    pub fn new(channel: Channel, batch_len: usize) -> AsyncChannel {
+        AsyncChannel::spawn(channel, batch_len)
+    }
+
+    fn spawn<E: Exchange + 'static>(exchange: E, batch_len: usize) -> AsyncChannel {
+        let (jobs, queue) = sync_channel(ASYNC_QUEUE_LEN);
+        let handle = thread::spawn( move || run_worker(exchange, queue, batch_len) );
+
+        AsyncChannel {
+            jobs: jobs,
+            next_id: Arc::new( AtomicUsize::new(0) ),
+            _worker: Arc::new( Worker(Some(handle)) ),
+        }
+    }
+
+    /// Queue `mp` and return the future result of its logging
+    ///
+    /// Block while `ASYNC_QUEUE_LEN` points are waiting.
+    pub fn add(&self, mp: &MeasurementPoint) -> ReplyFuture {
+        let (reply, receiver) = channel();
+
+        let job = Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) as u64,
+            frame: mp.to_frame(),
+            reply: reply,
+        };
+
+        // If the worker is gone, the future fail as its sender is dropped
+        let _ = self.jobs.send(job);
+
+        ReplyFuture {
+            receiver: receiver,
+            result: None,
+        }
+    }
+
+    /// Send `mp` and wait for the reply, like `Channel::add`
+    pub fn send(&self, mp: &MeasurementPoint) -> io::Result<()> {
+        self.add(mp).wait()
+    }
+}
+
+fn run_worker<E: Exchange>(mut exchange: E, queue: Receiver<Job>, batch_len: usize) {
+    // First job of the next batch, which didn't fit in the previous one
+    let mut next = None;
+
+    loop {
+        let job = match next.take() {
+            Some(job) => job,
+            None      => match queue.recv() {
+                Ok(job) => job,
+                Err(_)  => return,
+            },
+        };
+
+        let mut size = BATCH_PREFIX.len() + batch_frame_size(job.id, &job.frame);
+        let mut jobs = vec![job];
+
+        while jobs.len() < batch_len {
+            match queue.try_recv() {
+                Ok(job) => {
+                    size += batch_frame_size(job.id, &job.frame);
+
+                    if size > MAX_BATCH_SIZE {
+                        next = Some(job);
+                        break;
+                    }
+
+                    jobs.push(job);
+                },
+                Err(_)  => break,
+            }
+        }
+
+        let mut frames = Vec::with_capacity(jobs.len());
+        let mut replies = Vec::with_capacity(jobs.len());
+
+        for job in jobs.into_iter() {
+            frames.push( (job.id, job.frame) );
+            replies.push( (job.id, job.reply) );
+        }
+
+        let results = exchange.exchange(&encode_batch(&frames))
+                              .and_then(|reply| parse_batch_reply(&reply));
+
+        match results {
+            Ok(mut results) => for (id, reply) in replies.into_iter() {
+                let _ = reply.send( match results.remove(&id) {
+                    Some(Ok(_))    => Ok( () ),
+                    Some(Err(msg)) => Err( Error::new(ErrorKind::Other, msg) ),
+                    None           => Err( Error::new(ErrorKind::InvalidData,
+                                                      "No reply for the point") ),
+                });
+            },
+            Err(e) => for (_, reply) in replies.into_iter() {
+                let _ = reply.send( Err( Error::new(e.kind(), e.to_string()) ) );
+            },
+        }
+    }
+}
+
+
+#[test]
+fn test_async_channel() {
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use core::{Device, MeasurementsList};
+    use super::{decode_batch, batch_reply};
+    use chrono::UTC;
+
+    /// Refuse the points of `bad@test.lm`, reply in reverse order and
+    /// record the batch lengths and sizes
+    struct FakeServer(Arc<Mutex<Vec<(usize, usize)>>>);
+
+    impl Exchange for FakeServer {
+        fn exchange(&mut self, request: &[u8]) -> io::Result<String> {
+            let frames = try!( decode_batch(request) );
+            self.0.lock().unwrap().push( (frames.len(), request.len()) );
+
+            let results: Vec<_> = frames.iter().rev().map(|&(id, ref frame)| {
+                let mp = MeasurementPoint::from_frame(frame).unwrap();
+
+                if mp.get_device().get_slug() == "bad@test.lm" {
+                    (id, Err("Forbidden".to_string()))
+                } else {
+                    (id, Ok( () ))
+                }
+            }).collect();
+
+            Ok( batch_reply(&results) )
+        }
+    }
+
+    let point = |slug| MeasurementPoint::new(Device::with_slug(slug).unwrap(), UTC::now(),
+                                             MeasurementsList::from_str("1[V]").unwrap());
+
+    let batches = Arc::new( Mutex::new(Vec::new()) );
+    let channel = AsyncChannel::spawn(FakeServer(batches.clone()), 50);
+
+    let futures: Vec<_> = (0..200).map(|i| {
+        channel.add(&point(if i % 3 == 0 { "bad@test.lm" } else { "good@test.lm" }))
+    }).collect();
+
+    for (i, future) in futures.into_iter().enumerate() {
+        match future.wait() {
+            Ok(_)  => assert!( i % 3 != 0 ),
+            Err(e) => {
+                assert!( i % 3 == 0 );
+                assert_eq!( e.kind(), ErrorKind::Other );
+                assert_eq!( e.to_string(), "Forbidden" );
+            },
+        }
+    }
+
+    let clone = channel.clone();
+    let handle = thread::spawn( move || clone.send(&point("good@test.lm")) );
+    assert!( channel.send(&point("good@test.lm")).is_ok() );
+    assert!( handle.join().unwrap().is_ok() );
+
+    let mut future = channel.add(&point("bad@test.lm"));
+    drop(channel);
+    assert!( future.is_ready() );
+    assert!( future.wait().is_err() );
+
+    {
+        let batches = batches.lock().unwrap();
+        assert_eq!( batches.iter().fold(0, |a, &(len, _)| a + len), 203 );
+        assert!( batches.iter().all(|&(len, _)| len <= 50) );
+    }
+
+    // Big points are cut in batches below MAX_BATCH_SIZE
+    let batches = Arc::new( Mutex::new(Vec::new()) );
+    let channel = AsyncChannel::spawn(FakeServer(batches.clone()), 50);
+    let values = vec!["1[V]"; 20000].join(" ");
+    let big = MeasurementPoint::new(Device::with_slug("good@test.lm").unwrap(), UTC::now(),
+                                    MeasurementsList::from_str(&values).unwrap());
+
+    let futures: Vec<_> = (0..50).map(|_| channel.add(&big)).collect();
+    for future in futures.into_iter() {
+        assert!( future.wait().is_ok() );
+    }
+
+    let batches = batches.lock().unwrap();
+    assert_eq!( batches.iter().fold(0, |a, &(len, _)| a + len), 50 );
+    assert!( batches.iter().all(|&(_, size)| size <= MAX_BATCH_SIZE) );
+}
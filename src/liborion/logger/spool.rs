@@ -0,0 +1,350 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+#[cfg(unix)]
+use libc;
+
+use core::MeasurementPoint;
+use super::{last_value_line, parse_last_value};
+
+/// Points waiting for an unreachable server, kept in a file
+///
+/// Each line hold a point as written by `last_value_line`, in the order
+/// they were pushed. On Unix the file can be shared by several processes:
+/// reading and writing it hold an exclusive `flock` on `<path>.lock`, so a
+/// point pushed while another process drain the spool is never lost. The
+/// points are sent without this lock, a drain only hold `<path>.drain` to
+/// keep other drains out. Elsewhere a spool must not be shared.
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    /// Number of points seen by the last operation
+    len: usize,
+}
+
+impl Spool {
+
+    /// Open the spool at `path`, creating its directory if needed
+    pub fn open(path: &Path) -> io::Result<Spool> {
+        if let Some(parent) = path.parent() {
+            try!( fs::create_dir_all(parent) );
+        }
+
+        let mut spool = Spool {
+            path: path.to_path_buf(),
+            len: 0,
+        };
+
+        try!( spool.points() );
+        Ok(spool)
+    }
+
+    /// Append `mp`, it is on disk when this return
+    pub fn push(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        let _lock = try!( self.lock("lock") );
+        let mut file = try!( OpenOptions::new().create(true).append(true).open(&self.path) );
+
+        try!( file.write_all(last_value_line(mp).as_bytes()) );
+        try!( file.sync_data() );
+
+        self.len += 1;
+        Ok( () )
+    }
+
+    /// Return the spooled points, oldest first
+    ///
+    /// Invalid lines are logged and skipped.
+    pub fn points(&mut self) -> io::Result<Vec<MeasurementPoint>> {
+        let _lock = try!( self.lock("lock") );
+        let points = try!( self.read() );
+        self.len = points.len();
+
+        Ok(points)
+    }
+
+    /// Replace the spooled points by `points`, at once
+    pub fn replace(&mut self, points: &[MeasurementPoint]) -> io::Result<()> {
+        let _drain = try!( self.lock("drain") );
+        let _lock = try!( self.lock("lock") );
+        self.write(points)
+    }
+
+    /// Pass the spooled points to `send`, oldest first, until it fails
+    ///
+    /// The points sent are removed from the spool, the others stay in it
+    /// with the points pushed meanwhile, which `send` doesn't delay. Return
+    /// the number of points sent, or the error of `send`.
+    pub fn drain<F>(&mut self, mut send: F) -> io::Result<usize>
+        where F: FnMut(&MeasurementPoint) -> io::Result<()> {
+
+        let _drain = try!( self.lock("drain") );
+        let points = try!( self.points() );
+        let mut sent = 0;
+        let mut result = Ok( () );
+
+        for mp in points.iter() {
+            if let Err(e) = send(mp) {
+                result = Err(e);
+                break;
+            }
+            sent += 1;
+        }
+
+        if sent > 0 {
+            // Only a drain remove points, the sent ones are still first
+            let _lock = try!( self.lock("lock") );
+            let points = try!( self.read() );
+            try!( self.write(&points[cmp::min(sent, points.len())..]) );
+        }
+
+        result.map(|_| sent)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Take the lock `<path>.<name>`, released when the file is dropped
+    fn lock(&self, name: &str) -> io::Result<File> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(name);
+
+        let file = try!( OpenOptions::new().write(true).create(true).open(&path) );
+        try!( lock_file(&file) );
+
+        Ok(file)
+    }
+
+    fn read(&self) -> io::Result<Vec<MeasurementPoint>> {
+        let file = match File::open(&self.path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok( Vec::new() ),
+            Err(e) => return Err(e),
+        };
+
+        let mut points = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+
+            match parse_last_value(&line) {
+                Some(mp) => points.push(mp),
+                None     => warn!("Skip invalid spooled point '{}' of {:?}", line, self.path),
+            }
+        }
+
+        Ok(points)
+    }
+
+    fn write(&mut self, points: &[MeasurementPoint]) -> io::Result<()> {
+        if points.is_empty() {
+            self.len = 0;
+
+            return match fs::remove_file(&self.path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok( () ),
+                result => result,
+            };
+        }
+
+        let tmp = self.path.with_extension("tmp");
+
+        {
+            let mut file = try!( File::create(&tmp) );
+
+            for mp in points.iter() {
+                try!( file.write_all(last_value_line(mp).as_bytes()) );
+            }
+
+            try!( file.sync_all() );
+        }
+
+        try!( fs::rename(&tmp, &self.path) );
+        self.len = points.len();
+
+        Ok( () )
+    }
+}
+
+#[cfg(unix)]
+fn lock_file(file: &File) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err( io::Error::last_os_error() );
+    }
+
+    Ok( () )
+}
+
+/// Without `flock`, the lock files only mark the spool
+#[cfg(not(unix))]
+fn lock_file(_: &File) -> io::Result<()> {
+    Ok( () )
+}
+
+
+#[test]
+fn test_spool() {
+    use std::env;
+    use core::Device;
+
+    let root = env::temp_dir().join("orion_test_spool");
+    let _ = fs::remove_dir_all(&root);
+
+    let path = root.join("spool.txt");
+    let mut spool = Spool::open(&path).unwrap();
+    assert!( spool.is_empty() );
+
+    let device = Device::with_slug("temp1@core.lm").unwrap();
+    let points: Vec<_> = ["2015-06-01T12:00:00+00:00 300[K]",
+                          "2015-06-01T12:00:01+00:00 301[K]",
+                          "2015-06-01T12:00:02+00:00 302[K]"].iter().map(|line| {
+        MeasurementPoint::from_line(device.clone(), line).unwrap()
+    }).collect();
+
+    for mp in points.iter() {
+        spool.push(mp).unwrap();
+    }
+
+    assert_eq!( spool.len(), 3 );
+
+    let mut spool = Spool::open(&path).unwrap();
+    assert_eq!( spool.len(), 3 );
+    assert_eq!( spool.points().unwrap(), points );
+
+    spool.replace(&points[2..]).unwrap();
+    assert_eq!( spool.len(), 1 );
+    assert_eq!( Spool::open(&path).unwrap().points().unwrap(), &points[2..] );
+
+    spool.replace(&[]).unwrap();
+    assert!( spool.is_empty() );
+    assert!( !path.exists() );
+
+    // A failed drain keep the points not sent, before the new ones
+    for mp in points[..2].iter() {
+        spool.push(mp).unwrap();
+    }
+
+    let mut sent = Vec::new();
+    let result = spool.drain(|mp| {
+        if sent.len() == 1 {
+            return Err( io::Error::new(io::ErrorKind::TimedOut, "Server down") );
+        }
+        sent.push(mp.clone());
+        Ok( () )
+    });
+    assert!( result.is_err() );
+    assert_eq!( sent, &points[..1] );
+
+    spool.push(&points[2]).unwrap();
+    assert_eq!( spool.len(), 2 );
+
+    let mut sent = Vec::new();
+    assert_eq!( spool.drain(|mp| { sent.push(mp.clone()); Ok( () ) }).unwrap(), 2 );
+    assert_eq!( sent, &points[1..] );
+    assert!( spool.is_empty() );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_spool_shared() {
+    use std::env;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use core::Device;
+
+    let root = env::temp_dir().join("orion_test_spool_shared");
+    let _ = fs::remove_dir_all(&root);
+    let path = root.join("spool.txt");
+
+    let device = Device::with_slug("temp1@core.lm").unwrap();
+    let point = |i: usize| {
+        let line = format!("2015-06-01T12:00:{:02}+00:00 {}[K]", i, 300 + i);
+        MeasurementPoint::from_line(device.clone(), &line).unwrap()
+    };
+
+    let mut spool = Spool::open(&path).unwrap();
+    spool.push(&point(0)).unwrap();
+
+    // Another spool can push while this one send, the point is kept
+    let (started, wait) = channel();
+    let (pushed, done) = channel();
+    let other_path = path.clone();
+    let other_point = point(1);
+
+    let other = thread::spawn( move || {
+        wait.recv().unwrap();
+        Spool::open(&other_path).unwrap().push(&other_point).unwrap();
+        pushed.send(()).unwrap();
+    });
+
+    let mut sent = Vec::new();
+    spool.drain(|mp| {
+        started.send(()).unwrap();
+        done.recv_timeout(::std::time::Duration::from_secs(5)).unwrap();
+        sent.push(mp.clone());
+        Ok( () )
+    }).unwrap();
+
+    other.join().unwrap();
+    assert_eq!( sent, [point(0)] );
+    assert_eq!( spool.points().unwrap(), [point(1)] );
+
+    // A drain started meanwhile wait, then only send what is left
+    spool.push(&point(2)).unwrap();
+    let (started, wait) = channel();
+    let other_path = path.clone();
+
+    let other = thread::spawn( move || {
+        wait.recv().unwrap();
+        let mut sent = Vec::new();
+        Spool::open(&other_path).unwrap().drain(|mp| { sent.push(mp.clone()); Ok( () ) })
+                                          .unwrap();
+        sent
+    });
+
+    let mut sent = Vec::new();
+    spool.drain(|mp| {
+        if sent.is_empty() {
+            started.send(()).unwrap();
+            thread::sleep(::std::time::Duration::from_millis(100));
+            sent.push(mp.clone());
+            Ok( () )
+        } else {
+            Err( io::Error::new(io::ErrorKind::TimedOut, "Server down") )
+        }
+    }).unwrap_err();
+
+    assert_eq!( sent, [point(1)] );
+    assert_eq!( other.join().unwrap(), [point(2)] );
+    assert!( spool.points().unwrap().is_empty() );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,174 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::ops::Drop;
+use std::io::Read;
+use std::io::Result as IOResult;
+use nanomsg::Socket;
+use nanomsg::Endpoint;
+use nanomsg::Protocol;
+use nanomsg::Result as NanoResult;
+
+use core::{DeviceSelector, MeasurementPoint, ParseBinaryError};
+
+/// Address where the logger server publish every accepted point
+pub const PUBLISH_URL: &'static str = "ipc:///tmp/orion_logger_pub.ipc";
+
+/// Receive the points accepted by the logger server as they arrive
+///
+/// Only the points of the devices matching a `DeviceSelector` are
+/// returned. The literal start of the selector is used as nanomsg topic,
+/// so most other points are dropped before reaching the subscriber.
+///
+/// Points published while no subscriber is connected, or faster than it
+/// read them, are lost.
+pub struct Subscriber {
+    socket: Socket,
+    endpoint: Endpoint,
+    selector: DeviceSelector,
+}
+
+impl Subscriber {
+    pub fn new(selector: DeviceSelector) -> NanoResult<Subscriber> {
+        let mut socket = try!( Socket::new(Protocol::Sub) );
+        try!( socket.subscribe(&topic(&selector)) );
+        let endpoint = try!( socket.connect(PUBLISH_URL) );
+
+        Ok(
+            Subscriber {
+                socket: socket,
+                endpoint: endpoint,
+                selector: selector,
+            }
+        )
+    }
+
+    /// Wait for the next point of a selected device
+    ///
+    /// Invalid publications are logged and skipped.
+    pub fn recv(&mut self) -> IOResult<MeasurementPoint> {
+        let mut msg = Vec::new();
+
+        loop {
+            msg.clear();
+            try!( self.socket.read_to_end(&mut msg) );
+
+            match decode_publication(&msg) {
+                Ok(mp) => if self.selector.matches(mp.get_device()) {
+                    return Ok(mp);
+                },
+                Err(e) => warn!("Skip invalid publication: {}", e),
+            }
+        }
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = IOResult<MeasurementPoint>;
+
+    fn next(&mut self) -> Option<IOResult<MeasurementPoint>> {
+        Some( self.recv() )
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let _ = self.endpoint.shutdown();
+    }
+}
+
+/// Return the nanomsg topic of `selector`, its slug until the first wildcard
+///
+/// ```
+/// use orion::core::DeviceSelector;
+/// use orion::logger::topic;
+/// use std::str::FromStr;
+///
+/// let selector = DeviceSelector::from_str("temp1@core-*.lm-sensors").unwrap();
+/// assert_eq!( topic(&selector), "temp1@core-" );
+/// ```
+pub fn topic(selector: &DeviceSelector) -> String {
+    selector.to_string().chars().take_while(|c| !"*?[".contains(*c)).collect()
+}
+
+/// Encode `mp` as published by the logger server
+///
+/// A publication is the device slug, a space, then the binary frame of the
+/// point, see `MeasurementPoint::to_frame`.
+pub fn encode_publication(mp: &MeasurementPoint) -> Vec<u8> {
+    let mut msg = Vec::new();
+
+    msg.extend(mp.get_device().get_slug().as_bytes().iter().cloned());
+    msg.push(b' ');
+    msg.extend(mp.to_frame().into_iter());
+
+    msg
+}
+
+/// Decode a publication created by `encode_publication`
+///
+/// # Failures
+///
+/// Fail with `ParseBinaryError::InvalidFrame` if the message has no slug or
+/// if it is not the device of the frame, otherwise with the error of
+/// `MeasurementPoint::from_frame`.
+pub fn decode_publication(msg: &[u8]) -> Result<MeasurementPoint, ParseBinaryError> {
+    let pos = match msg.iter().position(|b| *b == b' ') {
+        Some(x) => x,
+        None    => return Err(ParseBinaryError::InvalidFrame),
+    };
+
+    let mp = try!( MeasurementPoint::from_frame(&msg[pos + 1..]) );
+
+    if mp.get_device().get_slug().as_bytes() != &msg[..pos] {
+        return Err(ParseBinaryError::InvalidFrame);
+    }
+
+    Ok(mp)
+}
+
+
+#[test]
+fn test_publication() {
+    use std::str::FromStr;
+    use core::{Device, Tags};
+
+    let mut mp = MeasurementPoint::from_line(
+        Device::with_slug("reg40001/slave3@gw1.modbus").unwrap(),
+        "2015-06-01T12:00:00+00:00 3[V]"
+    ).unwrap();
+    mp.set_tags( Tags::from_str("site=lab").unwrap() );
+
+    let msg = encode_publication(&mp);
+    assert!( msg.starts_with(b"reg40001/slave3@gw1.modbus ") );
+    assert_eq!( decode_publication(&msg).unwrap(), mp );
+
+    assert!( decode_publication(b"no-space").is_err() );
+
+    let mut other = b"a@b.c ".to_vec();
+    other.extend(mp.to_frame().into_iter());
+    assert!( decode_publication(&other).is_err() );
+
+    let topics = [ ("*@*.*", ""), ("reg4*/slave3@*.*", "reg4"),
+                   ("temp1@core.lm-sensors", "temp1@core.lm-sensors"),
+                   ("temp[12]@core.lm", "temp") ];
+
+    for &(s, t) in topics.iter() {
+        assert_eq!( topic(&DeviceSelector::from_str(s).unwrap()), t );
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::error::Error;
+use std::fmt;
+use std::str;
+use std::str::FromStr;
+use chrono::{UTC, DateTime};
+use rustc_serialize::json::Json;
+
+use core::{Device, MeasurementsList, MeasurementPoint, Tags};
+use super::Message;
+
+/// Driver of the devices whose topic pattern has no `{driver}`
+pub const DEFAULT_MQTT_DRIVER: &'static str = "mqtt";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `+`
+    Any,
+    /// `#`, only as last segment
+    Rest,
+    Port,
+    Node,
+    Driver,
+}
+
+/// Map the topics of MQTT messages on devices
+///
+/// A pattern is a MQTT topic filter whose segments may also be `{port}`,
+/// `{node}` or `{driver}`. They match one level, like `+`, and give the
+/// device of the message. `{port}` and `{node}` are mandatory, the driver
+/// default to `DEFAULT_MQTT_DRIVER`.
+///
+/// ```
+/// use orion::mqtt::TopicPattern;
+/// use std::str::FromStr;
+///
+/// let pattern = TopicPattern::from_str("home/{node}/+/{port}").unwrap();
+/// assert_eq!( pattern.filter(), "home/+/+/+" );
+///
+/// let device = pattern.device("home/kitchen/sensors/temp1").unwrap().unwrap();
+/// assert_eq!( device.get_slug(), "temp1@kitchen.mqtt" );
+///
+/// assert!( pattern.device("home/kitchen/temp1").is_none() );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicPattern {
+    segments: Vec<Segment>,
+}
+
+impl TopicPattern {
+
+    /// Return the MQTT topic filter to subscribe to
+    pub fn filter(&self) -> String {
+        self.segments.iter().map(|s| match *s {
+            Segment::Literal(ref x) => &x[..],
+            Segment::Rest           => "#",
+            _                       => "+",
+        }).collect::<Vec<&str>>().join("/")
+    }
+
+    /// Return the device of a message published on `topic`
+    ///
+    /// Return `None` if `topic` don't match the pattern, or the error of
+    /// `Device::new` if its segments are not valid names.
+    pub fn device(&self, topic: &str) -> Option<Result<Device, String>> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let (mut port, mut node, mut driver) = ("", "", DEFAULT_MQTT_DRIVER);
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if *segment == Segment::Rest {
+                break;
+            }
+
+            let level = match levels.get(i) {
+                Some(x) => *x,
+                None    => return None,
+            };
+
+            match *segment {
+                Segment::Literal(ref x) => if x != level { return None },
+                Segment::Port           => port = level,
+                Segment::Node           => node = level,
+                Segment::Driver         => driver = level,
+                _                       => {},
+            }
+        }
+
+        if self.segments.last() != Some(&Segment::Rest) && levels.len() != self.segments.len() {
+            return None;
+        }
+
+        Some( Device::new(port, node, driver).map_err(|e| format!("{} in topic '{}'", e, topic)) )
+    }
+}
+
+impl FromStr for TopicPattern {
+    type Err = ParseTopicPatternError;
+
+    fn from_str(s: &str) -> Result<TopicPattern, ParseTopicPatternError> {
+        let mut segments = Vec::new();
+        let levels: Vec<&str> = s.split('/').collect();
+
+        for (i, level) in levels.iter().enumerate() {
+            let segment = match *level {
+                "{port}"   => Segment::Port,
+                "{node}"   => Segment::Node,
+                "{driver}" => Segment::Driver,
+                "+"        => Segment::Any,
+                "#" if i == levels.len() - 1 => Segment::Rest,
+                x if !x.is_empty() && !x.contains(|c| "+#{}".contains(c)) => {
+                    Segment::Literal(x.to_string())
+                },
+                _ => return Err(ParseTopicPatternError::InvalidSegment),
+            };
+
+            match segment {
+                Segment::Port | Segment::Node | Segment::Driver if segments.contains(&segment) => {
+                    return Err(ParseTopicPatternError::DuplicateField);
+                },
+                _ => {},
+            }
+
+            segments.push(segment);
+        }
+
+        if !segments.contains(&Segment::Port) || !segments.contains(&Segment::Node) {
+            return Err(ParseTopicPatternError::MissingField);
+        }
+
+        Ok( TopicPattern { segments: segments } )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseTopicPatternError {
+    InvalidSegment,
+    MissingField,
+    DuplicateField,
+}
+
+impl fmt::Display for ParseTopicPatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseTopicPatternError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseTopicPatternError::InvalidSegment => "Invalid topic segment",
+            ParseTopicPatternError::MissingField   => "Topic pattern without {port} or {node}",
+            ParseTopicPatternError::DuplicateField => "Duplicate field in topic pattern",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+/// Parse the payload of a message from `device`
+///
+/// A payload is either a measurements list, like `3[V] 1.2[A]`, taken now,
+/// or a JSON object:
+///
+/// ```text
+/// {"value": "3[V] 1.2[A]", "timestamp": "2015-06-01T12:00:00Z", "tags": {"site": "lab"}}
+/// {"value": 294.5, "unit": "K"}
+/// ```
+///
+/// where `timestamp` and `tags` are optional.
+pub fn parse_payload(device: Device, payload: &[u8]) -> Result<MeasurementPoint, String> {
+    let text = match str::from_utf8(payload) {
+        Ok(x)  => x.trim(),
+        Err(_) => return Err( "Payload is not UTF-8".to_string() ),
+    };
+
+    if !text.starts_with('{') {
+        return MeasurementsList::from_str(text)
+                                .map(|data| MeasurementPoint::new(device, UTC::now(), data))
+                                .map_err(|e| e.to_string());
+    }
+
+    let json = match Json::from_str(text) {
+        Ok(x)  => x,
+        Err(e) => return Err( format!("Invalid JSON: {}", e) ),
+    };
+
+    let value = match (json.find("value"), json.find("unit").and_then(|x| x.as_string())) {
+        (Some(&Json::String(ref x)), None) => x.clone(),
+        (Some(x), Some(unit)) if x.is_number() => format!("{}[{}]", x, unit),
+        _ => return Err( "Expected a value string, or a value number and a unit".to_string() ),
+    };
+
+    let data = try!( MeasurementsList::from_str(&value).map_err(|e| e.to_string()) );
+
+    let date = match json.find("timestamp").map(|x| x.as_string()) {
+        Some(Some(x)) => match DateTime::parse_from_rfc3339(x) {
+            Ok(x)  => x.with_timezone(&UTC),
+            Err(_) => return Err( format!("Invalid timestamp '{}'", x) ),
+        },
+        Some(None) => return Err( "Timestamp must be a string".to_string() ),
+        None       => UTC::now(),
+    };
+
+    let mut tags = Tags::new();
+
+    if let Some(object) = json.find("tags") {
+        let object = match object.as_object() {
+            Some(x) => x,
+            None    => return Err( "Tags must be an object".to_string() ),
+        };
+
+        for (key, value) in object.iter() {
+            match value.as_string().map(|v| tags.insert(key, v)) {
+                Some(Ok(_))  => {},
+                Some(Err(e)) => return Err( e.to_string() ),
+                None         => return Err( "Tag values must be strings".to_string() ),
+            }
+        }
+    }
+
+    let mut mp = MeasurementPoint::new(device, date, data);
+    mp.set_tags(tags);
+    Ok(mp)
+}
+
+/// Convert MQTT messages to `MeasurementPoint`s
+#[derive(Debug, Clone)]
+pub struct Bridge {
+    patterns: Vec<TopicPattern>,
+}
+
+impl Bridge {
+    pub fn new(patterns: Vec<TopicPattern>) -> Bridge {
+        Bridge {
+            patterns: patterns,
+        }
+    }
+
+    /// Return the topic filters to subscribe to
+    pub fn filters(&self) -> Vec<String> {
+        self.patterns.iter().map(|p| p.filter()).collect()
+    }
+
+    /// Return the point of `message`, using the first pattern matching its
+    /// topic
+    ///
+    /// Return `None` if no pattern match.
+    pub fn point(&self, message: &Message) -> Option<Result<MeasurementPoint, String>> {
+        for pattern in self.patterns.iter() {
+            match pattern.device(&message.topic) {
+                Some(Ok(device)) => return Some( parse_payload(device, &message.payload) ),
+                Some(Err(e))     => return Some( Err(e) ),
+                None             => continue,
+            }
+        }
+
+        None
+    }
+}
+
+
+#[test]
+fn test_bridge() {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::{MqttClient, MqttOptions, MqttError, Packet};
+
+    let errors = [ ("sensors/{port}", ParseTopicPatternError::MissingField),
+                   ("{node}/{port}/{port}", ParseTopicPatternError::DuplicateField),
+                   ("{node}/#/{port}", ParseTopicPatternError::InvalidSegment),
+                   ("{node}/a+/{port}", ParseTopicPatternError::InvalidSegment) ];
+
+    for &(pattern, ref error) in errors.iter() {
+        assert_eq!( TopicPattern::from_str(pattern).as_ref().err(), Some(error) );
+    }
+
+    let bridge = Bridge::new(vec![
+        TopicPattern::from_str("sensors/{driver}/{node}/{port}").unwrap(),
+        TopicPattern::from_str("home/{node}/{port}/#").unwrap(),
+    ]);
+    assert_eq!( bridge.filters(), ["sensors/+/+/+", "home/+/+/#"] );
+
+    // A broker stand-in accepting the subscription and publishing messages
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    let broker = thread::spawn( move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        match Packet::read_from(&mut stream).unwrap() {
+            Packet::Connect { ref client_id, keep_alive, clean_session, .. } => {
+                assert_eq!( client_id, "orion" );
+                assert_eq!( keep_alive, 1 );
+                assert!( clean_session );
+            },
+            packet => panic!("Unexpected {:?}", packet),
+        }
+        Packet::ConnAck { return_code: 0 }.write_to(&mut stream).unwrap();
+
+        let packet_id = match Packet::read_from(&mut stream).unwrap() {
+            Packet::Subscribe { packet_id, filters } => {
+                assert_eq!( filters.len(), 2 );
+                packet_id
+            },
+            packet => panic!("Unexpected {:?}", packet),
+        };
+
+        let messages: [(&str, &[u8]); 3] = [
+            ("sensors/lm/core/temp1", b"300[K] 1[V]"),
+            ("home/kitchen/temp2/raw", br#"{"value": 294.5, "unit": "K", "timestamp": "2015-06-01T12:00:00Z", "tags": {"room": "kitchen"}}"#),
+            ("sensors/lm/core/temp$", b"300[K]"),
+        ];
+
+        // The first message is published before the subscription is
+        // acknowledged, and each one is acknowledged once handled
+        for (i, &(topic, payload)) in messages.iter().enumerate() {
+            Packet::Publish { topic: topic.to_string(), qos: 1, packet_id: Some(i as u16 + 10),
+                              payload: payload.to_vec() }.write_to(&mut stream).unwrap();
+
+            if i == 0 {
+                Packet::SubAck { packet_id: packet_id, return_codes: vec![1, 1] }
+                       .write_to(&mut stream).unwrap();
+            }
+
+            assert_eq!( Packet::read_from(&mut stream).unwrap(),
+                        Packet::PubAck { packet_id: i as u16 + 10 } );
+        }
+
+        // Pinged after a second without packet from the client
+        assert_eq!( Packet::read_from(&mut stream).unwrap(), Packet::PingReq );
+        Packet::PingResp.write_to(&mut stream).unwrap();
+
+        Packet::Publish { topic: "sensors/lm/core/temp1".to_string(), qos: 2,
+                          packet_id: Some(20), payload: b"1[V]".to_vec() }
+               .write_to(&mut stream).unwrap();
+
+        stream.flush().unwrap();
+        assert_eq!( Packet::read_from(&mut stream).unwrap(), Packet::Disconnect );
+    });
+
+    let mut options = MqttOptions::new("orion");
+    options.keep_alive = 1;
+    let mut client = MqttClient::connect(&address, &options).unwrap();
+    assert!( client.subscribe(&bridge.filters(), 2).is_err() );
+    client.subscribe(&bridge.filters(), 1).unwrap();
+
+    let message = client.recv().unwrap();
+    assert_eq!( message.packet_id, Some(10) );
+    let mp = bridge.point(&message).unwrap().unwrap();
+    assert_eq!( mp.get_device().get_slug(), "temp1@core.lm" );
+    assert_eq!( mp.get_data().to_string(), "300[K] 1[V]" );
+    client.ack(&message).unwrap();
+
+    let message = client.recv().unwrap();
+    let mp = bridge.point(&message).unwrap().unwrap();
+    assert_eq!( mp.to_line(), "2015-06-01T12:00:00+00:00 294.5[K]\n" );
+    assert_eq!( mp.get_device().get_slug(), "temp2@kitchen.mqtt" );
+    assert_eq!( mp.get_tags().get("room"), Some("kitchen") );
+    client.ack(&message).unwrap();
+
+    let message = client.recv().unwrap();
+    assert!( bridge.point(&message).unwrap().is_err() );
+    client.ack(&message).unwrap();
+
+    let other = Message { topic: "other".to_string(), payload: Vec::new(), packet_id: None };
+    assert!( bridge.point(&other).is_none() );
+
+    match client.recv() {
+        Err(MqttError::UnsupportedQos(2)) => {},
+        other => panic!("{:?}", other),
+    }
+
+    client.disconnect().unwrap();
+    broker.join().unwrap();
+}
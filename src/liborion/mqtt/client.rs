@@ -0,0 +1,237 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufReader, Read};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use super::{Packet, MqttError};
+
+/// Options of a connection to a broker
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttOptions {
+    pub client_id: String,
+    /// Seconds without packet sent before the client ping the broker
+    pub keep_alive: u16,
+    /// Start a new session, otherwise the broker keeps the subscriptions
+    /// and the QoS 1 messages not acknowledged while disconnected
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl MqttOptions {
+    pub fn new(client_id: &str) -> MqttOptions {
+        MqttOptions {
+            client_id: client_id.to_string(),
+            keep_alive: 60,
+            clean_session: true,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+/// A message published on a topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// Identifier to acknowledge a QoS 1 message with `MqttClient::ack`
+    pub packet_id: Option<u16>,
+}
+
+/// A MQTT 3.1.1 client receiving the messages of its subscriptions
+///
+/// QoS 1 messages are acknowledged by `ack` once they are handled. Without
+/// a clean session, a message received but not acknowledged is sent again
+/// by the broker after a reconnection.
+pub struct MqttClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u16,
+    keep_alive: Duration,
+    /// When the last packet was sent to the broker
+    last_sent: Instant,
+    /// When the broker was pinged, if it has not answered yet
+    pinged: Option<Instant>,
+    /// Messages received while waiting for another packet
+    queue: VecDeque<Message>,
+}
+
+impl MqttClient {
+
+    /// Connect to the broker at `address`, like `127.0.0.1:1883`
+    pub fn connect(address: &str, options: &MqttOptions) -> Result<MqttClient, MqttError> {
+        let stream = try!( TcpStream::connect(address) );
+        let keep_alive = if options.keep_alive > 0 { options.keep_alive } else { 60 };
+
+        let mut client = MqttClient {
+            reader: BufReader::new( try!( stream.try_clone() ) ),
+            writer: stream,
+            next_id: 1,
+            keep_alive: Duration::from_secs(keep_alive as u64),
+            last_sent: Instant::now(),
+            pinged: None,
+            queue: VecDeque::new(),
+        };
+
+        try!( client.send(&Packet::Connect {
+            client_id: options.client_id.clone(),
+            keep_alive: keep_alive,
+            clean_session: options.clean_session,
+            username: options.username.clone(),
+            password: options.password.clone(),
+        }) );
+
+        match try!( client.read() ) {
+            Packet::ConnAck { return_code: 0 } => Ok(client),
+            Packet::ConnAck { return_code }    => Err(MqttError::Refused(return_code)),
+            _                                  => Err(MqttError::Malformed),
+        }
+    }
+
+    /// Subscribe to `filters` with the maximum `qos`, 0 or 1
+    ///
+    /// Messages published before the broker acknowledged the subscription
+    /// are kept for `recv`.
+    pub fn subscribe(&mut self, filters: &[String], qos: u8) -> Result<(), MqttError> {
+        if qos > 1 {
+            return Err(MqttError::UnsupportedQos(qos));
+        }
+
+        let packet_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        try!( self.send(&Packet::Subscribe {
+            packet_id: packet_id,
+            filters: filters.iter().map(|f| (f.clone(), qos)).collect(),
+        }) );
+
+        loop {
+            match try!( self.read() ) {
+                Packet::SubAck { packet_id: id, ref return_codes } if id == packet_id => {
+                    if return_codes.iter().any(|c| *c == 0x80) {
+                        return Err(MqttError::SubscribeRefused);
+                    }
+                    return Ok( () );
+                },
+                Packet::Publish { topic, qos, packet_id, payload } => {
+                    let message = try!( message(topic, qos, packet_id, payload) );
+                    self.queue.push_back(message);
+                },
+                _ => return Err(MqttError::Malformed),
+            }
+        }
+    }
+
+    /// Wait for the next message
+    ///
+    /// The broker is pinged when no packet was sent to it for `keep_alive`
+    /// seconds, and `MqttError::Timeout` is returned if it don't answer in
+    /// as much time.
+    pub fn recv(&mut self) -> Result<Message, MqttError> {
+        if let Some(message) = self.queue.pop_front() {
+            return Ok(message);
+        }
+
+        loop {
+            match try!( self.read() ) {
+                Packet::Publish { topic, qos, packet_id, payload } => {
+                    return message(topic, qos, packet_id, payload);
+                },
+                Packet::PingResp => {},
+                packet => warn!("Ignore MQTT packet {:?}", packet),
+            }
+        }
+    }
+
+    /// Acknowledge `message` to the broker, if it was published with QoS 1
+    pub fn ack(&mut self, message: &Message) -> Result<(), MqttError> {
+        match message.packet_id {
+            Some(id) => self.send(&Packet::PubAck { packet_id: id }).map_err(MqttError::Io),
+            None     => Ok( () ),
+        }
+    }
+
+    pub fn disconnect(mut self) -> Result<(), MqttError> {
+        self.send(&Packet::Disconnect).map_err(MqttError::Io)
+    }
+
+    fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        try!( packet.write_to(&mut self.writer) );
+        self.last_sent = Instant::now();
+        Ok( () )
+    }
+
+    /// Read a packet, pinging the broker when nothing was sent to it for
+    /// `keep_alive`
+    fn read(&mut self) -> Result<Packet, MqttError> {
+        loop {
+            let now = Instant::now();
+            let deadline = match self.pinged {
+                Some(pinged) => pinged + self.keep_alive,
+                None         => self.last_sent + self.keep_alive,
+            };
+
+            if now >= deadline {
+                if self.pinged.is_some() {
+                    return Err(MqttError::Timeout);
+                }
+
+                try!( self.send(&Packet::PingReq) );
+                self.pinged = Some(now);
+                continue;
+            }
+
+            try!( self.reader.get_ref().set_read_timeout(Some(deadline - now)) );
+            let mut header = [0u8; 1];
+
+            match self.reader.read(&mut header) {
+                Ok(0) => return Err(MqttError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                                 "Connection closed"))),
+                Ok(_) => {
+                    self.pinged = None;
+                    return Packet::read_body(header[0], &mut self.reader);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                              || e.kind() == io::ErrorKind::TimedOut
+                              || e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(MqttError::Io(e)),
+            }
+        }
+    }
+}
+
+/// Return the message of a publish packet
+///
+/// QoS 2 is refused, as the subscriptions never ask for it.
+fn message(topic: String, qos: u8, packet_id: Option<u16>, payload: Vec<u8>)
+    -> Result<Message, MqttError> {
+    if qos > 1 {
+        return Err(MqttError::UnsupportedQos(qos));
+    }
+
+    Ok( Message {
+        topic: topic,
+        payload: payload,
+        packet_id: packet_id,
+    })
+}
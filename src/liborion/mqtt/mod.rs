@@ -0,0 +1,33 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Ingestion of points published over MQTT
+//!
+//! `MqttClient` is a minimal MQTT 3.1.1 subscriber, with QoS 0 and 1.
+//! `Bridge` map the topic of each message on a `Device` with a
+//! `TopicPattern` and parse its payload as a `MeasurementPoint`.
+
+mod packet;
+pub use self::packet::{Packet, MqttError, MAX_PACKET_LEN};
+
+mod client;
+pub use self::client::{MqttClient, MqttOptions, Message};
+
+mod bridge;
+pub use self::bridge::{Bridge, TopicPattern, ParseTopicPatternError, parse_payload};
+pub use self::bridge::DEFAULT_MQTT_DRIVER;
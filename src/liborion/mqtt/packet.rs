@@ -0,0 +1,382 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use varint;
+
+/// Maximum size of a packet sent by a broker
+pub const MAX_PACKET_LEN: usize = 1 << 20;
+
+/// A MQTT 3.1.1 control packet, only those needed by a subscriber
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Connect {
+        client_id: String,
+        keep_alive: u16,
+        /// Drop the session of `client_id` on the broker when disconnected
+        clean_session: bool,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    ConnAck { return_code: u8 },
+    Publish {
+        topic: String,
+        qos: u8,
+        /// Present only for a QoS above 0
+        packet_id: Option<u16>,
+        payload: Vec<u8>,
+    },
+    PubAck { packet_id: u16 },
+    Subscribe { packet_id: u16, filters: Vec<(String, u8)> },
+    SubAck { packet_id: u16, return_codes: Vec<u8> },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl Packet {
+
+    /// Read a packet from `r`
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Packet, MqttError> {
+        let mut header = [0u8; 1];
+        try!( r.read_exact(&mut header) );
+
+        Packet::read_body(header[0], r)
+    }
+
+    /// Read the packet starting with the `header` byte, already read
+    ///
+    /// # Failures
+    ///
+    /// - `MqttError::Io` if `r` fail or ends before the end of the packet
+    /// - `MqttError::Malformed` if the packet is invalid or larger than
+    ///   `MAX_PACKET_LEN`
+    pub fn read_body<R: Read>(header: u8, r: &mut R) -> Result<Packet, MqttError> {
+        let len = match try!( varint::read_u64(r) ) {
+            Some(x) if x <= MAX_PACKET_LEN as u64 => x as usize,
+            Some(_) => return Err(MqttError::Malformed),
+            None    => return Err(MqttError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                              "Truncated packet"))),
+        };
+
+        let mut body = Vec::with_capacity(len);
+        try!( r.take(len as u64).read_to_end(&mut body) );
+
+        if body.len() != len {
+            return Err(MqttError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                    "Truncated packet")));
+        }
+
+        let mut b = &body[..];
+        let flags = header & 0x0f;
+
+        let packet = match header >> 4 {
+            1 => {
+                if try!( read_string(&mut b) ) != "MQTT" || try!( read_u8(&mut b) ) != 4 {
+                    return Err(MqttError::Malformed);
+                }
+
+                let connect_flags = try!( read_u8(&mut b) );
+                let keep_alive = try!( read_u16(&mut b) );
+                let client_id = try!( read_string(&mut b) );
+
+                let username = if connect_flags & 0x80 != 0 {
+                    Some( try!( read_string(&mut b) ) )
+                } else {
+                    None
+                };
+
+                let password = if connect_flags & 0x40 != 0 {
+                    Some( try!( read_string(&mut b) ) )
+                } else {
+                    None
+                };
+
+                Packet::Connect {
+                    client_id: client_id,
+                    keep_alive: keep_alive,
+                    clean_session: connect_flags & 0x02 != 0,
+                    username: username,
+                    password: password,
+                }
+            },
+            2 => {
+                try!( read_u8(&mut b) );
+                Packet::ConnAck { return_code: try!( read_u8(&mut b) ) }
+            },
+            3 => {
+                let qos = (flags >> 1) & 0x3;
+                if qos == 3 {
+                    return Err(MqttError::Malformed);
+                }
+
+                let topic = try!( read_string(&mut b) );
+
+                let packet_id = if qos > 0 {
+                    Some( try!( read_u16(&mut b) ) )
+                } else {
+                    None
+                };
+
+                let payload = b.to_vec();
+                b = &[];
+
+                Packet::Publish {
+                    topic: topic,
+                    qos: qos,
+                    packet_id: packet_id,
+                    payload: payload,
+                }
+            },
+            4 => Packet::PubAck { packet_id: try!( read_u16(&mut b) ) },
+            8 => {
+                let packet_id = try!( read_u16(&mut b) );
+                let mut filters = Vec::new();
+
+                while !b.is_empty() {
+                    let filter = try!( read_string(&mut b) );
+                    filters.push( (filter, try!( read_u8(&mut b) )) );
+                }
+
+                Packet::Subscribe { packet_id: packet_id, filters: filters }
+            },
+            9 => {
+                let packet_id = try!( read_u16(&mut b) );
+                let return_codes = b.to_vec();
+                b = &[];
+
+                Packet::SubAck { packet_id: packet_id, return_codes: return_codes }
+            },
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            _  => return Err(MqttError::Unsupported(header >> 4)),
+        };
+
+        if !b.is_empty() {
+            return Err(MqttError::Malformed);
+        }
+
+        Ok(packet)
+    }
+
+    /// Write the packet to `w` in one call
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut body = Vec::new();
+
+        let header = match *self {
+            Packet::Connect { ref client_id, keep_alive, clean_session, ref username,
+                              ref password } => {
+                let mut flags = 0;
+                if clean_session { flags |= 0x02; }
+                if username.is_some() { flags |= 0x80; }
+                if password.is_some() { flags |= 0x40; }
+
+                write_string(&mut body, "MQTT");
+                body.push(4);
+                body.push(flags);
+                write_u16(&mut body, keep_alive);
+                write_string(&mut body, client_id);
+
+                for s in username.iter().chain(password.iter()) {
+                    write_string(&mut body, s);
+                }
+
+                0x10
+            },
+            Packet::ConnAck { return_code } => {
+                body.push(0);
+                body.push(return_code);
+                0x20
+            },
+            Packet::Publish { ref topic, qos, packet_id, ref payload } => {
+                write_string(&mut body, topic);
+                if let Some(id) = packet_id {
+                    write_u16(&mut body, id);
+                }
+                body.extend(payload.iter().cloned());
+                0x30 | (qos << 1)
+            },
+            Packet::PubAck { packet_id } => {
+                write_u16(&mut body, packet_id);
+                0x40
+            },
+            Packet::Subscribe { packet_id, ref filters } => {
+                write_u16(&mut body, packet_id);
+                for &(ref filter, qos) in filters.iter() {
+                    write_string(&mut body, filter);
+                    body.push(qos);
+                }
+                0x82
+            },
+            Packet::SubAck { packet_id, ref return_codes } => {
+                write_u16(&mut body, packet_id);
+                body.extend(return_codes.iter().cloned());
+                0x90
+            },
+            Packet::PingReq    => 0xc0,
+            Packet::PingResp   => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        let mut packet = vec![header];
+        try!( varint::write_u64(&mut packet, body.len() as u64) );
+        packet.extend(body.into_iter());
+
+        w.write_all(&packet)
+    }
+}
+
+fn read_u8(b: &mut &[u8]) -> Result<u8, MqttError> {
+    match b.first().cloned() {
+        Some(x) => {
+            *b = &b[1..];
+            Ok(x)
+        },
+        None => Err(MqttError::Malformed),
+    }
+}
+
+fn read_u16(b: &mut &[u8]) -> Result<u16, MqttError> {
+    let high = try!( read_u8(b) ) as u16;
+    Ok( high << 8 | try!( read_u8(b) ) as u16 )
+}
+
+fn read_string(b: &mut &[u8]) -> Result<String, MqttError> {
+    let len = try!( read_u16(b) ) as usize;
+
+    if b.len() < len {
+        return Err(MqttError::Malformed);
+    }
+
+    let s = try!( String::from_utf8(b[..len].to_vec()).map_err(|_| MqttError::Malformed) );
+    *b = &b[len..];
+    Ok(s)
+}
+
+fn write_u16(body: &mut Vec<u8>, value: u16) {
+    body.push( (value >> 8) as u8 );
+    body.push( value as u8 );
+}
+
+fn write_string(body: &mut Vec<u8>, s: &str) {
+    write_u16(body, s.len() as u16);
+    body.extend(s.as_bytes().iter().cloned());
+}
+
+#[derive(Debug)]
+pub enum MqttError {
+    Io(io::Error),
+    Malformed,
+    /// Packet type not handled by a subscriber
+    Unsupported(u8),
+    /// Connection refused by the broker, with its return code
+    Refused(u8),
+    /// Subscription to a topic filter refused by the broker
+    SubscribeRefused,
+    /// No packet from the broker since the last ping
+    Timeout,
+    /// QoS above 1, not handled by a subscriber
+    UnsupportedQos(u8),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match *self {
+            MqttError::Io(ref err) => write!(f, "{}", err),
+            MqttError::Unsupported(t) => write!(f, "{} {}", self.description(), t),
+            MqttError::Refused(code) => write!(f, "{} (code {})", self.description(), code),
+            MqttError::UnsupportedQos(qos) => write!(f, "{} {}", self.description(), qos),
+            _ => self.description().fmt(f),
+        }
+    }
+}
+
+impl Error for MqttError {
+    fn description(&self) -> &str {
+        match *self {
+            MqttError::Io(_)            => "I/O error",
+            MqttError::Malformed        => "Malformed MQTT packet",
+            MqttError::Unsupported(_)   => "Unsupported MQTT packet type",
+            MqttError::Refused(_)       => "Connection refused by the broker",
+            MqttError::SubscribeRefused => "Subscription refused by the broker",
+            MqttError::Timeout          => "Broker not responding",
+            MqttError::UnsupportedQos(_) => "Unsupported MQTT QoS",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            MqttError::Io(ref err) => Some(err as &Error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MqttError {
+    fn from(err: io::Error) -> MqttError {
+        MqttError::Io(err)
+    }
+}
+
+
+#[test]
+fn test_packet() {
+    let packets = vec![
+        Packet::Connect { client_id: "orion".to_string(), keep_alive: 60, clean_session: false,
+                          username: Some("user".to_string()), password: None },
+        Packet::ConnAck { return_code: 0 },
+        Packet::Publish { topic: "sensors/core/temp1".to_string(), qos: 1,
+                          packet_id: Some(7), payload: b"300[K]".to_vec() },
+        Packet::Publish { topic: "a".to_string(), qos: 0, packet_id: None,
+                          payload: vec![0; 200] },
+        Packet::PubAck { packet_id: 7 },
+        Packet::Subscribe { packet_id: 1, filters: vec![ ("sensors/#".to_string(), 1) ] },
+        Packet::SubAck { packet_id: 1, return_codes: vec![1] },
+        Packet::PingReq,
+        Packet::Disconnect,
+    ];
+
+    for packet in packets.iter() {
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        assert_eq!( &Packet::read_from(&mut &bytes[..]).unwrap(), packet );
+    }
+
+    let mut bytes = Vec::new();
+    Packet::PubAck { packet_id: 0x1234 }.write_to(&mut bytes).unwrap();
+    assert_eq!( bytes, [0x40, 2, 0x12, 0x34] );
+
+    // A remaining length over 127 takes two bytes
+    let mut bytes = Vec::new();
+    packets[3].write_to(&mut bytes).unwrap();
+    assert_eq!( &bytes[..3], &[0x30, 0xcb, 0x01] );
+
+    assert!( Packet::read_from(&mut &[0x40, 3, 0, 1, 2][..]).is_err() );
+    assert!( Packet::read_from(&mut &[0x40, 2, 0][..]).is_err() );
+
+    // QoS 3 is invalid
+    match Packet::read_from(&mut &[0x36, 5, 0, 1, b'a', 0, 1][..]) {
+        Err(MqttError::Malformed) => {},
+        other => panic!("{:?}", other),
+    }
+}
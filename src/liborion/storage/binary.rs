@@ -0,0 +1,313 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use chrono::{UTC, DateTime};
+
+use core::{Device, DeviceSelector, MeasurementPoint, ParseBinaryError};
+use super::{Storage, devices, select_devices, day_path, device_day_dirs, in_range};
+use super::files::WrittenFiles;
+
+/// Name of the file holding the data of a day
+pub const BINARY_FILENAME: &'static str = "data.bin";
+
+/// First bytes of every binary data file
+pub const BINARY_MAGIC: &'static [u8] = b"ORB1";
+
+/// Store each `MeasurementPoint` as a binary record
+///
+/// Data are written to:
+///
+/// ```text
+/// $(ROOT)/$(DRIVER)/$(NODE)/$(PORT)/$(YEAR)/$(MONTH)/$(DAY)/data.bin
+/// ```
+///
+/// A file starts with `BINARY_MAGIC` followed by records written by
+/// `MeasurementPoint::write_record`. A torn record left by a crash at the
+/// end of a file is removed before appending to it.
+pub struct BinaryStorage {
+    root: PathBuf,
+    files: WrittenFiles,
+}
+
+impl BinaryStorage {
+    pub fn new(root: &Path) -> BinaryStorage {
+        BinaryStorage {
+            root: root.to_path_buf(),
+            files: WrittenFiles::new(),
+        }
+    }
+
+    /// Read every point of a binary data file
+    ///
+    /// A torn record left by a crash at the end of the file is ignored, as
+    /// a file too short to hold `BINARY_MAGIC`.
+    ///
+    /// # Failures
+    ///
+    /// Fail with `io::ErrorKind::InvalidData` if the file don't start with
+    /// `BINARY_MAGIC` or hold an invalid record.
+    pub fn read_file(path: &Path, device: &Device) -> io::Result<Vec<MeasurementPoint>> {
+        let mut reader = BufReader::new( try!( File::open(path) ) );
+
+        let mut magic = [0u8; 4];
+        match reader.read_exact(&mut magic) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok( Vec::new() ),
+            Err(e) => return Err(e),
+        }
+
+        if &magic[..] != BINARY_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Not an orion binary data file"));
+        }
+
+        let mut points = Vec::new();
+
+        loop {
+            match MeasurementPoint::read_record(&mut reader, device) {
+                Ok(Some(mp)) => points.push(mp),
+                Ok(None)     => break,
+                Err(ParseBinaryError::Truncated) => {
+                    warn!("Ignore torn record at the end of {:?}", path);
+                    break;
+                },
+                Err(err)     => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                          err)),
+            }
+        }
+
+        Ok(points)
+    }
+}
+
+/// Remove a torn record at the end of the binary data file at `path`
+///
+/// # Failures
+///
+/// Fail with `io::ErrorKind::InvalidData` if the file is not a binary
+/// data file or hold an invalid record before its end.
+pub fn repair_tail(path: &Path, device: &Device) -> io::Result<()> {
+    let mut bytes = Vec::new();
+
+    match File::open(path) {
+        Ok(mut file) => { try!( file.read_to_end(&mut bytes) ); },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok( () ),
+        Err(e) => return Err(e),
+    }
+
+    let valid = if bytes.len() < BINARY_MAGIC.len() {
+        0
+    } else if &bytes[..BINARY_MAGIC.len()] != BINARY_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "Not an orion binary data file"));
+    } else {
+        let mut r = &bytes[BINARY_MAGIC.len()..];
+        let mut valid = BINARY_MAGIC.len();
+
+        loop {
+            match MeasurementPoint::read_record(&mut r, device) {
+                Ok(Some(_)) => valid = bytes.len() - r.len(),
+                Ok(None)    => break,
+                Err(ParseBinaryError::Truncated) => break,
+                Err(err)    => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                         err)),
+            }
+        }
+
+        valid
+    };
+
+    if valid < bytes.len() {
+        warn!("Remove torn record at the end of {:?}", path);
+
+        let file = try!( OpenOptions::new().write(true).open(path) );
+        try!( file.set_len(valid as u64) );
+    }
+
+    Ok( () )
+}
+
+/// Open `path` for appending, writing the magic header if it is a new file
+pub fn open_binary_file(path: &Path) -> io::Result<File> {
+    let mut file = try!( OpenOptions::new()
+                                     .create(true)
+                                     .write(true)
+                                     .append(true)
+                                     .open(path) );
+
+    if try!( file.metadata() ).len() == 0 {
+        try!( file.write_all(BINARY_MAGIC) );
+    }
+
+    Ok(file)
+}
+
+impl Storage for BinaryStorage {
+    fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        let path = day_path(&self.root, mp.get_device(), &mp.get_date());
+
+        debug!("Create all parent directory of {:?}", path.as_path());
+        try!( fs::create_dir_all(path.as_path()) );
+
+        let path = path.join(BINARY_FILENAME);
+
+        if self.files.first_write(&path) {
+            try!( repair_tail(&path, mp.get_device()) );
+        }
+
+        let mut file = try!( open_binary_file(&path) );
+
+        // Write the record in one call so it is appended as a whole
+        let mut record = Vec::with_capacity(32);
+        try!( mp.write_record(&mut record) );
+        try!( file.write_all(&record) );
+
+        self.files.written(&path);
+        Ok( () )
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.files.sync()
+    }
+
+    fn read_range(&self, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+        -> io::Result<Vec<MeasurementPoint>> {
+
+        let mut points = Vec::new();
+
+        for day in try!( device_day_dirs(&self.root, device) ) {
+            let path = day.path.join(BINARY_FILENAME);
+
+            if !day.in_range(from, to) || !path.is_file() {
+                continue;
+            }
+
+            for mp in try!( BinaryStorage::read_file(&path, device) ) {
+                if in_range(&mp, from, to) {
+                    points.push(mp);
+                }
+            }
+        }
+
+        points.sort_by(|a, b| a.get_date().cmp(&b.get_date()) );
+        Ok(points)
+    }
+
+    fn devices(&self) -> io::Result<Vec<Device>> {
+        devices(&self.root)
+    }
+
+    fn select(&self, selector: &DeviceSelector) -> io::Result<Vec<Device>> {
+        select_devices(&self.root, selector)
+    }
+}
+
+/// Convert a text data file of `device` to a binary data file
+///
+/// Lines that can't be parsed are skipped. Points are appended to
+/// `binary_path` if it already exist.
+///
+/// Return the number of converted and skipped lines.
+pub fn convert_text_file(text_path: &Path, binary_path: &Path, device: &Device)
+    -> io::Result<(usize, usize)> {
+
+    let reader = BufReader::new( try!( File::open(text_path) ) );
+    let mut writer = BufWriter::new( try!( open_binary_file(binary_path) ) );
+
+    let mut converted = 0;
+    let mut skipped = 0;
+
+    for line in reader.lines() {
+        let line = try!(line);
+
+        match MeasurementPoint::from_line(device.clone(), &line) {
+            Ok(mp) => {
+                try!( mp.write_record(&mut writer) );
+                converted += 1;
+            },
+            Err(err) => {
+                warn!("Skip line '{}' of {:?}: {}", line, text_path, err);
+                skipped += 1;
+            },
+        }
+    }
+
+    try!( writer.flush() );
+
+    Ok( (converted, skipped) )
+}
+
+
+#[test]
+fn test_binary_storage() {
+    use std::env;
+    use super::TextStorage;
+
+    let root = env::temp_dir().join("orion_test_binary_storage");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let lines = [ "2015-06-01T12:00:00+00:00 3[V]",
+                  "2015-06-01T12:00:01+00:00 3.5[V] -1[A]" ];
+
+    let mut text = TextStorage::new(&root);
+    let mut binary = BinaryStorage::new(&root);
+
+    for line in lines.iter() {
+        let mp = MeasurementPoint::from_line(device.clone(), line).unwrap();
+        text.append(&mp).unwrap();
+        binary.append(&mp).unwrap();
+    }
+
+    let day = root.join("driver/node/port/2015/6/1");
+    let points = BinaryStorage::read_file(&day.join(BINARY_FILENAME), &device).unwrap();
+    assert_eq!( points.len(), 2 );
+    assert_eq!( points[1].to_line(), "2015-06-01T12:00:01+00:00 3.5[V] -1[A]\n" );
+
+    // Converting the text file gives the same points, without bad lines
+    let text_path = day.join(super::TEXT_FILENAME);
+    let mut file = OpenOptions::new().append(true).open(&text_path).unwrap();
+    file.write_all(b"2015-06-01T12:00:02+00:00 torn\n").unwrap();
+
+    let converted = day.join("converted.bin");
+    let (ok, skipped) = convert_text_file(&text_path, &converted, &device).unwrap();
+    assert_eq!( (ok, skipped), (2, 1) );
+    assert_eq!( BinaryStorage::read_file(&converted, &device).unwrap(), points );
+
+    // A torn record is ignored, then removed before the next append
+    let mut file = OpenOptions::new().append(true).open(&converted).unwrap();
+    file.write_all(&[0x80]).unwrap();
+    assert_eq!( BinaryStorage::read_file(&converted, &device).unwrap(), points );
+
+    repair_tail(&converted, &device).unwrap();
+    assert_eq!( fs::metadata(&converted).unwrap().len(),
+                fs::metadata(day.join(BINARY_FILENAME)).unwrap().len() );
+    assert_eq!( BinaryStorage::read_file(&converted, &device).unwrap(), points );
+
+    // An invalid record is still an error
+    let mut file = OpenOptions::new().append(true).open(&converted).unwrap();
+    file.write_all(&[0xff; 16]).unwrap();
+    assert!( BinaryStorage::read_file(&converted, &device).is_err() );
+
+    let _ = fs::remove_dir_all(&root);
+}
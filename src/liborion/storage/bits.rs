@@ -0,0 +1,122 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Bit level reader and writer used by the compressed chunk format
+
+/// Append bits to a byte buffer, most significant bit first
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            used: 8,
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> self.used;
+        }
+
+        self.used += 1;
+    }
+
+    /// Write the `count` low bits of `value`
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit( (value >> i) & 1 == 1 );
+        }
+    }
+
+    /// Return the written bytes, the last one padded with zeros
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Read bits written by a `BitWriter`
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes: bytes,
+            pos: 0,
+        }
+    }
+
+    /// Return `None` at end of buffer
+    pub fn read_bit(&mut self) -> Option<bool> {
+        let byte = match self.bytes.get(self.pos / 8) {
+            Some(x) => *x,
+            None    => return None,
+        };
+
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+
+        Some(bit)
+    }
+
+    pub fn read_bits(&mut self, count: u32) -> Option<u64> {
+        let mut value = 0u64;
+
+        for _ in 0..count {
+            let bit = match self.read_bit() {
+                Some(x) => x,
+                None    => return None,
+            };
+            value = (value << 1) | (bit as u64);
+        }
+
+        Some(value)
+    }
+}
+
+
+#[test]
+fn test_bits_roundtrip() {
+    let mut w = BitWriter::new();
+    w.write_bit(true);
+    w.write_bits(0b101, 3);
+    w.write_bits(0xdead_beef_cafe_f00d, 64);
+    w.write_bit(false);
+
+    let bytes = w.into_bytes();
+    assert_eq!( bytes.len(), 9 );
+
+    let mut r = BitReader::new(&bytes);
+    assert_eq!( r.read_bit(), Some(true) );
+    assert_eq!( r.read_bits(3), Some(0b101) );
+    assert_eq!( r.read_bits(64), Some(0xdead_beef_cafe_f00d) );
+    assert_eq!( r.read_bit(), Some(false) );
+    assert_eq!( r.read_bits(8), None );
+}
@@ -0,0 +1,611 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::{UTC, DateTime, Datelike};
+
+use core::{Device, DeviceSelector, MeasurementPoint};
+use super::{Storage, devices, select_devices, day_path, day_dirs, device_day_dirs};
+use super::in_range;
+use super::binary::{BinaryStorage, open_binary_file, repair_tail};
+use super::files::WrittenFiles;
+use super::gorilla::{Chunk, encode_chunks, decode_chunk, to_nanos};
+
+/// Compressed chunks of a day
+pub const CHUNKS_FILENAME: &'static str = "chunks.dat";
+
+/// Index of the chunks of a day
+pub const INDEX_FILENAME: &'static str = "chunks.idx";
+
+/// Points not yet compressed, in the binary format
+pub const HEAD_FILENAME: &'static str = "head.bin";
+
+/// Created once a closed day has been compacted
+pub const COMPACTED_FILENAME: &'static str = "compacted";
+
+/// Current generation of the chunks of a day, see `Generation`
+pub const GENERATION_FILENAME: &'static str = "chunks.gen";
+
+/// Number of points of the chunks created while logging
+const CHUNK_POINTS: usize = 120;
+
+/// Maximum number of points of the chunks created by compaction
+const COMPACT_CHUNK_POINTS: usize = 4096;
+
+/// Size of an index entry: start, end, offset, length and count
+const INDEX_ENTRY_SIZE: usize = 32;
+
+/// Times a read is retried when a compaction replace the files it read
+const READ_RETRIES: usize = 8;
+
+/// Number of points in `head.bin` per day directory, guarded by the lock
+/// shared with the compaction
+type HeadCounts = HashMap<PathBuf, usize>;
+
+/// Store points in compressed chunks, one set of files per device and day
+///
+/// Incoming points are appended to `head.bin`. Once it holds enough points
+/// they are compressed in a chunk appended to `chunks.dat`, see the
+/// `gorilla` module for the encoding, and `chunks.idx` get an entry
+/// with the time range, position and size of the chunk. Range queries
+/// only decode the chunks whose time range overlap the requested one.
+///
+/// Closed days are compacted in larger chunks sorted by time by
+/// `compact_closed_days`, usually from the thread started by
+/// `spawn_compaction`. A compaction writes a new generation of the chunks
+/// and index files and switches to it by replacing `chunks.gen`, so
+/// readers never see half of a compaction.
+pub struct ColumnarStorage {
+    root: PathBuf,
+    lock: Arc<Mutex<HeadCounts>>,
+    files: WrittenFiles,
+}
+
+/// Content of `chunks.gen`
+///
+/// Generation 0 is stored in `chunks.dat` and `chunks.idx`, the following
+/// ones in `chunks.<n>.dat` and `chunks.<n>.idx`. `head` is set while the
+/// points of `head.bin` are already part of the generation, between the
+/// switch and the removal of `head.bin`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Generation {
+    number: u64,
+    head: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct IndexEntry {
+    start: i64,
+    end: i64,
+    offset: u64,
+    length: u32,
+    count: u32,
+}
+
+impl ColumnarStorage {
+    pub fn new(root: &Path) -> ColumnarStorage {
+        ColumnarStorage {
+            root: root.to_path_buf(),
+            lock: Arc::new(Mutex::new(HashMap::new())),
+            files: WrittenFiles::new(),
+        }
+    }
+
+    /// Start a thread compacting closed days every `interval` seconds
+    ///
+    /// An interval of 0 is handled as 1 second.
+    pub fn spawn_compaction(&self, interval: u64) -> thread::JoinHandle<()> {
+        let root = self.root.clone();
+        let lock = self.lock.clone();
+        let interval = ::std::cmp::max(interval, 1);
+
+        thread::spawn(move || {
+            loop {
+                match compact_closed_days(&root, &lock) {
+                    Ok(0)  => {},
+                    Ok(n)  => info!("Compacted {} days", n),
+                    Err(e) => error!("Compaction failed: {}", e),
+                }
+
+                thread::sleep(Duration::from_secs(interval));
+            }
+        })
+    }
+
+    /// Compress every point of `head.bin` and remove it
+    fn flush_head(&mut self, day: &Path, device: &Device) -> io::Result<()> {
+        let head = day.join(HEAD_FILENAME);
+        let points = try!( BinaryStorage::read_file(&head, device) );
+
+        debug!("Flush {} points of {:?}", points.len(), head);
+
+        let (data, index) = chunk_files(day, try!( read_generation(day) ).number);
+        try!( append_chunks(&data, &index, &encode_chunks(&points, CHUNK_POINTS), false) );
+        self.files.written(&index);
+
+        fs::remove_file(&head)
+    }
+}
+
+impl Storage for ColumnarStorage {
+    fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        let lock = self.lock.clone();
+        let mut head_counts = lock.lock().unwrap();
+
+        let day = day_path(&self.root, mp.get_device(), &mp.get_date());
+        try!( fs::create_dir_all(&day) );
+
+        let head = day.join(HEAD_FILENAME);
+
+        if self.files.first_write(&head) {
+            // Complete a compaction interrupted by a crash
+            if try!( read_generation(&day) ).head {
+                try!( finish_compaction(&day) );
+            }
+
+            try!( repair_tail(&head, mp.get_device()) );
+        }
+
+        let mut record = Vec::with_capacity(32);
+        try!( mp.write_record(&mut record) );
+        try!( try!( open_binary_file(&head) ).write_all(&record) );
+        self.files.written(&head);
+
+        // A late point reopen a compacted day
+        let marker = day.join(COMPACTED_FILENAME);
+        if marker.exists() {
+            try!( fs::remove_file(&marker) );
+        }
+
+        if !head_counts.contains_key(&day) {
+            if head_counts.len() > 4096 {
+                head_counts.clear();
+            }
+
+            let count = try!( BinaryStorage::read_file(&head, mp.get_device()) ).len();
+            head_counts.insert(day.clone(), count);
+        } else {
+            *head_counts.get_mut(&day).unwrap() += 1;
+        }
+
+        if head_counts[&day] >= CHUNK_POINTS {
+            head_counts.remove(&day);
+            try!( self.flush_head(&day, mp.get_device()) );
+        }
+
+        Ok( () )
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        self.files.sync()
+    }
+
+    fn read_range(&self, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+        -> io::Result<Vec<MeasurementPoint>> {
+
+        let _guard = self.lock.lock().unwrap();
+
+        let start = to_nanos(from);
+        let end = to_nanos(to);
+        let mut points = Vec::new();
+
+        for day in try!( device_day_dirs(&self.root, device) ) {
+            if !day.in_range(from, to) {
+                continue;
+            }
+
+            for mp in try!( read_day(&day.path, device, start, end) ) {
+                if in_range(&mp, from, to) {
+                    points.push(mp);
+                }
+            }
+        }
+
+        points.sort_by(|a, b| a.get_date().cmp(&b.get_date()) );
+        Ok(points)
+    }
+
+    fn devices(&self) -> io::Result<Vec<Device>> {
+        devices(&self.root)
+    }
+
+    fn select(&self, selector: &DeviceSelector) -> io::Result<Vec<Device>> {
+        select_devices(&self.root, selector)
+    }
+}
+
+/// Read points of a day directory, only decoding chunks overlapping
+/// `[start, end)`
+///
+/// The read is retried when a compaction from another `ColumnarStorage`
+/// switched to a new generation meanwhile.
+fn read_day(day: &Path, device: &Device, start: i64, end: i64)
+    -> io::Result<Vec<MeasurementPoint>> {
+
+    let mut retries = 0;
+
+    loop {
+        let generation = try!( read_generation(day) );
+        let result = read_generation_points(day, device, &generation, start, end);
+
+        if try!( read_generation(day) ) == generation {
+            return result;
+        }
+
+        retries += 1;
+        if retries == READ_RETRIES {
+            return Err( io::Error::new(io::ErrorKind::Other,
+                                       format!("{:?} keeps being compacted", day)) );
+        }
+    }
+}
+
+fn read_generation_points(day: &Path, device: &Device, generation: &Generation,
+                          start: i64, end: i64) -> io::Result<Vec<MeasurementPoint>> {
+
+    let mut points = Vec::new();
+    let (data, index) = chunk_files(day, generation.number);
+    let entries = try!( read_index(&index) );
+
+    if !entries.is_empty() {
+        let mut file = try!( File::open(&data) );
+
+        for entry in entries.iter().filter(|e| e.end >= start && e.start < end) {
+            let mut bytes = vec![0u8; entry.length as usize];
+
+            try!( file.seek(SeekFrom::Start(entry.offset)) );
+            try!( file.read_exact(&mut bytes) );
+
+            points.extend( try!( decode_chunk(&bytes, device) ).into_iter() );
+        }
+    }
+
+    // Already part of the generation while a compaction completes
+    let head = day.join(HEAD_FILENAME);
+    if !generation.head && head.exists() {
+        points.extend( try!( BinaryStorage::read_file(&head, device) ).into_iter() );
+    }
+
+    Ok(points)
+}
+
+/// Paths of the chunks and index files of `generation` in `day`
+fn chunk_files(day: &Path, generation: u64) -> (PathBuf, PathBuf) {
+    if generation == 0 {
+        (day.join(CHUNKS_FILENAME), day.join(INDEX_FILENAME))
+    } else {
+        (day.join(format!("chunks.{}.dat", generation)),
+         day.join(format!("chunks.{}.idx", generation)))
+    }
+}
+
+fn read_generation(day: &Path) -> io::Result<Generation> {
+    let mut content = String::new();
+
+    match File::open(day.join(GENERATION_FILENAME)) {
+        Ok(mut file) => { try!( file.read_to_string(&mut content) ); },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok( Generation { number: 0, head: false } );
+        },
+        Err(e) => return Err(e),
+    }
+
+    let fields = content.split_whitespace().collect::<Vec<_>>();
+
+    match (fields.get(0).and_then(|x| x.parse().ok()), fields.get(1)) {
+        (Some(number), Some(&"head")) => Ok( Generation { number: number, head: true } ),
+        (Some(number), None)          => Ok( Generation { number: number, head: false } ),
+        _ => Err( io::Error::new(io::ErrorKind::InvalidData,
+                                 format!("Invalid {:?}: {:?}", day.join(GENERATION_FILENAME), content)) ),
+    }
+}
+
+/// Atomically replace `chunks.gen`
+fn write_generation(day: &Path, generation: &Generation) -> io::Result<()> {
+    let tmp = day.join("chunks.gen.tmp");
+    let content = if generation.head {
+        format!("{} head\n", generation.number)
+    } else {
+        format!("{}\n", generation.number)
+    };
+
+    {
+        let mut file = try!( File::create(&tmp) );
+        try!( file.write_all(content.as_bytes()) );
+        try!( file.sync_data() );
+    }
+
+    fs::rename(&tmp, day.join(GENERATION_FILENAME))
+}
+
+/// Append `chunks` to the `data` file and index them in `index`
+///
+/// Chunks are always flushed to disk before being indexed, the index is
+/// only flushed if `sync_index` is set.
+fn append_chunks(data: &Path, index: &Path, chunks: &[Chunk], sync_index: bool)
+    -> io::Result<()> {
+
+    let mut data = try!( OpenOptions::new()
+                                     .create(true)
+                                     .write(true)
+                                     .append(true)
+                                     .open(data) );
+    let mut offset = try!( data.metadata() ).len();
+
+    let mut entries = Vec::with_capacity(chunks.len() * INDEX_ENTRY_SIZE);
+
+    for chunk in chunks.iter() {
+        try!( data.write_all(&chunk.bytes) );
+
+        write_index_entry(&mut entries, &IndexEntry {
+            start: chunk.start,
+            end: chunk.end,
+            offset: offset,
+            length: chunk.bytes.len() as u32,
+            count: chunk.count,
+        });
+
+        offset += chunk.bytes.len() as u64;
+    }
+
+    // Only index chunks once they are fully written
+    try!( data.sync_data() );
+
+    let mut file = try!( OpenOptions::new()
+                                     .create(true)
+                                     .write(true)
+                                     .append(true)
+                                     .open(index) );
+    try!( file.write_all(&entries) );
+
+    if sync_index {
+        try!( file.sync_data() );
+    }
+
+    Ok( () )
+}
+
+fn write_index_entry(buf: &mut Vec<u8>, entry: &IndexEntry) {
+    let fields = [ (entry.start as u64, 8), (entry.end as u64, 8),
+                   (entry.offset, 8), (entry.length as u64, 4),
+                   (entry.count as u64, 4) ];
+
+    for &(value, size) in fields.iter() {
+        for i in 0..size {
+            buf.push( (value >> (8 * i)) as u8 );
+        }
+    }
+}
+
+fn read_index(path: &Path) -> io::Result<Vec<IndexEntry>> {
+    let mut bytes = Vec::new();
+
+    match File::open(path) {
+        Ok(mut file) => { try!( file.read_to_end(&mut bytes) ); },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    }
+
+    // A torn last entry is ignored, its chunk is not referenced
+    Ok( bytes.chunks(INDEX_ENTRY_SIZE)
+             .filter(|e| e.len() == INDEX_ENTRY_SIZE)
+             .map(|e| IndexEntry {
+                 start:  read_le(&e[0..8]) as i64,
+                 end:    read_le(&e[8..16]) as i64,
+                 offset: read_le(&e[16..24]),
+                 length: read_le(&e[24..28]) as u32,
+                 count:  read_le(&e[28..32]) as u32,
+             })
+             .collect() )
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter()
+         .enumerate()
+         .fold(0, |value, (i, byte)| value | (*byte as u64) << (8 * i))
+}
+
+/// Rewrite every day before today that is not yet compacted
+///
+/// All points of a day are sorted by time and stored in chunks of up to
+/// 4096 points. Return the number of compacted days.
+pub fn compact_closed_days(root: &Path, lock: &Mutex<HeadCounts>) -> io::Result<usize> {
+    let today = UTC::today();
+    let today = (today.year(), today.month(), today.day());
+    let mut count = 0;
+
+    for day in try!( day_dirs(root) ) {
+        if (day.year, day.month, day.day) >= today {
+            continue;
+        }
+
+        if day.path.join(COMPACTED_FILENAME).exists() {
+            continue;
+        }
+
+        let mut head_counts = lock.lock().unwrap();
+        try!( compact_day(&day.path, &day.device) );
+        head_counts.remove(&day.path);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Rewrite the points of `day` in a new generation
+///
+/// The new chunks and index are fully written before `chunks.gen` switch
+/// to them, a crash at any point leaves either the old or the new
+/// generation readable.
+fn compact_day(day: &Path, device: &Device) -> io::Result<()> {
+    debug!("Compact {:?}", day);
+
+    let mut current = try!( read_generation(day) );
+    if current.head {
+        try!( finish_compaction(day) );
+        current.head = false;
+    }
+
+    let mut points = try!( read_day(day, device, ::std::i64::MIN, ::std::i64::MAX) );
+    points.sort_by(|a, b| a.get_date().cmp(&b.get_date()) );
+
+    let next = Generation {
+        number: current.number + 1,
+        head: day.join(HEAD_FILENAME).exists(),
+    };
+    let (data, index) = chunk_files(day, next.number);
+
+    // Left over by a compaction interrupted before the switch
+    let _ = fs::remove_file(&data);
+    let _ = fs::remove_file(&index);
+
+    try!( append_chunks(&data, &index, &encode_chunks(&points, COMPACT_CHUNK_POINTS), true) );
+    try!( write_generation(day, &next) );
+    try!( finish_compaction(day) );
+
+    try!( File::create(day.join(COMPACTED_FILENAME)) );
+    Ok( () )
+}
+
+/// Remove what the current generation of `day` replaced
+///
+/// Safe to call again after a crash: `head.bin` is only removed while
+/// `chunks.gen` tells its points are part of the generation.
+fn finish_compaction(day: &Path) -> io::Result<()> {
+    let generation = try!( read_generation(day) );
+
+    if generation.head {
+        let head = day.join(HEAD_FILENAME);
+        if head.exists() {
+            try!( fs::remove_file(&head) );
+        }
+
+        try!( write_generation(day, &Generation { number: generation.number, head: false }) );
+    }
+
+    for old in 0..generation.number {
+        let (data, index) = chunk_files(day, old);
+
+        for path in [data, index].iter() {
+            match fs::remove_file(path) {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok( () )
+}
+
+
+#[test]
+fn test_columnar_storage() {
+    use std::env;
+    use chrono::TimeZone;
+    use core::MeasurementsList;
+    use std::str::FromStr;
+
+    let root = env::temp_dir().join("orion_test_columnar_storage");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let other = Device::with_slug("port2@node.driver").unwrap();
+    let mut storage = ColumnarStorage::new(&root);
+
+    // 2.5 chunks on the first of June, a few points the next day
+    let start = UTC.ymd(2015, 6, 1).and_hms(23, 0, 0);
+    for i in 0..(CHUNK_POINTS * 5 / 2 + 10) as i64 {
+        let date = if i < (CHUNK_POINTS * 5 / 2) as i64 {
+            UTC.timestamp(start.timestamp() + i, 0)
+        } else {
+            UTC.timestamp(start.timestamp() + 86400 + i, 0)
+        };
+        let data = MeasurementsList::from_str(&format!("{}[V]", i)).unwrap();
+
+        storage.append(&MeasurementPoint::new(device.clone(), date, data.clone())).unwrap();
+        storage.append(&MeasurementPoint::new(other.clone(), date, data)).unwrap();
+    }
+
+    let day = root.join("driver/node/port/2015/6/1");
+    assert_eq!( read_index(&day.join(INDEX_FILENAME)).unwrap().len(), 2 );
+    assert!( day.join(HEAD_FILENAME).exists() );
+
+    let from = UTC.timestamp(start.timestamp() + 100, 0);
+    let to = UTC.timestamp(start.timestamp() + 200, 0);
+    let points = storage.read_range(&device, &from, &to).unwrap();
+    assert_eq!( points.len(), 100 );
+    assert_eq!( points[0].get_data().to_string(), "100[V]" );
+    assert_eq!( points[99].get_date(), UTC.timestamp(start.timestamp() + 199, 0) );
+
+    let all = storage.read_range(&device, &start, &UTC::now()).unwrap();
+    assert_eq!( all.len(), CHUNK_POINTS * 5 / 2 + 10 );
+
+    // Both days are closed
+    assert_eq!( compact_closed_days(&root, &storage.lock).unwrap(), 4 );
+    assert_eq!( compact_closed_days(&root, &storage.lock).unwrap(), 0 );
+    assert_eq!( read_generation(&day).unwrap(), Generation { number: 1, head: false } );
+    assert_eq!( read_index(&chunk_files(&day, 1).1).unwrap().len(), 1 );
+    assert!( !day.join(INDEX_FILENAME).exists() );
+    assert!( !day.join(HEAD_FILENAME).exists() );
+    assert!( storage.lock.lock().unwrap().is_empty() );
+    assert_eq!( storage.read_range(&device, &start, &UTC::now()).unwrap(), all );
+
+    // A late point reopen the day, the next compaction is a new generation
+    let late = MeasurementPoint::new(device.clone(), UTC.timestamp(start.timestamp() + 10, 500),
+                                     MeasurementsList::from_str("1[V]").unwrap());
+    storage.append(&late).unwrap();
+    assert_eq!( compact_closed_days(&root, &storage.lock).unwrap(), 1 );
+    assert_eq!( read_generation(&day).unwrap(), Generation { number: 2, head: false } );
+    assert!( !chunk_files(&day, 1).0.exists() );
+
+    let all = storage.read_range(&device, &start, &UTC::now()).unwrap();
+    assert_eq!( all.len(), CHUNK_POINTS * 5 / 2 + 11 );
+    assert_eq!( all[11], late );
+
+    // Crash after the switch to a generation holding the head: the
+    // head is not read twice, and removed by the next append
+    let head = day.join(HEAD_FILENAME);
+    storage.append(&late).unwrap();
+    let end = UTC.ymd(2015, 6, 2).and_hms(0, 0, 0);
+    let (data, index) = chunk_files(&day, 3);
+    let mut chunks = storage.read_range(&device, &start, &end).unwrap();
+    append_chunks(&data, &index, &encode_chunks(&chunks, COMPACT_CHUNK_POINTS), true).unwrap();
+    write_generation(&day, &Generation { number: 3, head: true }).unwrap();
+
+    assert_eq!( ColumnarStorage::new(&root).read_range(&device, &start, &end).unwrap(), chunks );
+
+    let mut storage = ColumnarStorage::new(&root);
+    storage.append(&late).unwrap();
+    assert_eq!( read_generation(&day).unwrap(), Generation { number: 3, head: false } );
+    assert!( !chunk_files(&day, 2).1.exists() );
+    assert_eq!( BinaryStorage::read_file(&head, &device).unwrap().len(), 1 );
+    chunks.push(late);
+    assert_eq!( storage.read_range(&device, &start, &end).unwrap().len(), chunks.len() );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,81 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of paths remembered before forgetting them all
+const MAX_TRACKED: usize = 4096;
+
+/// Track the files written by a file based `Storage`
+///
+/// Remember which files must be flushed by the next `Storage::sync` and
+/// which files had their tail checked since the process started: a crash
+/// can leave a torn point at the end of a file and it must be isolated
+/// before appending after it.
+pub struct WrittenFiles {
+    checked: HashSet<PathBuf>,
+    dirty: HashSet<PathBuf>,
+}
+
+impl WrittenFiles {
+    pub fn new() -> WrittenFiles {
+        WrittenFiles {
+            checked: HashSet::new(),
+            dirty: HashSet::new(),
+        }
+    }
+
+    /// Return `true` if `path` is written for the first time by this process
+    pub fn first_write(&mut self, path: &Path) -> bool {
+        if self.checked.contains(path) {
+            return false;
+        }
+
+        // Checking a file again is only a waste of time
+        if self.checked.len() > MAX_TRACKED {
+            self.checked.clear();
+        }
+
+        self.checked.insert(path.to_path_buf());
+        true
+    }
+
+    /// Remember that `path` must be flushed by `sync`
+    pub fn written(&mut self, path: &Path) {
+        if !self.dirty.contains(path) {
+            self.dirty.insert(path.to_path_buf());
+        }
+    }
+
+    /// Flush every written file to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        for path in self.dirty.drain() {
+            match OpenOptions::new().append(true).open(&path) {
+                Ok(file) => try!( file.sync_all() ),
+                // Removed since, like a flushed head of ColumnarStorage
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok( () )
+    }
+}
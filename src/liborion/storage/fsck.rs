@@ -0,0 +1,304 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use chrono::{UTC, DateTime, Datelike};
+
+use core::MeasurementPoint;
+use super::{DayDir, TEXT_FILENAME, day_path};
+
+/// Name of the file receiving the lines removed by `repair_text_file`
+pub const QUARANTINE_FILENAME: &'static str = "data.txt.quarantine";
+
+/// A problem found in a text data file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    /// The last line of the file is not terminated by a new line
+    TornLine,
+    /// The line is not valid UTF-8
+    InvalidUtf8,
+    /// The line can't be parsed by `MeasurementPoint::from_line`
+    Malformed,
+    /// The point is older than the previous point of the file
+    OutOfOrder,
+    /// The point was not taken on the day of its directory
+    WrongDay,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::TornLine    => write!(f, "{}", "torn line"),
+            Problem::InvalidUtf8 => write!(f, "{}", "invalid UTF-8"),
+            Problem::Malformed   => write!(f, "{}", "malformed line"),
+            Problem::OutOfOrder  => write!(f, "{}", "out of order timestamp"),
+            Problem::WrongDay    => write!(f, "{}", "point in the wrong date directory"),
+        }
+    }
+}
+
+/// A problem found at a line of a text data file
+#[derive(Debug, Clone)]
+pub struct Issue {
+    /// Line number, starting at 1
+    pub line: usize,
+    pub problem: Problem,
+    /// Content of the line, invalid UTF-8 sequences are replaced by U+FFFD
+    pub content: String,
+}
+
+/// Result of `check_text_file`
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub day: DayDir,
+    pub path: PathBuf,
+    /// Number of lines in the file
+    pub lines: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl FileReport {
+
+    /// Return `true` if no problem was found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Reparse every line of the text data file of `day`
+///
+/// A torn line is not parsed since it may hold a truncated value. Lines
+/// are decoded one by one, so invalid UTF-8 is reported at its line
+/// instead of failing the whole file. Out of order timestamps are compared
+/// to the last valid point of the file.
+pub fn check_text_file(day: &DayDir) -> io::Result<FileReport> {
+    let path = day.path.join(TEXT_FILENAME);
+
+    let mut content = Vec::new();
+    try!( try!( File::open(&path) ).read_to_end(&mut content) );
+
+    let mut report = FileReport {
+        day: day.clone(),
+        path: path,
+        lines: 0,
+        issues: Vec::new(),
+    };
+
+    let mut last: Option<DateTime<UTC>> = None;
+    let lines = split_lines(&content);
+    let torn = !content.is_empty() && !content.ends_with(b"\n");
+
+    for (i, bytes) in lines.iter().enumerate() {
+        report.lines += 1;
+
+        let mut push = |problem| report.issues.push( Issue {
+            line: i + 1,
+            problem: problem,
+            content: String::from_utf8_lossy(bytes).into_owned(),
+        });
+
+        if torn && i + 1 == lines.len() {
+            push(Problem::TornLine);
+            continue;
+        }
+
+        let line = match str::from_utf8(bytes) {
+            Ok(x)  => x,
+            Err(_) => {
+                push(Problem::InvalidUtf8);
+                continue;
+            },
+        };
+
+        let mp = match MeasurementPoint::from_line(day.device.clone(), line) {
+            Ok(x)  => x,
+            Err(_) => {
+                push(Problem::Malformed);
+                continue;
+            },
+        };
+
+        let date = mp.get_date();
+
+        if (date.year(), date.month(), date.day()) != (day.year, day.month, day.day) {
+            push(Problem::WrongDay);
+        }
+
+        if let Some(last) = last {
+            if date < last {
+                push(Problem::OutOfOrder);
+            }
+        }
+
+        last = Some(date);
+    }
+
+    Ok(report)
+}
+
+/// Fix the problems of `report` in the text data file it was made for
+///
+/// Torn, invalid and malformed lines are moved to `QUARANTINE_FILENAME`,
+/// byte for byte, in the same
+/// directory and points in the wrong date directory are appended to the
+/// file of their day under `root`. Out of order timestamps are left as is
+/// since every reader sort points by date.
+///
+/// The file is rewritten in a temporary file then renamed, so it must not
+/// be written by a running server at the same time.
+///
+/// Return the number of quarantined and moved lines.
+pub fn repair_text_file(root: &Path, report: &FileReport) -> io::Result<(usize, usize)> {
+    let mut quarantined = Vec::new();
+    let mut moved = Vec::new();
+
+    for issue in report.issues.iter() {
+        match issue.problem {
+            Problem::TornLine | Problem::InvalidUtf8 | Problem::Malformed => {
+                quarantined.push(issue.line)
+            },
+            Problem::WrongDay => moved.push(issue.line),
+            Problem::OutOfOrder => {},
+        }
+    }
+
+    if quarantined.is_empty() && moved.is_empty() {
+        return Ok( (0, 0) );
+    }
+
+    let mut content = Vec::new();
+    try!( try!( File::open(&report.path) ).read_to_end(&mut content) );
+
+    let mut kept = Vec::with_capacity(content.len());
+    let mut quarantine = Vec::new();
+
+    for (i, line) in split_lines(&content).into_iter().enumerate() {
+        let number = i + 1;
+
+        if quarantined.contains(&number) {
+            quarantine.extend_from_slice(line);
+            quarantine.push(b'\n');
+        } else if moved.contains(&number) {
+            // Was decoded and parsed by `check_text_file`
+            let line = str::from_utf8(line).unwrap();
+            let mp = MeasurementPoint::from_line(report.day.device.clone(), line).unwrap();
+            let dir = day_path(root, &report.day.device, &mp.get_date());
+
+            try!( fs::create_dir_all(&dir) );
+            try!( append(&dir.join(TEXT_FILENAME), mp.to_line().as_bytes()) );
+        } else {
+            kept.extend_from_slice(line);
+            kept.push(b'\n');
+        }
+    }
+
+    if !quarantine.is_empty() {
+        warn!("Quarantine {} lines of {:?}", quarantined.len(), report.path);
+        try!( append(&report.day.path.join(QUARANTINE_FILENAME), &quarantine) );
+    }
+
+    let tmp_path = report.day.path.join("data.txt.fsck");
+    {
+        let mut tmp = try!( File::create(&tmp_path) );
+        try!( tmp.write_all(&kept) );
+        try!( tmp.sync_all() );
+    }
+    try!( fs::rename(&tmp_path, &report.path) );
+
+    Ok( (quarantined.len(), moved.len()) )
+}
+
+/// Split `content` in lines like `str::lines`, without decoding them
+fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    let mut lines: Vec<&[u8]> = content.split(|b| *b == b'\n').collect();
+
+    if content.is_empty() || content.ends_with(b"\n") {
+        lines.pop();
+    }
+
+    lines.into_iter()
+         .map(|l| if l.ends_with(b"\r") { &l[..l.len() - 1] } else { l })
+         .collect()
+}
+
+fn append(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = try!( OpenOptions::new()
+                                     .create(true)
+                                     .append(true)
+                                     .open(path) );
+    try!( file.write_all(content) );
+    file.sync_all()
+}
+
+
+#[test]
+fn test_fsck() {
+    use std::env;
+    use core::Device;
+    use super::day_dirs;
+
+    let root = env::temp_dir().join("orion_test_fsck");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let day = root.join("driver/node/port/2015/6/1");
+    fs::create_dir_all(&day).unwrap();
+
+    let mut file = File::create(day.join(TEXT_FILENAME)).unwrap();
+    file.write_all(b"2015-06-01T12:00:00+00:00 3[V]\n\
+                     2015-06-01T11:00:00+00:00 3[V]\n\
+                     2015-06-01T12:00:00+00:00 3[Z]\n\
+                     2015-06-01T12:30:00+00:00 \xff[V]\n\
+                     2015-06-02T12:00:00+00:00 4[V]\n\
+                     2015-06-01T13:00:00+00:00 3.").unwrap();
+
+    let days = day_dirs(&root).unwrap();
+    let report = check_text_file(&days[0]).unwrap();
+
+    let problems: Vec<_> = report.issues.iter().map(|i| (i.line, i.problem)).collect();
+    assert_eq!( report.lines, 6 );
+    assert_eq!( problems, vec![ (2, Problem::OutOfOrder),
+                                (3, Problem::Malformed),
+                                (4, Problem::InvalidUtf8),
+                                (5, Problem::WrongDay),
+                                (6, Problem::TornLine) ] );
+    assert_eq!( report.issues[2].content, "2015-06-01T12:30:00+00:00 \u{fffd}[V]" );
+
+    assert_eq!( repair_text_file(&root, &report).unwrap(), (3, 1) );
+
+    let days = day_dirs(&root).unwrap();
+    assert_eq!( days.len(), 2 );
+    let problems: Vec<_> = days.iter()
+                               .map(|d| check_text_file(d).unwrap().issues.len())
+                               .collect();
+    assert_eq!( problems, vec![1, 0] ); // The out of order line is kept
+
+    let mut content = Vec::new();
+    File::open(day.join(QUARANTINE_FILENAME)).unwrap().read_to_end(&mut content).unwrap();
+    assert_eq!( content, &b"2015-06-01T12:00:00+00:00 3[Z]\n\
+                            2015-06-01T12:30:00+00:00 \xff[V]\n\
+                            2015-06-01T13:00:00+00:00 3.\n"[..] );
+
+    let _ = fs::remove_dir_all(&root);
+}
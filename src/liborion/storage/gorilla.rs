@@ -0,0 +1,443 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Compressed chunk of points, inspired by Facebook's Gorilla
+//!
+//! A chunk holds points of one device sharing the same list of units. It
+//! starts with a byte header:
+//!
+//! ```text
+//! varint(number of points) varint(number of columns) { unit code }*
+//! ```
+//!
+//! followed by a bit stream holding every timestamp, then every value of
+//! the first column, then every value of the second column, ...
+//!
+//! Timestamps are nanoseconds since epoch stored as delta-of-delta: a
+//! regular sampling rate costs one bit per point. Values are XORed with
+//! the previous value of the column and only the meaningful bits are kept:
+//! a constant value costs one bit per point.
+
+use std::io;
+use chrono::{UTC, DateTime, TimeZone, Timelike};
+
+use core::{Device, Measurement, MeasurementsList, MeasurementPoint, Unit};
+use varint;
+use super::bits::{BitReader, BitWriter};
+
+/// Like `try!` for `Option`
+macro_rules! try_opt(
+    ($e:expr) => (match $e { Some(x) => x, None => return None });
+);
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Return `date` as nanoseconds since epoch
+pub fn to_nanos(date: &DateTime<UTC>) -> i64 {
+    date.timestamp() * NANOS_PER_SEC + date.nanosecond() as i64
+}
+
+/// Reverse of `to_nanos`
+pub fn from_nanos(nanos: i64) -> DateTime<UTC> {
+    let mut secs = nanos / NANOS_PER_SEC;
+    let mut rem = nanos % NANOS_PER_SEC;
+
+    if rem < 0 {
+        secs -= 1;
+        rem += NANOS_PER_SEC;
+    }
+
+    UTC.timestamp(secs, rem as u32)
+}
+
+/// An encoded chunk with the metadata stored in the chunk index
+#[derive(Debug)]
+pub struct Chunk {
+    pub start: i64,
+    pub end: i64,
+    pub count: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Encode `points` in chunks of at most `max_points` points
+///
+/// A new chunk is started each time the units of a point differ from the
+/// previous one.
+pub fn encode_chunks(points: &[MeasurementPoint], max_points: usize) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut begin = 0;
+
+    for i in 1..points.len() + 1 {
+        let split = i == points.len()
+                    || i - begin == max_points
+                    || !same_units(&points[begin], &points[i]);
+
+        if split {
+            chunks.push( encode_chunk(&points[begin..i]) );
+            begin = i;
+        }
+    }
+
+    chunks
+}
+
+fn same_units(a: &MeasurementPoint, b: &MeasurementPoint) -> bool {
+    a.get_data().len() == b.get_data().len()
+    && a.get_data().iter()
+                   .zip(b.get_data().iter())
+                   .all(|(x, y)| x.get_unit() == y.get_unit())
+}
+
+/// Encode `points` in one chunk
+///
+/// Every point must have the units of the first one.
+fn encode_chunk(points: &[MeasurementPoint]) -> Chunk {
+    let first = &points[0];
+    let mut bytes = Vec::new();
+
+    // Writing to a Vec can't fail
+    varint::write_u64(&mut bytes, points.len() as u64).unwrap();
+    varint::write_u64(&mut bytes, first.get_data().len() as u64).unwrap();
+    for meas in first.get_data().iter() {
+        bytes.push( meas.get_unit().code() );
+    }
+
+    let mut w = BitWriter::new();
+
+    let mut start = ::std::i64::MAX;
+    let mut end = ::std::i64::MIN;
+    let mut prev = 0i64;
+    let mut prev_delta = 0i64;
+
+    for (i, mp) in points.iter().enumerate() {
+        let nanos = to_nanos(&mp.get_date());
+
+        if nanos < start { start = nanos; }
+        if nanos > end { end = nanos; }
+
+        if i == 0 {
+            w.write_bits(nanos as u64, 64);
+        } else {
+            let delta = nanos.wrapping_sub(prev);
+            write_dod(&mut w, delta.wrapping_sub(prev_delta));
+            prev_delta = delta;
+        }
+        prev = nanos;
+    }
+
+    for column in 0..first.get_data().len() {
+        let mut xor = XorWriter::new();
+
+        for mp in points.iter() {
+            let value = mp.get_data().iter().nth(column).unwrap().get_value();
+            xor.write(&mut w, value.to_bits());
+        }
+    }
+
+    bytes.extend( w.into_bytes().into_iter() );
+
+    Chunk {
+        start: start,
+        end: end,
+        count: points.len() as u32,
+        bytes: bytes,
+    }
+}
+
+/// Decode a chunk created by `encode_chunks` for `device`
+pub fn decode_chunk(bytes: &[u8], device: &Device) -> io::Result<Vec<MeasurementPoint>> {
+    let mut header = bytes;
+
+    let count = try!( read_header_u64(&mut header) ) as usize;
+    let columns = try!( read_header_u64(&mut header) ) as usize;
+
+    if columns > header.len() {
+        return Err( corrupted() );
+    }
+
+    let mut units = Vec::with_capacity(columns);
+    for code in header[..columns].iter() {
+        match Unit::from_code(*code) {
+            Some(x) => units.push(x),
+            None    => return Err( corrupted() ),
+        }
+    }
+
+    let stream = &header[columns..];
+
+    // Every point take at least one bit
+    if count > stream.len() * 8 + 1 {
+        return Err( corrupted() );
+    }
+
+    let mut r = BitReader::new(stream);
+
+    let mut dates = Vec::with_capacity(count);
+    let mut prev = 0i64;
+    let mut prev_delta = 0i64;
+
+    for i in 0..count {
+        let nanos = if i == 0 {
+            try!( r.read_bits(64).ok_or( corrupted() ) ) as i64
+        } else {
+            let dod = try!( read_dod(&mut r).ok_or( corrupted() ) );
+            prev_delta = prev_delta.wrapping_add(dod);
+            prev.wrapping_add(prev_delta)
+        };
+
+        dates.push( from_nanos(nanos) );
+        prev = nanos;
+    }
+
+    let mut lists = vec![MeasurementsList::new(); count];
+
+    for unit in units.iter() {
+        let mut xor = XorReader::new();
+
+        for list in lists.iter_mut() {
+            let bits = try!( xor.read(&mut r).ok_or( corrupted() ) );
+            list.push( Measurement::new(f32::from_bits(bits), *unit) );
+        }
+    }
+
+    Ok( dates.into_iter()
+             .zip(lists.into_iter())
+             .map(|(date, data)| MeasurementPoint::new(device.clone(), date, data))
+             .collect() )
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Corrupted chunk")
+}
+
+fn read_header_u64(r: &mut &[u8]) -> io::Result<u64> {
+    match try!( varint::read_u64(r) ) {
+        Some(x) => Ok(x),
+        None    => Err( corrupted() ),
+    }
+}
+
+/// Delta-of-delta buckets: prefix, prefix length and value bits
+const DOD_BUCKETS: [(u64, u32, u32); 4] = [
+    (0b10,    2, 7),
+    (0b110,   3, 9),
+    (0b1110,  4, 12),
+    (0b11110, 5, 32),
+];
+
+fn write_dod(w: &mut BitWriter, dod: i64) {
+    if dod == 0 {
+        w.write_bit(false);
+        return;
+    }
+
+    for &(prefix, prefix_len, bits) in DOD_BUCKETS.iter() {
+        // Bucket of n bits hold values in [-(2^(n-1) - 1), 2^(n-1)]
+        let max = 1i64 << (bits - 1);
+
+        if dod > -max && dod <= max {
+            w.write_bits(prefix, prefix_len);
+            w.write_bits((dod + max - 1) as u64, bits);
+            return;
+        }
+    }
+
+    w.write_bits(0b11111, 5);
+    w.write_bits(dod as u64, 64);
+}
+
+fn read_dod(r: &mut BitReader) -> Option<i64> {
+    if !try_opt!( r.read_bit() ) {
+        return Some(0);
+    }
+
+    for &(_, _, bits) in DOD_BUCKETS.iter() {
+        // Next bit of the prefix, a 0 end it
+        if !try_opt!( r.read_bit() ) {
+            let max = 1i64 << (bits - 1);
+            return r.read_bits(bits).map(|v| v as i64 - max + 1);
+        }
+    }
+
+    r.read_bits(64).map(|v| v as i64)
+}
+
+/// XOR compression of one column of `f32` values
+struct XorWriter {
+    prev: Option<u32>,
+    leading: u32,
+    trailing: u32,
+}
+
+impl XorWriter {
+    fn new() -> XorWriter {
+        XorWriter { prev: None, leading: 0, trailing: 0 }
+    }
+
+    fn write(&mut self, w: &mut BitWriter, bits: u32) {
+        let prev = match self.prev {
+            Some(x) => x,
+            None    => {
+                w.write_bits(bits as u64, 32);
+                self.prev = Some(bits);
+                // Force a new window for the next value
+                self.leading = 32;
+                return;
+            },
+        };
+
+        let xor = bits ^ prev;
+        self.prev = Some(bits);
+
+        if xor == 0 {
+            w.write_bit(false);
+            return;
+        }
+        w.write_bit(true);
+
+        let leading = xor.leading_zeros();
+        let trailing = xor.trailing_zeros();
+
+        if leading >= self.leading && trailing >= self.trailing {
+            // Meaningful bits fit in the previous window
+            w.write_bit(false);
+            w.write_bits((xor >> self.trailing) as u64,
+                         32 - self.leading - self.trailing);
+        } else {
+            let meaningful = 32 - leading - trailing;
+
+            w.write_bit(true);
+            w.write_bits(leading as u64, 5);
+            w.write_bits((meaningful - 1) as u64, 5);
+            w.write_bits((xor >> trailing) as u64, meaningful);
+
+            self.leading = leading;
+            self.trailing = trailing;
+        }
+    }
+}
+
+struct XorReader {
+    prev: Option<u32>,
+    leading: u32,
+    trailing: u32,
+}
+
+impl XorReader {
+    fn new() -> XorReader {
+        XorReader { prev: None, leading: 0, trailing: 0 }
+    }
+
+    fn read(&mut self, r: &mut BitReader) -> Option<u32> {
+        let prev = match self.prev {
+            Some(x) => x,
+            None    => {
+                let bits = try_opt!( r.read_bits(32) ) as u32;
+                self.prev = Some(bits);
+                return Some(bits);
+            },
+        };
+
+        if !try_opt!( r.read_bit() ) {
+            return Some(prev);
+        }
+
+        if try_opt!( r.read_bit() ) {
+            self.leading = try_opt!( r.read_bits(5) ) as u32;
+            let meaningful = try_opt!( r.read_bits(5) ) as u32 + 1;
+
+            if self.leading + meaningful > 32 {
+                return None;
+            }
+            self.trailing = 32 - self.leading - meaningful;
+        }
+
+        let meaningful = 32 - self.leading - self.trailing;
+        let xor = (try_opt!( r.read_bits(meaningful) ) as u32) << self.trailing;
+
+        let bits = prev ^ xor;
+        self.prev = Some(bits);
+
+        Some(bits)
+    }
+}
+
+
+#[test]
+fn test_gorilla_roundtrip() {
+    use std::str::FromStr;
+
+    let device = Device::with_slug("temp1@core-isa-000.lm-sensors").unwrap();
+    let mut points = Vec::new();
+
+    for i in 0..500 {
+        let data = MeasurementsList::from_str(
+            &format!("{}[K] 12[V] {}[A]", 300.0 + (i % 7) as f32 * 0.25, -(i as f32) / 3.0)
+        ).unwrap();
+
+        // Regular sampling with some jitter and one gap
+        let nanos = 1433160000 * NANOS_PER_SEC
+                    + i * NANOS_PER_SEC
+                    + (i % 3) * 1000
+                    + if i > 250 { 3600 * NANOS_PER_SEC } else { 0 };
+
+        points.push( MeasurementPoint::new(device.clone(), from_nanos(nanos), data) );
+    }
+
+    let chunks = encode_chunks(&points, 200);
+    assert_eq!( chunks.len(), 3 );
+    assert_eq!( chunks[0].count, 200 );
+    assert_eq!( chunks[0].start, to_nanos(&points[0].get_date()) );
+    assert_eq!( chunks[0].end, to_nanos(&points[199].get_date()) );
+
+    let mut decoded = Vec::new();
+    let mut size = 0;
+    for chunk in chunks.iter() {
+        decoded.extend( decode_chunk(&chunk.bytes, &device).unwrap().into_iter() );
+        size += chunk.bytes.len();
+    }
+    assert_eq!( decoded, points );
+
+    // Binary records take 25 bytes per point
+    assert!( size * 3 < 500 * 25 );
+}
+
+#[test]
+fn test_gorilla_split_on_units() {
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let lines = [ "2015-06-01T12:00:00+00:00 3[V]",
+                  "2015-06-01T12:00:01+00:00 3[V]",
+                  "2015-06-01T12:00:02+00:00 3[A]",
+                  "2015-06-01T11:00:00+00:00 3[A] 4[A]" ];
+
+    let points: Vec<_> = lines.iter()
+                              .map(|l| MeasurementPoint::from_line(device.clone(), l).unwrap())
+                              .collect();
+
+    let chunks = encode_chunks(&points, 100);
+    assert_eq!( chunks.len(), 3 );
+    assert_eq!( decode_chunk(&chunks[2].bytes, &device).unwrap(), &points[3..] );
+
+    assert!( decode_chunk(&chunks[0].bytes[..4], &device).is_err() );
+}
+
+#[test]
+fn test_gorilla_nanos() {
+    let date = UTC.ymd(1965, 3, 1).and_hms_nano(10, 0, 0, 250);
+    assert_eq!( from_nanos(to_nanos(&date)), date );
+}
@@ -0,0 +1,470 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use chrono::{UTC, DateTime, Datelike};
+
+use core::{Device, DeviceSelector, MAX_PORT_DEPTH};
+
+/// Return the directory holding data of `device`
+///
+/// The sub-addresses of the port are nested from the outermost, so every
+/// device behind a bus share its directory:
+///
+/// ```
+/// use orion::core::Device;
+/// use orion::storage::device_dir;
+/// use std::path::Path;
+///
+/// let device = Device::with_slug("reg40001/slave3@gw1.modbus").unwrap();
+///
+/// assert_eq!(
+///     device_dir(Path::new("/tmp/data"), &device),
+///     Path::new("/tmp/data/modbus/gw1/slave3/reg40001")
+/// );
+/// ```
+pub fn device_dir(root: &Path, device: &Device) -> PathBuf {
+    let mut path = root.join(device.get_driver()).join(device.get_node());
+
+    for name in device.get_port_path().iter().rev() {
+        path.push(name);
+    }
+
+    path
+}
+
+/// Return the directory holding data of `device` for the day of `date`
+///
+/// # Example
+///
+/// ```
+/// # extern crate orion;
+/// # extern crate chrono;
+/// use orion::core::Device;
+/// use orion::storage::day_path;
+/// use std::path::Path;
+/// use chrono::{UTC, TimeZone};
+///
+/// # fn main() {
+/// let device = Device::with_slug("port@node.driver").unwrap();
+/// let date = UTC.ymd(2015, 6, 1).and_hms(12, 0, 0);
+///
+/// assert_eq!(
+///     day_path(Path::new("/tmp/data"), &device, &date),
+///     Path::new("/tmp/data/driver/node/port/2015/6/1")
+/// );
+/// # }
+/// ```
+pub fn day_path(root: &Path, device: &Device, date: &DateTime<UTC>) -> PathBuf {
+    device_dir(root, device)
+        .join(format!("{}", date.year()))
+        .join(format!("{}", date.month()))
+        .join(format!("{}", date.day()))
+}
+
+/// A day directory found under a storage root
+#[derive(Debug, Clone)]
+pub struct DayDir {
+    pub device: Device,
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub path: PathBuf,
+}
+
+impl DayDir {
+
+    /// Return `true` if this day overlap `[from, to]`
+    pub fn in_range(&self, from: &DateTime<UTC>, to: &DateTime<UTC>) -> bool {
+        let day = (self.year, self.month, self.day);
+
+        day >= (from.year(), from.month(), from.day())
+        && day <= (to.year(), to.month(), to.day())
+    }
+}
+
+/// Find every day directory under `root`, grouped by device and sorted by
+/// date
+///
+/// Entries that don't fit the `driver/node/port/Y/M/D` layout are skipped.
+/// A missing `root` is treated as an empty storage.
+pub fn day_dirs(root: &Path) -> io::Result<Vec<DayDir>> {
+    let mut found = Vec::new();
+
+    for (driver, driver_path) in try!( sub_dirs(root) ) {
+        for (node, node_path) in try!( sub_dirs(&driver_path) ) {
+            for (port, port_path) in try!( port_dirs(&node_path) ) {
+
+                let device = match device_for(&port, &node, &driver) {
+                    Some(x) => x,
+                    None    => {
+                        debug!("Skip invalid device directory {:?}", port_path);
+                        continue;
+                    },
+                };
+
+                try!( push_days(&mut found, &device, &port_path) );
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Return every device having a directory under `root`
+pub fn devices(root: &Path) -> io::Result<Vec<Device>> {
+    let mut found = Vec::new();
+
+    for (driver, driver_path) in try!( sub_dirs(root) ) {
+        for (node, node_path) in try!( sub_dirs(&driver_path) ) {
+            for (port, _) in try!( port_dirs(&node_path) ) {
+                if let Some(device) = device_for(&port, &node, &driver) {
+                    found.push(device);
+                }
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Return every device having a directory under `root` selected by
+/// `selector`
+///
+/// Only the driver and node directories matching the selector are read.
+pub fn select_devices(root: &Path, selector: &DeviceSelector) -> io::Result<Vec<Device>> {
+    let mut found = Vec::new();
+
+    for (driver, driver_path) in try!( sub_dirs(root) ) {
+        if !selector.matches_driver(&driver) {
+            continue;
+        }
+
+        for (node, node_path) in try!( sub_dirs(&driver_path) ) {
+            if !selector.matches_node(&node) {
+                continue;
+            }
+
+            for (port, _) in try!( port_dirs(&node_path) ) {
+                if !selector.matches_port(&port) {
+                    continue;
+                }
+
+                if let Some(device) = device_for(&port, &node, &driver) {
+                    found.push(device);
+                }
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Find every day directory of `device` under `root`, sorted by date
+pub fn device_day_dirs(root: &Path, device: &Device) -> io::Result<Vec<DayDir>> {
+    let mut found = Vec::new();
+
+    try!( push_days(&mut found, device, &device_dir(root, device)) );
+    Ok(found)
+}
+
+/// List the port directories under a node directory with their port path
+///
+/// A port directory may hold the directories of its sub-addresses next to
+/// its year directories, they are found by skipping numeric names. A port
+/// directory only holding sub-addresses is not a device.
+fn port_dirs(node_path: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut found = Vec::new();
+    let mut pending = try!( sub_dirs(node_path) );
+    let mut depth = 1;
+
+    while !pending.is_empty() && depth <= MAX_PORT_DEPTH {
+        let mut next = Vec::new();
+
+        for (port, port_path) in pending.into_iter() {
+            let mut has_days = false;
+            let mut has_subs = false;
+
+            for (name, path) in try!( sub_dirs(&port_path) ) {
+                if name.chars().all(|c| c.is_digit(10)) {
+                    has_days = true;
+                } else if depth < MAX_PORT_DEPTH {
+                    has_subs = true;
+                    next.push( (format!("{}/{}", name, port), path) );
+                }
+            }
+
+            if has_days || !has_subs {
+                found.push( (port, port_path) );
+            }
+        }
+
+        pending = next;
+        depth += 1;
+    }
+
+    found.sort();
+    Ok(found)
+}
+
+/// A device directory hidden from the storage since its names are not a
+/// canonical device
+///
+/// Device names used to be case sensitive and less restricted, see
+/// `Device`, so storages written before may hold such directories.
+#[derive(Debug, Clone)]
+pub struct LegacyDeviceDir {
+    /// Slug built from the directory names, as found on disk
+    pub slug: String,
+    pub path: PathBuf,
+    /// Canonical device of the same name, `None` if the names are not
+    /// valid anymore
+    pub device: Option<Device>,
+}
+
+/// Find the device directories under `root` which are not canonical
+///
+/// Their points are not read by the storages, see `rename_device_dir` to
+/// move them. Directories starting with a `.` are never devices and
+/// ignored.
+pub fn legacy_device_dirs(root: &Path) -> io::Result<Vec<LegacyDeviceDir>> {
+    let mut found = Vec::new();
+
+    for (driver, driver_path) in try!( sub_dirs(root) ) {
+        for (node, node_path) in try!( sub_dirs(&driver_path) ) {
+            for (port, port_path) in try!( port_dirs(&node_path) ) {
+                let hidden = driver.starts_with('.') || node.starts_with('.')
+                             || port.split('/').any(|name| name.starts_with('.'));
+
+                if hidden || device_for(&port, &node, &driver).is_some() {
+                    continue;
+                }
+
+                found.push( LegacyDeviceDir {
+                    slug: format!("{}@{}.{}", port, node, driver),
+                    path: port_path,
+                    device: Device::new(&port, &node, &driver).ok(),
+                });
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Move the day directories of `dir` to the directory of `device`
+///
+/// Days already present for `device` are left in place and returned,
+/// their files must be merged by hand. Directories of `dir` emptied by
+/// the move are removed.
+pub fn rename_device_dir(root: &Path, dir: &LegacyDeviceDir, device: &Device)
+    -> io::Result<Vec<PathBuf>> {
+
+    let target = device_dir(root, device);
+    let mut conflicts = Vec::new();
+
+    for (year, year_path) in try!( sub_dirs(&dir.path) ) {
+        if !year.chars().all(|c| c.is_digit(10)) {
+            continue;
+        }
+
+        for (month, month_path) in try!( sub_dirs(&year_path) ) {
+            for (day, day_path) in try!( sub_dirs(&month_path) ) {
+                let to = target.join(&year).join(&month).join(&day);
+
+                if to.exists() {
+                    conflicts.push(day_path);
+                    continue;
+                }
+
+                try!( fs::create_dir_all(to.parent().unwrap()) );
+                try!( fs::rename(&day_path, &to) );
+            }
+
+            let _ = fs::remove_dir(&month_path);
+        }
+
+        let _ = fs::remove_dir(&year_path);
+    }
+
+    // Stop at the first directory still holding something
+    let mut path = dir.path.clone();
+    while path.starts_with(root) && path != root && fs::remove_dir(&path).is_ok() {
+        path.pop();
+    }
+
+    Ok(conflicts)
+}
+
+/// Return the device stored in the `driver/node/port` directory
+///
+/// Directory names which are not valid and canonical device names are
+/// rejected, they are never written by a storage. They are found by
+/// `legacy_device_dirs`.
+fn device_for(port: &str, node: &str, driver: &str) -> Option<Device> {
+    match Device::new(port, node, driver) {
+        Ok(device) => if device.get_port() == port && device.get_node() == node
+                         && device.get_driver() == driver {
+            Some(device)
+        } else {
+            None
+        },
+        Err(_) => None,
+    }
+}
+
+fn push_days(found: &mut Vec<DayDir>, device: &Device, port_path: &Path)
+    -> io::Result<()> {
+
+    let mut days = Vec::new();
+
+    for (year, year_path) in try!( sub_dirs(port_path) ) {
+        let year = match i32::from_str(&year) { Ok(x) => x, Err(_) => continue };
+
+        for (month, month_path) in try!( sub_dirs(&year_path) ) {
+            let month = match u32::from_str(&month) { Ok(x) => x, Err(_) => continue };
+
+            for (day, day_path) in try!( sub_dirs(&month_path) ) {
+                let day = match u32::from_str(&day) { Ok(x) => x, Err(_) => continue };
+
+                days.push( DayDir {
+                    device: device.clone(),
+                    year: year,
+                    month: month,
+                    day: day,
+                    path: day_path,
+                });
+            }
+        }
+    }
+
+    days.sort_by(|a, b| (a.year, a.month, a.day).cmp(&(b.year, b.month, b.day)) );
+    found.extend(days.into_iter());
+
+    Ok( () )
+}
+
+/// List the sub directories of `path` with their name, sorted by name
+fn sub_dirs(path: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+
+    let entries = match fs::read_dir(path) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = try!(entry);
+
+        if !try!( entry.file_type() ).is_dir() {
+            continue;
+        }
+
+        if let Some(name) = entry.file_name().to_str() {
+            dirs.push( (name.to_string(), entry.path()) );
+        }
+    }
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+
+#[test]
+fn test_sub_address_layout() {
+    use std::env;
+    use chrono::TimeZone;
+
+    let root = env::temp_dir().join("orion_test_sub_address_layout");
+    let _ = fs::remove_dir_all(&root);
+
+    let date = UTC.ymd(2015, 6, 1).and_hms(12, 0, 0);
+    let slugs = [ "port@node.driver", "reg40001/slave3@gw1.modbus",
+                  "reg40002/slave3@gw1.modbus", "slave4@gw1.modbus" ];
+
+    for slug in slugs.iter() {
+        let device = Device::with_slug(slug).unwrap();
+        fs::create_dir_all(day_path(&root, &device, &date)).unwrap();
+    }
+
+    assert!( root.join("modbus/gw1/slave3/reg40001/2015/6/1").is_dir() );
+
+    let mut found: Vec<String> = devices(&root).unwrap().iter()
+                                               .map(|d| d.get_slug().to_string())
+                                               .collect();
+    found.sort();
+    assert_eq!( found, [ "port@node.driver", "reg40001/slave3@gw1.modbus",
+                         "reg40002/slave3@gw1.modbus", "slave4@gw1.modbus" ] );
+
+    let selector = DeviceSelector::from_str("*/slave3@*.modbus").unwrap();
+    assert_eq!( select_devices(&root, &selector).unwrap().len(), 2 );
+
+    let days = day_dirs(&root).unwrap();
+    assert_eq!( days.len(), 4 );
+
+    let device = Device::with_slug("reg40001/slave3@gw1.modbus").unwrap();
+    let days = device_day_dirs(&root, &device).unwrap();
+    assert_eq!( days.len(), 1 );
+    assert_eq!( days[0].device, device );
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_legacy_device_dirs() {
+    use std::env;
+    use std::fs::File;
+
+    let root = env::temp_dir().join("orion_test_legacy_device_dirs");
+    let _ = fs::remove_dir_all(&root);
+
+    for dir in [ "lm/Core/Temp1/2015/6/1", "lm/Core/Temp1/2015/6/2", "lm/core/temp1/2015/6/2",
+                 "lm/core/-fan/2015/6/1", "lm/core/temp2/2015/6/1", ".rollups/a/b/2015/6/1" ].iter() {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        File::create(root.join(dir).join("data.txt")).unwrap();
+    }
+
+    let legacy = legacy_device_dirs(&root).unwrap();
+    let slugs: Vec<_> = legacy.iter().map(|d| d.slug.clone()).collect();
+    assert_eq!( slugs, vec!["Temp1@Core.lm", "-fan@core.lm"] );
+
+    let temp1 = Device::with_slug("temp1@core.lm").unwrap();
+    assert_eq!( legacy[0].device, Some(temp1.clone()) );
+    assert_eq!( legacy[1].device, None );
+    assert_eq!( devices(&root).unwrap().len(), 2 );
+
+    // The day present under both names is left in place
+    let conflicts = rename_device_dir(&root, &legacy[0], &temp1).unwrap();
+    assert_eq!( conflicts, vec![root.join("lm/Core/Temp1/2015/6/2")] );
+    assert_eq!( device_day_dirs(&root, &temp1).unwrap().len(), 2 );
+    assert!( !root.join("lm/Core/Temp1/2015/6/1").exists() );
+
+    let fan = Device::with_slug("fan@core.lm").unwrap();
+    assert!( rename_device_dir(&root, &legacy[1], &fan).unwrap().is_empty() );
+    assert!( !root.join("lm/core/-fan").exists() );
+    assert_eq!( device_day_dirs(&root, &fan).unwrap().len(), 1 );
+
+    let slugs: Vec<_> = legacy_device_dirs(&root).unwrap().iter().map(|d| d.slug.clone()).collect();
+    assert_eq!( slugs, vec!["Temp1@Core.lm"] );
+
+    let _ = fs::remove_dir_all(&root);
+}
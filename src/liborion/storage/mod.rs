@@ -0,0 +1,216 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Persistent storage of `MeasurementPoint`
+//!
+//! Every backend keep the same directory layout under a root directory:
+//!
+//! ```text
+//! $(ROOT)/$(DRIVER)/$(NODE)/$(PORT)/$(YEAR)/$(MONTH)/$(DAY)/
+//! ```
+//!
+//! and only differ by the file they write in each day directory, except
+//! `SqliteStorage` which keep everything in one database file. The tags of
+//! the points are kept aside by `TaggedStorage` for every backend.
+
+use std::io;
+use std::fmt;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use chrono::{UTC, DateTime};
+
+use core::{Device, DeviceSelector, MeasurementPoint, Tags};
+
+mod files;
+
+mod layout;
+pub use self::layout::{device_dir, day_path, day_dirs, device_day_dirs, DayDir};
+pub use self::layout::{devices, select_devices};
+pub use self::layout::{legacy_device_dirs, rename_device_dir, LegacyDeviceDir};
+
+mod text;
+pub use self::text::{TextStorage, TEXT_FILENAME};
+
+mod binary;
+pub use self::binary::{BinaryStorage, BINARY_FILENAME, BINARY_MAGIC};
+pub use self::binary::{convert_text_file, repair_tail};
+
+mod bits;
+mod gorilla;
+
+mod columnar;
+pub use self::columnar::{ColumnarStorage, compact_closed_days};
+pub use self::columnar::{CHUNKS_FILENAME, INDEX_FILENAME, HEAD_FILENAME, GENERATION_FILENAME};
+
+mod sqlite;
+pub use self::sqlite::{SqliteStorage, SQLITE_FILENAME};
+
+mod fsck;
+pub use self::fsck::{check_text_file, repair_text_file, QUARANTINE_FILENAME};
+pub use self::fsck::{Problem, Issue, FileReport};
+
+mod retention;
+pub use self::retention::{RetentionPolicy, ParseRetentionError, PruneReport, prune};
+
+mod rollup;
+pub use self::rollup::{Rollup, Resolution, ParseResolutionError, ROLLUP_DIRNAME};
+pub use self::rollup::{compute_rollups, merge_rollups, rollup_root, rolled_until};
+pub use self::rollup::{rollup_device, rollup_closed_days, read_rollups, query_rollups};
+pub use self::rollup::parse_step;
+
+mod query;
+pub use self::query::{Query, Series, Aggregate, ParseAggregateError};
+
+mod registry;
+pub use self::registry::{Registry, DeviceInfo, ChannelSchema, REGISTRY_FILENAME};
+pub use self::registry::{RegistryError, SchemaError};
+
+mod tags;
+pub use self::tags::{TaggedStorage, TAGS_FILENAME, TAG_INDEX_FILENAME};
+pub use self::tags::{read_tags, read_index, prune_index, TagRecord};
+
+mod wal;
+pub use self::wal::{WriteAheadLog, FsyncPolicy, ParseFsyncPolicyError};
+pub use self::wal::{WAL_FILENAME, WAL_MAGIC};
+
+/// A place where `MeasurementPoint` can be saved
+pub trait Storage {
+
+    /// Save `mp`
+    ///
+    /// `mp` may only be durable after the next call to `sync`.
+    fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()>;
+
+    /// Flush every point saved since the last call to disk
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Return the points of `device` taken in `[from, to)`, sorted by date
+    fn read_range(&self, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+        -> io::Result<Vec<MeasurementPoint>>;
+
+    /// Return every device with stored points
+    fn devices(&self) -> io::Result<Vec<Device>>;
+
+    /// Return every device with stored points selected by `selector`
+    fn select(&self, selector: &DeviceSelector) -> io::Result<Vec<Device>> {
+        Ok( try!( self.devices() ).into_iter().filter(|d| selector.matches(d)).collect() )
+    }
+
+    /// Return every device and tags pair of the stored points
+    ///
+    /// Storages which don't keep tags have no series.
+    fn series(&self) -> io::Result<Vec<(Device, Tags)>> {
+        Ok( Vec::new() )
+    }
+}
+
+/// Return `true` if `mp` was taken in `[from, to)`
+pub fn in_range(mp: &MeasurementPoint, from: &DateTime<UTC>, to: &DateTime<UTC>) -> bool {
+    mp.get_date() >= *from && mp.get_date() < *to
+}
+
+/// Available storage backends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One `data.txt` file per day, see `TextStorage`
+    Text,
+    /// One `data.bin` file per day, see `BinaryStorage`
+    Binary,
+    /// Compressed chunks with an index, see `ColumnarStorage`
+    Columnar,
+    /// One SQLite database, see `SqliteStorage`
+    Sqlite,
+}
+
+impl Format {
+
+    /// Open a `Storage` of this format in `root` directory
+    ///
+    /// The storage keeps the tags of the points, see `TaggedStorage`.
+    pub fn open(&self, root: &Path) -> io::Result<Box<Storage>> {
+        let storage: Box<Storage> = match *self {
+            Format::Text     => Box::new( TextStorage::new(root) ),
+            Format::Binary   => Box::new( BinaryStorage::new(root) ),
+            Format::Columnar => Box::new( ColumnarStorage::new(root) ),
+            Format::Sqlite   => Box::new( try!( SqliteStorage::open(root) ) ),
+        };
+
+        Ok( Box::new( try!( TaggedStorage::new(storage, root) ) ) )
+    }
+}
+
+impl FromStr for Format {
+
+    type Err = ParseFormatError;
+
+    /// Parse a storage format name
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::storage::Format;
+    /// use std::str::FromStr;
+    ///
+    /// assert_eq!( Format::from_str("binary").unwrap(), Format::Binary );
+    /// assert!( Format::from_str("xml").is_err() );
+    /// ```
+    fn from_str(s: &str) -> Result<Format, ParseFormatError> {
+        match s {
+            "text"   => Ok(Format::Text),
+            "binary" => Ok(Format::Binary),
+            "columnar" => Ok(Format::Columnar),
+            "sqlite" => Ok(Format::Sqlite),
+            _        => Err(ParseFormatError::Invalid),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::Text   => write!(f, "{}", "text"),
+            Format::Binary => write!(f, "{}", "binary"),
+            Format::Columnar => write!(f, "{}", "columnar"),
+            Format::Sqlite => write!(f, "{}", "sqlite"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseFormatError {
+    Invalid,
+}
+
+impl fmt::Display for ParseFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseFormatError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseFormatError::Invalid => "Unknown storage format",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
@@ -0,0 +1,484 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use chrono::{UTC, DateTime, TimeZone, Duration};
+
+use core::{Device, DeviceSelector, Measurement, MeasurementPoint, Tags, Unit};
+use super::{Storage, Rollup, Resolution, query_rollups, compute_rollups};
+
+/// Function summarizing the values of a bucket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Min,
+    Max,
+    Mean,
+    Sum,
+    Count,
+    First,
+    Last,
+    /// Population standard deviation
+    Stddev,
+    /// Nearest-rank percentile, from 1 to 100
+    Percentile(u8),
+    /// Increase per second of a counter, resets are handled as a restart
+    /// from zero
+    Rate,
+}
+
+impl Aggregate {
+
+    /// Return `true` if this aggregate can be computed from rollups
+    fn from_rollups(&self) -> bool {
+        match *self {
+            Aggregate::Stddev | Aggregate::Percentile(_) | Aggregate::Rate => false,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for Aggregate {
+
+    type Err = ParseAggregateError;
+
+    /// Parse an aggregate name
+    ///
+    /// Valid names are `min`, `max`, `mean`, `sum`, `count`, `first`,
+    /// `last`, `stddev`, `rate` and `pN` for the Nth percentile.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::storage::Aggregate;
+    /// use std::str::FromStr;
+    ///
+    /// assert_eq!( Aggregate::from_str("p95").unwrap(), Aggregate::Percentile(95) );
+    /// assert!( Aggregate::from_str("p101").is_err() );
+    /// ```
+    fn from_str(s: &str) -> Result<Aggregate, ParseAggregateError> {
+        match s {
+            "min"    => Ok(Aggregate::Min),
+            "max"    => Ok(Aggregate::Max),
+            "mean"   => Ok(Aggregate::Mean),
+            "sum"    => Ok(Aggregate::Sum),
+            "count"  => Ok(Aggregate::Count),
+            "first"  => Ok(Aggregate::First),
+            "last"   => Ok(Aggregate::Last),
+            "stddev" => Ok(Aggregate::Stddev),
+            "rate"   => Ok(Aggregate::Rate),
+            _ if s.starts_with('p') => match u8::from_str(&s[1..]) {
+                Ok(n) if n >= 1 && n <= 100 => Ok(Aggregate::Percentile(n)),
+                _                           => Err(ParseAggregateError::Invalid),
+            },
+            _ => Err(ParseAggregateError::Invalid),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Aggregate::Min    => write!(f, "{}", "min"),
+            Aggregate::Max    => write!(f, "{}", "max"),
+            Aggregate::Mean   => write!(f, "{}", "mean"),
+            Aggregate::Sum    => write!(f, "{}", "sum"),
+            Aggregate::Count  => write!(f, "{}", "count"),
+            Aggregate::First  => write!(f, "{}", "first"),
+            Aggregate::Last   => write!(f, "{}", "last"),
+            Aggregate::Stddev => write!(f, "{}", "stddev"),
+            Aggregate::Percentile(n) => write!(f, "p{}", n),
+            Aggregate::Rate   => write!(f, "{}", "rate"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseAggregateError {
+    Invalid,
+}
+
+impl fmt::Display for ParseAggregateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseAggregateError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseAggregateError::Invalid => "Unknown aggregate",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+/// Aggregated values of a measurement position of a device
+///
+/// Each value is dated by the start of its bucket and keep the unit of the
+/// measurements, a count too. Empty buckets are not included.
+///
+/// `tags` hold the values of the grouping tags of the query shared by the
+/// aggregated points, it is empty without grouping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub device: Device,
+    pub tags: Tags,
+    pub channel: usize,
+    pub values: Vec<(DateTime<UTC>, Measurement)>,
+}
+
+/// Aggregate the points of the devices matching a selector by time bucket
+///
+/// Points can also be filtered by tags with `set_filter` and the series of
+/// a device split by the values of some tags with `set_group_by`. Both read
+/// the raw points instead of the rollups.
+///
+/// # Example
+///
+/// ```
+/// # extern crate orion;
+/// # extern crate chrono;
+/// use orion::core::DeviceSelector;
+/// use orion::storage::{Query, Aggregate};
+/// use chrono::{UTC, TimeZone, Duration};
+/// use std::str::FromStr;
+///
+/// # fn main() {
+/// let query = Query::new(
+///     DeviceSelector::from_str("temp*@node.driver").unwrap(),
+///     UTC.ymd(2015, 6, 1).and_hms(0, 0, 0),
+///     UTC.ymd(2015, 6, 2).and_hms(0, 0, 0),
+///     Duration::minutes(5),
+///     Aggregate::Mean,
+/// ).unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Query {
+    selector: DeviceSelector,
+    from: DateTime<UTC>,
+    to: DateTime<UTC>,
+    step: Duration,
+    agg: Aggregate,
+    filter: Tags,
+    group_by: Vec<String>,
+}
+
+impl Query {
+
+    /// Construct a new `Query` on `[from, to)` with buckets of `step`
+    ///
+    /// # Failures
+    ///
+    /// Return `None` if `step` is not a positive number of seconds.
+    pub fn new(selector: DeviceSelector, from: DateTime<UTC>, to: DateTime<UTC>,
+               step: Duration, agg: Aggregate) -> Option<Query> {
+
+        if step.num_seconds() <= 0 || step != Duration::seconds(step.num_seconds()) {
+            return None;
+        }
+
+        Some( Query {
+            selector: selector,
+            from: from,
+            to: to,
+            step: step,
+            agg: agg,
+            filter: Tags::new(),
+            group_by: Vec::new(),
+        })
+    }
+
+    /// Only aggregate the points having every tag of `filter`
+    pub fn set_filter(&mut self, filter: Tags) {
+        self.filter = filter;
+    }
+
+    /// Aggregate separately the points of a device having different values
+    /// for the tags of `keys`
+    ///
+    /// Points without some of these tags form their own groups.
+    pub fn set_group_by(&mut self, keys: Vec<String>) {
+        self.group_by = keys.iter().map(|k| k.to_lowercase()).collect();
+    }
+
+    /// Run this query on `storage`, whose root directory is `root`
+    ///
+    /// Aggregates computed from rollups use the stored rollups of
+    /// `resolutions` when possible, see `query_rollups`. Series are
+    /// sorted by device slug, then by tags and channel.
+    pub fn run(&self, storage: &Storage, root: &Path, resolutions: &[Resolution])
+        -> io::Result<Vec<Series>> {
+
+        let mut devices = try!( storage.select(&self.selector) );
+        devices.sort_by(|a, b| a.get_slug().cmp(b.get_slug()) );
+
+        if !self.filter.is_empty() {
+            let tagged: Vec<Device> = try!( storage.series() ).into_iter()
+                .filter(|&(_, ref tags)| tags.contains(&self.filter))
+                .map(|(device, _)| device)
+                .collect();
+
+            devices.retain(|d| tagged.contains(d));
+        }
+
+        let mut series = Vec::new();
+
+        for device in devices.iter() {
+            let mut groups: BTreeMap<Tags, Vec<(usize, DateTime<UTC>, Measurement)>> =
+                BTreeMap::new();
+
+            if self.filter.is_empty() && self.group_by.is_empty() && self.agg.from_rollups() {
+                let rollups = try!( query_rollups(storage, root, device, resolutions,
+                                                  &self.from, &self.to, &self.step) );
+                groups.insert(Tags::new(), rollups.iter().map(|r| {
+                    (r.channel, r.start, self.from_rollup(r))
+                }).collect());
+            } else {
+                let mut points: BTreeMap<Tags, Vec<MeasurementPoint>> = BTreeMap::new();
+
+                for mp in try!( storage.read_range(device, &self.from, &self.to) ) {
+                    if mp.get_tags().contains(&self.filter) {
+                        points.entry( mp.get_tags().select(&self.group_by) )
+                              .or_insert(Vec::new())
+                              .push(mp);
+                    }
+                }
+
+                for (tags, points) in points {
+                    groups.insert(tags, self.from_points(&points));
+                }
+            }
+
+            for (tags, values) in groups {
+                let mut channels: BTreeMap<usize, Vec<(DateTime<UTC>, Measurement)>> =
+                    BTreeMap::new();
+
+                for (channel, date, m) in values {
+                    channels.entry(channel).or_insert(Vec::new()).push( (date, m) );
+                }
+
+                for (channel, values) in channels {
+                    series.push( Series {
+                        device: device.clone(),
+                        tags: tags.clone(),
+                        channel: channel,
+                        values: values,
+                    });
+                }
+            }
+        }
+
+        Ok(series)
+    }
+
+    fn from_rollup(&self, r: &Rollup) -> Measurement {
+        let unit = r.get_unit();
+
+        match self.agg {
+            Aggregate::Min   => r.min.clone(),
+            Aggregate::Max   => r.max.clone(),
+            Aggregate::Mean  => r.mean(),
+            Aggregate::Sum   => Measurement::new(r.sum as f32, unit),
+            Aggregate::Count => Measurement::new(r.count as f32, unit),
+            Aggregate::First => r.first.clone(),
+            Aggregate::Last  => r.last.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Compute the aggregate of `points` by bucket
+    fn from_points(&self, points: &[MeasurementPoint])
+        -> Vec<(usize, DateTime<UTC>, Measurement)> {
+
+        if self.agg.from_rollups() {
+            return compute_rollups(points, &self.step).iter()
+                .map(|r| (r.channel, r.start, self.from_rollup(r)))
+                .collect();
+        }
+
+        let width = self.step.num_seconds();
+        let mut buckets: BTreeMap<(i64, usize, u8), Vec<(DateTime<UTC>, f32)>> =
+            BTreeMap::new();
+
+        for mp in points.iter() {
+            let secs = mp.get_date().timestamp();
+            let start = secs - ((secs % width) + width) % width;
+
+            for (channel, m) in mp.get_data().iter().enumerate() {
+                buckets.entry( (start, channel, m.get_unit().code()) )
+                       .or_insert(Vec::new())
+                       .push( (mp.get_date(), m.get_value()) );
+            }
+        }
+
+        let mut values = Vec::new();
+
+        for ((start, channel, unit), samples) in buckets {
+            let unit = Unit::from_code(unit).unwrap();
+
+            let value = match self.agg {
+                Aggregate::Stddev => Some( stddev(&samples) ),
+                Aggregate::Percentile(n) => Some( percentile(&samples, n) ),
+                Aggregate::Rate => rate(&samples),
+                _ => unreachable!(),
+            };
+
+            if let Some(value) = value {
+                values.push( (channel, UTC.timestamp(start, 0), Measurement::new(value, unit)) );
+            }
+        }
+
+        values
+    }
+}
+
+fn stddev(samples: &[(DateTime<UTC>, f32)]) -> f32 {
+    let n = samples.len() as f64;
+    let mean = samples.iter().map(|s| s.1 as f64).sum::<f64>() / n;
+    let var = samples.iter().map(|s| (s.1 as f64 - mean).powi(2)).sum::<f64>() / n;
+
+    var.sqrt() as f32
+}
+
+fn percentile(samples: &[(DateTime<UTC>, f32)], n: u8) -> f32 {
+    let mut values: Vec<f32> = samples.iter().map(|s| s.1).collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal) );
+
+    let rank = ((n as f64 / 100.0) * values.len() as f64).ceil() as usize;
+    values[if rank == 0 { 0 } else { rank - 1 }]
+}
+
+/// Return `None` if the samples don't span any time
+fn rate(samples: &[(DateTime<UTC>, f32)]) -> Option<f32> {
+    let first = samples[0].0;
+    let last = samples[samples.len() - 1].0;
+
+    let elapsed = match (last - first).num_microseconds() {
+        Some(x) if x > 0 => x as f64 / 1e6,
+        _                => return None,
+    };
+
+    let mut increase = 0f64;
+
+    for pair in samples.windows(2) {
+        let (a, b) = (pair[0].1 as f64, pair[1].1 as f64);
+
+        increase += if b >= a { b - a } else { b };
+    }
+
+    Some( (increase / elapsed) as f32 )
+}
+
+
+#[test]
+fn test_query() {
+    use std::env;
+    use std::fs;
+    use core::MeasurementsList;
+    use super::{TextStorage, TaggedStorage};
+
+    let root = env::temp_dir().join("orion_test_query");
+    let _ = fs::remove_dir_all(&root);
+
+    let mut storage = TextStorage::new(&root);
+    let start = UTC.ymd(2015, 6, 1).and_hms(0, 0, 0);
+
+    // A counter reset after 10 minutes on `a`, a constant on `b`
+    for (slug, values) in [ ("a@node.driver", [0, 60, 120, 180, 240, 300, 20, 80]),
+                            ("b@node.driver", [5, 5, 5, 5, 5, 5, 5, 5]),
+                            ("c@other.driver", [1, 1, 1, 1, 1, 1, 1, 1]) ].iter() {
+        let device = Device::with_slug(slug).unwrap();
+
+        for (i, v) in values.iter().enumerate() {
+            let data = MeasurementsList::from_str( &format!("{}[V]", v) ).unwrap();
+            let date = start + Duration::minutes(2 * i as i64);
+            storage.append(&MeasurementPoint::new(device.clone(), date, data)).unwrap();
+        }
+    }
+
+    let end = start + Duration::hours(1);
+    let query = |s, agg| Query::new(DeviceSelector::from_str(s).unwrap(),
+                                    start, end, Duration::minutes(8), agg)
+                                    .unwrap()
+                                    .run(&storage, &root, &[])
+                                    .unwrap();
+
+    let values = |series: &Series| series.values.iter()
+                                                .map(|v| v.1.to_string())
+                                                .collect::<Vec<_>>();
+
+    let mean = query("*@node.driver", Aggregate::Mean);
+    assert_eq!( mean.len(), 2 );
+    assert_eq!( mean[0].device.get_slug(), "a@node.driver" );
+    assert_eq!( values(&mean[0]), vec!["90[V]", "160[V]"] );
+    assert_eq!( mean[0].values[1].0, start + Duration::minutes(8) );
+
+    let rate = query("a@*.*", Aggregate::Rate);
+    assert_eq!( values(&rate[0]), vec!["0.5[V]", "0.3888889[V]"] );
+
+    let p50 = query("[a-c]@*.driver", Aggregate::Percentile(50));
+    assert_eq!( p50.len(), 3 );
+    assert_eq!( values(&p50[0]), vec!["60[V]", "80[V]"] );
+
+    let stddev = query("b@node.driver", Aggregate::Stddev);
+    assert_eq!( values(&stddev[0]), vec!["0[V]", "0[V]"] );
+
+    assert!( Query::new(DeviceSelector::all(), start, end, Duration::zero(),
+                        Aggregate::Min).is_none() );
+
+    // Points of `d` are taken on two sites
+    let mut tagged = TaggedStorage::new(Box::new(TextStorage::new(&root)), &root).unwrap();
+    let device = Device::with_slug("d@node.driver").unwrap();
+
+    for (i, site) in ["lab", "roof", "lab", "roof"].iter().enumerate() {
+        let data = MeasurementsList::from_str( &format!("{}[K]", i) ).unwrap();
+        let mut mp = MeasurementPoint::new(device.clone(), start + Duration::minutes(i as i64),
+                                           data);
+        mp.set_tags( Tags::from_str( &format!("site={},run=1", site) ).unwrap() );
+        tagged.append(&mp).unwrap();
+    }
+
+    let mut query = Query::new(DeviceSelector::from_str("*@node.driver").unwrap(),
+                               start, end, Duration::minutes(8), Aggregate::Mean).unwrap();
+    query.set_group_by(vec!["Site".to_string()]);
+
+    let series = query.run(&tagged, &root, &[]).unwrap();
+    assert_eq!( series.len(), 4 );
+    assert!( series[0].tags.is_empty() );
+    assert_eq!( series[2].tags.to_string(), "site=lab" );
+    assert_eq!( values(&series[2]), vec!["1[K]"] );
+    assert_eq!( series[3].tags.to_string(), "site=roof" );
+    assert_eq!( values(&series[3]), vec!["2[K]"] );
+
+    query.set_filter( Tags::from_str("site=roof").unwrap() );
+    let series = query.run(&tagged, &root, &[]).unwrap();
+    assert_eq!( series.len(), 1 );
+    assert_eq!( series[0].device, device );
+    assert_eq!( values(&series[0]), vec!["2[K]"] );
+
+    let _ = fs::remove_dir_all(&root);
+}
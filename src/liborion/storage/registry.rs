@@ -0,0 +1,495 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::str::FromStr;
+use std::time::SystemTime;
+use toml;
+use regex;
+
+use core::{Device, MeasurementsList, Unit};
+
+/// Name of the registry file in the storage root
+pub const REGISTRY_FILENAME: &'static str = "registry.toml";
+
+/// A measurement position declared for a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSchema {
+    pub name: String,
+    pub unit: Unit,
+}
+
+impl FromStr for ChannelSchema {
+
+    type Err = RegistryError;
+
+    /// Parse a channel declared like `name[unit]`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::core::Unit;
+    /// use orion::storage::ChannelSchema;
+    /// use std::str::FromStr;
+    ///
+    /// let channel = ChannelSchema::from_str("temp[K]").unwrap();
+    /// assert_eq!( channel.unit, Unit::Kelvin );
+    /// assert_eq!( channel.to_string(), "temp[K]" );
+    /// ```
+    fn from_str(s: &str) -> Result<ChannelSchema, RegistryError> {
+        let re = regex!(r"^([\w-]+)\[(.+)\]$");
+
+        let invalid = || RegistryError::Invalid(format!("Invalid channel '{}'", s));
+
+        let data = match re.captures(s) {
+            Some(x) => x,
+            None    => return Err(invalid()),
+        };
+
+        let unit = match Unit::from_str(data.at(2).unwrap()) {
+            Ok(x)  => x,
+            Err(_) => return Err(invalid()),
+        };
+
+        Ok( ChannelSchema {
+            name: data.at(1).unwrap().to_string(),
+            unit: unit,
+        })
+    }
+}
+
+impl fmt::Display for ChannelSchema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]", self.name, self.unit)
+    }
+}
+
+/// Description of a device kept in the `Registry`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub device: Device,
+    /// Human readable name
+    pub name: String,
+    pub location: String,
+    pub tags: Vec<String>,
+    /// Measurements expected from the device, in order. An empty schema
+    /// accept any measurement.
+    pub channels: Vec<ChannelSchema>,
+}
+
+impl DeviceInfo {
+
+    /// Construct a new `DeviceInfo` without description nor schema
+    pub fn new(device: Device) -> DeviceInfo {
+        DeviceInfo {
+            device: device,
+            name: String::new(),
+            location: String::new(),
+            tags: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// Check that `data` follow the channel schema of the device
+    ///
+    /// # Failures
+    ///
+    /// - `SchemaError::ChannelCount` if `data` don't have one measurement
+    ///   per channel
+    /// - `SchemaError::Unit` for the first measurement whose unit is not
+    ///   the one of its channel
+    pub fn check(&self, data: &MeasurementsList) -> Result<(), SchemaError> {
+        if self.channels.is_empty() {
+            return Ok( () );
+        }
+
+        if data.len() != self.channels.len() {
+            return Err( SchemaError::ChannelCount(self.channels.len(), data.len()) );
+        }
+
+        for (channel, m) in self.channels.iter().zip(data.iter()) {
+            if channel.unit != m.get_unit() {
+                return Err( SchemaError::Unit(channel.name.clone(), channel.unit,
+                                              m.get_unit()) );
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+/// Devices described in the `registry.toml` file of a storage root
+///
+/// The file hold a `[[device]]` table per device:
+///
+/// ```toml
+/// [[device]]
+/// slug = "temp1@core-isa-000.lm-sensors"
+/// name = "CPU temperature"
+/// location = "Server room"
+/// tags = ["cpu"]
+/// channels = ["temp[K]"]
+/// ```
+#[derive(Debug)]
+pub struct Registry {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    devices: Vec<DeviceInfo>,
+}
+
+impl Registry {
+
+    /// Load the registry of the storage at `root`
+    ///
+    /// A missing file give an empty registry.
+    pub fn open(root: &Path) -> Result<Registry, RegistryError> {
+        let mut registry = Registry {
+            path: root.join(REGISTRY_FILENAME),
+            modified: None,
+            devices: Vec::new(),
+        };
+
+        try!( registry.load() );
+        Ok(registry)
+    }
+
+    /// Load the file again if it changed since the last load
+    ///
+    /// Return `true` if the file was loaded. On error, the devices of the
+    /// last successful load are kept and the file is loaded again by the
+    /// next call.
+    pub fn reload_if_changed(&mut self) -> Result<bool, RegistryError> {
+        if try!( modified(&self.path) ) == self.modified {
+            return Ok(false);
+        }
+
+        try!( self.load() );
+        Ok(true)
+    }
+
+    /// Replace the devices by the content of the file, only if it is valid
+    fn load(&mut self) -> Result<(), RegistryError> {
+        // Taken before reading, a change while reading is loaded next time
+        let modified = try!( modified(&self.path) );
+        let devices = try!( read_devices(&self.path) );
+
+        self.modified = modified;
+        self.devices = devices;
+        Ok( () )
+    }
+
+    /// Write the registry to its file
+    pub fn save(&self) -> io::Result<()> {
+        let devices = self.devices.iter().map(to_toml).collect();
+
+        let mut table = BTreeMap::new();
+        table.insert("device".to_string(), toml::Value::Array(devices));
+
+        if let Some(parent) = self.path.parent() {
+            try!( fs::create_dir_all(parent) );
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = try!( File::create(&tmp) );
+            try!( write!(file, "{}", toml::Value::Table(table)) );
+            try!( file.sync_all() );
+        }
+
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Return the description of `device`
+    pub fn get(&self, device: &Device) -> Option<&DeviceInfo> {
+        self.devices.iter().find(|info| info.device == *device)
+    }
+
+    /// Add or replace the description of a device
+    pub fn insert(&mut self, info: DeviceInfo) {
+        match self.devices.iter().position(|i| i.device == info.device) {
+            Some(pos) => self.devices[pos] = info,
+            None      => self.devices.push(info),
+        }
+
+        self.devices.sort_by(|a, b| a.device.get_slug().cmp(b.device.get_slug()) );
+    }
+
+    /// Remove the description of `device`
+    pub fn remove(&mut self, device: &Device) -> Option<DeviceInfo> {
+        match self.devices.iter().position(|i| i.device == *device) {
+            Some(pos) => Some( self.devices.remove(pos) ),
+            None      => None,
+        }
+    }
+
+    /// Iterate over every device, sorted by slug
+    pub fn iter(&self) -> slice::Iter<DeviceInfo> {
+        self.devices.iter()
+    }
+
+    /// Check that `data` follow the schema of `device`
+    ///
+    /// Devices missing from the registry accept any data.
+    pub fn check(&self, device: &Device, data: &MeasurementsList) -> Result<(), SchemaError> {
+        match self.get(device) {
+            Some(info) => info.check(data),
+            None       => Ok( () ),
+        }
+    }
+}
+
+/// Parse the devices of a registry file, a missing file has none
+fn read_devices(path: &Path) -> Result<Vec<DeviceInfo>, RegistryError> {
+    let mut content = String::new();
+
+    match File::open(path) {
+        Ok(mut file) => { try!( file.read_to_string(&mut content) ); },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err( RegistryError::Io(e) ),
+    }
+
+    let mut parser = toml::Parser::new(&content);
+
+    let table = match parser.parse() {
+        Some(x) => x,
+        None    => {
+            let msg = parser.errors.iter()
+                                   .map(|e| e.desc.clone())
+                                   .collect::<Vec<_>>()
+                                   .join(", ");
+            return Err( RegistryError::Invalid(msg) );
+        },
+    };
+
+    let devices = match table.get("device") {
+        Some(x) => match x.as_slice() {
+            Some(x) => x,
+            None    => return Err( invalid("device must be an array of tables") ),
+        },
+        None => return Ok(Vec::new()),
+    };
+
+    let mut result = Vec::with_capacity(devices.len());
+    for entry in devices.iter() {
+        result.push( try!( from_toml(entry) ) );
+    }
+
+    Ok(result)
+}
+
+fn modified(path: &Path) -> Result<Option<SystemTime>, RegistryError> {
+    match fs::metadata(path) {
+        Ok(x)  => Ok( Some( try!( x.modified() ) ) ),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err( RegistryError::Io(e) ),
+    }
+}
+
+fn invalid(msg: &str) -> RegistryError {
+    RegistryError::Invalid(msg.to_string())
+}
+
+fn from_toml(entry: &toml::Value) -> Result<DeviceInfo, RegistryError> {
+    let string = |key: &str| match entry.lookup(key) {
+        Some(x) => match x.as_str() {
+            Some(x) => Ok( x.to_string() ),
+            None    => Err( invalid(&format!("{} must be a string", key)) ),
+        },
+        None => Ok( String::new() ),
+    };
+
+    let strings = |key: &str| match entry.lookup(key) {
+        Some(x) => match x.as_slice() {
+            Some(x) => x.iter().map(|v| match v.as_str() {
+                Some(x) => Ok( x.to_string() ),
+                None    => Err( invalid(&format!("{} must be an array of strings", key)) ),
+            }).collect(),
+            None => Err( invalid(&format!("{} must be an array of strings", key)) ),
+        },
+        None => Ok( Vec::new() ),
+    };
+
+    let slug = try!( string("slug") );
+    let device = match Device::with_slug(&slug) {
+        Ok(x)  => x,
+        Err(e) => return Err( invalid(&format!("Invalid device '{}': {}", slug, e)) ),
+    };
+
+    let mut channels = Vec::new();
+    for channel in try!( strings("channels") ) {
+        channels.push( try!( ChannelSchema::from_str(&channel) ) );
+    }
+
+    Ok( DeviceInfo {
+        device: device,
+        name: try!( string("name") ),
+        location: try!( string("location") ),
+        tags: try!( strings("tags") ),
+        channels: channels,
+    })
+}
+
+fn to_toml(info: &DeviceInfo) -> toml::Value {
+    let strings = |v: Vec<String>| toml::Value::Array(
+        v.into_iter().map(toml::Value::String).collect()
+    );
+
+    let mut table = BTreeMap::new();
+    table.insert("slug".to_string(), toml::Value::String(info.device.get_slug().to_string()));
+    table.insert("name".to_string(), toml::Value::String(info.name.clone()));
+    table.insert("location".to_string(), toml::Value::String(info.location.clone()));
+    table.insert("tags".to_string(), strings(info.tags.clone()));
+    table.insert("channels".to_string(),
+                 strings(info.channels.iter().map(|c| c.to_string()).collect()));
+
+    toml::Value::Table(table)
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegistryError::Io(ref err)      => write!(f, "{}", err),
+            RegistryError::Invalid(ref msg) => write!(f, "Invalid registry: {}", msg),
+        }
+    }
+}
+
+impl Error for RegistryError {
+    fn description(&self) -> &str {
+        match *self {
+            RegistryError::Io(ref err)  => err.description(),
+            RegistryError::Invalid(..)  => "Invalid registry",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            RegistryError::Io(ref err) => Some(err),
+            _                          => None,
+        }
+    }
+}
+
+impl From<io::Error> for RegistryError {
+    fn from(err: io::Error) -> RegistryError {
+        RegistryError::Io(err)
+    }
+}
+
+/// Data not following the schema of its device
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    /// Expected and received number of measurements
+    ChannelCount(usize, usize),
+    /// Channel name, expected and received unit
+    Unit(String, Unit, Unit),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::ChannelCount(expected, found) =>
+                write!(f, "Expected {} measurements, got {}", expected, found),
+            SchemaError::Unit(ref name, expected, found) =>
+                write!(f, "Expected [{}] for {}, got [{}]", expected, name, found),
+        }
+    }
+}
+
+impl Error for SchemaError {
+    fn description(&self) -> &str {
+        match *self {
+            SchemaError::ChannelCount(..) => "Invalid number of measurements",
+            SchemaError::Unit(..)         => "Invalid unit",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+
+#[test]
+fn test_registry() {
+    use std::env;
+
+    let root = env::temp_dir().join("orion_test_registry");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("temp1@core.lm-sensors").unwrap();
+    let other = Device::with_slug("a0@uno.arduino").unwrap();
+
+    let mut registry = Registry::open(&root).unwrap();
+    assert_eq!( registry.iter().count(), 0 );
+
+    let mut info = DeviceInfo::new(device.clone());
+    info.name = "CPU \"temperature\"".to_string();
+    info.tags = vec!["cpu".to_string(), "server".to_string()];
+    info.channels = vec![ ChannelSchema::from_str("temp[K]").unwrap(),
+                          ChannelSchema::from_str("fan[W]").unwrap() ];
+
+    registry.insert(info.clone());
+    registry.insert(DeviceInfo::new(other.clone()));
+    registry.save().unwrap();
+
+    let mut registry = Registry::open(&root).unwrap();
+    assert_eq!( registry.get(&device), Some(&info) );
+    assert_eq!( registry.iter().count(), 2 );
+
+    let data = |s| MeasurementsList::from_str(s).unwrap();
+    assert!( registry.check(&device, &data("300[K] 10[W]")).is_ok() );
+    assert_eq!( registry.check(&device, &data("300[K]")),
+                Err(SchemaError::ChannelCount(2, 1)) );
+    assert_eq!( registry.check(&device, &data("300[K] 10[V]")),
+                Err(SchemaError::Unit("fan".to_string(), Unit::Watt, Unit::Volt)) );
+    assert!( registry.check(&other, &data("1[A] 2[V]")).is_ok() );
+
+    assert!( registry.remove(&other).is_some() );
+    assert!( registry.remove(&other).is_none() );
+    assert!( !registry.reload_if_changed().unwrap() );
+
+    // An invalid file keep the devices of the last load
+    File::create(root.join(REGISTRY_FILENAME)).unwrap().write_all(b"[[device]]\nslug = 3").unwrap();
+    registry.modified = None; // Don't depend on the resolution of the mtime
+    assert!( registry.reload_if_changed().is_err() );
+    assert_eq!( registry.get(&device), Some(&info) );
+    assert_eq!( registry.modified, None );
+    assert!( registry.reload_if_changed().is_err() );
+
+    registry.save().unwrap();
+    assert!( registry.reload_if_changed().unwrap() );
+    assert!( registry.modified.is_some() );
+    assert_eq!( registry.iter().count(), 1 );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,307 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fs;
+use std::fmt;
+use std::io;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use chrono::{UTC, Date, Datelike, Duration};
+
+use core::{Device, DeviceSelector};
+use super::{DayDir, day_dirs, prune_index};
+
+/// How many days of data are kept for each device
+///
+/// A rule apply to the devices of a `DeviceSelector`, a driver (`driver`)
+/// or a node (`node.driver`). Rules on ports take precedence over rules on
+/// nodes, which take precedence over rules on drivers, whatever their
+/// patterns. Between matching rules of the same level, the most specific
+/// one is used, see `DeviceSelector::specificity`. Without matching rule,
+/// the default one is used and without any rule, data are kept forever.
+///
+/// # Example
+///
+/// ```
+/// use orion::core::Device;
+/// use orion::storage::RetentionPolicy;
+///
+/// let mut policy = RetentionPolicy::new();
+/// policy.set_default(Some(365));
+/// policy.add_rule("lm-sensors", 30).unwrap();
+/// policy.add_rule("temp*@core.lm-sensors", 7).unwrap();
+/// policy.add_rule("temp1@core.lm-sensors", 1).unwrap();
+/// policy.add_rule("workshop-controller.arduino", 90).unwrap();
+/// policy.add_rule("a*@*.arduino", 14).unwrap();
+///
+/// let temp1 = Device::with_slug("temp1@core.lm-sensors").unwrap();
+/// let temp2 = Device::with_slug("temp2@core.lm-sensors").unwrap();
+/// let fan1 = Device::with_slug("fan1@core.lm-sensors").unwrap();
+/// let other = Device::with_slug("a0@uno.arduino").unwrap();
+/// let a1 = Device::with_slug("a1@workshop-controller.arduino").unwrap();
+/// let d1 = Device::with_slug("d1@workshop-controller.arduino").unwrap();
+///
+/// assert_eq!( policy.keep_days(&temp1), Some(1) );
+/// assert_eq!( policy.keep_days(&temp2), Some(7) );
+/// assert_eq!( policy.keep_days(&fan1), Some(30) );
+/// assert_eq!( policy.keep_days(&other), Some(14) );
+/// // A port rule win over a more specific node rule
+/// assert_eq!( policy.keep_days(&a1), Some(14) );
+/// assert_eq!( policy.keep_days(&d1), Some(90) );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    default: Option<u32>,
+    rules: Vec<(DeviceSelector, u32)>,
+}
+
+impl RetentionPolicy {
+
+    /// Construct a policy keeping everything forever
+    pub fn new() -> RetentionPolicy {
+        RetentionPolicy {
+            default: None,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Set the number of days kept for devices without rule, `None` keep
+    /// them forever
+    pub fn set_default(&mut self, days: Option<u32>) {
+        self.default = days;
+    }
+
+    /// Return `true` if no data ever expire
+    pub fn keeps_forever(&self) -> bool {
+        self.default.is_none() && self.rules.is_empty()
+    }
+
+    /// Keep `days` days of data for the devices matching `pattern`
+    ///
+    /// # Failures
+    ///
+    /// `ParseRetentionError::InvalidPattern` if `pattern` is not a
+    /// `DeviceSelector`, a driver or a `node.driver` pair.
+    pub fn add_rule(&mut self, pattern: &str, days: u32) -> Result<(), ParseRetentionError> {
+        let selector = if pattern.contains('@') {
+            pattern.to_string()
+        } else {
+            match pattern.find('.') {
+                Some(_) => format!("*@{}", pattern),
+                None    => format!("*@*.{}", pattern),
+            }
+        };
+
+        match DeviceSelector::from_str(&selector) {
+            Ok(x)  => self.rules.push( (x, days) ),
+            Err(_) => return Err(ParseRetentionError::InvalidPattern),
+        }
+
+        Ok( () )
+    }
+
+    /// Return the number of days of data kept for `device`, `None` if they
+    /// are kept forever
+    pub fn keep_days(&self, device: &Device) -> Option<u32> {
+        let mut best: Option<&(DeviceSelector, u32)> = None;
+
+        for rule in self.rules.iter() {
+            if !rule.0.matches(device) {
+                continue;
+            }
+
+            // Ties are broken by the selector to not depend on the order
+            // of the rules
+            let key = |r: &(DeviceSelector, u32)| {
+                (level(&r.0), r.0.specificity(), r.0.to_string())
+            };
+
+            best = match best {
+                Some(b) if key(b) >= key(rule) => Some(b),
+                _ => Some(rule),
+            };
+        }
+
+        match best {
+            Some(&(_, days)) => Some(days),
+            None             => self.default,
+        }
+    }
+
+    /// Return `true` if the data of `day` are expired on `today`
+    ///
+    /// Keeping `n` days keep `today` and the `n - 1` days before.
+    pub fn is_expired(&self, day: &DayDir, today: &Date<UTC>) -> bool {
+        match self.keep_days(&day.device) {
+            Some(days) => {
+                let first = *today - Duration::days(days as i64 - 1);
+                (day.year, day.month, day.day) < (first.year(), first.month(), first.day())
+            },
+            None => false,
+        }
+    }
+}
+
+/// Return 3 for a rule on ports, 2 on nodes and 1 on drivers
+fn level(selector: &DeviceSelector) -> u8 {
+    if selector.get_port() != "*" {
+        3
+    } else if selector.get_node() != "*" {
+        2
+    } else {
+        1
+    }
+}
+
+/// Day directories removed, or to remove, by `prune`
+#[derive(Debug)]
+pub struct PruneReport {
+    pub days: Vec<DayDir>,
+    /// Size of the removed files in bytes
+    pub bytes: u64,
+    /// Number of series removed from the tag index, see `prune_index`
+    pub series: usize,
+}
+
+/// Remove every day directory under `root` expired on `today`
+///
+/// Month and year directories left empty are removed too, then the series
+/// of the tag index without points left. With `dry_run`, nothing is
+/// removed but the report is the same, without the series.
+///
+/// Only storages keeping one directory per day are pruned, the
+/// `SqliteStorage` database is left untouched.
+pub fn prune(root: &Path, policy: &RetentionPolicy, today: &Date<UTC>, dry_run: bool)
+    -> io::Result<PruneReport> {
+
+    let mut report = PruneReport {
+        days: Vec::new(),
+        bytes: 0,
+        series: 0,
+    };
+
+    for day in try!( day_dirs(root) ) {
+        if !policy.is_expired(&day, today) {
+            continue;
+        }
+
+        report.bytes += try!( dir_size(&day.path) );
+
+        if !dry_run {
+            info!("Remove expired directory {:?}", day.path);
+            try!( fs::remove_dir_all(&day.path) );
+
+            let month = day.path.parent().unwrap();
+            try!( remove_if_empty(month) );
+            try!( remove_if_empty(month.parent().unwrap()) );
+        }
+
+        report.days.push(day);
+    }
+
+    if !dry_run && !report.days.is_empty() {
+        report.series = try!( prune_index(root) );
+    }
+
+    Ok(report)
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in try!( fs::read_dir(path) ) {
+        let entry = try!(entry);
+        let metadata = try!( entry.metadata() );
+
+        size += if metadata.is_dir() {
+            try!( dir_size(&entry.path()) )
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+fn remove_if_empty(path: &Path) -> io::Result<()> {
+    if try!( fs::read_dir(path) ).next().is_none() {
+        try!( fs::remove_dir(path) );
+    }
+
+    Ok( () )
+}
+
+#[derive(Debug)]
+pub enum ParseRetentionError {
+    InvalidPattern,
+}
+
+impl fmt::Display for ParseRetentionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseRetentionError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseRetentionError::InvalidPattern => "Invalid retention pattern",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+
+#[test]
+fn test_prune() {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use chrono::TimeZone;
+
+    let root = env::temp_dir().join("orion_test_prune");
+    let _ = fs::remove_dir_all(&root);
+
+    for dir in [ "driver/node/port/2015/5/31", "driver/node/port/2015/6/1",
+                 "driver/node/port/2015/6/2", "driver/other/port/2015/5/1" ].iter() {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        File::create(root.join(dir).join("data.txt")).unwrap().write_all(b"0123").unwrap();
+    }
+
+    let mut policy = RetentionPolicy::new();
+    policy.add_rule("node.driver", 2).unwrap();
+    assert!( policy.add_rule("node.driver.x", 2).is_err() );
+
+    let today = UTC.ymd(2015, 6, 2);
+
+    let report = prune(&root, &policy, &today, true).unwrap();
+    assert_eq!( (report.days.len(), report.bytes), (1, 4) );
+    assert!( root.join("driver/node/port/2015/5/31").is_dir() );
+
+    let report = prune(&root, &policy, &today, false).unwrap();
+    assert_eq!( (report.days.len(), report.bytes), (1, 4) );
+    assert!( !root.join("driver/node/port/2015/5").exists() );
+    assert!( root.join("driver/node/port/2015/6/1").is_dir() );
+    assert!( root.join("driver/other/port/2015/5/1").is_dir() );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,571 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use chrono::{UTC, DateTime, TimeZone, Duration};
+
+use core::{Device, Measurement, MeasurementPoint, Unit};
+use super::{Storage, day_path, device_day_dirs};
+
+/// Directory of the storage root holding the rollups
+///
+/// Its name is not a valid driver name, so it is never seen as a device
+/// directory nor pruned by the retention rules.
+pub const ROLLUP_DIRNAME: &'static str = ".rollups";
+
+/// Width of the buckets of a rollup series
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+
+    /// Every resolution, from the finest to the coarsest
+    pub fn all() -> Vec<Resolution> {
+        vec![Resolution::Minute, Resolution::Hour, Resolution::Day]
+    }
+
+    /// Width of a bucket in seconds
+    pub fn seconds(&self) -> i64 {
+        match *self {
+            Resolution::Minute => 60,
+            Resolution::Hour   => 3600,
+            Resolution::Day    => 86400,
+        }
+    }
+
+    /// Name of the file holding a day of rollups
+    pub fn filename(&self) -> String {
+        format!("{}.txt", self)
+    }
+
+    /// Return the coarsest resolution of `available` fitting the buckets
+    /// of `step` and starting at `from`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # extern crate orion;
+    /// # extern crate chrono;
+    /// use orion::storage::Resolution;
+    /// use chrono::{UTC, TimeZone, Duration};
+    ///
+    /// # fn main() {
+    /// let from = UTC.ymd(2015, 6, 1).and_hms(0, 0, 0);
+    ///
+    /// let res = Resolution::pick(&Resolution::all(), &from, &Duration::hours(2));
+    /// assert_eq!( res, Some(Resolution::Hour) );
+    ///
+    /// let res = Resolution::pick(&Resolution::all(), &from, &Duration::seconds(30));
+    /// assert_eq!( res, None );
+    /// # }
+    /// ```
+    pub fn pick(available: &[Resolution], from: &DateTime<UTC>, step: &Duration)
+        -> Option<Resolution> {
+
+        let fits = |res: &Resolution| {
+            let width = res.seconds();
+
+            step.num_seconds() > 0 && step.num_seconds() % width == 0
+            && from.timestamp() % width == 0 && from.timestamp_subsec_nanos() == 0
+        };
+
+        available.iter().filter(|res| fits(res)).max().map(|res| *res)
+    }
+}
+
+impl FromStr for Resolution {
+
+    type Err = ParseResolutionError;
+
+    /// Parse a resolution name: `1m`, `1h` or `1d`
+    fn from_str(s: &str) -> Result<Resolution, ParseResolutionError> {
+        match s {
+            "1m" => Ok(Resolution::Minute),
+            "1h" => Ok(Resolution::Hour),
+            "1d" => Ok(Resolution::Day),
+            _    => Err(ParseResolutionError::Invalid),
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resolution::Minute => write!(f, "{}", "1m"),
+            Resolution::Hour   => write!(f, "{}", "1h"),
+            Resolution::Day    => write!(f, "{}", "1d"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseResolutionError {
+    Invalid,
+}
+
+impl fmt::Display for ParseResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseResolutionError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseResolutionError::Invalid => "Unknown resolution",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+/// Parse a step like `30s`, `5m`, `1h` or `1d`
+///
+/// # Example
+///
+/// ```
+/// # extern crate orion;
+/// # extern crate chrono;
+/// use orion::storage::parse_step;
+/// use chrono::Duration;
+///
+/// # fn main() {
+/// assert_eq!( parse_step("5m"), Some(Duration::minutes(5)) );
+/// assert_eq!( parse_step("5"), None );
+/// # }
+/// ```
+pub fn parse_step(s: &str) -> Option<Duration> {
+    if s.len() < 2 {
+        return None;
+    }
+
+    let (count, unit) = s.split_at(s.len() - 1);
+
+    let count = match i64::from_str(count) {
+        Ok(x) if x > 0 => x,
+        _              => return None,
+    };
+
+    match unit {
+        "s" => Some(Duration::seconds(count)),
+        "m" => Some(Duration::minutes(count)),
+        "h" => Some(Duration::hours(count)),
+        "d" => Some(Duration::days(count)),
+        _   => None,
+    }
+}
+
+/// Summary of the values of a measurement position in a bucket
+///
+/// `channel` is the position of the measurement in the
+/// `MeasurementsList` of the points. Every value use the unit of the
+/// summarized measurements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub start: DateTime<UTC>,
+    pub channel: usize,
+    pub count: u32,
+    pub min: Measurement,
+    pub max: Measurement,
+    /// Sum of the values, kept in double precision for `mean`
+    pub sum: f64,
+    pub first: Measurement,
+    pub last: Measurement,
+}
+
+impl Rollup {
+
+    /// Return the unit of the summarized measurements
+    pub fn get_unit(&self) -> Unit {
+        self.first.get_unit()
+    }
+
+    /// Return the mean of the values
+    pub fn mean(&self) -> Measurement {
+        Measurement::new( (self.sum / self.count as f64) as f32, self.get_unit() )
+    }
+
+    /// Add the values summarized by `other`, which must follow this
+    /// rollup and have the same unit
+    pub fn merge(&mut self, other: &Rollup) {
+        if other.min.get_value() < self.min.get_value() {
+            self.min = other.min.clone();
+        }
+
+        if other.max.get_value() > self.max.get_value() {
+            self.max = other.max.clone();
+        }
+
+        self.sum += other.sum;
+        self.last = other.last.clone();
+        self.count += other.count;
+    }
+
+    /// Format this rollup as a line of a rollup file
+    ///
+    /// A line has this form:
+    /// `RFC3339-start channel count min max mean first last sum`
+    ///
+    /// The sum is written in double precision, so merging rollups read back
+    /// from a file give the same mean as merging the original ones.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {} {} {} {} {} {} {}\n", self.start.to_rfc3339(), self.channel,
+                self.count, self.min, self.max, self.mean(), self.first, self.last, self.sum)
+    }
+
+    /// Parse a line written by `to_line`
+    ///
+    /// Lines without the sum, written by previous versions, get it from
+    /// the mean and the count.
+    pub fn from_line(line: &str) -> Option<Rollup> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if fields.len() != 8 && fields.len() != 9 {
+            return None;
+        }
+
+        let measurement = |s: &str| Measurement::from_str(s).ok();
+        let count = match u32::from_str(fields[2]) { Ok(x) => x, Err(_) => return None };
+        let mean = match measurement(fields[5]) { Some(x) => x, None => return None };
+        let sum = match fields.get(8) {
+            Some(x) => match f64::from_str(x) { Ok(x) => x, Err(_) => return None },
+            None    => mean.get_value() as f64 * count as f64,
+        };
+
+        Some( Rollup {
+            start: match DateTime::parse_from_rfc3339(fields[0]) {
+                Ok(x)  => x.with_timezone(&UTC),
+                Err(_) => return None,
+            },
+            channel: match usize::from_str(fields[1]) { Ok(x) => x, Err(_) => return None },
+            count: count,
+            min: match measurement(fields[3]) { Some(x) => x, None => return None },
+            max: match measurement(fields[4]) { Some(x) => x, None => return None },
+            sum: sum,
+            first: match measurement(fields[6]) { Some(x) => x, None => return None },
+            last: match measurement(fields[7]) { Some(x) => x, None => return None },
+        })
+    }
+}
+
+/// Return the start of the bucket of `width` seconds holding `date`
+fn bucket_start(date: &DateTime<UTC>, width: i64) -> DateTime<UTC> {
+    let secs = date.timestamp();
+    let start = secs - ((secs % width) + width) % width;
+
+    UTC.timestamp(start, 0)
+}
+
+/// Summarize `points` in buckets of `width`, aligned on the epoch
+///
+/// Rollups are sorted by bucket, then by channel. A channel whose unit
+/// change in a bucket get one rollup per unit.
+pub fn compute_rollups(points: &[MeasurementPoint], width: &Duration) -> Vec<Rollup> {
+    merge_rollups(points.iter().flat_map(|mp| {
+        let start = mp.get_date();
+
+        mp.get_data().iter().enumerate().map(move |(channel, m)| Rollup {
+            start: start,
+            channel: channel,
+            count: 1,
+            min: m.clone(),
+            max: m.clone(),
+            sum: m.get_value() as f64,
+            first: m.clone(),
+            last: m.clone(),
+        })
+    }), width)
+}
+
+/// Merge `rollups`, sorted by time, in buckets of `width`
+///
+/// `width` must be a multiple of the width of the merged rollups.
+pub fn merge_rollups<I>(rollups: I, width: &Duration) -> Vec<Rollup>
+    where I: Iterator<Item=Rollup> {
+
+    let width = width.num_seconds();
+    let mut buckets: BTreeMap<(i64, usize, u8), Rollup> = BTreeMap::new();
+
+    for mut rollup in rollups {
+        rollup.start = bucket_start(&rollup.start, width);
+        let key = (rollup.start.timestamp(), rollup.channel, rollup.get_unit().code());
+
+        if let Some(bucket) = buckets.get_mut(&key) {
+            bucket.merge(&rollup);
+            continue;
+        }
+
+        buckets.insert(key, rollup);
+    }
+
+    buckets.into_iter().map(|(_, rollup)| rollup).collect()
+}
+
+/// Return the directory holding the rollups of the storage at `root`
+pub fn rollup_root(root: &Path) -> PathBuf {
+    root.join(ROLLUP_DIRNAME)
+}
+
+/// Return the end of the last day of `device` rolled up under `root`
+pub fn rolled_until(root: &Path, device: &Device) -> io::Result<Option<DateTime<UTC>>> {
+    let days = try!( device_day_dirs(&rollup_root(root), device) );
+
+    Ok( days.last().map(|day| {
+        UTC.ymd(day.year, day.month, day.day).and_hms(0, 0, 0) + Duration::days(1)
+    }))
+}
+
+/// Write the rollups of `device` for the day starting at `day`
+fn write_day(root: &Path, device: &Device, day: &DateTime<UTC>,
+             points: &[MeasurementPoint], resolutions: &[Resolution]) -> io::Result<()> {
+
+    let dir = day_path(&rollup_root(root), device, day);
+    try!( fs::create_dir_all(&dir) );
+
+    for res in resolutions.iter() {
+        let mut content = String::new();
+
+        for rollup in compute_rollups(points, &Duration::seconds(res.seconds())) {
+            content.push_str(&rollup.to_line());
+        }
+
+        // Replace a previous file as a whole
+        let tmp = dir.join("rollup.tmp");
+        {
+            let mut file = try!( File::create(&tmp) );
+            try!( file.write_all(content.as_bytes()) );
+        }
+        try!( fs::rename(&tmp, dir.join(res.filename())) );
+    }
+
+    Ok( () )
+}
+
+/// Roll up the days of `device` ending before `until`
+///
+/// The last day already rolled up is computed again in case more points
+/// were added after, older days are left untouched. The first rollup
+/// start at the first day directory of `device` or at its first point.
+///
+/// Return the number of days holding points.
+pub fn rollup_device(storage: &Storage, root: &Path, device: &Device,
+                     resolutions: &[Resolution], until: &DateTime<UTC>)
+    -> io::Result<usize> {
+
+    let mut day = match try!( rolled_until(root, device) ) {
+        Some(x) => x - Duration::days(1),
+        None    => match try!( device_day_dirs(root, device) ).first() {
+            Some(d) => UTC.ymd(d.year, d.month, d.day).and_hms(0, 0, 0),
+            None    => match try!( storage.read_range(device, &UTC.timestamp(0, 0), until) )
+                                 .first() {
+                Some(mp) => bucket_start(&mp.get_date(), 86400),
+                None     => return Ok(0),
+            },
+        },
+    };
+
+    let mut count = 0;
+
+    while day + Duration::days(1) <= *until {
+        let next = day + Duration::days(1);
+        let points = try!( storage.read_range(device, &day, &next) );
+
+        if !points.is_empty() {
+            try!( write_day(root, device, &day, &points, resolutions) );
+            count += 1;
+        }
+
+        day = next;
+    }
+
+    Ok(count)
+}
+
+/// Roll up every closed day of every device of `storage`
+///
+/// Return the number of days holding points.
+pub fn rollup_closed_days(storage: &Storage, root: &Path, resolutions: &[Resolution])
+    -> io::Result<usize> {
+
+    let today = UTC::today().and_hms(0, 0, 0);
+    let mut count = 0;
+
+    for device in try!( storage.devices() ) {
+        count += try!( rollup_device(storage, root, &device, resolutions, &today) );
+    }
+
+    Ok(count)
+}
+
+/// Read the stored rollups of `device` at `res` starting in `[from, to)`
+pub fn read_rollups(root: &Path, device: &Device, res: Resolution,
+                    from: &DateTime<UTC>, to: &DateTime<UTC>) -> io::Result<Vec<Rollup>> {
+
+    let mut rollups = Vec::new();
+
+    for day in try!( device_day_dirs(&rollup_root(root), device) ) {
+        let path = day.path.join(res.filename());
+
+        if !day.in_range(from, to) || !path.is_file() {
+            continue;
+        }
+
+        let reader = BufReader::new( try!( File::open(&path) ) );
+
+        for line in reader.lines() {
+            let line = try!(line);
+
+            match Rollup::from_line(&line) {
+                Some(r) => if r.start >= *from && r.start < *to {
+                    rollups.push(r);
+                },
+                None => warn!("Skip line '{}' of {:?}", line, path),
+            }
+        }
+    }
+
+    Ok(rollups)
+}
+
+/// Summarize the points of `device` taken in `[from, to)` in buckets of
+/// `step`
+///
+/// The stored rollups of the coarsest resolution of `resolutions` fitting
+/// the start and the step are used, see `Resolution::pick`. Points not
+/// rolled up yet, or after the last full bucket before `to`, or every
+/// point if no resolution fit, are read from `storage`.
+pub fn query_rollups(storage: &Storage, root: &Path, device: &Device,
+                     resolutions: &[Resolution], from: &DateTime<UTC>,
+                     to: &DateTime<UTC>, step: &Duration) -> io::Result<Vec<Rollup>> {
+
+    let res = Resolution::pick(resolutions, from, step);
+
+    let rolled = match res {
+        Some(res) => match try!( rolled_until(root, device) ) {
+            Some(x) => {
+                let end = bucket_start(to, res.seconds());
+                let end = if x < end { x } else { end };
+
+                if end > *from { end } else { *from }
+            },
+            None => *from,
+        },
+        None => *from,
+    };
+
+    let mut rollups = match res {
+        Some(res) if rolled > *from => try!( read_rollups(root, device, res, from, &rolled) ),
+        _                           => Vec::new(),
+    };
+
+    if rolled < *to {
+        let points = try!( storage.read_range(device, &rolled, to) );
+        rollups.extend(compute_rollups(&points, step).into_iter());
+    }
+
+    Ok( merge_rollups(rollups.into_iter(), step) )
+}
+
+
+#[test]
+fn test_rollups() {
+    use std::env;
+    use core::MeasurementsList;
+    use super::TextStorage;
+
+    let root = env::temp_dir().join("orion_test_rollups");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let mut storage = TextStorage::new(&root);
+    let start = UTC.ymd(2015, 6, 1).and_hms(0, 0, 0);
+
+    // Two days of one point every 10 minutes, with a changing value
+    for i in 0..288 {
+        let data = MeasurementsList::from_str( &format!("{}[V] 1[A]", i % 6) ).unwrap();
+        let mp = MeasurementPoint::new(device.clone(), start + Duration::minutes(i * 10), data);
+        storage.append(&mp).unwrap();
+    }
+
+    let hourly = compute_rollups( &storage.read_range(&device, &start,
+                                                      &(start + Duration::hours(1))).unwrap(),
+                                  &Duration::hours(1) );
+    assert_eq!( hourly.len(), 2 );
+    assert_eq!( hourly[0].to_line(),
+                "2015-06-01T00:00:00+00:00 0 6 0[V] 5[V] 2.5[V] 0[V] 5[V] 15\n" );
+    assert_eq!( Rollup::from_line(&hourly[0].to_line()), Some(hourly[0].clone()) );
+
+    // The sum is not rounded to the precision of the mean
+    let mut precise = hourly[0].clone();
+    precise.sum = 15.000000123456789;
+    assert_eq!( Rollup::from_line(&precise.to_line()), Some(precise) );
+
+    // Line written before the sum was stored
+    let old = Rollup::from_line("2015-06-01T00:00:00+00:00 0 6 0[V] 5[V] 2.5[V] 0[V] 5[V]");
+    assert_eq!( old, Some(hourly[0].clone()) );
+
+    let until = start + Duration::days(1);
+    let resolutions = [Resolution::Hour, Resolution::Day];
+    assert_eq!( rollup_device(&storage, &root, &device, &resolutions, &until).unwrap(), 1 );
+    assert_eq!( rolled_until(&root, &device).unwrap(), Some(until) );
+
+    // The device list ignore rollups
+    assert_eq!( storage.devices().unwrap(), vec![device.clone()] );
+
+    // The first day come from the rollups, the second from the raw points
+    let end = start + Duration::days(2);
+    let rollups = query_rollups(&storage, &root, &device, &resolutions,
+                                &start, &end, &Duration::days(1)).unwrap();
+    assert_eq!( rollups.len(), 4 );
+    assert_eq!( rollups[0].to_line(),
+                "2015-06-01T00:00:00+00:00 0 144 0[V] 5[V] 2.5[V] 0[V] 5[V] 360\n" );
+    assert_eq!( rollups[2].to_line(),
+                "2015-06-02T00:00:00+00:00 0 144 0[V] 5[V] 2.5[V] 0[V] 5[V] 360\n" );
+
+    let from_rollups = query_rollups(&storage, &root, &device, &resolutions,
+                                     &start, &until, &Duration::hours(2)).unwrap();
+    let from_points = query_rollups(&storage, &root, &device, &[],
+                                    &start, &until, &Duration::hours(2)).unwrap();
+    assert_eq!( from_rollups.len(), 24 );
+    assert_eq!( from_rollups, from_points );
+
+    // A range ending in the middle of a bucket
+    let to = until + Duration::minutes(30);
+    let from_rollups = query_rollups(&storage, &root, &device, &resolutions,
+                                     &start, &to, &Duration::hours(1)).unwrap();
+    let from_points = query_rollups(&storage, &root, &device, &[],
+                                    &start, &to, &Duration::hours(1)).unwrap();
+    assert_eq!( from_rollups.len(), 50 );
+    assert_eq!( from_rollups, from_points );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,379 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use chrono::{UTC, DateTime};
+use rusqlite;
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+
+use core::{Device, Measurement, MeasurementsList, MeasurementPoint, Unit};
+use super::Storage;
+use super::gorilla::{to_nanos, from_nanos};
+
+/// Name of the database file in the storage root
+pub const SQLITE_FILENAME: &'static str = "orion.sqlite";
+
+/// Seconds a connection wait for the lock held by another one
+const BUSY_TIMEOUT: u64 = 5;
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS devices (
+        id      INTEGER PRIMARY KEY,
+        slug    TEXT NOT NULL UNIQUE,
+        port    TEXT NOT NULL,
+        node    TEXT NOT NULL,
+        driver  TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS points (
+        device_id  INTEGER NOT NULL REFERENCES devices(id),
+        timestamp  INTEGER NOT NULL,
+        channel    INTEGER NOT NULL,
+        value      REAL NOT NULL,
+        unit       TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS points_by_device_and_time
+        ON points (device_id, timestamp);
+";
+
+/// Store points in a SQLite database
+///
+/// The database is `$(ROOT)/orion.sqlite`, created with its tables on
+/// first use:
+///
+/// - `devices` hold one row per device with its `slug`, `port`, `node`
+///   and `driver`.
+/// - `points` hold one row per measurement: the `device_id`, the
+///   `timestamp` in nanoseconds since epoch, the `channel` index of the
+///   measurement in its list, the `value` and the `unit` symbol.
+///
+/// For example, the mean voltage of a device by day is:
+///
+/// ```sql
+/// SELECT date(timestamp / 1000000000, 'unixepoch'), avg(value)
+///   FROM points JOIN devices ON devices.id = points.device_id
+///  WHERE slug = 'port@node.driver' AND unit = 'V'
+///  GROUP BY 1;
+/// ```
+pub struct SqliteStorage {
+    conn: Connection,
+    device_ids: HashMap<String, i64>,
+}
+
+impl SqliteStorage {
+
+    /// Open or create the database of `root`
+    ///
+    /// The database is in WAL mode, so readers on other connections don't
+    /// block appends, and a locked database is waited for `BUSY_TIMEOUT`.
+    pub fn open(root: &Path) -> io::Result<SqliteStorage> {
+        try!( fs::create_dir_all(root) );
+
+        let conn = try!( Connection::open(root.join(SQLITE_FILENAME))
+                                    .map_err(to_io_error) );
+        try!( conn.busy_timeout(Duration::from_secs(BUSY_TIMEOUT)).map_err(to_io_error) );
+        try!( conn.execute_batch("PRAGMA journal_mode=WAL;").map_err(to_io_error) );
+        try!( conn.execute_batch(SCHEMA).map_err(to_io_error) );
+
+        Ok( SqliteStorage {
+            conn: conn,
+            device_ids: HashMap::new(),
+        })
+    }
+
+    /// Return the id of `device`, inserting it if needed
+    fn device_id(&mut self, device: &Device) -> rusqlite::Result<i64> {
+        if let Some(id) = self.device_ids.get(device.get_slug()) {
+            return Ok(*id);
+        }
+
+        try!( self.conn.execute(
+            "INSERT OR IGNORE INTO devices (slug, port, node, driver)
+             VALUES (?1, ?2, ?3, ?4)",
+            &[&device.get_slug() as &ToSql, &device.get_port(),
+              &device.get_node(), &device.get_driver()]
+        ));
+
+        let id = try!( self.conn.query_row(
+            "SELECT id FROM devices WHERE slug = ?1",
+            &[&device.get_slug() as &ToSql],
+            |row| row.get(0)
+        ));
+
+        self.device_ids.insert(device.get_slug().to_string(), id);
+        Ok(id)
+    }
+
+    fn insert(&mut self, mp: &MeasurementPoint) -> rusqlite::Result<()> {
+        let device_id = try!( self.device_id(mp.get_device()) );
+        let timestamp = to_nanos(&mp.get_date());
+
+        let tx = try!( self.conn.transaction() );
+
+        for (channel, meas) in mp.get_data().iter().enumerate() {
+            try!( tx.execute(
+                "INSERT INTO points (device_id, timestamp, channel, value, unit)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                &[&device_id as &ToSql, &timestamp, &(channel as i64),
+                  &(meas.get_value() as f64), &meas.get_unit().to_string()]
+            ));
+        }
+
+        tx.commit()
+    }
+
+    fn select(&self, device: &Device, from: i64, to: i64)
+        -> rusqlite::Result<Vec<MeasurementPoint>> {
+
+        let mut stmt = try!( self.conn.prepare(
+            "SELECT timestamp, channel, value, unit
+               FROM points JOIN devices ON devices.id = points.device_id
+              WHERE slug = ?1 AND timestamp >= ?2 AND timestamp < ?3
+              ORDER BY timestamp, points.rowid"
+        ));
+
+        let rows = try!( stmt.query_map(
+            &[&device.get_slug() as &ToSql, &from, &to],
+            |row| {
+                let timestamp: i64 = try!( row.get(0) );
+                let channel: i64 = try!( row.get(1) );
+                let value: f64 = try!( row.get(2) );
+                let unit: String = try!( row.get(3) );
+
+                Ok( (timestamp, channel, value, unit) )
+            }
+        ));
+
+        let mut points: Vec<MeasurementPoint> = Vec::new();
+        let mut current: Option<(i64, i64, MeasurementsList)> = None;
+
+        for row in rows {
+            let (timestamp, channel, value, unit) = try!(row);
+
+            let unit = match Unit::from_str(&unit) {
+                Ok(x)  => x,
+                Err(_) => {
+                    warn!("Skip value with invalid unit '{}' of {}", unit,
+                          device.get_slug());
+                    continue;
+                },
+            };
+
+            // Each point start with channel 0, even with the same timestamp
+            let same_point = match current {
+                Some((t, c, _)) => t == timestamp && c < channel,
+                None            => false,
+            };
+
+            if !same_point {
+                if let Some((t, _, data)) = current.take() {
+                    points.push( MeasurementPoint::new(device.clone(), from_nanos(t), data) );
+                }
+                current = Some( (timestamp, channel, MeasurementsList::new()) );
+            }
+
+            if let Some((_, ref mut c, ref mut data)) = current {
+                *c = channel;
+                data.push( Measurement::new(value as f32, unit) );
+            }
+        }
+
+        if let Some((t, _, data)) = current {
+            points.push( MeasurementPoint::new(device.clone(), from_nanos(t), data) );
+        }
+
+        Ok(points)
+    }
+
+    fn select_devices(&self) -> rusqlite::Result<Vec<Device>> {
+        let mut stmt = try!( self.conn.prepare(
+            "SELECT slug FROM devices ORDER BY driver, node, port"
+        ));
+
+        let rows = try!( stmt.query_map(&[] as &[&ToSql], |row| row.get(0)) );
+
+        let mut devices = Vec::new();
+        for slug in rows {
+            let slug: String = try!(slug);
+
+            match Device::with_slug(&slug) {
+                Ok(x)  => devices.push(x),
+                Err(_) => warn!("Skip invalid device '{}'", slug),
+            }
+        }
+
+        Ok(devices)
+    }
+
+    /// Return the slugs of the devices table which are not canonical
+    ///
+    /// Their points are not read anymore, see `LegacyDeviceDir` for the
+    /// directory storages and `rename_device` to move them.
+    pub fn legacy_devices(&self) -> io::Result<Vec<String>> {
+        let mut stmt = try!( self.conn.prepare("SELECT slug FROM devices ORDER BY slug")
+                                      .map_err(to_io_error) );
+        let rows = try!( stmt.query_map(&[] as &[&ToSql], |row| row.get(0))
+                             .map_err(to_io_error) );
+
+        let mut legacy = Vec::new();
+        for slug in rows {
+            let slug: String = try!( slug.map_err(to_io_error) );
+
+            match Device::with_slug(&slug) {
+                Ok(ref x) if x.get_slug() == slug => {},
+                _ => legacy.push(slug),
+            }
+        }
+
+        Ok(legacy)
+    }
+
+    /// Move the points stored under `slug` to `device`
+    pub fn rename_device(&mut self, slug: &str, device: &Device) -> io::Result<()> {
+        let target = try!( self.device_id(device).map_err(to_io_error) );
+
+        let tx = try!( self.conn.transaction().map_err(to_io_error) );
+
+        try!( tx.execute(
+            "UPDATE points SET device_id = ?1
+              WHERE device_id = (SELECT id FROM devices WHERE slug = ?2)",
+            &[&target as &ToSql, &slug]
+        ).map_err(to_io_error) );
+
+        try!( tx.execute("DELETE FROM devices WHERE slug = ?1", &[&slug as &ToSql])
+                .map_err(to_io_error) );
+
+        tx.commit().map_err(to_io_error)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        self.insert(mp).map_err(to_io_error)
+    }
+
+    /// Every point is already durable once its transaction is committed
+    fn sync(&mut self) -> io::Result<()> {
+        Ok( () )
+    }
+
+    fn read_range(&self, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+        -> io::Result<Vec<MeasurementPoint>> {
+
+        self.select(device, to_nanos(from), to_nanos(to)).map_err(to_io_error)
+    }
+
+    fn devices(&self) -> io::Result<Vec<Device>> {
+        self.select_devices().map_err(to_io_error)
+    }
+}
+
+fn to_io_error(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+
+#[test]
+fn test_sqlite_storage() {
+    use std::env;
+    use chrono::TimeZone;
+
+    let root = env::temp_dir().join("orion_test_sqlite_storage");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let lines = [ "2015-06-01T12:00:01+00:00 3.5[V] -1[A]",
+                  "2015-06-01T12:00:00+00:00 3[V]",
+                  "2015-06-01T12:00:00+00:00 4[V]",
+                  "2015-06-02T12:00:00+00:00 5[K]" ];
+
+    let mut storage = SqliteStorage::open(&root).unwrap();
+    for line in lines.iter() {
+        storage.append( &MeasurementPoint::from_line(device.clone(), line).unwrap() ).unwrap();
+    }
+    storage.append( &MeasurementPoint::from_line(
+        Device::with_slug("a@b.c").unwrap(), lines[0]
+    ).unwrap() ).unwrap();
+
+    // Reopening keep the data
+    let storage = SqliteStorage::open(&root).unwrap();
+
+    let from = DateTime::parse_from_rfc3339("2015-06-01T00:00:00Z").unwrap().with_timezone(&UTC);
+    let to = DateTime::parse_from_rfc3339("2015-06-02T00:00:00Z").unwrap().with_timezone(&UTC);
+    let lines: Vec<_> = storage.read_range(&device, &from, &to).unwrap()
+                               .iter()
+                               .map(|mp| mp.to_line())
+                               .collect();
+
+    assert_eq!( lines, vec![ "2015-06-01T12:00:00+00:00 3[V]\n",
+                             "2015-06-01T12:00:00+00:00 4[V]\n",
+                             "2015-06-01T12:00:01+00:00 3.5[V] -1[A]\n" ] );
+
+    let devices = storage.devices().unwrap();
+    assert_eq!( devices.len(), 2 );
+    assert_eq!( devices[0].get_slug(), "a@b.c" );
+
+    // Written when slugs were case sensitive
+    let mut storage = storage;
+    storage.conn.execute_batch(
+        "INSERT INTO devices (slug, port, node, driver) VALUES ('Port@Node.driver', 'Port', 'Node', 'driver');
+         INSERT INTO points SELECT last_insert_rowid(), 0, 0, 1.5, 'V';"
+    ).unwrap();
+    assert_eq!( storage.legacy_devices().unwrap(), vec!["Port@Node.driver"] );
+
+    storage.rename_device("Port@Node.driver", &device).unwrap();
+    assert!( storage.legacy_devices().unwrap().is_empty() );
+    assert_eq!( storage.read_range(&device, &UTC.timestamp(0, 0), &to).unwrap().len(), 4 );
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_sqlite_concurrent() {
+    use std::env;
+    use chrono::TimeZone;
+
+    let root = env::temp_dir().join("orion_test_sqlite_concurrent");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let point = |line| MeasurementPoint::from_line(device.clone(), line).unwrap();
+
+    let mut storage = SqliteStorage::open(&root).unwrap();
+    storage.append(&point("2015-06-01T12:00:00+00:00 3[V]")).unwrap();
+
+    // A reader in the middle of a transaction, like the HTTP API
+    let reader = SqliteStorage::open(&root).unwrap();
+    reader.conn.execute_batch("BEGIN; SELECT count(*) FROM points;").unwrap();
+
+    storage.append(&point("2015-06-01T12:00:01+00:00 4[V]")).unwrap();
+    reader.conn.execute_batch("COMMIT;").unwrap();
+
+    let from = UTC.timestamp(0, 0);
+    let to = UTC::now();
+    assert_eq!( reader.read_range(&device, &from, &to).unwrap().len(), 2 );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,413 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use chrono::{UTC, DateTime};
+
+use core::{Device, DeviceSelector, MeasurementPoint, Tags};
+use super::{Storage, day_path, device_day_dirs};
+use super::files::WrittenFiles;
+use super::text;
+
+/// Name of the file holding the tags of the points of a day
+pub const TAGS_FILENAME: &'static str = "tags.txt";
+
+/// Name of the file listing every series in the storage root
+pub const TAG_INDEX_FILENAME: &'static str = "tags.idx";
+
+/// Keep the `Tags` of the points saved by another `Storage`
+///
+/// The tags of the points of a day are written next to their data:
+///
+/// ```text
+/// $(ROOT)/$(DRIVER)/$(NODE)/$(PORT)/$(YEAR)/$(MONTH)/$(DAY)/tags.txt
+/// ```
+///
+/// with a line per tagged point, `RFC3339-timestamp tags measurements`, so
+/// they are given back to the points read by `read_range` with the same date
+/// and measurements. Points of the same device taken at the same time get
+/// their tags in the order they were appended. Untagged points don't take
+/// any space.
+///
+/// Each tags used by a device, a series, is recorded once in `tags.idx` at
+/// the root as a `slug tags` line. `series` use it to find the devices
+/// having some tags without reading their data. Series not used anymore by
+/// the points left by `prune` are removed by `prune_index`.
+///
+/// The index is loaded again when it is changed by another storage, like
+/// the one of the server for the storage of the HTTP API.
+pub struct TaggedStorage {
+    inner: Box<Storage>,
+    root: PathBuf,
+    series: RefCell<BTreeSet<(Device, Tags)>>,
+    /// Modification time of the index when `series` was loaded
+    index_modified: Cell<Option<SystemTime>>,
+    files: WrittenFiles,
+}
+
+impl TaggedStorage {
+
+    /// Keep the tags of the points saved to `inner`, whose root is `root`
+    pub fn new(inner: Box<Storage>, root: &Path) -> io::Result<TaggedStorage> {
+        let storage = TaggedStorage {
+            inner: inner,
+            root: root.to_path_buf(),
+            series: RefCell::new(BTreeSet::new()),
+            index_modified: Cell::new(None),
+            files: WrittenFiles::new(),
+        };
+
+        try!( storage.reload_series() );
+        Ok(storage)
+    }
+
+    /// Load the index again if it changed since it was loaded
+    fn reload_series(&self) -> io::Result<()> {
+        let path = self.root.join(TAG_INDEX_FILENAME);
+
+        let modified = match fs::metadata(&path) {
+            Ok(x)  => Some( try!( x.modified() ) ),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        if modified != self.index_modified.get() || modified.is_none() {
+            *self.series.borrow_mut() = try!( read_index(&path) ).into_iter().collect();
+            self.index_modified.set(modified);
+        }
+
+        Ok( () )
+    }
+
+    /// Append `line` to the file at `path`, after a torn line left by a crash
+    fn append_line(&mut self, path: &Path, line: &str) -> io::Result<()> {
+        let mut file = try!( OpenOptions::new()
+                                         .create(true)
+                                         .read(true)
+                                         .append(true)
+                                         .open(path) );
+
+        if self.files.first_write(path) {
+            try!( text::repair_tail(&mut file, path) );
+        }
+
+        try!( file.write_all(line.as_bytes()) );
+
+        self.files.written(path);
+        Ok( () )
+    }
+}
+
+impl Storage for TaggedStorage {
+
+    /// Save the tags of `mp`, then `mp` to the inner storage
+    ///
+    /// Recovering from the write-ahead log after a crash between the two
+    /// writes only write the tags twice.
+    fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        if !mp.get_tags().is_empty() {
+            let device = mp.get_device();
+            let tags = mp.get_tags();
+
+            let day = day_path(&self.root, device, &mp.get_date());
+            try!( fs::create_dir_all(&day) );
+
+            let line = format!("{} {} {}\n", mp.get_date().to_rfc3339(), tags, mp.get_data());
+            try!( self.append_line(&day.join(TAGS_FILENAME), &line) );
+
+            try!( self.reload_series() );
+
+            if !self.series.borrow().contains(&(device.clone(), tags.clone())) {
+                let line = format!("{} {}\n", device.get_slug(), tags);
+                let index = self.root.join(TAG_INDEX_FILENAME);
+
+                try!( self.append_line(&index, &line) );
+                try!( self.reload_series() );
+                self.series.borrow_mut().insert( (device.clone(), tags.clone()) );
+            }
+        }
+
+        self.inner.append(mp)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        try!( self.inner.sync() );
+        self.files.sync()
+    }
+
+    fn read_range(&self, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+        -> io::Result<Vec<MeasurementPoint>> {
+
+        let mut points = try!( self.inner.read_range(device, from, to) );
+        let mut tags = try!( read_tags(&self.root, device, from, to) );
+
+        if !tags.is_empty() {
+            for mp in points.iter_mut() {
+                let records = match tags.get_mut(&mp.get_date()) {
+                    Some(x) => x,
+                    None    => continue,
+                };
+
+                // Each record tag one point
+                let data = mp.get_data().to_string();
+                let found = records.iter().position(|r| match r.data {
+                    Some(ref d) => *d == data,
+                    None        => true,
+                });
+
+                if let Some(pos) = found {
+                    mp.set_tags( records.remove(pos).tags );
+                }
+            }
+        }
+
+        Ok(points)
+    }
+
+    fn devices(&self) -> io::Result<Vec<Device>> {
+        self.inner.devices()
+    }
+
+    fn select(&self, selector: &DeviceSelector) -> io::Result<Vec<Device>> {
+        self.inner.select(selector)
+    }
+
+    fn series(&self) -> io::Result<Vec<(Device, Tags)>> {
+        try!( self.reload_series() );
+        Ok( self.series.borrow().iter().cloned().collect() )
+    }
+}
+
+/// Tags of a point, read from a `tags.txt` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagRecord {
+    /// Measurements of the point, `None` for lines written before they
+    /// were recorded, which match any point of their date
+    pub data: Option<String>,
+    pub tags: Tags,
+}
+
+/// Read the tags of the points of `device` taken in `[from, to)`, by date
+///
+/// The records of a date are in the order they were appended. Invalid
+/// lines are logged and skipped.
+pub fn read_tags(root: &Path, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+    -> io::Result<BTreeMap<DateTime<UTC>, Vec<TagRecord>>> {
+
+    let mut tags = BTreeMap::new();
+
+    for day in try!( device_day_dirs(root, device) ) {
+        let path = day.path.join(TAGS_FILENAME);
+
+        if !day.in_range(from, to) || !path.is_file() {
+            continue;
+        }
+
+        for (date, record) in try!( read_tags_file(&path) ) {
+            if date >= *from && date < *to {
+                tags.entry(date).or_insert(Vec::new()).push(record);
+            }
+        }
+    }
+
+    Ok(tags)
+}
+
+fn read_tags_file(path: &Path) -> io::Result<Vec<(DateTime<UTC>, TagRecord)>> {
+    let mut records = Vec::new();
+
+    for line in BufReader::new( try!( File::open(path) ) ).lines() {
+        let line = try!(line);
+        let mut fields = line.splitn(3, ' ');
+
+        let date = fields.next().and_then(|x| DateTime::parse_from_rfc3339(x).ok());
+        let tags = fields.next().and_then(|x| Tags::from_str(x).ok());
+        let data = fields.next().map(|x| x.to_string());
+
+        match (date, tags) {
+            (Some(date), Some(tags)) => records.push( (date.with_timezone(&UTC), TagRecord {
+                data: data,
+                tags: tags,
+            })),
+            _ => warn!("Skip line '{}' of {:?}", line, path),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Remove from the tag index of `root` the series whose tags are not
+/// used anymore by the points of their device
+///
+/// Return the number of removed series.
+pub fn prune_index(root: &Path) -> io::Result<usize> {
+    let path = root.join(TAG_INDEX_FILENAME);
+    let series = try!( read_index(&path) );
+    let mut used: BTreeMap<Device, BTreeSet<Tags>> = BTreeMap::new();
+
+    for &(ref device, _) in series.iter() {
+        if used.contains_key(device) {
+            continue;
+        }
+
+        let mut tags = BTreeSet::new();
+        for day in try!( device_day_dirs(root, device) ) {
+            let file = day.path.join(TAGS_FILENAME);
+
+            if file.is_file() {
+                for (_, record) in try!( read_tags_file(&file) ) {
+                    tags.insert(record.tags);
+                }
+            }
+        }
+
+        used.insert(device.clone(), tags);
+    }
+
+    let kept: Vec<_> = series.iter()
+                             .filter(|&&(ref device, ref tags)| used[device].contains(tags))
+                             .collect();
+    let removed = series.len() - kept.len();
+
+    if removed > 0 {
+        let tmp = root.join("tags.idx.tmp");
+        {
+            let mut file = try!( File::create(&tmp) );
+            for &&(ref device, ref tags) in kept.iter() {
+                try!( write!(file, "{} {}\n", device.get_slug(), tags) );
+            }
+            try!( file.sync_all() );
+        }
+        try!( fs::rename(&tmp, &path) );
+    }
+
+    Ok(removed)
+}
+
+/// Read the series of a tag index, a missing index is empty
+///
+/// Invalid lines are logged and skipped.
+pub fn read_index(path: &Path) -> io::Result<Vec<(Device, Tags)>> {
+    let file = match File::open(path) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut series = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+
+        let parsed = line.find(' ').and_then(|pos| {
+            match (Device::with_slug(&line[..pos]), Tags::from_str(&line[pos + 1..])) {
+                (Ok(device), Ok(tags)) => Some( (device, tags) ),
+                _                      => None,
+            }
+        });
+
+        match parsed {
+            Some(x) => series.push(x),
+            None    => warn!("Skip line '{}' of {:?}", line, path),
+        }
+    }
+
+    Ok(series)
+}
+
+
+#[test]
+fn test_tagged_storage() {
+    use std::env;
+    use chrono::TimeZone;
+    use super::TextStorage;
+
+    let root = env::temp_dir().join("orion_test_tagged_storage");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let lines = [ "2015-06-01T12:00:00+00:00 3[V]",
+                  "2015-06-01T12:00:01+00:00 4[V]",
+                  "2015-06-01T12:00:02+00:00 5[V]",
+                  "2015-06-01T12:00:02+00:00 5[V]",
+                  "2015-06-01T12:00:02+00:00 6[V]",
+                  "2015-06-01T12:00:03+00:00 7[V]",
+                  "2015-06-02T12:00:00+00:00 8[V]" ];
+    let tags = [ "site=lab", "", "site=lab", "site=roof", "", "", "site=old" ];
+
+    let mut points = Vec::new();
+    let mut storage = TaggedStorage::new(Box::new(TextStorage::new(&root)), &root).unwrap();
+
+    for (line, t) in lines.iter().zip(tags.iter()) {
+        let mut mp = MeasurementPoint::from_line(device.clone(), line).unwrap();
+        mp.set_tags( Tags::from_str(t).unwrap() );
+
+        storage.append(&mp).unwrap();
+        points.push(mp);
+    }
+    storage.sync().unwrap();
+
+    let from = UTC.ymd(2015, 6, 1).and_hms(0, 0, 0);
+    let to = UTC.ymd(2015, 6, 3).and_hms(0, 0, 0);
+    assert_eq!( storage.read_range(&device, &from, &to).unwrap(), points );
+
+    // Data files are unchanged, tags are only written for tagged points
+    let day = root.join("driver/node/port/2015/6/1");
+    assert_eq!( read_tags(&root, &device, &from, &to).unwrap().len(), 3 );
+    assert_eq!( super::TextStorage::read_file(&day.join(super::TEXT_FILENAME), &device)
+                                   .unwrap().len(), 6 );
+
+    // Lines written without the measurements still tag the first point
+    let mut file = OpenOptions::new().append(true).open(day.join(TAGS_FILENAME)).unwrap();
+    file.write_all(b"2015-06-01T12:00:03+00:00 site=cellar\n").unwrap();
+    points[5].set_tags( Tags::from_str("site=cellar").unwrap() );
+    assert_eq!( storage.read_range(&device, &from, &to).unwrap(), points );
+
+    // The series are found again by a new storage
+    let reader = TaggedStorage::new(Box::new(TextStorage::new(&root)), &root).unwrap();
+    let series: Vec<_> = ["site=lab", "site=old", "site=roof"].iter()
+        .map(|t| (device.clone(), Tags::from_str(t).unwrap()))
+        .collect();
+    assert_eq!( reader.series().unwrap(), series );
+    assert_eq!( read_index(&root.join(TAG_INDEX_FILENAME)).unwrap().len(), 3 );
+
+    // And the ones added by another storage since
+    let mut mp = points[0].clone();
+    mp.set_tags( Tags::from_str("site=new").unwrap() );
+    storage.append(&mp).unwrap();
+    assert_eq!( reader.series().unwrap().len(), 4 );
+
+    let storage = reader;
+
+    // Only the series of the removed day is pruned
+    fs::remove_dir_all(root.join("driver/node/port/2015/6/2")).unwrap();
+    assert_eq!( prune_index(&root).unwrap(), 1 );
+    assert_eq!( prune_index(&root).unwrap(), 0 );
+    assert_eq!( read_index(&root.join(TAG_INDEX_FILENAME)).unwrap().len(), 3 );
+    assert_eq!( storage.series().unwrap().len(), 3 );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,211 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use chrono::{UTC, DateTime};
+
+use core::{Device, DeviceSelector, MeasurementPoint};
+use super::{Storage, devices, select_devices, day_path, device_day_dirs, in_range};
+use super::files::WrittenFiles;
+
+/// Name of the file holding the data of a day
+pub const TEXT_FILENAME: &'static str = "data.txt";
+
+/// Store each `MeasurementPoint` as a line of text
+///
+/// Data are written to:
+///
+/// ```text
+/// $(ROOT)/$(DRIVER)/$(NODE)/$(PORT)/$(YEAR)/$(MONTH)/$(DAY)/data.txt
+/// ```
+///
+/// See `MeasurementPoint::to_line` for the line format.
+///
+/// If a crash left a torn line at the end of a file, a new line is started
+/// before appending to it so only the torn line is lost.
+pub struct TextStorage {
+    root: PathBuf,
+    files: WrittenFiles,
+}
+
+impl TextStorage {
+    pub fn new(root: &Path) -> TextStorage {
+        TextStorage {
+            root: root.to_path_buf(),
+            files: WrittenFiles::new(),
+        }
+    }
+
+    /// Read every valid line of a text data file
+    ///
+    /// Invalid lines are logged and skipped.
+    pub fn read_file(path: &Path, device: &Device) -> io::Result<Vec<MeasurementPoint>> {
+        let reader = BufReader::new( try!( File::open(path) ) );
+        let mut points = Vec::new();
+
+        for line in reader.lines() {
+            let line = try!(line);
+
+            match MeasurementPoint::from_line(device.clone(), &line) {
+                Ok(mp)   => points.push(mp),
+                Err(err) => warn!("Skip line '{}' of {:?}: {}", line, path, err),
+            }
+        }
+
+        Ok(points)
+    }
+
+    /// Return the path and a `io::Result<File>` for the given
+    /// `MeasurementPoint`
+    ///
+    /// This function create every missing parent directory and open the file
+    /// whith `create`, `read` and `append` flags
+    ///
+    /// See [`OpenOptions` from `std::fs`](http://doc.rust-lang.org/std/fs/struct.OpenOptions.html)
+    ///
+    /// # Failures
+    ///
+    /// This function can fail if:
+    ///     - Invalid permission is set on folder $(ROOT)
+    ///     - $(ROOT) is read only
+    ///     - Other system error with file handling
+    fn open_file_for(&self, mp: &MeasurementPoint) -> io::Result<(PathBuf, File)> {
+        let path = day_path(&self.root, mp.get_device(), &mp.get_date());
+
+        debug!("Create all parent directory of {:?}", path.as_path());
+        try!(fs::create_dir_all(path.as_path()));
+
+        let file_path = path.join(TEXT_FILENAME);
+
+        debug!("Open or create file {:?}", file_path.as_path());
+
+        let file = try!( OpenOptions::new()
+                                     .create(true)
+                                     .read(true)
+                                     .append(true)
+                                     .open(&file_path) );
+
+        Ok( (file_path, file) )
+    }
+}
+
+/// End a torn last line of `file` with a new line
+pub fn repair_tail(file: &mut File, path: &Path) -> io::Result<()> {
+    if try!( file.metadata() ).len() == 0 {
+        return Ok( () );
+    }
+
+    let mut last = [0u8; 1];
+    try!( file.seek(SeekFrom::End(-1)) );
+    try!( file.read_exact(&mut last) );
+
+    if last[0] != b'\n' {
+        warn!("Isolate torn line at the end of {:?}", path);
+        try!( file.write_all(b"\n") );
+    }
+
+    Ok( () )
+}
+
+impl Storage for TextStorage {
+    fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        let (path, mut file) = try!( self.open_file_for(mp) );
+
+        if self.files.first_write(&path) {
+            try!( repair_tail(&mut file, &path) );
+        }
+
+        let line = mp.to_line();
+
+        debug!("Append line '{}' to file", line);
+        try!( file.write_all(line.as_bytes()) );
+
+        self.files.written(&path);
+        Ok( () )
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.files.sync()
+    }
+
+    fn read_range(&self, device: &Device, from: &DateTime<UTC>, to: &DateTime<UTC>)
+        -> io::Result<Vec<MeasurementPoint>> {
+
+        let mut points = Vec::new();
+
+        for day in try!( device_day_dirs(&self.root, device) ) {
+            let path = day.path.join(TEXT_FILENAME);
+
+            if !day.in_range(from, to) || !path.is_file() {
+                continue;
+            }
+
+            for mp in try!( TextStorage::read_file(&path, device) ) {
+                if in_range(&mp, from, to) {
+                    points.push(mp);
+                }
+            }
+        }
+
+        points.sort_by(|a, b| a.get_date().cmp(&b.get_date()) );
+        Ok(points)
+    }
+
+    fn devices(&self) -> io::Result<Vec<Device>> {
+        devices(&self.root)
+    }
+
+    fn select(&self, selector: &DeviceSelector) -> io::Result<Vec<Device>> {
+        select_devices(&self.root, selector)
+    }
+}
+
+
+#[test]
+fn test_text_storage_torn_line() {
+    use std::env;
+
+    let root = env::temp_dir().join("orion_test_text_storage_torn_line");
+    let _ = fs::remove_dir_all(&root);
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let mp = MeasurementPoint::from_line(device.clone(), "2015-06-01T12:00:00+00:00 3[V]")
+                              .unwrap();
+
+    let day = root.join("driver/node/port/2015/6/1");
+    fs::create_dir_all(&day).unwrap();
+    let mut file = File::create(day.join(TEXT_FILENAME)).unwrap();
+    file.write_all(b"2015-06-01T11:00:00+00:00 3[V]\n2015-06-01T11:").unwrap();
+
+    let mut storage = TextStorage::new(&root);
+    storage.append(&mp).unwrap();
+    storage.append(&mp).unwrap();
+    storage.sync().unwrap();
+
+    let mut content = String::new();
+    File::open(day.join(TEXT_FILENAME)).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!( content, "2015-06-01T11:00:00+00:00 3[V]\n2015-06-01T11:\n\
+                          2015-06-01T12:00:00+00:00 3[V]\n\
+                          2015-06-01T12:00:00+00:00 3[V]\n" );
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,360 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fmt;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use chrono;
+
+use core::MeasurementPoint;
+use crc32;
+use super::Storage;
+
+/// Name of the write-ahead log in the storage root
+pub const WAL_FILENAME: &'static str = "wal.log";
+
+/// First bytes of a write-ahead log
+pub const WAL_MAGIC: &'static [u8] = b"ORW1";
+
+/// When the write-ahead log is flushed to disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every point, nothing acknowledged can be lost
+    Always,
+    /// After a point if the last flush is older than this number of
+    /// milliseconds
+    Interval(u64),
+    /// Let the operating system decide
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+
+    type Err = ParseFsyncPolicyError;
+
+    /// Parse `always`, `never` or a number of milliseconds
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use orion::storage::FsyncPolicy;
+    /// use std::str::FromStr;
+    ///
+    /// assert_eq!( FsyncPolicy::from_str("always").unwrap(), FsyncPolicy::Always );
+    /// assert_eq!( FsyncPolicy::from_str("500").unwrap(), FsyncPolicy::Interval(500) );
+    /// ```
+    fn from_str(s: &str) -> Result<FsyncPolicy, ParseFsyncPolicyError> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never"  => Ok(FsyncPolicy::Never),
+            _        => match u64::from_str(s) {
+                Ok(ms) => Ok(FsyncPolicy::Interval(ms)),
+                Err(_) => Err(ParseFsyncPolicyError::Invalid),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseFsyncPolicyError {
+    Invalid,
+}
+
+impl fmt::Display for ParseFsyncPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        self.description().fmt(f)
+    }
+}
+
+impl Error for ParseFsyncPolicyError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseFsyncPolicyError::Invalid => "Invalid fsync policy",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+/// Log of the points received but maybe not yet durable in a `Storage`
+///
+/// The file starts with `WAL_MAGIC`, each record is:
+///
+/// ```text
+/// u32 length of frame | u32 CRC-32 of frame | frame
+/// ```
+///
+/// with integers in little-endian and the frame created by
+/// `MeasurementPoint::to_frame`. A record with a bad checksum, usually
+/// torn by a crash, end the log.
+///
+/// Points are appended with `append` before being given to the storage.
+/// Once the storage is synced, `checkpoint` empty the log. After a crash,
+/// `recover` give back to the storage every point of the log.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    last_sync: Instant,
+}
+
+impl WriteAheadLog {
+
+    /// Open or create the log at `path`
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<WriteAheadLog> {
+        let mut file = try!( OpenOptions::new()
+                                         .create(true)
+                                         .read(true)
+                                         .write(true)
+                                         .open(path) );
+
+        if try!( file.metadata() ).len() == 0 {
+            try!( file.write_all(WAL_MAGIC) );
+            try!( file.sync_all() );
+        }
+        try!( file.seek(SeekFrom::End(0)) );
+
+        Ok( WriteAheadLog {
+            path: path.to_path_buf(),
+            file: file,
+            policy: policy,
+            last_sync: Instant::now(),
+        })
+    }
+
+    /// Log `mp`, flushing it to disk according to the `FsyncPolicy`
+    pub fn append(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        let frame = mp.to_frame();
+        let mut record = Vec::with_capacity(frame.len() + 8);
+
+        push_u32(&mut record, frame.len() as u32);
+        push_u32(&mut record, crc32::checksum(&frame));
+        record.extend(frame.into_iter());
+
+        try!( self.file.write_all(&record) );
+
+        let sync = match self.policy {
+            FsyncPolicy::Always       => true,
+            FsyncPolicy::Never        => false,
+            FsyncPolicy::Interval(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+        };
+
+        if sync {
+            try!( self.file.sync_data() );
+            self.last_sync = Instant::now();
+        }
+
+        Ok( () )
+    }
+
+    /// Sync `storage` then empty the log
+    pub fn checkpoint(&mut self, storage: &mut Storage) -> io::Result<()> {
+        try!( storage.sync() );
+
+        try!( self.file.set_len(WAL_MAGIC.len() as u64) );
+        try!( self.file.seek(SeekFrom::End(0)) );
+        try!( self.file.sync_all() );
+
+        self.last_sync = Instant::now();
+        debug!("Checkpoint of {:?}", self.path);
+        Ok( () )
+    }
+
+    /// Give every logged point missing from `storage` to it, then checkpoint
+    ///
+    /// Return the number of points added to `storage`.
+    pub fn recover(&mut self, storage: &mut Storage) -> io::Result<usize> {
+        let mut count = 0;
+
+        for mp in try!( WriteAheadLog::read(&self.path) ) {
+            let end = mp.get_date() + chrono::Duration::nanoseconds(1);
+            let stored = try!( storage.read_range(mp.get_device(), &mp.get_date(), &end) );
+
+            if !stored.contains(&mp) {
+                try!( storage.append(&mp) );
+                count += 1;
+            }
+        }
+
+        try!( self.checkpoint(storage) );
+        Ok(count)
+    }
+
+    /// Read every valid point of the log at `path`
+    pub fn read(path: &Path) -> io::Result<Vec<MeasurementPoint>> {
+        let mut reader = BufReader::new( try!( File::open(path) ) );
+        let mut points = Vec::new();
+
+        let mut magic = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || &magic[..] != WAL_MAGIC {
+            return Err( io::Error::new(io::ErrorKind::InvalidData,
+                                       "Not an orion write-ahead log") );
+        }
+
+        loop {
+            let mut header = [0u8; 8];
+            match reader.read_exact(&mut header) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+
+            let len = read_u32(&header[0..4]) as usize;
+            let crc = read_u32(&header[4..8]);
+
+            let mut frame = Vec::new();
+            try!( (&mut reader).take(len as u64).read_to_end(&mut frame) );
+
+            if frame.len() != len || crc32::checksum(&frame) != crc {
+                warn!("Ignore torn record at the end of {:?}", path);
+                break;
+            }
+
+            match MeasurementPoint::from_frame(&frame) {
+                Ok(mp) => points.push(mp),
+                Err(e) => warn!("Ignore invalid record of {:?}: {}", path, e),
+            }
+        }
+
+        Ok(points)
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        buf.push( (value >> (8 * i)) as u8 );
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes.iter()
+         .enumerate()
+         .fold(0, |value, (i, byte)| value | (*byte as u32) << (8 * i))
+}
+
+
+#[test]
+fn test_wal_recover() {
+    use std::env;
+    use std::fs;
+    use core::Device;
+    use super::TextStorage;
+
+    let root = env::temp_dir().join("orion_test_wal_recover");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let points: Vec<_> = [ "2015-06-01T12:00:00+00:00 3[V]",
+                           "2015-06-01T12:00:01+00:00 4[V]",
+                           "2015-06-01T12:00:02+00:00 5[V]" ]
+                         .iter()
+                         .map(|l| MeasurementPoint::from_line(device.clone(), l).unwrap())
+                         .collect();
+
+    let path = root.join(WAL_FILENAME);
+    let mut storage = TextStorage::new(&root);
+
+    {
+        let mut wal = WriteAheadLog::open(&path, FsyncPolicy::Always).unwrap();
+        for mp in points.iter() {
+            wal.append(mp).unwrap();
+        }
+
+        // Crash after the first point reached the storage
+        storage.append(&points[0]).unwrap();
+    }
+
+    // Torn record at the end of the log
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[12, 0, 0, 0, 1, 2]).unwrap();
+    assert_eq!( WriteAheadLog::read(&path).unwrap(), points );
+
+    let mut wal = WriteAheadLog::open(&path, FsyncPolicy::Interval(100)).unwrap();
+    assert_eq!( wal.recover(&mut storage).unwrap(), 2 );
+    assert_eq!( fs::metadata(&path).unwrap().len(), WAL_MAGIC.len() as u64 );
+
+    let from = points[0].get_date();
+    let to = points[2].get_date() + chrono::Duration::seconds(1);
+    assert_eq!( storage.read_range(&device, &from, &to).unwrap(), points );
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_wal_recover_torn_data() {
+    use std::env;
+    use std::fs;
+    use core::Device;
+    use super::{BinaryStorage, ColumnarStorage, BINARY_FILENAME, HEAD_FILENAME};
+
+    let device = Device::with_slug("port@node.driver").unwrap();
+    let points: Vec<_> = [ "2015-06-01T12:00:00+00:00 3[V]",
+                           "2015-06-01T12:00:01+00:00 4[V]",
+                           "2015-06-01T12:00:02+00:00 5[V]" ]
+                         .iter()
+                         .map(|l| MeasurementPoint::from_line(device.clone(), l).unwrap())
+                         .collect();
+
+    for &(format, filename) in [ ("binary", BINARY_FILENAME), ("columnar", HEAD_FILENAME) ].iter() {
+        let root = env::temp_dir().join(format!("orion_test_wal_recover_torn_{}", format));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let new_storage = || -> Box<Storage> {
+            match format {
+                "binary" => Box::new( BinaryStorage::new(&root) ),
+                _        => Box::new( ColumnarStorage::new(&root) ),
+            }
+        };
+
+        let path = root.join(WAL_FILENAME);
+
+        {
+            let mut storage = new_storage();
+            let mut wal = WriteAheadLog::open(&path, FsyncPolicy::Always).unwrap();
+            for mp in points.iter() {
+                wal.append(mp).unwrap();
+            }
+
+            // Crash in the middle of writing the second point
+            storage.append(&points[0]).unwrap();
+        }
+
+        let data = root.join("driver/node/port/2015/6/1").join(filename);
+        let mut file = OpenOptions::new().append(true).open(&data).unwrap();
+        file.write_all(&[0x80]).unwrap();
+
+        let mut storage = new_storage();
+        let mut wal = WriteAheadLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!( wal.recover(&mut *storage).unwrap(), 2 );
+
+        let from = points[0].get_date();
+        let to = points[2].get_date() + chrono::Duration::seconds(1);
+        assert_eq!( storage.read_range(&device, &from, &to).unwrap(), points );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+//! TLS for the TCP connections of the logger
+//!
+//! Certificates and keys are PEM files. Peers are verified against the
+//! `ca` file only, the host name of the server is not checked, so use a
+//! certificate authority dedicated to the logger.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::error::Error;
+use std::net::{TcpStream, IpAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use openssl::ssl::{SslContext, SslMethod, SslStream, SslError};
+use openssl::ssl::{SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+use openssl::x509::{X509FileType, X509Generator};
+use openssl::crypto::hash::Type as HashType;
+use openssl::nid::Nid;
+
+/// Biggest message accepted by `read_message`
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// A TLS connection over TCP
+pub type TlsStream = SslStream<TcpStream>;
+
+/// Certificates used by a TLS client or server
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsOptions {
+    /// Certificate presented to the peer, needed by a server
+    pub cert: Option<PathBuf>,
+    /// Private key of `cert`
+    pub key: Option<PathBuf>,
+    /// Authority of the peer certificates, needed by a client and by a
+    /// server verifying its clients
+    pub ca: Option<PathBuf>,
+    /// Refuse the clients without a certificate signed by `ca`
+    pub require_client_cert: bool,
+}
+
+impl TlsOptions {
+    pub fn new() -> TlsOptions {
+        TlsOptions {
+            cert: None,
+            key: None,
+            ca: None,
+            require_client_cert: false,
+        }
+    }
+
+    /// Build the context of a server
+    ///
+    /// Client certificates are requested when `ca` is set.
+    pub fn server_context(&self) -> Result<SslContext, TlsError> {
+        if self.cert.is_none() || self.key.is_none() {
+            return Err( TlsError::Missing("cert and key") );
+        }
+
+        let mut ctx = try!( self.context() );
+
+        if self.ca.is_some() {
+            if self.require_client_cert {
+                ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+            } else {
+                ctx.set_verify(SSL_VERIFY_PEER, None);
+            }
+        } else if self.require_client_cert {
+            return Err( TlsError::Missing("ca") );
+        }
+
+        Ok(ctx)
+    }
+
+    /// Build the context of a client, presenting `cert` if set
+    pub fn client_context(&self) -> Result<SslContext, TlsError> {
+        if self.ca.is_none() {
+            return Err( TlsError::Missing("ca") );
+        }
+
+        let mut ctx = try!( self.context() );
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+
+        Ok(ctx)
+    }
+
+    fn context(&self) -> Result<SslContext, TlsError> {
+        let mut ctx = try!( SslContext::new(SslMethod::Sslv23) );
+        ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+
+        if let Some(ref ca) = self.ca {
+            try!( ctx.set_CA_file(ca) );
+        }
+
+        match (&self.cert, &self.key) {
+            (&Some(ref cert), &Some(ref key)) => {
+                try!( ctx.set_certificate_file(cert, X509FileType::PEM) );
+                try!( ctx.set_private_key_file(key, X509FileType::PEM) );
+                try!( ctx.check_private_key() );
+            },
+            (&None, &None) => {},
+            _              => return Err( TlsError::Missing("cert and key") ),
+        }
+
+        Ok(ctx)
+    }
+}
+
+/// Run the server side of the handshake on `stream`
+pub fn accept<S: Read + Write>(ctx: &SslContext, stream: S) -> io::Result<SslStream<S>> {
+    SslStream::accept(ctx, stream).map_err(to_io_error)
+}
+
+/// Connect to the server at `address` and run the client side of the
+/// handshake
+///
+/// The certificate of the server must be issued for the host of `address`,
+/// a name or an IP address.
+pub fn connect(ctx: &SslContext, address: &str) -> io::Result<TlsStream> {
+    let stream = try!( TcpStream::connect(address) );
+    let stream = try!( SslStream::connect(ctx, stream).map_err(to_io_error) );
+
+    let host = match address.rfind(':') {
+        Some(pos) => &address[..pos],
+        None      => address,
+    };
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+
+    if !certificate_matches(&stream, host) {
+        return Err( io::Error::new(io::ErrorKind::Other,
+                                   format!("Server certificate not issued for {}", host)) );
+    }
+
+    Ok(stream)
+}
+
+/// Return `true` if the certificate of the peer is issued for `host`
+///
+/// The subject alternative names are checked when the certificate has
+/// some, the common name otherwise.
+fn certificate_matches(stream: &TlsStream, host: &str) -> bool {
+    let cert = match stream.ssl().peer_certificate() {
+        Some(x) => x,
+        None    => return false,
+    };
+    let ip = IpAddr::from_str(host).ok();
+
+    if let Some(names) = cert.subject_alt_names() {
+        let names: Vec<_> = names.iter().collect();
+
+        if !names.is_empty() {
+            return names.iter().any(|name| match ip {
+                Some(ref ip) => name.ipaddress().map_or(false, |bytes| same_ip(ip, bytes)),
+                None         => name.dnsname().map_or(false, |name| host_matches(name, host)),
+            });
+        }
+    }
+
+    match (cert.subject_name().text_by_nid(Nid::CN), ip) {
+        (Some(name), Some(ip)) => IpAddr::from_str(&name).ok() == Some(ip),
+        (Some(name), None)     => host_matches(&name, host),
+        (None, _)              => false,
+    }
+}
+
+fn same_ip(ip: &IpAddr, bytes: &[u8]) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => bytes == &ip.octets()[..],
+        IpAddr::V6(ref ip) => bytes == &ip.octets()[..],
+    }
+}
+
+/// Return `true` if the certificate name `name` match `host`
+///
+/// A name starting by `*.` match any single first label.
+fn host_matches(name: &str, host: &str) -> bool {
+    let name = name.to_lowercase();
+    let host = host.trim_right_matches('.').to_lowercase();
+
+    if name.starts_with("*.") {
+        match host.find('.') {
+            Some(pos) if pos > 0 => host[pos + 1..] == name[2..],
+            _                    => false,
+        }
+    } else {
+        host == name
+    }
+}
+
+fn to_io_error(err: SslError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// Return the common name of the certificate presented by the peer
+///
+/// With a context requesting certificates, only verified certificates
+/// are presented.
+pub fn peer_name(stream: &TlsStream) -> Option<String> {
+    stream.ssl()
+          .peer_certificate()
+          .and_then(|cert| cert.subject_name().text_by_nid(Nid::CN).map(|x| x.to_string()))
+}
+
+/// Write `data` prefixed by its length, as a 32 bits big endian integer
+pub fn write_message<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    let len = data.len() as u32;
+    let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+    try!( w.write_all(&header) );
+    try!( w.write_all(data) );
+    w.flush()
+}
+
+/// Read a message written by `write_message`
+///
+/// Return `None` if the connection is closed before the message.
+///
+/// # Example
+///
+/// ```
+/// use orion::tls::{write_message, read_message};
+///
+/// let mut buf = Vec::new();
+/// write_message(&mut buf, b"LOGGER/1.0 STOP").unwrap();
+/// assert_eq!( buf.len(), 4 + 15 );
+///
+/// let mut r = &buf[..];
+/// assert_eq!( read_message(&mut r).unwrap(), Some(b"LOGGER/1.0 STOP".to_vec()) );
+/// assert_eq!( read_message(&mut r).unwrap(), None );
+/// ```
+pub fn read_message<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    let mut read = 0;
+
+    while read < header.len() {
+        match try!( r.read(&mut header[read..]) ) {
+            0 if read == 0 => return Ok(None),
+            0              => return Err( io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                         "Truncated message length") ),
+            n              => read += n,
+        }
+    }
+
+    let len = header.iter().fold(0, |acc, &b| (acc << 8) | b as usize);
+
+    if len > MAX_MESSAGE_LEN {
+        return Err( io::Error::new(io::ErrorKind::InvalidData, "Message too long") );
+    }
+
+    let mut data = vec![0; len];
+    try!( r.read_exact(&mut data) );
+
+    Ok( Some(data) )
+}
+
+/// Write a new self-signed certificate for `name` and its key
+///
+/// Such a certificate is its own authority, it can be used as `ca` by the
+/// peers. Valid for `days`. The key file is only readable by its owner.
+pub fn generate_self_signed(name: &str, days: u32, cert: &Path, key: &Path)
+    -> Result<(), TlsError> {
+
+    let generator = X509Generator::new()
+                                  .set_bitlength(2048)
+                                  .set_valid_period(days)
+                                  .add_name("CN".to_string(), name.to_string())
+                                  .set_sign_hash(HashType::SHA256);
+
+    let (x509, pkey) = try!( generator.generate() );
+
+    try!( x509.write_pem(&mut try!( File::create(cert) )) );
+
+    let mut key = try!( OpenOptions::new().write(true).create(true).truncate(true)
+                                          .mode(0o600).open(key) );
+    try!( pkey.write_pem(&mut key) );
+
+    Ok( () )
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(io::Error),
+    Ssl(SslError),
+    /// Options needed but not set
+    Missing(&'static str),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsError::Io(ref err)  => write!(f, "{}", err),
+            TlsError::Ssl(ref err) => write!(f, "{}", err),
+            TlsError::Missing(opt) => write!(f, "Missing TLS {}", opt),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn description(&self) -> &str {
+        match *self {
+            TlsError::Io(ref err)  => err.description(),
+            TlsError::Ssl(ref err) => err.description(),
+            TlsError::Missing(..)  => "Missing TLS option",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            TlsError::Io(ref err)  => Some(err),
+            TlsError::Ssl(ref err) => Some(err),
+            TlsError::Missing(..)  => None,
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(err: io::Error) -> TlsError {
+        TlsError::Io(err)
+    }
+}
+
+impl From<SslError> for TlsError {
+    fn from(err: SslError) -> TlsError {
+        TlsError::Ssl(err)
+    }
+}
+
+
+#[test]
+fn test_tls() {
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let root = env::temp_dir().join("orion_test_tls");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let path = |name: &str| root.join(name);
+
+    generate_self_signed("localhost", 1, &path("server.pem"), &path("server.key")).unwrap();
+    generate_self_signed("sensor-1", 1, &path("client.pem"), &path("client.key")).unwrap();
+
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path("server.key")).unwrap().permissions().mode();
+        assert_eq!( mode & 0o777, 0o600 );
+    }
+
+    let mut server = TlsOptions::new();
+    server.cert = Some(path("server.pem"));
+    server.key = Some(path("server.key"));
+    server.ca = Some(path("client.pem"));
+    server.require_client_cert = true;
+
+    let mut client = TlsOptions::new();
+    client.cert = Some(path("client.pem"));
+    client.key = Some(path("client.key"));
+    client.ca = Some(path("server.pem"));
+
+    let mut anonymous = TlsOptions::new();
+    anonymous.ca = Some(path("server.pem"));
+
+    assert!( anonymous.server_context().is_err() );
+    assert!( TlsOptions::new().client_context().is_err() );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let address = format!("localhost:{}", port);
+    let ctx = server.server_context().unwrap();
+
+    let handle = thread::spawn( move || {
+        // The client refuse a certificate not issued for its address
+        let (stream, _) = listener.accept().unwrap();
+        let _ = accept(&ctx, stream);
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = accept(&ctx, stream).unwrap();
+        let name = peer_name(&stream);
+
+        let request = read_message(&mut stream).unwrap().unwrap();
+        write_message(&mut stream, &request).unwrap();
+
+        // Anonymous clients are refused
+        let (stream, _) = listener.accept().unwrap();
+        assert!( accept(&ctx, stream).is_err() );
+
+        name
+    });
+
+    let ctx = client.client_context().unwrap();
+    assert!( connect(&ctx, &format!("127.0.0.1:{}", port)).is_err() );
+
+    let mut stream = connect(&ctx, &address).unwrap();
+    write_message(&mut stream, b"LOGGER/1.0 ALERTS").unwrap();
+    assert_eq!( read_message(&mut stream).unwrap(), Some(b"LOGGER/1.0 ALERTS".to_vec()) );
+
+    let ctx = anonymous.client_context().unwrap();
+    if let Ok(mut stream) = connect(&ctx, &address) {
+        assert!( write_message(&mut stream, b"LOGGER/1.0 STOP").is_err() ||
+                 read_message(&mut stream).map(|m| m.is_none()).unwrap_or(true) );
+    }
+
+    assert_eq!( handle.join().unwrap(), Some("sensor-1".to_string()) );
+
+    // A server not trusting the client certificate refuse it
+    server.ca = Some(path("server.pem"));
+    let ctx = server.server_context().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+
+    let handle = thread::spawn( move || {
+        let (stream, _) = listener.accept().unwrap();
+        accept(&ctx, stream).is_err()
+    });
+
+    let ctx = client.client_context().unwrap();
+    let _ = connect(&ctx, &address).and_then(|mut s| {
+        try!( write_message(&mut s, b"LOGGER/1.0 ALERTS") );
+        read_message(&mut s)
+    });
+    assert!( handle.join().unwrap() );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_host_matches() {
+    assert!( host_matches("logger.example.com", "logger.example.com") );
+    assert!( host_matches("Logger.Example.com", "logger.example.COM.") );
+    assert!( host_matches("*.example.com", "logger.example.com") );
+    assert!( !host_matches("*.example.com", "example.com") );
+    assert!( !host_matches("*.example.com", "a.logger.example.com") );
+    assert!( !host_matches("logger.example.com", "other.example.com") );
+
+    let ip = IpAddr::from_str("10.0.0.1").unwrap();
+    assert!( same_ip(&ip, &[10, 0, 0, 1]) );
+    assert!( !same_ip(&ip, &[10, 0, 0, 2]) );
+}
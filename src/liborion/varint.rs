@@ -0,0 +1,137 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! LEB128 variable length integers used by the binary formats
+//!
+//! Unsigned values are written 7 bits at a time, least significant group
+//! first, with the high bit of each byte set when more bytes follow.
+//! Signed values are zigzag encoded first so that small negative numbers
+//! stay small.
+
+use std::io;
+use std::io::{Read, Write};
+
+/// Map a signed integer on an unsigned one: 0, -1, 1, -2 -> 0, 1, 2, 3
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Reverse of `zigzag`
+pub fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+pub fn write_u64<W: Write>(w: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+
+    w.write_all(&buf[..len])
+}
+
+pub fn write_i64<W: Write>(w: &mut W, value: i64) -> io::Result<()> {
+    write_u64(w, zigzag(value))
+}
+
+/// Read an unsigned varint
+///
+/// Return `Ok(None)` if the reader is at end of file before the first
+/// byte, and an `UnexpectedEof` error if it ends in the middle of a value.
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    let mut byte = [0u8; 1];
+
+    loop {
+        if try!( r.read(&mut byte) ) == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Truncated varint"));
+        }
+
+        // The tenth byte can only hold the most significant bit
+        if shift > 63 || (shift == 63 && byte[0] & 0x7e != 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Varint overflow"));
+        }
+
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+
+        shift += 7;
+    }
+}
+
+pub fn read_i64<R: Read>(r: &mut R) -> io::Result<Option<i64>> {
+    Ok( try!( read_u64(r) ).map(unzigzag) )
+}
+
+
+#[test]
+fn test_varint_roundtrip() {
+    let values = [0i64, 1, -1, 63, -64, 64, 300, -300,
+                  1433160000, ::std::i64::MAX, ::std::i64::MIN];
+
+    for value in values.iter() {
+        let mut buf = Vec::new();
+        write_i64(&mut buf, *value).unwrap();
+
+        let mut reader = &buf[..];
+        assert_eq!( read_i64(&mut reader).unwrap(), Some(*value) );
+        assert!( reader.is_empty() );
+    }
+}
+
+#[test]
+fn test_varint_size_and_eof() {
+    let mut buf = Vec::new();
+    write_u64(&mut buf, 127).unwrap();
+    assert_eq!( buf.len(), 1 );
+    write_u64(&mut buf, 128).unwrap();
+    assert_eq!( buf.len(), 3 );
+
+    let mut empty: &[u8] = &[];
+    assert_eq!( read_u64(&mut empty).unwrap(), None );
+
+    let mut truncated: &[u8] = &[0x80];
+    assert!( read_u64(&mut truncated).is_err() );
+
+    let mut max: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+    assert_eq!( read_u64(&mut max).unwrap(), Some(::std::u64::MAX) );
+
+    let mut overflow: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02];
+    assert!( read_u64(&mut overflow).is_err() );
+}
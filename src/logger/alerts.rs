@@ -0,0 +1,70 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use super::Args;
+use super::config::Config;
+use super::messages::*;
+
+use orion::alert::Status;
+
+/// Print the state of the alerting rules of the running server
+///
+/// Each line hold the rule, the device slug, the state, the date of the
+/// last change of state and the last checked measurement. Only the devices
+/// seen by the server since it started are listed.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Alerts command");
+
+    if !args.cmd_list {
+        panic!("Undefined task in Alerts command");
+    }
+
+    let mut channel = match config.channel() {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            ::std::process::exit(1);
+        },
+    };
+
+    let reply = match channel.request("LOGGER/1.0 ALERTS".to_string()) {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            ::std::process::exit(1);
+        },
+    };
+
+    let mut lines = reply.lines();
+
+    if lines.next() != Some("LOGGER/1.0 OK") {
+        println!("Server error: {}", reply);
+        ::std::process::exit(1);
+    }
+
+    for line in lines {
+        match Status::from_line(line) {
+            Some(status) => println!("{:<16} {:<32} {:<8} {} {}", status.rule,
+                                     status.device.get_slug(), status.state,
+                                     status.since.to_rfc3339(),
+                                     status.value.map(|m| m.to_string())
+                                                 .unwrap_or("-".to_string())),
+            None => println!("{}", line),
+        }
+    }
+}
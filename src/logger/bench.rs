@@ -0,0 +1,100 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use chrono::UTC;
+
+use super::Args;
+use super::config::Config;
+use super::messages::*;
+
+use orion::core::{Device, MeasurementsList, MeasurementPoint};
+use orion::logger::{Channel, AsyncChannel};
+
+/// Compare the throughput of `Channel` and `AsyncChannel`
+///
+/// Send `--points` points of a device with each client and print the
+/// points per second. The points are logged by the server like any other,
+/// so use a device dedicated to the benchmark.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Bench command");
+
+    let device = match Device::with_slug(&args.arg_device) {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", INVALID_DEVICE);
+            return
+        },
+    };
+
+    let count = args.flag_points;
+    let batch = args.flag_batch;
+
+    if count == 0 || batch == 0 {
+        println!("--points and --batch must be positive.");
+        return
+    }
+
+    let mut channel = connect(config);
+    let start = Instant::now();
+
+    for i in 0..count {
+        if let Err(e) = channel.add(&point(&device, i)) {
+            println!("Failed to log with Channel: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+
+    report("Channel", count, start.elapsed());
+
+    let pipelined = AsyncChannel::new(connect(config), batch);
+    let start = Instant::now();
+
+    let futures: Vec<_> = (0..count).map(|i| pipelined.add(&point(&device, i))).collect();
+
+    for future in futures.into_iter() {
+        if let Err(e) = future.wait() {
+            println!("Failed to log with AsyncChannel: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+
+    report(&format!("AsyncChannel, {} points by batch", batch), count, start.elapsed());
+}
+
+fn connect(config: &Config) -> Channel {
+    match config.channel() {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            ::std::process::exit(1);
+        },
+    }
+}
+
+fn point(device: &Device, i: usize) -> MeasurementPoint {
+    let data = MeasurementsList::from_str(&format!("{}[V]", i)).unwrap();
+    MeasurementPoint::new(device.clone(), UTC::now(), data)
+}
+
+fn report(client: &str, count: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+    println!("{}: {} points in {:.3}s, {:.0} points/s", client, count, secs,
+             count as f64 / secs);
+}
@@ -0,0 +1,657 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use rustc_serialize::Decodable;
+use toml;
+
+use chrono::Duration;
+use std::time::Duration as StdDuration;
+
+use orion::core::{DeviceSelector, Measurement, Unit};
+use orion::storage::{Format, FsyncPolicy, RetentionPolicy, Resolution};
+use orion::alert::{Rule, Condition, Action};
+use orion::mqtt::{TopicPattern, MqttOptions};
+use orion::ingest::{GraphiteRule, InfluxRule};
+use orion::logger::{Channel, ChannelOptions};
+use orion::tls::TlsOptions;
+
+use super::DATA_PATH;
+
+pub static CONFIG_PATH: &'static str = "/tmp/orion_logger.toml";
+
+/// Configuration of orion-logger
+///
+/// Loaded from a TOML file, every key is optional:
+///
+/// ```toml
+/// [storage]
+/// path = "/tmp/data"          # Root of the data directory
+/// format = "text"             # text, binary, columnar or sqlite
+/// compaction_interval = 3600  # Seconds between compactions (columnar)
+///
+/// [wal]
+/// fsync = "always"            # always, never or milliseconds like "500"
+/// checkpoint = 1000           # Points between two checkpoints
+///
+/// [retention]
+/// default = 365               # Days kept without rule, omit to keep forever
+/// interval = 86400            # Seconds between two prunings by the server
+///
+/// [retention.keep]            # Days kept for a driver, node or selector
+/// "lm-sensors" = 30
+/// "core-isa-000.lm-sensors" = 7
+/// "temp*@core-isa-*.lm-sensors" = 1
+///
+/// [rollup]
+/// resolutions = ["1h", "1d"]  # Rollups computed from 1m, 1h and 1d
+/// interval = 3600             # Seconds between two rollups by the server
+///
+/// [[alert]]                   # Alerting rules evaluated by the server
+/// name = "core-hot"
+/// device = "temp1@core-isa-000.lm-sensors"
+/// channel = 0                 # Measurement position, default to 0
+/// when = "> 80[K]"            # Fire when the measurement meet this condition
+/// hysteresis = "2[K]"         # Resolve only at 78[K] or below
+/// duration = 60               # Seconds the condition must hold to fire
+/// stale = 300                 # Fire after this many seconds without point
+/// actions = ["log", "command:notify-send hot", "socket:ipc:///tmp/alerts.ipc"]
+///
+/// [http]
+/// listen = "127.0.0.1:8080"   # Serve the HTTP/JSON API and /metrics, omit to disable
+/// feed = "127.0.0.1:8081"     # Push new points to WebSocket clients
+///
+/// [mqtt]                      # Used by `orion-logger mqtt`
+/// broker = "127.0.0.1:1883"
+/// topics = ["sensors/{driver}/{node}/{port}", "home/{node}/+/{port}"]
+/// qos = 1                     # 0 or 1, default to 0, 1 keeps the session on the broker
+/// client_id = "orion-logger"
+/// keep_alive = 60             # Seconds between two pings of the broker
+/// username = "orion"          # Optional credentials
+/// password = "secret"
+///
+/// [graphite]                  # Listen for Graphite plaintext, on TCP, UDP or both
+/// tcp = "0.0.0.0:2003"
+/// udp = "0.0.0.0:2003"
+///
+/// [[graphite.rule]]           # Metric paths stored, the first matching rule is used
+/// pattern = "servers.{node}.{driver}.{port}"
+/// unit = "K"
+///
+/// [influx]                    # Listen for InfluxDB line protocol
+/// tcp = "0.0.0.0:8089"
+/// udp = "0.0.0.0:8089"
+///
+/// [[influx.rule]]             # Measurements stored, "*" for any
+/// measurement = "sensors"
+/// device = "{sensor}@{host}.influx"   # Filled with the tags and {measurement}
+/// fields = ["temp[K]", "power[W]"]    # Fields stored, in order
+///
+/// [auth]
+/// file = "/etc/orion/clients.toml"   # Clients allowed by the server, omit to allow anyone
+/// token = "c2VjcmV0LXRva2Vu"         # Sent by the commands and the MQTT bridge
+/// ingest_client = "collectd"         # Client writing the Graphite and Influx points, with a file
+///
+/// [tls]
+/// listen = "0.0.0.0:7878"             # Accept remote clients over TLS, omit to disable
+/// server = "logger.example.com:7878"  # Used by the commands instead of the local server
+/// cert = "/etc/orion/server.pem"      # Certificate presented to the peers, PEM encoded
+/// key = "/etc/orion/server.key"
+/// ca = "/etc/orion/ca.pem"            # Authority of the peer certificates
+/// require_client_cert = false
+/// http = false                        # Serve the HTTP API over HTTPS
+///
+/// [channel]                   # Connection of the commands to the server
+/// timeout = 10                # Seconds to wait for a reply, 0 to wait forever
+/// retries = 3                 # Attempts after a failure, 1, 2, 4... seconds apart, default to 0
+/// spool = "/var/spool/orion/spool.txt"  # Keep the points while the server is unreachable
+/// ```
+///
+/// The format of the clients file is described by `AccessList`. With a
+/// clients file, `auth.token` must belong to a client writing every device
+/// received by MQTT, the HTTP clients send their own token and the Graphite
+/// and Influx points are written as `auth.ingest_client`. A TLS client whose
+/// certificate common name is the name of a client of the file gets its
+/// permissions without token.
+///
+/// A rule needs `when`, `stale` or both. Without `actions`, events are only
+/// written to the alerts log of the data directory.
+#[derive(Debug)]
+pub struct Config {
+    pub data_path: PathBuf,
+    pub storage_format: Format,
+    pub compaction_interval: u64,
+    pub wal_fsync: FsyncPolicy,
+    pub wal_checkpoint: usize,
+    pub retention: RetentionPolicy,
+    pub prune_interval: u64,
+    pub rollup_resolutions: Vec<Resolution>,
+    pub rollup_interval: u64,
+    pub alerts: Vec<Rule>,
+    pub http_listen: Option<String>,
+    pub http_feed: Option<String>,
+    pub mqtt_broker: Option<String>,
+    pub mqtt_topics: Vec<TopicPattern>,
+    pub mqtt_qos: u8,
+    pub mqtt_options: MqttOptions,
+    pub graphite_tcp: Option<String>,
+    pub graphite_udp: Option<String>,
+    pub graphite_rules: Vec<GraphiteRule>,
+    pub influx_tcp: Option<String>,
+    pub influx_udp: Option<String>,
+    pub influx_rules: Vec<InfluxRule>,
+    pub auth_file: Option<PathBuf>,
+    pub auth_token: Option<String>,
+    /// Client of the `auth_file` whose permissions apply to the Graphite
+    /// and Influx points
+    pub ingest_client: Option<String>,
+    pub tls_listen: Option<String>,
+    pub tls_server: Option<String>,
+    pub tls_options: TlsOptions,
+    pub tls_http: bool,
+    pub channel_options: ChannelOptions,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct ConfigFile {
+    storage: Option<StorageSection>,
+    wal: Option<WalSection>,
+    retention: Option<RetentionSection>,
+    rollup: Option<RollupSection>,
+    alert: Option<Vec<AlertSection>>,
+    http: Option<HttpSection>,
+    mqtt: Option<MqttSection>,
+    graphite: Option<GraphiteSection>,
+    influx: Option<InfluxSection>,
+    auth: Option<AuthSection>,
+    tls: Option<TlsSection>,
+    channel: Option<ChannelSection>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct StorageSection {
+    path: Option<String>,
+    format: Option<String>,
+    compaction_interval: Option<u64>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct WalSection {
+    fsync: Option<String>,
+    checkpoint: Option<usize>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct RetentionSection {
+    default: Option<u32>,
+    interval: Option<u64>,
+    keep: Option<HashMap<String, u32>>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct RollupSection {
+    resolutions: Option<Vec<String>>,
+    interval: Option<u64>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct AlertSection {
+    name: String,
+    device: String,
+    channel: Option<usize>,
+    when: Option<String>,
+    hysteresis: Option<String>,
+    duration: Option<u64>,
+    stale: Option<u64>,
+    actions: Option<Vec<String>>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct HttpSection {
+    listen: Option<String>,
+    feed: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct MqttSection {
+    broker: Option<String>,
+    topics: Option<Vec<String>>,
+    qos: Option<u8>,
+    client_id: Option<String>,
+    keep_alive: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct GraphiteSection {
+    tcp: Option<String>,
+    udp: Option<String>,
+    rule: Option<Vec<GraphiteRuleSection>>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct GraphiteRuleSection {
+    pattern: String,
+    unit: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct InfluxSection {
+    tcp: Option<String>,
+    udp: Option<String>,
+    rule: Option<Vec<InfluxRuleSection>>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct InfluxRuleSection {
+    measurement: String,
+    device: String,
+    fields: Vec<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct AuthSection {
+    file: Option<String>,
+    token: Option<String>,
+    ingest_client: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct TlsSection {
+    listen: Option<String>,
+    server: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    ca: Option<String>,
+    require_client_cert: Option<bool>,
+    http: Option<bool>,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct ChannelSection {
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    spool: Option<String>,
+}
+
+impl AlertSection {
+    fn to_rule(&self) -> Result<Rule, String> {
+        let selector = match DeviceSelector::from_str(&self.device) {
+            Ok(x)  => x,
+            Err(e) => return Err( format!("{} '{}'", e, self.device) ),
+        };
+
+        let mut rule = try!( Rule::new(&self.name, selector, self.channel.unwrap_or(0))
+                                 .map_err(|e| e.to_string()) );
+
+        if self.when.is_none() && self.stale.is_none() {
+            return Err( "A rule needs 'when' or 'stale'".to_string() );
+        }
+
+        if let Some(ref when) = self.when {
+            let condition = try!( Condition::from_str(when).map_err(|e| e.to_string()) );
+
+            let hysteresis = match self.hysteresis {
+                Some(ref h) => match Measurement::from_str(h) {
+                    Ok(x)  => Some(x),
+                    Err(e) => return Err( format!("{} '{}'", e, h) ),
+                },
+                None => None,
+            };
+
+            try!( rule.set_condition(condition, hysteresis).map_err(|e| e.to_string()) );
+        }
+
+        rule.set_duration( Duration::seconds(self.duration.unwrap_or(0) as i64) );
+        rule.set_stale( self.stale.map(|s| Duration::seconds(s as i64)) );
+
+        let actions = match self.actions {
+            Some(ref x) => x.clone(),
+            None        => vec!["log".to_string()],
+        };
+
+        for action in actions.iter() {
+            rule.add_action( try!( Action::from_str(action).map_err(|e| e.to_string()) ) );
+        }
+
+        Ok(rule)
+    }
+}
+
+impl Config {
+
+    /// Return the configuration used when no file exist
+    pub fn default() -> Config {
+        Config {
+            data_path: PathBuf::from(DATA_PATH),
+            storage_format: Format::Text,
+            compaction_interval: 3600,
+            wal_fsync: FsyncPolicy::Always,
+            wal_checkpoint: 1000,
+            retention: RetentionPolicy::new(),
+            prune_interval: 86400,
+            rollup_resolutions: Vec::new(),
+            rollup_interval: 3600,
+            alerts: Vec::new(),
+            http_listen: None,
+            http_feed: None,
+            mqtt_broker: None,
+            mqtt_topics: Vec::new(),
+            mqtt_qos: 0,
+            mqtt_options: MqttOptions::new("orion-logger"),
+            graphite_tcp: None,
+            graphite_udp: None,
+            graphite_rules: Vec::new(),
+            influx_tcp: None,
+            influx_udp: None,
+            influx_rules: Vec::new(),
+            auth_file: None,
+            auth_token: None,
+            ingest_client: None,
+            tls_listen: None,
+            tls_server: None,
+            tls_options: TlsOptions::new(),
+            tls_http: false,
+            channel_options: ChannelOptions {
+                send_timeout: Some(StdDuration::from_secs(10)),
+                receive_timeout: Some(StdDuration::from_secs(10)),
+                .. ChannelOptions::new()
+            },
+        }
+    }
+
+    /// Load configuration from `path`
+    ///
+    /// A missing file give the default configuration.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let mut content = String::new();
+
+        match File::open(path) {
+            Ok(mut file) => { try!( file.read_to_string(&mut content) ); },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No configuration file at {:?}", path);
+                return Ok( Config::default() );
+            },
+            Err(e) => return Err( ConfigError::Io(e) ),
+        }
+
+        Config::from_toml(&content)
+    }
+
+    fn from_toml(content: &str) -> Result<Config, ConfigError> {
+        let mut parser = toml::Parser::new(content);
+
+        let table = match parser.parse() {
+            Some(x) => x,
+            None    => {
+                let msg = parser.errors.iter()
+                                       .map(|e| e.desc.clone())
+                                       .collect::<Vec<_>>()
+                                       .join(", ");
+                return Err( ConfigError::Parse(msg) );
+            },
+        };
+
+        let mut decoder = toml::Decoder::new( toml::Value::Table(table) );
+        let file = match ConfigFile::decode(&mut decoder) {
+            Ok(x)  => x,
+            Err(e) => return Err( ConfigError::Parse(e.to_string()) ),
+        };
+
+        let mut config = Config::default();
+
+        if let Some(storage) = file.storage {
+            if let Some(path) = storage.path {
+                config.data_path = PathBuf::from(path);
+            }
+
+            if let Some(format) = storage.format {
+                config.storage_format = match Format::from_str(&format) {
+                    Ok(x)  => x,
+                    Err(_) => return Err( ConfigError::InvalidValue(
+                                  format!("storage.format = \"{}\"", format)
+                              )),
+                };
+            }
+
+            if let Some(interval) = storage.compaction_interval {
+                if interval == 0 {
+                    return Err( ConfigError::InvalidValue(
+                                    "storage.compaction_interval = 0".to_string()) );
+                }
+                config.compaction_interval = interval;
+            }
+        }
+
+        if let Some(wal) = file.wal {
+            if let Some(fsync) = wal.fsync {
+                config.wal_fsync = match FsyncPolicy::from_str(&fsync) {
+                    Ok(x)  => x,
+                    Err(_) => return Err( ConfigError::InvalidValue(
+                                  format!("wal.fsync = \"{}\"", fsync)
+                              )),
+                };
+            }
+
+            if let Some(checkpoint) = wal.checkpoint {
+                config.wal_checkpoint = checkpoint;
+            }
+        }
+
+        if let Some(retention) = file.retention {
+            if retention.default == Some(0) {
+                return Err( ConfigError::InvalidValue("retention.default = 0".to_string()) );
+            }
+            config.retention.set_default(retention.default);
+
+            if let Some(interval) = retention.interval {
+                config.prune_interval = interval;
+            }
+
+            for (pattern, days) in retention.keep.unwrap_or(HashMap::new()) {
+                if days == 0 || config.retention.add_rule(&pattern, days).is_err() {
+                    return Err( ConfigError::InvalidValue(
+                        format!("retention.keep.\"{}\" = {}", pattern, days)
+                    ));
+                }
+            }
+        }
+
+        if let Some(rollup) = file.rollup {
+            for res in rollup.resolutions.unwrap_or(Vec::new()) {
+                match Resolution::from_str(&res) {
+                    Ok(x)  => config.rollup_resolutions.push(x),
+                    Err(_) => return Err( ConfigError::InvalidValue(
+                                  format!("rollup.resolutions = \"{}\"", res)
+                              )),
+                }
+            }
+
+            if let Some(interval) = rollup.interval {
+                config.rollup_interval = interval;
+            }
+        }
+
+        for alert in file.alert.unwrap_or(Vec::new()) {
+            match alert.to_rule() {
+                Ok(rule) => config.alerts.push(rule),
+                Err(e)   => return Err( ConfigError::InvalidValue(
+                                format!("alert \"{}\": {}", alert.name, e)
+                            )),
+            }
+        }
+
+        if let Some(http) = file.http {
+            config.http_listen = http.listen;
+            config.http_feed = http.feed;
+        }
+
+        if let Some(mqtt) = file.mqtt {
+            config.mqtt_broker = mqtt.broker;
+
+            for topic in mqtt.topics.unwrap_or(Vec::new()) {
+                match TopicPattern::from_str(&topic) {
+                    Ok(x)  => config.mqtt_topics.push(x),
+                    Err(e) => return Err( ConfigError::InvalidValue(
+                                  format!("mqtt.topics = \"{}\": {}", topic, e)
+                              )),
+                }
+            }
+
+            match mqtt.qos {
+                Some(qos) if qos > 1 => return Err( ConfigError::InvalidValue(
+                                            format!("mqtt.qos = {}", qos)
+                                        )),
+                Some(qos) => config.mqtt_qos = qos,
+                None      => {},
+            }
+
+            // The broker keeps the messages not logged yet until reconnected
+            config.mqtt_options.clean_session = config.mqtt_qos == 0;
+
+            if let Some(client_id) = mqtt.client_id {
+                config.mqtt_options.client_id = client_id;
+            }
+
+            if let Some(keep_alive) = mqtt.keep_alive {
+                config.mqtt_options.keep_alive = keep_alive;
+            }
+
+            config.mqtt_options.username = mqtt.username;
+            config.mqtt_options.password = mqtt.password;
+        }
+
+        if let Some(graphite) = file.graphite {
+            config.graphite_tcp = graphite.tcp;
+            config.graphite_udp = graphite.udp;
+
+            for rule in graphite.rule.unwrap_or(Vec::new()) {
+                let parsed = Unit::from_str(&rule.unit)
+                                  .map_err(|_| format!("Invalid unit '{}'", rule.unit))
+                                  .and_then(|unit| GraphiteRule::new(&rule.pattern, unit));
+
+                match parsed {
+                    Ok(x)  => config.graphite_rules.push(x),
+                    Err(e) => return Err( ConfigError::InvalidValue(
+                                  format!("graphite.rule \"{}\": {}", rule.pattern, e)
+                              )),
+                }
+            }
+        }
+
+        if let Some(influx) = file.influx {
+            config.influx_tcp = influx.tcp;
+            config.influx_udp = influx.udp;
+
+            for rule in influx.rule.unwrap_or(Vec::new()) {
+                match InfluxRule::new(&rule.measurement, &rule.device, &rule.fields) {
+                    Ok(x)  => config.influx_rules.push(x),
+                    Err(e) => return Err( ConfigError::InvalidValue(
+                                  format!("influx.rule \"{}\": {}", rule.measurement, e)
+                              )),
+                }
+            }
+        }
+
+        if let Some(auth) = file.auth {
+            config.auth_file = auth.file.map(PathBuf::from);
+            config.auth_token = auth.token;
+            config.ingest_client = auth.ingest_client;
+        }
+
+        if let Some(tls) = file.tls {
+            config.tls_listen = tls.listen;
+            config.tls_server = tls.server;
+            config.tls_options.cert = tls.cert.map(PathBuf::from);
+            config.tls_options.key = tls.key.map(PathBuf::from);
+            config.tls_options.ca = tls.ca.map(PathBuf::from);
+            config.tls_options.require_client_cert = tls.require_client_cert.unwrap_or(false);
+            config.tls_http = tls.http.unwrap_or(false);
+        }
+
+        if let Some(channel) = file.channel {
+            if let Some(timeout) = channel.timeout {
+                let timeout = match timeout {
+                    0 => None,
+                    t => Some( StdDuration::from_secs(t) ),
+                };
+
+                config.channel_options.send_timeout = timeout;
+                config.channel_options.receive_timeout = timeout;
+            }
+
+            if let Some(retries) = channel.retries {
+                config.channel_options.retries = retries;
+            }
+
+            config.channel_options.spool = channel.spool.map(PathBuf::from);
+        }
+
+        Ok(config)
+    }
+
+    /// Connect a `Channel` to the logger server, authenticated with
+    /// `auth.token` if set
+    ///
+    /// Connect to `tls.server` if set, else to the local server, with the
+    /// options of the `[channel]` section.
+    pub fn channel(&self) -> io::Result<Channel> {
+        let mut channel = match self.tls_server {
+            Some(ref address) => try!( Channel::connect_tls(address, &self.tls_options) ),
+            None              => try!( Channel::new() ),
+        };
+
+        try!( channel.set_options(self.channel_options.clone()) );
+
+        if let Some(ref token) = self.auth_token {
+            channel.set_token(token);
+        }
+
+        Ok(channel)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    InvalidValue(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref err)           => write!(f, "{}", err),
+            ConfigError::Parse(ref msg)        => write!(f, "Invalid configuration: {}", msg),
+            ConfigError::InvalidValue(ref msg) => write!(f, "Invalid value: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::io;
+
+use super::Args;
+use super::config::Config;
+
+use orion::storage::{day_dirs, convert_text_file};
+use orion::storage::{TEXT_FILENAME, BINARY_FILENAME};
+
+/// Convert every `data.txt` file of the data directory to `data.bin`
+///
+/// Days already holding a `data.bin` file are left untouched so running
+/// the conversion twice don't duplicate points.
+pub fn run ( _args: Args, config: &Config ) {
+    trace!("Convert command");
+
+    match convert_all(config) {
+        Ok( (files, points, skipped) ) => {
+            println!("Converted {} points in {} files.", points, files);
+
+            if skipped > 0 {
+                println!("Skipped {} invalid lines.", skipped);
+            }
+        },
+        Err(e) => println!("Conversion failed: {}", e),
+    }
+}
+
+fn convert_all(config: &Config) -> io::Result<(usize, usize, usize)> {
+    let mut files = 0;
+    let mut points = 0;
+    let mut skipped = 0;
+
+    for day in try!( day_dirs(&config.data_path) ) {
+        let text_path = day.path.join(TEXT_FILENAME);
+        let binary_path = day.path.join(BINARY_FILENAME);
+
+        if !text_path.is_file() {
+            continue;
+        }
+
+        if binary_path.exists() {
+            info!("Skip {:?}, already converted", day.path);
+            continue;
+        }
+
+        info!("Convert {:?}", text_path);
+        let (ok, bad) = try!( convert_text_file(&text_path, &binary_path, &day.device) );
+
+        files += 1;
+        points += ok;
+        skipped += bad;
+    }
+
+    Ok( (files, points, skipped) )
+}
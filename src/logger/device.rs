@@ -0,0 +1,108 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::str::FromStr;
+
+use super::Args;
+use super::config::Config;
+use super::messages::*;
+
+use orion::core::Device;
+use orion::storage::{Registry, DeviceInfo, ChannelSchema};
+
+/// Manage the device registry of the data directory
+///
+/// `add` replace the description of an already registered device.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Device command");
+
+    let mut registry = match Registry::open(&config.data_path) {
+        Ok(x)  => x,
+        Err(e) => {
+            println!("Failed to open the device registry: {}", e);
+            ::std::process::exit(1);
+        },
+    };
+
+    if args.cmd_list {
+        for info in registry.iter() {
+            println!("{} {}", info.device.get_slug(), info.name);
+        }
+        return;
+    }
+
+    let device = match Device::with_slug( &args.arg_device ) {
+        Ok(x)  => x,
+        Err(_) => {
+                    print!("{}", INVALID_DEVICE);
+                    ::std::process::exit(1);
+        },
+    };
+
+    if args.cmd_show {
+        match registry.get(&device) {
+            Some(info) => show(info),
+            None       => {
+                println!("Device {} is not registered.", device.get_slug());
+                ::std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if args.cmd_add {
+        let mut info = DeviceInfo::new(device);
+        info.name = args.flag_name.clone();
+        info.location = args.flag_location.clone();
+        info.tags = args.flag_tag.clone();
+
+        for channel in args.flag_channel.iter() {
+            match ChannelSchema::from_str(channel) {
+                Ok(x)  => info.channels.push(x),
+                Err(_) => {
+                    println!("Invalid channel '{}', use name[unit] like temp[K].", channel);
+                    ::std::process::exit(1);
+                },
+            }
+        }
+
+        registry.insert(info);
+    } else if args.cmd_rm {
+        if registry.remove(&device).is_none() {
+            println!("Device {} is not registered.", device.get_slug());
+            ::std::process::exit(1);
+        }
+    } else {
+        panic!("Undefined task in Device");
+    }
+
+    if let Err(e) = registry.save() {
+        println!("Failed to save the device registry: {}", e);
+        ::std::process::exit(1);
+    }
+}
+
+fn show(info: &DeviceInfo) {
+    let channels: Vec<String> = info.channels.iter().map(|c| c.to_string()).collect();
+
+    println!("Device:   {}", info.device.get_slug());
+    println!("Name:     {}", info.name);
+    println!("Location: {}", info.location);
+    println!("Tags:     {}", info.tags.join(" "));
+    println!("Channels: {}", channels.join(" "));
+}
@@ -0,0 +1,74 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::io;
+use chrono::{UTC, DateTime, TimeZone};
+
+use super::Args;
+use super::config::Config;
+use super::validator::OrionLoggerValidator;
+use super::messages::*;
+
+use orion::storage::Storage;
+
+/// Print every stored point of every device
+///
+/// Each line is the device slug followed by a line of a text data file,
+/// like `port@node.driver 2015-06-01T12:00:00+00:00 3[V]`. The time range
+/// can be restricted like with the `query` command.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Export command");
+
+    if (args.flag_from != "" && !args.flag_from.is_rfc3339_timestamp())
+       || (args.flag_to != "" && !args.flag_to.is_rfc3339_timestamp()) {
+        println!("{}", INVALID_TIMESTAMP);
+        return;
+    }
+
+    let from = match DateTime::parse_from_rfc3339(&args.flag_from) {
+        Ok(x)  => x.with_timezone(&UTC),
+        Err(_) => UTC.timestamp(0, 0),
+    };
+
+    let to = match DateTime::parse_from_rfc3339(&args.flag_to) {
+        Ok(x)  => x.with_timezone(&UTC),
+        Err(_) => UTC::now(),
+    };
+
+    let storage = match config.storage_format.open(&config.data_path) {
+        Ok(x)  => x,
+        Err(e) => {
+            println!("Failed to open {} storage: {}", config.storage_format, e);
+            return
+        },
+    };
+
+    if let Err(e) = export(&*storage, &from, &to) {
+        println!("Export failed: {}", e);
+    }
+}
+
+fn export(storage: &Storage, from: &DateTime<UTC>, to: &DateTime<UTC>) -> io::Result<()> {
+    for device in try!( storage.devices() ) {
+        for mp in try!( storage.read_range(&device, from, to) ) {
+            print!("{} {}", device.get_slug(), mp.to_line());
+        }
+    }
+
+    Ok( () )
+}
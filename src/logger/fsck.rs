@@ -0,0 +1,191 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::io;
+
+use super::Args;
+use super::config::Config;
+
+use orion::core::Device;
+use orion::storage::{day_dirs, check_text_file, repair_text_file};
+use orion::storage::{legacy_device_dirs, rename_device_dir};
+use orion::storage::{Format, SqliteStorage, SQLITE_FILENAME};
+use orion::storage::{TEXT_FILENAME, QUARANTINE_FILENAME};
+
+/// Check the devices and every `data.txt` file of the data directory
+///
+/// Every problem is printed as `path:line: problem: 'content'`. With
+/// `--repair`, bad lines are moved to a `data.txt.quarantine` file and
+/// points found in the wrong date directory are moved to the right one.
+///
+/// Devices stored under a name which is not canonical anymore, like
+/// `Temp1@Core.lm`, are hidden from every command. They are reported, and
+/// moved to their lowercase name by `--repair`. Names which are not valid
+/// anymore are moved with `--rename=<old>=<new>`.
+///
+/// The server should be stopped before repairing.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Fsck command");
+
+    let renames = parse_renames(&args.flag_rename);
+
+    let hidden = check_devices(config, args.flag_repair, &renames).unwrap_or_else(|e| {
+        println!("Check failed: {}", e);
+        ::std::process::exit(1);
+    });
+
+    match fsck(config, args.flag_repair) {
+        Ok( (files, issues, quarantined, moved) ) => {
+            println!("Checked {} files, found {} problems.", files, issues);
+
+            if args.flag_repair && (quarantined > 0 || moved > 0) {
+                println!("Quarantined {} lines in {} files, moved {} points.",
+                         quarantined, QUARANTINE_FILENAME, moved);
+            }
+
+            if hidden > 0 {
+                println!("{} devices are hidden by their name.", hidden);
+            }
+
+            if (issues > 0 && !args.flag_repair) || hidden > 0 {
+                ::std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            println!("Check failed: {}", e);
+            ::std::process::exit(1);
+        },
+    }
+}
+
+fn fsck(config: &Config, repair: bool) -> io::Result<(usize, usize, usize, usize)> {
+    let mut files = 0;
+    let mut issues = 0;
+    let mut quarantined = 0;
+    let mut moved = 0;
+
+    for day in try!( day_dirs(&config.data_path) ) {
+        if !day.path.join(TEXT_FILENAME).is_file() {
+            continue;
+        }
+
+        info!("Check {:?}", day.path);
+        let report = try!( check_text_file(&day) );
+
+        for issue in report.issues.iter() {
+            println!("{}:{}: {}: '{}'", report.path.display(), issue.line,
+                                        issue.problem, issue.content);
+        }
+
+        files += 1;
+        issues += report.issues.len();
+
+        if repair && !report.is_clean() {
+            let (q, m) = try!( repair_text_file(&config.data_path, &report) );
+            quarantined += q;
+            moved += m;
+        }
+    }
+
+    Ok( (files, issues, quarantined, moved) )
+}
+
+/// Parse the `old=new` arguments of `--rename`
+fn parse_renames(args: &[String]) -> Vec<(String, Device)> {
+    args.iter().map(|arg| {
+        let device = arg.find('=').and_then(|pos| {
+            Device::with_slug(&arg[pos + 1..]).ok().map(|d| (arg[..pos].to_string(), d))
+        });
+
+        device.unwrap_or_else(|| {
+            println!("Invalid rename '{}', use --rename=<old>=<device>", arg);
+            ::std::process::exit(1);
+        })
+    }).collect()
+}
+
+/// Report and move the devices whose name is not canonical
+///
+/// Return the number of devices still hidden.
+fn check_devices(config: &Config, repair: bool, renames: &[(String, Device)])
+    -> io::Result<usize> {
+
+    // The target of a device, by `--rename` or by `--repair`
+    let target = |slug: &str| {
+        match renames.iter().find(|r| r.0 == slug) {
+            Some(r) => Some(r.1.clone()),
+            None if repair => Device::with_slug(slug).ok(),
+            None => None,
+        }
+    };
+
+    let mut hidden = 0;
+
+    if config.storage_format == Format::Sqlite {
+        let mut storage = try!( SqliteStorage::open(&config.data_path) );
+
+        for slug in try!( storage.legacy_devices() ) {
+            match target(&slug) {
+                Some(device) => {
+                    try!( storage.rename_device(&slug, &device) );
+                    println!("Moved '{}' to '{}'", slug, device);
+                },
+                None => {
+                    report(&config.data_path.join(SQLITE_FILENAME).display().to_string(), &slug);
+                    hidden += 1;
+                },
+            }
+        }
+
+        return Ok(hidden);
+    }
+
+    for dir in try!( legacy_device_dirs(&config.data_path) ) {
+        match target(&dir.slug) {
+            Some(device) => {
+                let conflicts = try!( rename_device_dir(&config.data_path, &dir, &device) );
+
+                for path in conflicts.iter() {
+                    println!("{}: already exists for '{}', merge it by hand",
+                             path.display(), device);
+                }
+
+                if conflicts.is_empty() {
+                    println!("Moved '{}' to '{}'", dir.slug, device);
+                } else {
+                    hidden += 1;
+                }
+            },
+            None => {
+                report(&dir.path.display().to_string(), &dir.slug);
+                hidden += 1;
+            },
+        }
+    }
+
+    Ok(hidden)
+}
+
+fn report(place: &str, slug: &str) {
+    match Device::with_slug(slug) {
+        Ok(device) => println!("{}: hidden device '{}', use --repair to move it to '{}'",
+                               place, slug, device),
+        Err(e)     => println!("{}: hidden device '{}': {}, use --rename={}=<device>",
+                               place, slug, e, slug),
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use chrono::{UTC, Duration};
+
+use super::Args;
+use super::config::Config;
+use super::messages::*;
+
+use orion::logger::parse_last_value;
+
+/// Print the latest point of each device matching a selector, with its age
+///
+/// Lines have the format of the `export` command followed by the age of the
+/// point, like `temp1@core.lm 2015-06-01T12:00:00+00:00 300[K] 5m 2s`. Only
+/// the points received by the server, since it started or before it was
+/// last stopped, are known.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Get command");
+
+    let mut channel = match config.channel() {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            ::std::process::exit(1);
+        },
+    };
+
+    let reply = match channel.request(format!("LOGGER/1.0 GET {}", args.arg_device)) {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            ::std::process::exit(1);
+        },
+    };
+
+    let mut lines = reply.lines();
+
+    if lines.next() != Some("LOGGER/1.0 OK") {
+        println!("Server error: {}", reply);
+        ::std::process::exit(1);
+    }
+
+    let now = UTC::now();
+
+    for line in lines {
+        match parse_last_value(line) {
+            Some(mp) => println!("{} {} {}", mp.get_device().get_slug(),
+                                 mp.to_line().trim_right(), format_age(now - mp.get_date())),
+            None     => println!("{}", line),
+        }
+    }
+}
+
+/// Format `age` with its two largest units, like `2h 5m`
+fn format_age(age: Duration) -> String {
+    let secs = age.num_seconds();
+
+    if secs < 0 {
+        return "in the future".to_string();
+    }
+
+    let units = [ (secs / 86400, "d"), (secs % 86400 / 3600, "h"),
+                  (secs % 3600 / 60, "m"), (secs % 60, "s") ];
+
+    let parts: Vec<String> = units.iter()
+                                  .skip_while(|u| u.0 == 0)
+                                  .take(2)
+                                  .filter(|u| u.0 > 0)
+                                  .map(|u| format!("{}{}", u.0, u.1))
+                                  .collect();
+
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
@@ -28,17 +28,35 @@ extern crate nanomsg;
 
 extern crate env_logger;
 extern crate chrono;
+extern crate toml;
+extern crate openssl;
 
 extern crate orion;
 
 use docopt::Docopt;
+use std::path::Path;
 
 pub mod validator;
 pub mod messages;
 use messages::*;
 
+pub mod config;
+use config::{Config, CONFIG_PATH};
+
 pub mod add;
 pub mod server;
+pub mod convert;
+pub mod query;
+pub mod export;
+pub mod fsck;
+pub mod prune;
+pub mod rollup;
+pub mod device;
+pub mod tail;
+pub mod alerts;
+pub mod mqtt;
+pub mod get;
+pub mod bench;
 
 pub static DATA_PATH: &'static str = "/tmp/data";
 
@@ -46,23 +64,67 @@ static USAGE: &'static str = "
 Orion Backend
 
 Usage:
-    orion-logger [-v --debug] add <value> --now from <device>
-    orion-logger [-v --debug] add <value> --timestamp=<timestamp> from <device>
-    orion-logger [-v --debug] server (start | stop)
+    orion-logger [-v --debug --config=<file>] add <value> --now from <device> [--tag=<tag>...]
+    orion-logger [-v --debug --config=<file>] add <value> --timestamp=<timestamp> from <device> [--tag=<tag>...]
+    orion-logger [-v --debug --config=<file>] server (start | stop)
+    orion-logger [-v --debug --config=<file>] convert
+    orion-logger [-v --debug --config=<file>] query <device> [--from=<timestamp>] [--to=<timestamp>] [--step=<step>] [--agg=<agg>] [--tag=<tag>...] [--group-by=<key>...]
+    orion-logger [-v --debug --config=<file>] export [--from=<timestamp>] [--to=<timestamp>]
+    orion-logger [-v --debug --config=<file>] fsck [--repair] [--rename=<rename>...]
+    orion-logger [-v --debug --config=<file>] prune [--dry-run]
+    orion-logger [-v --debug --config=<file>] rollup
+    orion-logger [-v --debug --config=<file>] device add <device> [--name=<name>] [--location=<location>] [--tag=<tag>...] [--channel=<channel>...]
+    orion-logger [-v --debug --config=<file>] device (list | show <device> | rm <device>)
+    orion-logger [-v --debug --config=<file>] tail <device>
+    orion-logger [-v --debug --config=<file>] alerts list
+    orion-logger [-v --debug --config=<file>] mqtt
+    orion-logger [-v --debug --config=<file>] get <device>
+    orion-logger [-v --debug --config=<file>] bench <device> [--points=<n>] [--batch=<n>]
     orion-logger -h | --help
     orion-logger --version
 
 Options:
     --now                     Use current time as timestamp
     --timestamp <timestamp>   Use an IETF RFC3339 timestamp
+    --from <timestamp>        Start of the time range (RFC3339, included)
+    --to <timestamp>          End of the time range (RFC3339, excluded)
+    --step <step>             Summarize data by step, like 30s, 5m, 1h or 1d
+    --agg <agg>               Aggregate by step with min, max, mean, sum, count,
+                              first, last, stddev, rate or p50, p95, p99...
+    --repair                  Quarantine bad lines and move misplaced points
+                              and devices with a non canonical name
+    --rename <rename>         Move a device with an invalid name, as old=new
+    --dry-run                 Only print what would be removed
+    --name <name>             Human readable name of a device
+    --location <location>     Location of a device
+    --tag <tag>               Tag of a device, or key=value tag of a point
+    --group-by <key>          Aggregate separately by value of a point tag
+    --channel <channel>       Measurement of a device, like temp[K]
+    --points <n>              Points sent by each client [default: 10000]
+    --batch <n>               Most points by request of the async client
+                              [default: 100]
     -v, --verbose             Verbose output.
     -h, --help                Show help.
     --version                 Show version.
     --debug                   Very verbose output.
+    --config <file>           Use this configuration file
+                              [default: /tmp/orion_logger.toml]
 
 Commands:
     add                       Log a new set of data
     server                    Manage orion-logger server
+    convert                   Convert text data files to binary data files
+    query                     Print logged data of a device
+    export                    Print logged data of every device
+    fsck                      Check devices and text data files of the data directory
+    prune                     Remove data expired by the retention rules
+    rollup                    Summarize closed days at the configured resolutions
+    device                    Manage the device registry
+    tail                      Print points of matching devices as they are logged
+    alerts                    Show the state of the alerting rules
+    mqtt                      Log the points published on a MQTT broker
+    get                       Print the latest point of matching devices
+    bench                     Compare the throughput of the sync and async clients
 
 See 'orion-logger help <command>' for more information on a specific command.
 
@@ -78,7 +140,9 @@ fn main() {
                             .and_then(|d|  d.decode())
                             .unwrap_or_else(|e| e.exit() );
     init_logger_with_args(&args);
-    get_command(&args).run( args );
+
+    let config = load_config(&args);
+    get_command(&args).run( args, &config );
 }
 
 
@@ -86,14 +150,38 @@ fn main() {
 enum Command {
     Add,
     Server,
+    Convert,
+    Query,
+    Export,
+    Fsck,
+    Prune,
+    Rollup,
+    Device,
+    Tail,
+    Alerts,
+    Mqtt,
+    Get,
+    Bench,
     Default,
 }
 
 impl Command {
-    fn run ( &self, args: Args ) {
+    fn run ( &self, args: Args, config: &Config ) {
         match *self {
-            Command::Add => add::run( args ),
-            Command::Server => server::run( args ),
+            Command::Add => add::run( args, config ),
+            Command::Server => server::run( args, config ),
+            Command::Convert => convert::run( args, config ),
+            Command::Query => query::run( args, config ),
+            Command::Export => export::run( args, config ),
+            Command::Fsck => fsck::run( args, config ),
+            Command::Prune => prune::run( args, config ),
+            Command::Rollup => rollup::run( args, config ),
+            Command::Device => device::run( args, config ),
+            Command::Tail => tail::run( args ),
+            Command::Alerts => alerts::run( args, config ),
+            Command::Mqtt => mqtt::run( args, config ),
+            Command::Get => get::run( args, config ),
+            Command::Bench => bench::run( args, config ),
             Command::Default => default_cmd_run( args ),
         }
     }
@@ -101,10 +189,35 @@ impl Command {
 
 fn get_command(args: &Args) -> Command {
 
-    if args.cmd_add {
+    // `device add` also set `cmd_add`
+    if args.cmd_device {
+        Command::Device
+    } else if args.cmd_add {
         Command::Add
     } else if args.cmd_server {
         Command::Server
+    } else if args.cmd_convert {
+        Command::Convert
+    } else if args.cmd_query {
+        Command::Query
+    } else if args.cmd_export {
+        Command::Export
+    } else if args.cmd_fsck {
+        Command::Fsck
+    } else if args.cmd_prune {
+        Command::Prune
+    } else if args.cmd_rollup {
+        Command::Rollup
+    } else if args.cmd_tail {
+        Command::Tail
+    } else if args.cmd_alerts {
+        Command::Alerts
+    } else if args.cmd_mqtt {
+        Command::Mqtt
+    } else if args.cmd_get {
+        Command::Get
+    } else if args.cmd_bench {
+        Command::Bench
     } else {
         Command::Default
     }
@@ -125,6 +238,19 @@ fn init_logger_with_args( args: &Args ) {
     env_logger::init().unwrap();
 }
 
+fn load_config( args: &Args ) -> Config {
+    let path = if args.flag_config != "" {
+        args.flag_config.as_ref()
+    } else {
+        CONFIG_PATH
+    };
+
+    Config::load( Path::new(path) ).unwrap_or_else(|e| {
+        println!("Failed to load configuration {}: {}", path, e);
+        ::std::process::exit(1);
+    })
+}
+
 fn default_cmd_run(args: Args) {
 
     if args.flag_version {
@@ -141,10 +267,40 @@ pub struct Args {
     cmd_add: bool,
     cmd_start: bool,
     cmd_stop: bool,
+    cmd_convert: bool,
+    cmd_query: bool,
+    cmd_export: bool,
+    cmd_fsck: bool,
+    cmd_prune: bool,
+    cmd_rollup: bool,
+    cmd_device: bool,
+    cmd_list: bool,
+    cmd_show: bool,
+    cmd_rm: bool,
+    cmd_tail: bool,
+    cmd_alerts: bool,
+    cmd_mqtt: bool,
+    cmd_get: bool,
+    cmd_bench: bool,
     arg_device: String,
     arg_value: String,
     flag_timestamp: String,
     flag_now: bool,
+    flag_from: String,
+    flag_to: String,
+    flag_step: String,
+    flag_agg: String,
+    flag_repair: bool,
+    flag_rename: Vec<String>,
+    flag_dry_run: bool,
+    flag_name: String,
+    flag_location: String,
+    flag_tag: Vec<String>,
+    flag_channel: Vec<String>,
+    flag_group_by: Vec<String>,
+    flag_points: usize,
+    flag_batch: usize,
+    flag_config: String,
     flag_verbose: bool,
     flag_help: bool,
     flag_version: bool,
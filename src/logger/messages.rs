@@ -57,8 +57,19 @@ Valid unit:
 pub static INVALID_DEVICE: &'static str = "
 Invalid device - Device should be port@node.driver
 
+Port, node and driver must start with a letter or a digit, only contain
+letters, digits, `-` or `_` and be at most 64 characters long.
+
+A port reached through several buses is a path of at most 8 sub-addresses
+separated by `/`, from the innermost, the inner ones can't be only digits.
+
 Example:
 
   - temp1@core-isa-000.lm-sensors
   - temp_0@arduino100.arduino_usb
+  - reg40001/slave3@gw1.modbus
+";
+
+pub static SERVER_UNREACHABLE: &'static str = "
+Server unreachable - Start it with `orion-logger server start`
 ";
@@ -0,0 +1,112 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use super::Args;
+use super::config::Config;
+use super::messages::*;
+
+use orion::logger::Channel;
+use orion::mqtt::{Bridge, MqttClient, MqttError};
+
+/// Seconds to wait before connecting again to the broker
+const RECONNECT_DELAY: u64 = 5;
+
+/// Log the points published on the MQTT broker of the configuration
+///
+/// Each message matching a `[mqtt] topics` pattern is sent to the server
+/// like `orion-logger add`. Invalid messages are logged and skipped, the
+/// connection to the broker is retried every few seconds until the server
+/// stop.
+pub fn run ( _: Args, config: &Config ) {
+    trace!("MQTT command");
+
+    let broker = match config.mqtt_broker {
+        Some(ref x) if !config.mqtt_topics.is_empty() => x,
+        _ => {
+            println!("Set the broker and the topics in the [mqtt] section of the configuration.");
+            return
+        },
+    };
+
+    let bridge = Bridge::new(config.mqtt_topics.clone());
+
+    let mut channel = match config.channel() {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            return
+        },
+    };
+
+    loop {
+        match bridge_messages(broker, config, &bridge, &mut channel) {
+            Ok(_)  => return,
+            Err(e) => warn!("MQTT connection to {} failed: {}", broker, e),
+        }
+
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY));
+    }
+}
+
+/// Connect to `broker` and send its messages to the server
+///
+/// Return `Ok` if the server is gone.
+fn bridge_messages(broker: &str, config: &Config, bridge: &Bridge, channel: &mut Channel)
+    -> Result<(), MqttError> {
+
+    let mut client = try!( MqttClient::connect(broker, &config.mqtt_options) );
+    try!( client.subscribe(&bridge.filters(), config.mqtt_qos) );
+    info!("Subscribed to {} on {}", bridge.filters().join(" "), broker);
+
+    loop {
+        let message = try!( client.recv() );
+
+        let mp = match bridge.point(&message) {
+            Some(Ok(x))  => x,
+            Some(Err(e)) => {
+                warn!("Skip message on {}: {}", message.topic, e);
+                try!( client.ack(&message) );
+                continue;
+            },
+            None => {
+                try!( client.ack(&message) );
+                continue;
+            },
+        };
+
+        // Acknowledged once logged, so the broker send it again if the
+        // server is gone
+        match channel.add(&mp) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::Other => {
+                warn!("Point {:?} refused: {}", mp, e);
+            },
+            Err(e) => {
+                println!("Failed to log {:?}: {}", mp, e);
+                let _ = client.disconnect();
+                return Ok( () );
+            },
+        }
+
+        try!( client.ack(&message) );
+    }
+}
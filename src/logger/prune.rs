@@ -0,0 +1,59 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use chrono::UTC;
+
+use super::Args;
+use super::config::Config;
+
+use orion::storage::prune;
+
+/// Remove the day directories expired according to the retention rules
+///
+/// With `--dry-run`, only print what would be removed.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Prune command");
+
+    if config.retention.keeps_forever() {
+        println!("No retention rule, nothing to prune.");
+        return;
+    }
+
+    let report = match prune(&config.data_path, &config.retention,
+                             &UTC::today(), args.flag_dry_run) {
+        Ok(x)  => x,
+        Err(e) => {
+            println!("Pruning failed: {}", e);
+            ::std::process::exit(1);
+        },
+    };
+
+    for day in report.days.iter() {
+        println!("{} {}-{:02}-{:02}", day.device.get_slug(), day.year, day.month, day.day);
+    }
+
+    if args.flag_dry_run {
+        println!("Would remove {} days and reclaim {} bytes.", report.days.len(), report.bytes);
+    } else {
+        println!("Removed {} days and reclaimed {} bytes.", report.days.len(), report.bytes);
+
+        if report.series > 0 {
+            println!("Removed {} unused series from the tag index.", report.series);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use chrono::{UTC, DateTime, TimeZone};
+use std::str::FromStr;
+
+use super::Args;
+use super::config::Config;
+use super::validator::OrionLoggerValidator;
+use super::messages::*;
+
+use orion::core::{Device, DeviceSelector, Tags};
+use orion::storage::{parse_step, query_rollups, Query, Aggregate};
+
+/// Print every point of a device taken in a time range
+///
+/// `--from` default to the epoch and `--to` to the current time.
+///
+/// With `--step`, print instead a summary of each measurement position for
+/// every step, see `Rollup::to_line` for the format.
+///
+/// With `--agg` and `--step`, `<device>` is a `DeviceSelector` like
+/// `temp*@core-isa-*.lm-sensors` and each line hold the slug, the measurement
+/// position, the start of the step and the aggregated value. Only the points
+/// having every `--tag` are aggregated, and with `--group-by` the tags of
+/// each group are printed after the slug.
+pub fn run ( args: Args, config: &Config ) {
+    trace!("Query command");
+
+    if (args.flag_from != "" && !args.flag_from.is_rfc3339_timestamp())
+       || (args.flag_to != "" && !args.flag_to.is_rfc3339_timestamp()) {
+        println!("{}", INVALID_TIMESTAMP);
+        return;
+    }
+
+    let from = match parse_timestamp(&args.flag_from) {
+        Some(x) => x,
+        None    => UTC.timestamp(0, 0),
+    };
+
+    let to = match parse_timestamp(&args.flag_to) {
+        Some(x) => x,
+        None    => UTC::now(),
+    };
+
+    let step = if args.flag_step != "" {
+        match parse_step(&args.flag_step) {
+            Some(x) => Some(x),
+            None    => {
+                println!("Invalid step '{}', use a number followed by s, m, h or d.",
+                         args.flag_step);
+                return
+            },
+        }
+    } else {
+        None
+    };
+
+    let storage = match config.storage_format.open(&config.data_path) {
+        Ok(x)  => x,
+        Err(e) => {
+            println!("Failed to open {} storage: {}", config.storage_format, e);
+            return
+        },
+    };
+
+    if args.flag_agg != "" {
+        let agg = match Aggregate::from_str(&args.flag_agg) {
+            Ok(x)  => x,
+            Err(_) => {
+                println!("Invalid aggregate '{}', use min, max, mean, sum, count, first, \
+                          last, stddev, rate or a percentile like p95.", args.flag_agg);
+                return
+            },
+        };
+
+        let step = match step {
+            Some(x) => x,
+            None    => {
+                println!("--agg needs a --step.");
+                return
+            },
+        };
+
+        let selector = match DeviceSelector::from_str(&args.arg_device) {
+            Ok(x)  => x,
+            Err(_) => {
+                print!("{}", INVALID_DEVICE);
+                return
+            },
+        };
+
+        let filter = match Tags::from_str( &args.flag_tag.join(",") ) {
+            Ok(x)  => x,
+            Err(e) => {
+                println!("Invalid tags '{}': {}, use --tag key=value.",
+                         args.flag_tag.join(" "), e);
+                return
+            },
+        };
+
+        // `parse_step` only return positive steps
+        let mut query = Query::new(selector, from, to, step, agg).unwrap();
+        query.set_filter(filter);
+        query.set_group_by(args.flag_group_by.clone());
+
+        match query.run(&*storage, &config.data_path, &config.rollup_resolutions) {
+            Ok(series) => for s in series.iter() {
+                let slug = if args.flag_group_by.is_empty() {
+                    s.device.get_slug().to_string()
+                } else {
+                    format!("{} {{{}}}", s.device.get_slug(), s.tags)
+                };
+
+                for &(ref date, ref value) in s.values.iter() {
+                    println!("{} {} {} {}", slug, s.channel, date.to_rfc3339(), value);
+                }
+            },
+            Err(e) => println!("Query failed: {}", e),
+        }
+        return
+    }
+
+    let device = match Device::with_slug( &args.arg_device ) {
+        Ok(x)  => x,
+        Err(_) => {
+                    print!("{}", INVALID_DEVICE);
+                    return
+        },
+    };
+
+    if !args.flag_tag.is_empty() || !args.flag_group_by.is_empty() {
+        println!("--tag and --group-by need an --agg.");
+        return
+    }
+
+    if let Some(step) = step {
+        match query_rollups(&*storage, &config.data_path, &device,
+                            &config.rollup_resolutions, &from, &to, &step) {
+            Ok(rollups) => for rollup in rollups.iter() {
+                print!("{}", rollup.to_line());
+            },
+            Err(e) => println!("Query failed: {}", e),
+        }
+        return
+    }
+
+    match storage.read_range(&device, &from, &to) {
+        Ok(points) => for mp in points.iter() {
+            print!("{}", mp.to_line());
+        },
+        Err(e) => println!("Query failed: {}", e),
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<UTC>> {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(x)  => Some( x.with_timezone(&UTC) ),
+        Err(_) => None,
+    }
+}
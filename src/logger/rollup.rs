@@ -0,0 +1,50 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use super::Args;
+use super::config::Config;
+
+use orion::storage::rollup_closed_days;
+
+/// Roll up every closed day of the data directory at the resolutions of
+/// the configuration
+pub fn run ( _args: Args, config: &Config ) {
+    trace!("Rollup command");
+
+    if config.rollup_resolutions.is_empty() {
+        println!("No rollup resolution configured.");
+        return;
+    }
+
+    let storage = match config.storage_format.open(&config.data_path) {
+        Ok(x)  => x,
+        Err(e) => {
+            println!("Failed to open {} storage: {}", config.storage_format, e);
+            ::std::process::exit(1);
+        },
+    };
+
+    match rollup_closed_days(&*storage, &config.data_path,
+                                   &config.rollup_resolutions) {
+        Ok(n)  => println!("Rolled up {} days.", n),
+        Err(e) => {
+            println!("Rollup failed: {}", e);
+            ::std::process::exit(1);
+        },
+    }
+}
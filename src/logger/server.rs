@@ -16,23 +16,50 @@
 // along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::Args;
-use super::DATA_PATH;
+use super::config::Config;
 
 use orion::core::*;
-use orion::logger::Channel;
+use orion::logger::{Channel, PUBLISH_URL, encode_publication};
+use orion::logger::{Metrics, SharedMetrics, LastValues, LAST_VALUES_FILENAME};
+use orion::logger::last_value_line;
+use orion::logger::{AccessList, Client, split_token, authorize, STOP_REQUEST};
+use orion::logger::{is_batch, decode_batch, batch_reply};
+use orion::storage::{Storage, Format, ColumnarStorage, TaggedStorage};
+use orion::storage::{WriteAheadLog, WAL_FILENAME};
+use orion::storage::{RetentionPolicy, prune};
+use orion::storage::rollup_closed_days;
+use orion::storage::Registry;
+use orion::alert::{AlertEngine, Event, Action, ALERT_LOG_FILENAME};
+use orion::http::{Api, HttpServer, FeedServer, FeedSender};
+use orion::ingest::{LineProtocol, listen_tcp, listen_udp, INGEST_QUEUE_LEN};
+use orion::tls;
+use openssl::ssl::SslContext;
 
 use nanomsg::{Socket, Protocol};
+use std::collections::HashMap;
 use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::fs;
+use std::fs::OpenOptions;
+use std::error::Error;
+use std::io;
 use std::io::{Read, Write};
-use std::sync::mpsc::channel;
-
-pub fn run ( args: Args ) {
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+use chrono::UTC;
+
+pub fn run ( args: Args, config: &Config ) {
     trace!("Logger server command");
 
     if args.cmd_start {
-        start();
+        start(config);
     } else if args.cmd_stop {
-        stop();
+        stop(config);
     } else {
         panic!("Undefined task in Logger server");
     }
@@ -41,9 +68,139 @@ pub fn run ( args: Args ) {
 const CLIENT_DEVICE_URL: &'static str = "ipc:///tmp/orion_logger_front.ipc";
 const SERVER_DEVICE_URL: &'static str = "ipc:///tmp/orion_logger_back.ipc";
 
-pub fn start() {
+/// Most TLS clients served at the same time
+const MAX_TLS_CLIENTS: usize = 64;
+
+/// Seconds given to a TLS client for its handshake
+const TLS_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Seconds a TLS client may stay silent, it is disconnected after
+const TLS_IDLE_TIMEOUT: u64 = 300;
+
+pub fn start(config: &Config) {
     trace!("Logger server task 'start'");
 
+    let mut storage = match config.storage_format {
+        Format::Columnar => {
+            let storage = ColumnarStorage::new(&config.data_path);
+            storage.spawn_compaction(config.compaction_interval);
+
+            TaggedStorage::new(Box::new(storage), &config.data_path)
+                          .map(|x| Box::new(x) as Box<Storage>)
+                          .unwrap_or_else(|e| {
+                println!("Failed to open the tag index: {}", e);
+                ::std::process::exit(1);
+            })
+        },
+        format => format.open(&config.data_path).unwrap_or_else(|e| {
+            println!("Failed to open {} storage: {}", format, e);
+            ::std::process::exit(1);
+        }),
+    };
+    info!("Store {} data in {:?}", config.storage_format, config.data_path);
+
+    if !config.retention.keeps_forever() {
+        spawn_pruning(&config.data_path, &config.retention, config.prune_interval);
+    }
+
+    if !config.rollup_resolutions.is_empty() {
+        spawn_rollup(config);
+    }
+
+    if let Err(e) = fs::create_dir_all(&config.data_path) {
+        println!("Failed to create {:?}: {}", config.data_path, e);
+        ::std::process::exit(1);
+    }
+
+    let wal_path = config.data_path.join(WAL_FILENAME);
+    let mut wal = WriteAheadLog::open(&wal_path, config.wal_fsync).unwrap_or_else(|e| {
+        println!("Failed to open write-ahead log {:?}: {}", wal_path, e);
+        ::std::process::exit(1);
+    });
+
+    match wal.recover(&mut *storage) {
+        Ok(0) => {},
+        Ok(n) => println!("Recovered {} points from the write-ahead log.", n),
+        Err(e) => {
+            println!("Failed to recover write-ahead log {:?}: {}", wal_path, e);
+            ::std::process::exit(1);
+        },
+    }
+
+    let registry = Registry::open(&config.data_path).unwrap_or_else(|e| {
+        println!("Failed to open the device registry: {}", e);
+        ::std::process::exit(1);
+    });
+
+    let mut publisher = Socket::new(Protocol::Pub).unwrap();
+    let mut publisher_endpoint = publisher.bind(PUBLISH_URL).unwrap();
+
+    let mut alert_sockets = HashMap::new();
+    let mut alert_endpoints = Vec::new();
+
+    for rule in config.alerts.iter() {
+        for action in rule.get_actions().iter() {
+            if let Action::Socket(ref address) = *action {
+                if alert_sockets.contains_key(address) {
+                    continue;
+                }
+
+                let mut socket = Socket::new(Protocol::Pub).unwrap();
+                let endpoint = socket.bind(address).unwrap_or_else(|e| {
+                    println!("Failed to bind alert socket {}: {}", address, e);
+                    ::std::process::exit(1);
+                });
+
+                alert_sockets.insert(address.clone(), socket);
+                alert_endpoints.push(endpoint);
+            }
+        }
+    }
+
+    let access = config.auth_file.as_ref().map(|path| {
+        let access = AccessList::load(path).unwrap_or_else(|e| {
+            println!("Failed to load the clients {:?}: {}", path, e);
+            ::std::process::exit(1);
+        });
+        info!("Accept requests from {} clients", access.len());
+        access
+    });
+
+    let metrics = Metrics::shared();
+    let last_values_path = config.data_path.join(LAST_VALUES_FILENAME);
+
+    match LastValues::load(&last_values_path) {
+        Ok(values) => metrics.lock().unwrap().last_values = values,
+        Err(e)     => println!("Failed to load the last values {:?}: {}", last_values_path, e),
+    }
+
+    if let Some(ref address) = config.http_listen {
+        spawn_http(config, address, &metrics, &access);
+    }
+
+    let feed = config.http_feed.as_ref().map(|address| spawn_feed(address));
+
+    spawn_ingest(config, &access);
+
+    if let Some(ref address) = config.tls_listen {
+        spawn_tls(config, address, access.clone());
+    }
+
+    let mut server = Server {
+        storage: storage,
+        registry: registry,
+        wal: wal,
+        checkpoint: config.wal_checkpoint,
+        since_checkpoint: 0,
+        publisher: publisher,
+        feed: feed,
+        alerts: AlertEngine::new(config.alerts.clone()),
+        alert_log: config.data_path.join(ALERT_LOG_FILENAME),
+        alert_sockets: alert_sockets,
+        metrics: metrics,
+        access: access,
+    };
+
     thread::spawn( move || {
         let mut front_socket = Socket::new_for_device(Protocol::Rep).unwrap();
         let mut front_endpoint = front_socket.bind(CLIENT_DEVICE_URL).unwrap();
@@ -60,28 +217,24 @@ pub fn start() {
 
     let mut socket = Socket::new(Protocol::Rep).unwrap();
     let mut endpoint = socket.connect(SERVER_DEVICE_URL).unwrap();
-    let mut count = 1u32;
 
-    let mut request = String::new();
+    // Wake up regularly to find silent devices
+    if server.alerts.checks_stale() {
+        socket.set_receive_timeout(1000).unwrap();
+    }
+
+    let mut request = Vec::new();
 
     println!("Server is ready.");
 
     loop {
 
-        match socket.read_to_string(&mut request) {
+        match socket.read_to_end(&mut request) {
             Ok(_) => {
-                let mut q_flag = false;
-                println!("Recv '{}'.", request);
-
-                let reply = if request == "LOGGER/1.0 STOP" {
-                    q_flag = true;
-                     "LOGGER/1.0 OK".to_string()
-                } else {
-                    format!("{} -> Reply #{}", request, count)
-                };
+                let (reply, q_flag) = server.handle_request(&request);
 
                 match socket.write_all(reply.as_bytes()) {
-                    Ok(..) => println!("Sent '{}'.", reply),
+                    Ok(..) => debug!("Sent '{}'.", reply),
                     Err(err) => {
                         println!("Server failed to send reply '{}'.", err);
                         break
@@ -93,8 +246,11 @@ pub fn start() {
                     break
                 }
 
-                thread::sleep_ms(400);
-                count += 1;
+                server.check_stale();
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                request.clear();
+                server.check_stale();
             },
             Err(err) => {
                 println!("Server failed to receive request '{}'.", err);
@@ -103,17 +259,582 @@ pub fn start() {
         }
     }
 
+    if let Err(e) = server.wal.checkpoint(&mut *server.storage) {
+        println!("Failed to flush data: {}", e);
+    }
+
+    let saved = match server.metrics.lock() {
+        Ok(metrics) => metrics.last_values.save(&last_values_path),
+        Err(_)      => Err( io::Error::new(io::ErrorKind::Other, "Metrics poisoned by a panic") ),
+    };
+
+    if let Err(e) = saved {
+        println!("Failed to save the last values {:?}: {}", last_values_path, e);
+    }
+
+    let _ = publisher_endpoint.shutdown();
+
+    for mut endpoint in alert_endpoints {
+        let _ = endpoint.shutdown();
+    }
     endpoint.shutdown();
 }
 
-pub fn stop() {
+/// State of a running server
+struct Server {
+    storage: Box<Storage>,
+    /// Reloaded when changed by `orion-logger device`
+    registry: Registry,
+    wal: WriteAheadLog,
+    /// Number of points between two checkpoints of the write-ahead log
+    checkpoint: usize,
+    since_checkpoint: usize,
+    /// Publish every stored point to the `Subscriber`s
+    publisher: Socket,
+    /// WebSocket live feed, if enabled
+    feed: Option<FeedSender>,
+    alerts: AlertEngine,
+    alert_log: PathBuf,
+    /// PUB sockets of the `Action::Socket` of the rules, by address
+    alert_sockets: HashMap<String, Socket>,
+    /// Counters and latest values, exported by the HTTP API
+    metrics: SharedMetrics,
+    /// Clients allowed to send requests, anyone can without list
+    access: Option<AccessList>,
+}
+
+impl Server {
+
+    /// Process one request and return the reply with a flag set if the
+    /// server must stop
+    fn handle_request(&mut self, request: &[u8]) -> (String, bool) {
+        self.count(|m| m.requests += 1);
+
+        let (client, request) = match authorize(self.access.as_ref(), request) {
+            Ok((client, request)) => (client.cloned(), request),
+            Err(e) => {
+                self.count(|m| m.rejected += 1);
+                return (format!("LOGGER/1.0 ERROR {}", e), false);
+            },
+        };
+
+        if is_binary_frame(request) {
+            return match self.add_point(request, &client) {
+                Ok(_)  => ("LOGGER/1.0 OK".to_string(), false),
+                Err(e) => (format!("LOGGER/1.0 ERROR {}", e), false),
+            };
+        }
+
+        if is_batch(request) {
+            let frames = match decode_batch(request) {
+                Ok(x)  => x,
+                Err(e) => {
+                    self.count(|m| m.rejected += 1);
+                    return (format!("LOGGER/1.0 ERROR {}", e), false);
+                },
+            };
+
+            let results: Vec<_> = frames.iter().map(|&(id, ref frame)| {
+                (id, self.add_point(frame, &client))
+            }).collect();
+
+            return (batch_reply(&results), false);
+        }
+
+        let request = String::from_utf8_lossy(request);
+        debug!("Recv '{}'.", request);
+
+        if request == STOP_REQUEST {
+            ("LOGGER/1.0 OK".to_string(), true)
+        } else if request == "LOGGER/1.0 ALERTS" {
+            let mut reply = "LOGGER/1.0 OK\n".to_string();
+
+            for status in self.alerts.statuses() {
+                reply.push_str(&status.to_line());
+            }
+
+            (reply, false)
+        } else if request.starts_with("LOGGER/1.0 GET ") {
+            let selector = match DeviceSelector::from_str(&request["LOGGER/1.0 GET ".len()..]) {
+                Ok(x)  => x,
+                Err(e) => return (format!("LOGGER/1.0 ERROR {}", e), false),
+            };
+
+            let mut reply = "LOGGER/1.0 OK\n".to_string();
+
+            if let Ok(metrics) = self.metrics.lock() {
+                for mp in metrics.last_values.select(&selector) {
+                    reply.push_str(&last_value_line(mp));
+                }
+            }
+
+            (reply, false)
+        } else {
+            ("LOGGER/1.0 ERROR Unknown request".to_string(), false)
+        }
+    }
+
+    /// Check, store and publish the point of a binary frame sent by
+    /// `client`
+    ///
+    /// Return the reason of the failure.
+    fn add_point(&mut self, frame: &[u8], client: &Option<Client>) -> Result<(), String> {
+        let mp = match MeasurementPoint::from_frame(frame) {
+            Ok(x)  => x,
+            Err(e) => {
+                self.count(|m| m.rejected += 1);
+                return Err( e.description().to_string() );
+            },
+        };
+
+        debug!("Recv point {:?}.", mp);
+
+        if !client.as_ref().map_or(true, |c| c.can_write(mp.get_device())) {
+            self.count(|m| m.rejected += 1);
+            return Err( "Forbidden".to_string() );
+        }
+
+        if let Err(e) = self.registry.reload_if_changed() {
+            error!("Failed to reload the device registry: {}", e);
+        }
+
+        if let Err(e) = self.registry.check(mp.get_device(), mp.get_data()) {
+            self.count(|m| m.rejected += 1);
+            return Err( e.to_string() );
+        }
+
+        match self.store(&mp) {
+            Ok(_)  => {
+                let depth = self.since_checkpoint as u64;
+                self.count(|m| {
+                    m.points += 1;
+                    m.queue_depth = depth;
+                    m.last_values.update(&mp);
+                });
+                self.publish(&mp);
+
+                let events = self.alerts.observe(&mp, &UTC::now());
+                self.run_actions(&events);
+
+                Ok( () )
+            },
+            Err(e) => {
+                self.count(|m| m.write_errors += 1);
+                error!("Failed to store {:?}: {}", mp, e);
+                Err( "Storage failure".to_string() )
+            },
+        }
+    }
+
+    /// Update the shared metrics with `f`
+    fn count<F: FnOnce(&mut Metrics)>(&self, f: F) {
+        match self.metrics.lock() {
+            Ok(mut metrics) => f(&mut metrics),
+            Err(_)          => error!("Metrics poisoned by a panic"),
+        }
+    }
+
+    /// Log `mp` in the write-ahead log then save it in the storage
+    fn store(&mut self, mp: &MeasurementPoint) -> io::Result<()> {
+        try!( self.wal.append(mp) );
+        try!( self.storage.append(mp) );
+
+        self.since_checkpoint += 1;
+
+        if self.since_checkpoint >= self.checkpoint {
+            try!( self.wal.checkpoint(&mut *self.storage) );
+            self.since_checkpoint = 0;
+        }
+
+        Ok( () )
+    }
+
+    /// Send `mp` to the subscribers, it is lost for those too slow
+    fn publish(&mut self, mp: &MeasurementPoint) {
+        if let Err(e) = self.publisher.write_all(&encode_publication(mp)) {
+            error!("Failed to publish {:?}: {}", mp, e);
+        }
+
+        if let Some(ref feed) = self.feed {
+            if !feed.send(mp.clone()) {
+                error!("WebSocket feed stopped");
+            }
+        }
+    }
+
+    fn check_stale(&mut self) {
+        let events = self.alerts.check_stale(&UTC::now());
+        self.run_actions(&events);
+    }
+
+    /// Run the actions of the rule of each event, failures are only logged
+    fn run_actions(&mut self, events: &[Event]) {
+        for event in events.iter() {
+            let line = event.to_line();
+            info!("Alert {}", line.trim_right());
+
+            for action in event.actions.iter() {
+                let result = match *action {
+                    Action::Log => OpenOptions::new()
+                                               .create(true)
+                                               .append(true)
+                                               .open(&self.alert_log)
+                                               .and_then(|mut f| f.write_all(line.as_bytes())),
+                    Action::Command(ref command) => run_command(command, event),
+                    Action::Socket(ref address) => match self.alert_sockets.get_mut(address) {
+                        Some(socket) => socket.write_all(line.as_bytes()),
+                        None         => Ok( () ),
+                    },
+                };
+
+                if let Err(e) = result {
+                    error!("Failed to run alert action {}: {}", action, e);
+                }
+            }
+        }
+    }
+}
+
+/// Run `command` with `sh` without waiting for it
+///
+/// The event is given in the `ORION_ALERT_*` environment variables.
+fn run_command(command: &str, event: &Event) -> io::Result<()> {
+    let value = match event.value {
+        Some(ref m) => m.to_string(),
+        None        => String::new(),
+    };
+
+    let mut child = try!( Command::new("sh")
+                                  .arg("-c")
+                                  .arg(command)
+                                  .env("ORION_ALERT_RULE", &event.rule)
+                                  .env("ORION_ALERT_DEVICE", event.device.get_slug())
+                                  .env("ORION_ALERT_STATE", event.state.to_string())
+                                  .env("ORION_ALERT_PREVIOUS", event.previous.to_string())
+                                  .env("ORION_ALERT_DATE", event.date.to_rfc3339())
+                                  .env("ORION_ALERT_VALUE", value)
+                                  .spawn() );
+
+    thread::spawn( move || {
+        match child.wait() {
+            Ok(status) if !status.success() => warn!("Alert command failed: {}", status),
+            Err(e) => warn!("Alert command failed: {}", e),
+            _ => {},
+        }
+    });
+
+    Ok( () )
+}
+
+/// Remove expired data every `interval` seconds, starting now
+fn spawn_pruning(root: &Path, policy: &RetentionPolicy, interval: u64) {
+    let root = root.to_path_buf();
+    let policy = policy.clone();
+
+    thread::spawn( move || {
+        loop {
+            match prune(&root, &policy, &UTC::today(), false) {
+                Ok(report) => if !report.days.is_empty() {
+                    info!("Pruned {} days and {} series, reclaimed {} bytes",
+                          report.days.len(), report.series, report.bytes);
+                },
+                Err(e) => error!("Pruning failed: {}", e),
+            }
+
+            thread::sleep(Duration::from_secs(interval));
+        }
+    });
+}
+
+/// Roll up closed days every `rollup_interval` seconds, starting now
+///
+/// Points are read through a second `Storage` opened by this thread.
+fn spawn_rollup(config: &Config) {
+    let root = config.data_path.clone();
+    let format = config.storage_format;
+    let resolutions = config.rollup_resolutions.clone();
+    let interval = config.rollup_interval;
+
+    thread::spawn( move || {
+        let storage = match format.open(&root) {
+            Ok(x)  => x,
+            Err(e) => {
+                error!("Rollup disabled, failed to open {} storage: {}", format, e);
+                return;
+            },
+        };
+
+        loop {
+            match rollup_closed_days(&*storage, &root, &resolutions) {
+                Ok(0)  => {},
+                Ok(n)  => info!("Rolled up {} days", n),
+                Err(e) => error!("Rollup failed: {}", e),
+            }
+
+            thread::sleep(Duration::from_secs(interval));
+        }
+    });
+}
+
+/// Serve the HTTP/JSON API on `address`
+///
+/// The API reads through a second `Storage` opened by this thread and send
+/// posted points to the server like `orion-logger add`, so they are checked
+/// and logged in the write-ahead log as any other point. The `metrics` of
+/// the server are exported on `/metrics`. With a client list, the points
+/// are posted with the token of a client and sent with it.
+fn spawn_http(config: &Config, address: &str, metrics: &SharedMetrics,
+              access: &Option<AccessList>) {
+    let mut http = HttpServer::bind(address).unwrap_or_else(|e| {
+        println!("Failed to listen for HTTP on {}: {}", address, e);
+        ::std::process::exit(1);
+    });
+    info!("Serve the HTTP API on {}", address);
+
+    if config.tls_http {
+        http.set_tls( tls_context(config) );
+    }
+
+    let root = config.data_path.clone();
+    let format = config.storage_format;
+    let resolutions = config.rollup_resolutions.clone();
+    let metrics = metrics.clone();
+    let access = access.clone();
+    let token = match access {
+        Some(_) => None,
+        None    => config.auth_token.clone(),
+    };
+
+    thread::spawn( move || {
+        let storage = match format.open(&root) {
+            Ok(x)  => x,
+            Err(e) => {
+                error!("HTTP API disabled, failed to open {} storage: {}", format, e);
+                return;
+            },
+        };
+
+        let mut channel = match Channel::new() {
+            Ok(x)  => x,
+            Err(e) => {
+                error!("HTTP API disabled, failed to connect to the server: {}", e);
+                return;
+            },
+        };
+
+        if let Some(ref token) = token {
+            channel.set_token(token);
+        }
+
+        let mut api = Api::new(storage, &root, Box::new(channel));
+        api.set_resolutions(resolutions);
+        api.set_metrics(metrics);
+        if let Some(access) = access {
+            api.set_access(access);
+        }
+        http.serve(&mut api);
+    });
+}
+
+/// Serve the WebSocket live feed on `address`
+///
+/// Return the handle used by the server to push the accepted points.
+fn spawn_feed(address: &str) -> FeedSender {
+    let feed = FeedServer::bind(address).unwrap_or_else(|e| {
+        println!("Failed to listen for WebSocket on {}: {}", address, e);
+        ::std::process::exit(1);
+    });
+    info!("Serve the WebSocket feed on {}", address);
+
+    let sender = feed.sender();
+    thread::spawn( move || feed.serve() );
+
+    sender
+}
+
+/// Listen for the Graphite and Influx protocols
+///
+/// Their points are sent to the server like `orion-logger add`, by a single
+/// thread reading a queue of `INGEST_QUEUE_LEN` points. With a client list,
+/// they are sent with the token of the `auth.ingest_client` client, so only
+/// its devices are accepted.
+fn spawn_ingest(config: &Config, access: &Option<AccessList>) {
+    let listeners = [
+        (&config.graphite_tcp, true, LineProtocol::Graphite(config.graphite_rules.clone())),
+        (&config.graphite_udp, false, LineProtocol::Graphite(config.graphite_rules.clone())),
+        (&config.influx_tcp, true, LineProtocol::Influx(config.influx_rules.clone())),
+        (&config.influx_udp, false, LineProtocol::Influx(config.influx_rules.clone())),
+    ];
+
+    if listeners.iter().all(|l| l.0.is_none()) {
+        return;
+    }
+
+    let token = match *access {
+        Some(ref access) => {
+            let client = config.ingest_client.as_ref().and_then(|name| access.find(name));
+
+            match client {
+                Some(client) => Some( client.token().to_string() ),
+                None         => {
+                    println!("Set auth.ingest_client to a client of {:?} to accept Graphite \
+                              and Influx points.", config.auth_file.as_ref().unwrap());
+                    ::std::process::exit(1);
+                },
+            }
+        },
+        None => config.auth_token.clone(),
+    };
+
+    let (sender, points) = mpsc::sync_channel(INGEST_QUEUE_LEN);
+
+    for &(address, tcp, ref protocol) in listeners.iter() {
+        let address = match *address {
+            Some(ref x) => x,
+            None        => continue,
+        };
+
+        let result = if tcp {
+            listen_tcp(address, protocol.clone(), sender.clone())
+        } else {
+            listen_udp(address, protocol.clone(), sender.clone())
+        };
+
+        match result {
+            Ok(_)  => info!("Listen for {} on {} {}", protocol.name(),
+                            if tcp { "TCP" } else { "UDP" }, address),
+            Err(e) => {
+                println!("Failed to listen for {} on {}: {}", protocol.name(), address, e);
+                ::std::process::exit(1);
+            },
+        }
+    }
+
+    thread::spawn( move || {
+        let mut channel = match Channel::new() {
+            Ok(x)  => x,
+            Err(e) => {
+                error!("Graphite and Influx disabled, failed to connect to the server: {}", e);
+                return;
+            },
+        };
+
+        if let Some(ref token) = token {
+            channel.set_token(token);
+        }
+
+        for mp in points.iter() {
+            if let Err(e) = channel.add(&mp) {
+                warn!("Point {:?} refused: {}", mp, e);
+            }
+        }
+    });
+}
+
+fn tls_context(config: &Config) -> SslContext {
+    config.tls_options.server_context().unwrap_or_else(|e| {
+        println!("Failed to set up TLS: {}", e);
+        ::std::process::exit(1);
+    })
+}
+
+/// Accept remote clients on `address` over TLS
+///
+/// Each connection is served by a thread relaying its requests to the
+/// server, for at most `MAX_TLS_CLIENTS` at the same time. A client whose
+/// certificate is named like a client of `access` gets its permissions,
+/// others must send their token.
+fn spawn_tls(config: &Config, address: &str, access: Option<AccessList>) {
+    let ctx = Arc::new( tls_context(config) );
+    let access = Arc::new(access);
+
+    let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+        println!("Failed to listen for TLS on {}: {}", address, e);
+        ::std::process::exit(1);
+    });
+    info!("Accept TLS clients on {}", address);
+
+    thread::spawn( move || {
+        let active = Arc::new( AtomicUsize::new(0) );
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(_) if active.load(Ordering::SeqCst) >= MAX_TLS_CLIENTS => {
+                    warn!("TLS client dropped, {} clients already served", MAX_TLS_CLIENTS);
+                    continue;
+                },
+                Ok(x)  => x,
+                Err(e) => {
+                    warn!("Failed to accept a TLS client: {}", e);
+                    continue;
+                },
+            };
+
+            let ctx = ctx.clone();
+            let access = access.clone();
+            let active = active.clone();
+
+            active.fetch_add(1, Ordering::SeqCst);
+            thread::spawn( move || {
+                if let Err(e) = relay_tls(&ctx, stream, &access) {
+                    warn!("TLS client failed: {}", e);
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+/// Relay the requests of a TLS client to the server until it disconnect,
+/// fail its handshake within `TLS_HANDSHAKE_TIMEOUT` or stay silent for
+/// `TLS_IDLE_TIMEOUT`
+fn relay_tls(ctx: &SslContext, stream: TcpStream, access: &Option<AccessList>)
+    -> io::Result<()> {
+
+    let peer = try!( stream.peer_addr() );
+    try!( stream.set_read_timeout(Some(Duration::from_secs(TLS_HANDSHAKE_TIMEOUT))) );
+    try!( stream.set_write_timeout(Some(Duration::from_secs(TLS_HANDSHAKE_TIMEOUT))) );
+    let mut stream = try!( tls::accept(ctx, stream) );
+
+    let token = match (tls::peer_name(&stream), access.as_ref()) {
+        (Some(name), Some(access)) => access.find(&name).map(|c| c.token().to_string()),
+        _                          => None,
+    };
+    debug!("TLS client {} authenticated by certificate: {}", peer, token.is_some());
+
+    let mut channel = try!( Channel::new() );
+
+    if let Some(ref token) = token {
+        channel.set_token(token);
+    }
+
+    loop {
+        let idle = Duration::from_secs(TLS_IDLE_TIMEOUT);
+        try!( stream.get_ref().set_read_timeout(Some(idle)) );
+
+        let request = match try!( tls::read_message(&mut stream) ) {
+            Some(x) => x,
+            None    => return Ok( () ),
+        };
+
+        let request = match token {
+            Some(_) => split_token(&request).1,
+            None    => &request[..],
+        };
+
+        let reply = try!( channel.request_bytes(request) );
+        try!( tls::write_message(&mut stream, reply.as_bytes()) );
+    }
+}
+
+pub fn stop(config: &Config) {
 
     fn stop_failed() -> ! {
         writeln!(&mut ::std::io::stderr(), "Error..").unwrap();
         ::std::process::exit(1);
     }
 
-    let mut channel = Channel::new().unwrap_or_else( |e| stop_failed() );
+    let mut channel = config.channel().unwrap_or_else( |e| stop_failed() );
     let reply = channel.request("LOGGER/1.0 STOP".to_string() )
                        .unwrap_or_else(|e| stop_failed() );
 
@@ -0,0 +1,59 @@
+// Copyright 2015 © Samuel Dolt <samuel@dolt.ch>
+//
+// This file is part of orion_backend.
+//
+// Orion_backend is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Orion_backend is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with orion_backend.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::str::FromStr;
+
+use super::Args;
+use super::messages::*;
+
+use orion::core::DeviceSelector;
+use orion::logger::Subscriber;
+
+/// Print the points of the devices matching a selector as the server
+/// accept them
+///
+/// Lines have the format of the `export` command.
+pub fn run ( args: Args ) {
+    trace!("Tail command");
+
+    let selector = match DeviceSelector::from_str(&args.arg_device) {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", INVALID_DEVICE);
+            return
+        },
+    };
+
+    let subscriber = match Subscriber::new(selector) {
+        Ok(x)  => x,
+        Err(_) => {
+            print!("{}", SERVER_UNREACHABLE);
+            return
+        },
+    };
+
+    for mp in subscriber {
+        match mp {
+            Ok(mp) => print!("{} {}", mp.get_device().get_slug(), mp.to_line()),
+            Err(e) => {
+                println!("Failed to receive points: {}", e);
+                return
+            },
+        }
+    }
+}